@@ -14,7 +14,11 @@ use shared::*;
 static mut CLEANUP_TIMER: Option<TimerId> = None;
 
 #[init]
-fn init() {
+fn init(args: Option<ServiceInitArgs>) {
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    use_local_role_source(|principal| with_storage(|storage| storage.get_user_roles(principal)));
     ic_cdk::println!("Authentication Service canister initialized");
     
     // Start session cleanup timer (runs every hour)
@@ -39,7 +43,12 @@ fn pre_upgrade() {
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<ServiceInitArgs>) {
+    // Keep the previously registered canisters unless a new registry is supplied
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    use_local_role_source(|principal| with_storage(|storage| storage.get_user_roles(principal)));
     ic_cdk::println!("Authentication Service canister upgraded successfully");
 }
 
@@ -120,24 +129,42 @@ async fn create_session(auth_request: AuthRequest) -> StudiFiResult<AuthResponse
     Ok(response)
 }
 
-/// Validate a session and check permissions
-#[query]
-#[candid_method(query)]
+/// Validate a session and check permissions.
+///
+/// An update so that denials can be recorded in the audit log.
+#[update]
+#[candid_method(update)]
 fn validate_session(session_id: String, required_permission: Option<Permission>) -> StudiFiResult<UserSession> {
-    let session = with_storage(|storage| storage.get_session(&session_id))
-        .ok_or_else(|| StudiFiError::NotFound("Session not found".to_string()))?;
+    let caller = caller();
+
+    let session = match with_storage(|storage| storage.get_session(&session_id)) {
+        Some(session) => session,
+        None => {
+            log_access_denied(AuthEventType::InvalidAccess, caller, &session_id, caller, "session not found");
+            return Err(StudiFiError::NotFound("Session not found".to_string()));
+        }
+    };
     
     if session.is_expired() {
+        log_access_denied(AuthEventType::InvalidAccess, session.user_principal, &session_id, caller, "session expired");
         return Err(StudiFiError::Unauthorized("Session expired".to_string()));
     }
     
     if !session.is_active {
+        log_access_denied(AuthEventType::InvalidAccess, session.user_principal, &session_id, caller, "session inactive");
         return Err(StudiFiError::Unauthorized("Session inactive".to_string()));
     }
     
     // Check permission if required
     if let Some(permission) = required_permission {
         if !session.has_permission(&permission) {
+            log_access_denied(
+                AuthEventType::PermissionDenied,
+                session.user_principal,
+                &session_id,
+                caller,
+                &format!("missing {:?}", permission),
+            );
             return Err(StudiFiError::Unauthorized("Insufficient permissions".to_string()));
         }
     }
@@ -145,6 +172,32 @@ fn validate_session(session_id: String, required_permission: Option<Permission>)
     Ok(session)
 }
 
+/// Record a failed session validation requested by `requested_by`
+fn log_access_denied(
+    event_type: AuthEventType,
+    user_principal: Principal,
+    session_id: &str,
+    requested_by: Principal,
+    reason: &str,
+) {
+    let requester = service_for_principal(&requested_by)
+        .map(|service| service.as_str().to_string())
+        .unwrap_or_else(|| requested_by.to_text());
+
+    with_storage_mut(|storage| {
+        let audit_event = AuthAuditEvent {
+            event_id: storage.next_audit_id(),
+            event_type,
+            user_principal,
+            session_id: Some(session_id.to_string()),
+            details: format!("Session validation for {} denied: {}", requester, reason),
+            timestamp: current_time(),
+            ip_address: None,
+        };
+        storage.log_audit_event(audit_event);
+    });
+}
+
 /// Terminate a session
 #[update]
 #[candid_method(update)]
@@ -155,11 +208,8 @@ async fn terminate_session(session_id: String) -> StudiFiResult<String> {
         .ok_or_else(|| StudiFiError::NotFound("Session not found".to_string()))?;
     
     // Only allow termination by session owner or admin
-    if session.user_principal != caller {
-        let caller_roles = with_storage(|storage| storage.get_user_roles(&caller));
-        if !caller_roles.contains(&Role::Admin) && !caller_roles.contains(&Role::SystemAdmin) {
-            return Err(StudiFiError::Unauthorized("Cannot terminate other user's session".to_string()));
-        }
+    if session.user_principal != caller && !is_authorized(&caller, &Permission::ManageUsers) {
+        return Err(StudiFiError::Unauthorized("Cannot terminate other user's session".to_string()));
     }
     
     with_storage_mut(|storage| {
@@ -212,19 +262,13 @@ async fn update_session_activity(session_id: String) -> StudiFiResult<UserSessio
 // ============================================================================
 
 /// Assign roles to a user (admin only)
-#[update]
+#[update(guard = "require_manage_users")]
 #[candid_method(update)]
 async fn assign_roles(request: RoleAssignmentRequest) -> StudiFiResult<String> {
     let caller = caller();
     
-    // Check if caller has admin permissions
-    let caller_roles = with_storage(|storage| storage.get_user_roles(&caller));
-    if !caller_roles.contains(&Role::Admin) && !caller_roles.contains(&Role::SystemAdmin) {
-        return Err(StudiFiError::Unauthorized("Admin role required".to_string()));
-    }
-    
     // Prevent non-system-admins from assigning system admin role
-    if request.roles.contains(&Role::SystemAdmin) && !caller_roles.contains(&Role::SystemAdmin) {
+    if request.roles.contains(&Role::SystemAdmin) && !is_authorized(&caller, &Permission::ManageCanisters) {
         return Err(StudiFiError::Unauthorized("SystemAdmin role required to assign SystemAdmin".to_string()));
     }
     
@@ -254,6 +298,13 @@ fn get_user_roles(user_principal: Principal) -> Vec<Role> {
     with_storage(|storage| storage.get_user_roles(&user_principal))
 }
 
+/// Get every role assignment, for other canisters' role caches
+#[query(guard = "require_service_or_view_all_data")]
+#[candid_method(query)]
+fn get_role_assignments() -> Vec<RoleAssignment> {
+    with_storage(|storage| storage.get_role_assignments())
+}
+
 /// Get current caller's roles
 #[query]
 #[candid_method(query)]
@@ -281,17 +332,17 @@ fn get_role_permissions(role: &Role) -> Vec<Permission> {
             ValidateCredit, VoteOnProposals, ViewOwnData
         ],
         Role::TeamMember => vec![
-            ViewAllData, ManageUsers, AccessAuditLogs, 
+            ViewAllData, ManageUsers, AccessAuditLogs, ReviewCompliance,
             CreateProposals, VoteOnProposals
         ],
         Role::Admin => vec![
             ManageUsers, ViewAllData, ManageSystem, 
-            AccessAuditLogs, CreateProposals, VoteOnProposals
+            AccessAuditLogs, ReviewCompliance, CreateProposals, VoteOnProposals
         ],
         Role::SystemAdmin => vec![
             CreateProfile, UpdateProfile, SubmitLoanApplication, MakePayment, ViewOwnData,
             VoteOnProposals, CreateProposals, ValidateCredit, VerifyStudents, IssueCredentials,
-            ManageUsers, ViewAllData, ManageSystem, AccessAuditLogs, ManageCanisters, SystemMaintenance
+            ManageUsers, ViewAllData, ManageSystem, AccessAuditLogs, ReviewCompliance, ManageCanisters, SystemMaintenance
         ],
     }
 }
@@ -307,11 +358,8 @@ fn get_user_sessions(user_principal: Principal) -> Vec<UserSession> {
     let caller = caller();
 
     // Users can only see their own sessions, admins can see any
-    if caller != user_principal {
-        let caller_roles = with_storage(|storage| storage.get_user_roles(&caller));
-        if !caller_roles.contains(&Role::Admin) && !caller_roles.contains(&Role::SystemAdmin) {
-            return vec![];
-        }
+    if caller != user_principal && !is_authorized(&caller, &Permission::ManageUsers) {
+        return vec![];
     }
 
     with_storage(|storage| storage.get_user_sessions(&user_principal))
@@ -326,16 +374,9 @@ fn get_my_sessions() -> Vec<UserSession> {
 }
 
 /// Get session statistics (admin only)
-#[query]
+#[query(guard = "require_view_all_data")]
 #[candid_method(query)]
 fn get_session_stats() -> StudiFiResult<SessionStats> {
-    let caller = caller();
-    let caller_roles = with_storage(|storage| storage.get_user_roles(&caller));
-
-    if !caller_roles.contains(&Role::Admin) && !caller_roles.contains(&Role::SystemAdmin) {
-        return Err(StudiFiError::Unauthorized("Admin role required".to_string()));
-    }
-
     Ok(with_storage(|storage| storage.calculate_session_stats()))
 }
 
@@ -346,11 +387,8 @@ fn get_audit_events(user_principal: Principal, limit: Option<u32>) -> StudiFiRes
     let caller = caller();
 
     // Users can only see their own audit events, admins can see any
-    if caller != user_principal {
-        let caller_roles = with_storage(|storage| storage.get_user_roles(&caller));
-        if !caller_roles.contains(&Role::Admin) && !caller_roles.contains(&Role::SystemAdmin) {
-            return Err(StudiFiError::Unauthorized("Cannot view other user's audit events".to_string()));
-        }
+    if caller != user_principal && !is_authorized(&caller, &Permission::AccessAuditLogs) {
+        return Err(StudiFiError::Unauthorized("Cannot view other user's audit events".to_string()));
     }
 
     let events = with_storage(|storage| storage.get_user_audit_events(&user_principal));
@@ -375,27 +413,52 @@ fn get_platform_stats() -> Statistics {
     }
 }
 
+// ============================================================================
+// DATA SUBJECT REQUESTS
+// ============================================================================
+
+/// Everything held about a principal, as JSON
+#[query(guard = "require_compliance_service")]
+#[candid_method(query)]
+fn export_subject_data(subject: Principal) -> StudiFiResult<String> {
+    to_export_json(&with_storage(|storage| storage.export_subject_data(&subject)))
+}
+
+/// End a principal's sessions and remove their roles
+#[update(guard = "require_compliance_service")]
+#[candid_method(update)]
+fn erase_subject_data(request: DataErasureRequest) -> StudiFiResult<ErasureReceipt> {
+    Ok(with_storage_mut(|storage| storage.erase_subject_data(&request)))
+}
+
 // ============================================================================
 // MAINTENANCE FUNCTIONS
 // ============================================================================
 
 /// Manually clean up expired sessions (admin only)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 async fn cleanup_expired_sessions() -> StudiFiResult<u32> {
-    let caller = caller();
-    let caller_roles = with_storage(|storage| storage.get_user_roles(&caller));
-
-    if !caller_roles.contains(&Role::Admin) && !caller_roles.contains(&Role::SystemAdmin) {
-        return Err(StudiFiError::Unauthorized("Admin role required".to_string()));
-    }
-
     let cleaned = with_storage_mut(|storage| storage.cleanup_expired_sessions());
 
     ic_cdk::println!("Manually cleaned up {} expired sessions", cleaned);
     Ok(cleaned)
 }
 
+/// Get the canister registry used for inter-canister calls
+#[query]
+#[candid_method(query)]
+fn get_canister_registry() -> CanisterRegistry {
+    current_canister_registry()
+}
+
+/// Get the health of downstream canisters this canister has called since its last upgrade
+#[query]
+#[candid_method(query)]
+fn get_circuit_breakers() -> Vec<CircuitBreaker> {
+    circuit_breaker_states()
+}
+
 candid::export_service!();
 
 #[query(name = "__get_candid_interface_tmp_hack")]
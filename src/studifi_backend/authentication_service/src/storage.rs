@@ -1,87 +1,87 @@
 use std::collections::HashMap;
 use std::cell::RefCell;
 use candid::Principal;
-use ic_stable_structures::{DefaultMemoryImpl, RestrictedMemory, StableBTreeMap, Storable, storable::Bound};
-use std::borrow::Cow;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    DefaultMemoryImpl,
+};
 
 use crate::types::*;
 use shared::*;
 
-type Memory = RestrictedMemory<DefaultMemoryImpl>;
+type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-const SESSIONS_MEMORY_ID: u64 = 0;
-const USER_ROLES_MEMORY_ID: u64 = 1;
-const AUDIT_EVENTS_MEMORY_ID: u64 = 2;
+const SESSIONS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const USER_ROLES_MEMORY_ID: MemoryId = MemoryId::new(1);
+const AUDIT_EVENTS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const CANISTER_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(3);
+const COUNTERS_MEMORY_ID: MemoryId = MemoryId::new(4);
 
-// Storable implementations
-impl Storable for UserSession {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
-    }
+// Stable record versions (UserSession's lives in the shared crate)
 
-    const BOUND: Bound = Bound::Unbounded;
-}
-
-// Wrapper type for Vec<Role> to implement Storable
-#[derive(Clone, Debug)]
+// Wrapper type for Vec<Role> to store as a single record
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct RoleList(pub Vec<Role>);
 
-impl Storable for RoleList {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(&self.0).unwrap())
-    }
+impl VersionedRecord for RoleList {
+    const VERSION: u16 = 1;
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        RoleList(candid::decode_one(&bytes).unwrap())
+    // Role lists were candid-encoded before the envelope was introduced
+    fn decode_legacy(bytes: &[u8]) -> Result<Self, String> {
+        candid::decode_one(bytes)
+            .map(RoleList)
+            .map_err(|e| format!("cannot decode legacy role list: {}", e))
     }
-
-    const BOUND: Bound = Bound::Unbounded;
 }
 
-impl Storable for AuthAuditEvent {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
-    }
+impl VersionedRecord for AuthAuditEvent {
+    const VERSION: u16 = 1;
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+    // Audit events were candid-encoded before the envelope was introduced
+    fn decode_legacy(bytes: &[u8]) -> Result<Self, String> {
+        candid::decode_one(bytes).map_err(|e| format!("cannot decode legacy audit event: {}", e))
     }
+}
+
+// Counter structure for ID generation
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Counters {
+    pub session_counter: u64,
+    pub audit_counter: u64,
+}
 
-    const BOUND: Bound = Bound::Unbounded;
+impl VersionedRecord for Counters {
+    const VERSION: u16 = 1;
 }
 
 /// Authentication storage structure
 pub struct AuthStorage {
-    pub sessions: StableBTreeMap<String, UserSession, Memory>,
-    pub user_roles: StableBTreeMap<Principal, RoleList, Memory>,
-    pub audit_events: StableBTreeMap<String, AuthAuditEvent, Memory>,
-    pub session_counter: u64,
-    pub audit_counter: u64,
+    pub sessions: VersionedMap<String, UserSession, Memory>,
+    pub user_roles: VersionedMap<Principal, RoleList, Memory>,
+    pub audit_events: VersionedMap<String, AuthAuditEvent, Memory>,
+    pub canister_registry: VersionedMap<String, CanisterRegistry, Memory>,
+    pub counters: VersionedMap<String, Counters, Memory>,
 }
 
-impl Default for AuthStorage {
-    fn default() -> Self {
+impl AuthStorage {
+    pub fn init(memory: DefaultMemoryImpl) -> Self {
+        // Canisters installed before the MemoryManager layout kept each map in its own page
+        let memory_manager = init_memory_manager(memory, |memory| vec![
+            read_legacy_map::<String, _>(memory, 0, SESSIONS_MEMORY_ID),
+            read_legacy_map::<Principal, _>(memory, 1, USER_ROLES_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 2, AUDIT_EVENTS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 3, CANISTER_REGISTRY_MEMORY_ID),
+        ]);
+
         Self {
-            sessions: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), SESSIONS_MEMORY_ID..SESSIONS_MEMORY_ID+1)
-            ),
-            user_roles: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), USER_ROLES_MEMORY_ID..USER_ROLES_MEMORY_ID+1)
-            ),
-            audit_events: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), AUDIT_EVENTS_MEMORY_ID..AUDIT_EVENTS_MEMORY_ID+1)
-            ),
-            session_counter: 0,
-            audit_counter: 0,
+            sessions: VersionedMap::init(memory_manager.get(SESSIONS_MEMORY_ID)),
+            user_roles: VersionedMap::init(memory_manager.get(USER_ROLES_MEMORY_ID)),
+            audit_events: VersionedMap::init(memory_manager.get(AUDIT_EVENTS_MEMORY_ID)),
+            canister_registry: VersionedMap::init(memory_manager.get(CANISTER_REGISTRY_MEMORY_ID)),
+            counters: VersionedMap::init(memory_manager.get(COUNTERS_MEMORY_ID)),
         }
     }
-}
 
-impl AuthStorage {
     /// Create a new session
     pub fn create_session(&mut self, session: UserSession) -> String {
         let session_id = session.session_id.clone();
@@ -132,6 +132,14 @@ impl AuthStorage {
         self.user_roles.get(user_principal).map(|role_list| role_list.0).unwrap_or_default()
     }
 
+    /// Get every principal's assigned roles
+    pub fn get_role_assignments(&self) -> Vec<RoleAssignment> {
+        self.user_roles
+            .iter()
+            .map(|(principal, role_list)| RoleAssignment { principal, roles: role_list.0 })
+            .collect()
+    }
+
     /// Add role to user
     pub fn add_user_role(&mut self, user_principal: Principal, role: Role) {
         let mut roles = self.get_user_roles(&user_principal);
@@ -148,10 +156,10 @@ impl AuthStorage {
         self.set_user_roles(user_principal, roles);
     }
 
-    /// Log audit event
+    /// Log audit event, mirroring it to the central audit log
     pub fn log_audit_event(&mut self, event: AuthAuditEvent) {
+        shared::log_audit_event(event.to_audit_event(ic_cdk::id()));
         self.audit_events.insert(event.event_id.clone(), event);
-        self.audit_counter += 1;
     }
 
     /// Get audit events for user
@@ -173,16 +181,30 @@ impl AuthStorage {
             .collect()
     }
 
+    /// ID counters, seeded from existing records for canisters that kept them on the heap
+    fn get_counters(&self) -> Counters {
+        self.counters.get(&"default".to_string()).unwrap_or_else(|| Counters {
+            session_counter: max_generated_id("SESSION", self.sessions.iter().map(|(id, _)| id)),
+            audit_counter: max_generated_id("AUDIT", self.audit_events.iter().map(|(id, _)| id)),
+        })
+    }
+
     /// Generate next session ID
     pub fn next_session_id(&mut self) -> String {
-        self.session_counter += 1;
-        generate_id("SESSION", self.session_counter)
+        let mut counters = self.get_counters();
+        counters.session_counter += 1;
+        let id = generate_id("SESSION", counters.session_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
     }
 
     /// Generate next audit event ID
     pub fn next_audit_id(&mut self) -> String {
-        self.audit_counter += 1;
-        generate_id("AUDIT", self.audit_counter)
+        let mut counters = self.get_counters();
+        counters.audit_counter += 1;
+        let id = generate_id("AUDIT", counters.audit_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
     }
 
     /// Calculate session statistics
@@ -230,10 +252,56 @@ impl AuthStorage {
 
         count
     }
+
+    /// Every session of a user, active or not
+    fn get_all_user_sessions(&self, user_principal: &Principal) -> Vec<UserSession> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| &session.user_principal == user_principal)
+            .map(|(_, session)| session)
+            .collect()
+    }
+
+    pub fn export_subject_data(&self, subject: &Principal) -> AuthSubjectData {
+        AuthSubjectData {
+            sessions: self.get_all_user_sessions(subject),
+            roles: self.get_user_roles(subject),
+            audit_events: self.get_user_audit_events(subject),
+        }
+    }
+
+    /// Remove the subject's sessions and roles. Audit events are retained.
+    pub fn erase_subject_data(&mut self, request: &DataErasureRequest) -> ErasureReceipt {
+        let mut receipt = ErasureReceipt::new(ServiceName::Authentication);
+
+        for session in self.get_all_user_sessions(&request.subject) {
+            self.remove_session(&session.session_id);
+            receipt.erased += 1;
+        }
+        if self.user_roles.remove(&request.subject).is_some() {
+            receipt.erased += 1;
+        }
+        receipt.retain(
+            self.get_user_audit_events(&request.subject).len() as u32,
+            "authentication audit events are kept for the audit retention period",
+        );
+
+        receipt
+    }
+
+    pub fn get_canister_registry(&self) -> CanisterRegistry {
+        self.canister_registry
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_canister_registry(&mut self, registry: CanisterRegistry) {
+        self.canister_registry.insert("default".to_string(), registry);
+    }
 }
 
 thread_local! {
-    static STORAGE: RefCell<AuthStorage> = RefCell::new(AuthStorage::default());
+    static STORAGE: RefCell<AuthStorage> = RefCell::new(AuthStorage::init(DefaultMemoryImpl::default()));
 }
 
 /// Execute a function with read access to storage
@@ -2,60 +2,6 @@ use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
 use shared::*;
 
-/// User session information
-#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
-pub struct UserSession {
-    pub session_id: String,
-    pub user_principal: Principal,
-    pub roles: Vec<Role>,
-    pub created_at: Timestamp,
-    pub expires_at: Timestamp,
-    pub last_activity: Timestamp,
-    pub is_active: bool,
-}
-
-/// User roles in the system
-#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Serialize)]
-pub enum Role {
-    Student,
-    Donor,
-    University,
-    CommunityValidator,
-    TeamMember,
-    Admin,
-    SystemAdmin,
-}
-
-/// Permissions for different operations
-#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Serialize)]
-pub enum Permission {
-    // Student permissions
-    CreateProfile,
-    UpdateProfile,
-    SubmitLoanApplication,
-    MakePayment,
-    ViewOwnData,
-    
-    // Community permissions
-    ValidateCredit,
-    VoteOnProposals,
-    CreateProposals,
-    
-    // University permissions
-    VerifyStudents,
-    IssueCredentials,
-    
-    // Admin permissions
-    ManageUsers,
-    ViewAllData,
-    ManageSystem,
-    AccessAuditLogs,
-    
-    // System permissions
-    ManageCanisters,
-    SystemMaintenance,
-}
-
 /// Authentication request
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct AuthRequest {
@@ -95,6 +41,33 @@ pub struct AuthAuditEvent {
     pub ip_address: Option<String>,
 }
 
+impl AuthAuditEvent {
+    /// Shared form of the event for the central audit log
+    pub fn to_audit_event(&self, canister_id: Principal) -> AuditEvent {
+        let (event_type, function_name, success) = match self.event_type {
+            AuthEventType::SessionCreated => (AuditEventType::SessionCreated, "create_session", true),
+            AuthEventType::SessionExpired => (AuditEventType::SessionTerminated, "cleanup_expired_sessions", true),
+            AuthEventType::SessionTerminated => (AuditEventType::SessionTerminated, "terminate_session", true),
+            AuthEventType::RoleAssigned => (AuditEventType::RoleAssigned, "assign_roles", true),
+            AuthEventType::RoleRevoked => (AuditEventType::RoleRevoked, "assign_roles", true),
+            AuthEventType::PermissionDenied | AuthEventType::InvalidAccess => {
+                (AuditEventType::AccessDenied, "validate_session", false)
+            }
+        };
+
+        AuditEvent {
+            event_id: self.event_id.clone(),
+            event_type,
+            user_principal: self.user_principal,
+            canister_id,
+            function_name: function_name.to_string(),
+            details: self.details.clone(),
+            timestamp: self.timestamp,
+            success,
+        }
+    }
+}
+
 /// Types of authentication events
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub enum AuthEventType {
@@ -117,87 +90,12 @@ pub struct SessionStats {
     pub users_by_role: Vec<(Role, u32)>,
 }
 
-impl UserSession {
-    pub fn new(
-        session_id: String,
-        user_principal: Principal,
-        roles: Vec<Role>,
-        duration_hours: u64,
-    ) -> Self {
-        let now = current_time();
-        let expires_at = now + (duration_hours * 60 * 60 * 1_000_000_000);
-        
-        Self {
-            session_id,
-            user_principal,
-            roles,
-            created_at: now,
-            expires_at,
-            last_activity: now,
-            is_active: true,
-        }
-    }
-    
-    pub fn is_expired(&self) -> bool {
-        current_time() > self.expires_at
-    }
-    
-    pub fn update_activity(&mut self) {
-        self.last_activity = current_time();
-    }
-    
-    pub fn has_role(&self, role: &Role) -> bool {
-        self.roles.contains(role)
-    }
-    
-    pub fn has_permission(&self, permission: &Permission) -> bool {
-        self.roles.iter().any(|role| role_has_permission(role, permission))
-    }
-}
-
-/// Check if a role has a specific permission
-pub fn role_has_permission(role: &Role, permission: &Permission) -> bool {
-    match role {
-        Role::Student => matches!(permission,
-            Permission::CreateProfile |
-            Permission::UpdateProfile |
-            Permission::SubmitLoanApplication |
-            Permission::MakePayment |
-            Permission::ViewOwnData |
-            Permission::VoteOnProposals
-        ),
-        Role::Donor => matches!(permission,
-            Permission::ViewOwnData |
-            Permission::VoteOnProposals |
-            Permission::CreateProposals
-        ),
-        Role::University => matches!(permission,
-            Permission::VerifyStudents |
-            Permission::IssueCredentials |
-            Permission::ViewOwnData
-        ),
-        Role::CommunityValidator => matches!(permission,
-            Permission::ValidateCredit |
-            Permission::VoteOnProposals |
-            Permission::ViewOwnData
-        ),
-        Role::TeamMember => matches!(permission,
-            Permission::ViewAllData |
-            Permission::ManageUsers |
-            Permission::AccessAuditLogs |
-            Permission::CreateProposals |
-            Permission::VoteOnProposals
-        ),
-        Role::Admin => matches!(permission,
-            Permission::ManageUsers |
-            Permission::ViewAllData |
-            Permission::ManageSystem |
-            Permission::AccessAuditLogs |
-            Permission::CreateProposals |
-            Permission::VoteOnProposals
-        ),
-        Role::SystemAdmin => true, // System admin has all permissions
-    }
+/// Everything this service holds about a data subject, for export
+#[derive(Clone, Debug, Serialize)]
+pub struct AuthSubjectData {
+    pub sessions: Vec<UserSession>,
+    pub roles: Vec<Role>,
+    pub audit_events: Vec<AuthAuditEvent>,
 }
 
 /// Default session duration in hours
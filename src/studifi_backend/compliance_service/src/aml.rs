@@ -0,0 +1,422 @@
+use candid::Principal;
+
+use crate::types::*;
+use crate::storage::*;
+use shared::*;
+
+/// Rule hit produced by evaluating a transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleHit {
+    pub rule: AmlRule,
+    pub severity: AlertSeverity,
+    pub description: String,
+}
+
+/// AML transaction monitoring
+pub struct AmlEngine;
+
+impl AmlEngine {
+    /// Record a reported transaction and raise alerts for any rules it trips.
+    ///
+    /// Reports are idempotent per party, reference and type so reporting canisters
+    /// can safely retry.
+    pub fn record_transaction(
+        report: AmlTransactionReport,
+        reported_by: Principal,
+        now: Timestamp,
+    ) -> StudiFiResult<Vec<Alert>> {
+        validate_amount(report.amount)?;
+        if report.reference_id.trim().is_empty() {
+            return Err(StudiFiError::InvalidInput("Reference id cannot be empty".to_string()));
+        }
+
+        with_storage_mut(|storage| {
+            if storage.has_reported_transaction(&report.party, &report.reference_id, &report.transaction_type) {
+                return Ok(Vec::new());
+            }
+
+            let config = storage.get_aml_config();
+            let transaction = AmlTransaction {
+                id: storage.next_aml_transaction_id(),
+                reference_id: report.reference_id,
+                transaction_type: report.transaction_type,
+                amount: report.amount,
+                party: report.party,
+                counterparty: report.counterparty,
+                loan_id: report.loan_id,
+                occurred_at: report.occurred_at,
+                reported_by,
+                recorded_at: now,
+            };
+
+            let lookback = config.structuring_window
+                .max(config.velocity_window)
+                .max(config.circular_flow_window);
+            let since = transaction.occurred_at.saturating_sub(lookback);
+            let history = storage.get_party_transactions_since(&transaction.party, since);
+            let counterparty_history = match transaction.counterparty {
+                Some(counterparty) if counterparty != transaction.party => {
+                    storage.get_party_transactions_since(&counterparty, since)
+                }
+                _ => Vec::new(),
+            };
+
+            storage.insert_aml_transaction(transaction.clone());
+
+            let alerts = Self::evaluate(&transaction, &history, &counterparty_history, &config)
+                .into_iter()
+                .map(|hit| Self::raise_alert(storage, &transaction, hit, now))
+                .collect();
+            Ok(alerts)
+        })
+    }
+
+    /// Apply every rule to `transaction`, given the party's and counterparty's
+    /// earlier transactions
+    pub fn evaluate(
+        transaction: &AmlTransaction,
+        history: &[AmlTransaction],
+        counterparty_history: &[AmlTransaction],
+        config: &AmlConfig,
+    ) -> Vec<RuleHit> {
+        let mut hits = Vec::new();
+        let within = |window: Timestamp| {
+            let since = transaction.occurred_at.saturating_sub(window);
+            move |earlier: &&AmlTransaction| earlier.occurred_at >= since
+        };
+
+        // Threshold
+        if transaction.amount >= config.threshold {
+            let severity = if transaction.amount >= config.threshold.saturating_mul(5) {
+                AlertSeverity::High
+            } else {
+                AlertSeverity::Medium
+            };
+            hits.push(RuleHit {
+                rule: AmlRule::Threshold,
+                severity,
+                description: format!(
+                    "{:?} of {} meets the {} reporting threshold",
+                    transaction.transaction_type,
+                    format_currency(transaction.amount),
+                    format_currency(config.threshold)
+                ),
+            });
+        }
+
+        // Structuring: repeated payments kept just under the threshold
+        let floor = config.structuring_floor();
+        let is_near_threshold = |candidate: &AmlTransaction| {
+            is_payment(&candidate.transaction_type)
+                && candidate.amount >= floor
+                && candidate.amount < config.threshold
+        };
+        if is_near_threshold(transaction) {
+            let near_threshold: Vec<&AmlTransaction> = history
+                .iter()
+                .filter(within(config.structuring_window))
+                .filter(|earlier| is_near_threshold(earlier))
+                .chain(std::iter::once(transaction))
+                .collect();
+            let total: Amount = near_threshold.iter().map(|candidate| candidate.amount).sum();
+
+            if near_threshold.len() as u32 >= config.structuring_min_count && total >= config.threshold {
+                hits.push(RuleHit {
+                    rule: AmlRule::Structuring,
+                    severity: AlertSeverity::High,
+                    description: format!(
+                        "{} payments between {} and {} totalling {} within the structuring window",
+                        near_threshold.len(),
+                        format_currency(floor),
+                        format_currency(config.threshold),
+                        format_currency(total)
+                    ),
+                });
+            }
+        }
+
+        // Velocity
+        let recent: Vec<&AmlTransaction> = history
+            .iter()
+            .filter(within(config.velocity_window))
+            .chain(std::iter::once(transaction))
+            .collect();
+        let recent_total: Amount = recent.iter().map(|candidate| candidate.amount).sum();
+        if recent.len() as u32 > config.velocity_max_count || recent_total > config.velocity_max_amount {
+            hits.push(RuleHit {
+                rule: AmlRule::Velocity,
+                severity: AlertSeverity::Medium,
+                description: format!(
+                    "{} transactions totalling {} within the velocity window",
+                    recent.len(),
+                    format_currency(recent_total)
+                ),
+            });
+        }
+
+        // Circular flow: the counterparty recently paid on a loan of this party
+        if let Some(counterparty) = transaction.counterparty {
+            if counterparty != transaction.party && is_payment(&transaction.transaction_type) {
+                let reverse_flows: Vec<&AmlTransaction> = counterparty_history
+                    .iter()
+                    .filter(within(config.circular_flow_window))
+                    .filter(|earlier| {
+                        is_payment(&earlier.transaction_type) && earlier.counterparty == Some(transaction.party)
+                    })
+                    .collect();
+
+                if !reverse_flows.is_empty() {
+                    let returned: Amount = reverse_flows.iter().map(|earlier| earlier.amount).sum();
+                    hits.push(RuleHit {
+                        rule: AmlRule::CircularFlow,
+                        severity: AlertSeverity::High,
+                        description: format!(
+                            "Paid {} towards a loan of {} after receiving {} in payments from them",
+                            format_currency(transaction.amount),
+                            counterparty,
+                            format_currency(returned)
+                        ),
+                    });
+                }
+            }
+        }
+
+        hits
+    }
+
+    /// Add the transaction to the subject's open alert for the rule, or raise a new one
+    fn raise_alert(
+        storage: &mut ComplianceStorage,
+        transaction: &AmlTransaction,
+        hit: RuleHit,
+        now: Timestamp,
+    ) -> Alert {
+        let alert = match storage.get_active_alert(&transaction.party, &hit.rule) {
+            Some(mut alert) => {
+                alert.transaction_ids.push(transaction.id.clone());
+                if hit.severity > alert.severity {
+                    alert.severity = hit.severity;
+                }
+                alert.description = hit.description;
+                alert.updated_at = now;
+                alert
+            }
+            None => Alert {
+                id: storage.next_alert_id(),
+                rule: hit.rule,
+                severity: hit.severity,
+                status: AlertStatus::Open,
+                subject: transaction.party,
+                transaction_ids: vec![transaction.id.clone()],
+                description: hit.description,
+                assigned_to: None,
+                notes: Vec::new(),
+                raised_at: now,
+                updated_at: now,
+                closed_at: None,
+            },
+        };
+
+        ic_cdk::println!("AML alert {} ({:?}) on {}: {}", alert.id, alert.rule, alert.subject, alert.description);
+        storage.insert_alert(alert.clone());
+        alert
+    }
+
+    /// Unresolved alerts, most severe and then oldest first
+    pub fn get_queue() -> Vec<Alert> {
+        let mut queue: Vec<Alert> = with_storage(|storage| storage.get_all_alerts())
+            .into_iter()
+            .filter(|alert| !alert.status.is_closed())
+            .collect();
+
+        queue.sort_by(|a, b| {
+            b.severity
+                .partial_cmp(&a.severity)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.raised_at.cmp(&b.raised_at))
+        });
+        queue
+    }
+
+    /// Take an open or escalated alert into investigation
+    pub fn assign(alert_id: &str, analyst: Principal, now: Timestamp) -> StudiFiResult<Alert> {
+        Self::update_alert(alert_id, |alert| {
+            if alert.status != AlertStatus::Open && alert.status != AlertStatus::Escalated {
+                return Err(StudiFiError::InvalidInput(
+                    format!("Cannot investigate an alert in status {:?}", alert.status)
+                ));
+            }
+
+            alert.assigned_to = Some(analyst);
+            alert.status = AlertStatus::UnderInvestigation;
+            alert.updated_at = now;
+            Ok(())
+        })
+    }
+
+    pub fn add_note(alert_id: &str, author: Principal, note: String, now: Timestamp) -> StudiFiResult<Alert> {
+        let note = sanitize_text(&note);
+        if note.is_empty() {
+            return Err(StudiFiError::InvalidInput("Note cannot be empty".to_string()));
+        }
+
+        Self::update_alert(alert_id, |alert| {
+            alert.notes.push(AlertNote { author, note, created_at: now });
+            alert.updated_at = now;
+            Ok(())
+        })
+    }
+
+    /// Record the investigating analyst's disposition of an alert
+    pub fn resolve(
+        alert_id: &str,
+        analyst: Principal,
+        disposition: AlertDisposition,
+        reason: String,
+        now: Timestamp,
+    ) -> StudiFiResult<Alert> {
+        let reason = sanitize_text(&reason);
+        if reason.is_empty() {
+            return Err(StudiFiError::InvalidInput("A reason is required for every disposition".to_string()));
+        }
+
+        Self::update_alert(alert_id, |alert| {
+            if alert.status != AlertStatus::UnderInvestigation {
+                return Err(StudiFiError::InvalidInput(
+                    format!("Cannot resolve an alert in status {:?}", alert.status)
+                ));
+            }
+            if alert.assigned_to != Some(analyst) {
+                return Err(StudiFiError::Unauthorized("Alert is assigned to another analyst".to_string()));
+            }
+
+            match disposition {
+                AlertDisposition::Escalate => {
+                    // Escalated alerts go back to the queue for a senior analyst to pick up
+                    alert.status = AlertStatus::Escalated;
+                    alert.assigned_to = None;
+                }
+                AlertDisposition::Dismiss => {
+                    alert.status = AlertStatus::Dismissed;
+                    alert.closed_at = Some(now);
+                }
+                AlertDisposition::Report => {
+                    alert.status = AlertStatus::Reported;
+                    alert.closed_at = Some(now);
+                }
+            }
+
+            alert.notes.push(AlertNote {
+                author: analyst,
+                note: format!("{:?}: {}", disposition, reason),
+                created_at: now,
+            });
+            alert.updated_at = now;
+            Ok(())
+        })
+    }
+
+    pub fn get_stats() -> AmlStats {
+        with_storage(|storage| {
+            let alerts = storage.get_all_alerts();
+
+            let mut stats = AmlStats {
+                total_transactions: storage.count_aml_transactions(),
+                total_alerts: alerts.len() as u32,
+                ..AmlStats::default()
+            };
+            for alert in &alerts {
+                match alert.status {
+                    AlertStatus::Open => stats.open += 1,
+                    AlertStatus::UnderInvestigation => stats.under_investigation += 1,
+                    AlertStatus::Escalated => stats.escalated += 1,
+                    AlertStatus::Dismissed => stats.dismissed += 1,
+                    AlertStatus::Reported => stats.reported += 1,
+                }
+            }
+            stats
+        })
+    }
+
+    fn update_alert(alert_id: &str, update: impl FnOnce(&mut Alert) -> StudiFiResult<()>) -> StudiFiResult<Alert> {
+        with_storage_mut(|storage| {
+            let mut alert = storage.get_alert(alert_id)
+                .ok_or_else(|| StudiFiError::NotFound("Alert not found".to_string()))?;
+
+            update(&mut alert)?;
+            storage.insert_alert(alert.clone());
+            Ok(alert)
+        })
+    }
+}
+
+fn is_payment(transaction_type: &AmlTransactionType) -> bool {
+    matches!(transaction_type, AmlTransactionType::LoanPayment | AmlTransactionType::EarlyPayoff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Timestamp = 60 * 60 * 1_000_000_000;
+
+    fn payment(reference_id: &str, party: Principal, counterparty: Option<Principal>, amount: Amount, at: Timestamp) -> AmlTransactionReport {
+        AmlTransactionReport {
+            reference_id: reference_id.to_string(),
+            transaction_type: AmlTransactionType::LoanPayment,
+            amount,
+            party,
+            counterparty,
+            loan_id: Some("LOAN-1".to_string()),
+            occurred_at: at,
+        }
+    }
+
+    #[test]
+    fn test_rules_and_triage() {
+        let loan_service = Principal::from_slice(&[1]);
+        let student = Principal::from_slice(&[2]);
+        let cosigner = Principal::from_slice(&[3]);
+        let analyst = Principal::from_slice(&[4]);
+
+        // Threshold
+        let alerts = AmlEngine::record_transaction(payment("PAY-1", student, None, AML_CHECK_THRESHOLD, HOUR), loan_service, HOUR).unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule, AmlRule::Threshold);
+
+        // Retried reports are ignored
+        assert!(AmlEngine::record_transaction(payment("PAY-1", student, None, AML_CHECK_THRESHOLD, HOUR), loan_service, HOUR).unwrap().is_empty());
+
+        // Structuring: a third payment just under the threshold within a week
+        let near = AML_CHECK_THRESHOLD - AML_CHECK_THRESHOLD / 100;
+        assert!(AmlEngine::record_transaction(payment("PAY-2", cosigner, None, near, 30 * HOUR), loan_service, 30 * HOUR).unwrap().is_empty());
+        assert!(AmlEngine::record_transaction(payment("PAY-3", cosigner, None, near, 60 * HOUR), loan_service, 60 * HOUR).unwrap().is_empty());
+        let alerts = AmlEngine::record_transaction(payment("PAY-4", cosigner, None, near, 90 * HOUR), loan_service, 90 * HOUR).unwrap();
+        assert_eq!(alerts.iter().map(|alert| alert.rule.clone()).collect::<Vec<_>>(), vec![AmlRule::Structuring]);
+
+        // Circular flow: the cosigner pays the student's loan after the student paid theirs
+        AmlEngine::record_transaction(payment("PAY-5", student, Some(cosigner), MIN_LOAN_AMOUNT, 200 * HOUR), loan_service, 200 * HOUR).unwrap();
+        let alerts = AmlEngine::record_transaction(payment("PAY-6", cosigner, Some(student), MIN_LOAN_AMOUNT, 201 * HOUR), loan_service, 201 * HOUR).unwrap();
+        assert!(alerts.iter().any(|alert| alert.rule == AmlRule::CircularFlow && alert.subject == cosigner));
+
+        // The queue puts the high severity alerts first
+        let queue = AmlEngine::get_queue();
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue[2].rule, AmlRule::Threshold);
+
+        // Triage
+        let alert_id = queue[0].id.clone();
+        assert!(AmlEngine::resolve(&alert_id, analyst, AlertDisposition::Dismiss, "ok".to_string(), 300 * HOUR).is_err());
+        AmlEngine::assign(&alert_id, analyst, 300 * HOUR).unwrap();
+        let escalated = AmlEngine::resolve(&alert_id, analyst, AlertDisposition::Escalate, "needs review".to_string(), 301 * HOUR).unwrap();
+        assert_eq!(escalated.status, AlertStatus::Escalated);
+        AmlEngine::assign(&alert_id, analyst, 302 * HOUR).unwrap();
+        let reported = AmlEngine::resolve(&alert_id, analyst, AlertDisposition::Report, "SAR filed".to_string(), 303 * HOUR).unwrap();
+        assert_eq!(reported.status, AlertStatus::Reported);
+        assert_eq!(AmlEngine::get_queue().len(), 2);
+
+        let stats = AmlEngine::get_stats();
+        assert_eq!(stats.total_transactions, 6);
+        assert_eq!(stats.reported, 1);
+    }
+}
@@ -0,0 +1,220 @@
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+use crate::types::*;
+use crate::storage::*;
+use shared::*;
+
+/// `previous_hash` of the first entry in the chain
+pub const AUDIT_GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Hash-chained, append-only audit log
+pub struct AuditLog;
+
+impl AuditLog {
+    /// Append a batch of events shipped by `source_canister`, returning the new chain length
+    pub fn append(events: Vec<AuditEvent>, source_canister: Principal, now: Timestamp) -> StudiFiResult<u64> {
+        if events.is_empty() {
+            return Err(StudiFiError::InvalidInput("No audit events to append".to_string()));
+        }
+        if events.len() > AUDIT_BATCH_SIZE {
+            return Err(StudiFiError::InvalidInput(
+                format!("At most {} audit events can be appended at once", AUDIT_BATCH_SIZE)
+            ));
+        }
+
+        with_storage_mut(|storage| {
+            let mut previous_hash = storage
+                .get_last_audit_entry()
+                .map(|entry| entry.hash)
+                .unwrap_or_else(|| AUDIT_GENESIS_HASH.to_string());
+
+            for event in events {
+                let mut entry = ChainedAuditEvent {
+                    sequence: storage.audit_log_length(),
+                    event,
+                    source_canister,
+                    ingested_at: now,
+                    previous_hash,
+                    hash: String::new(),
+                };
+                entry.hash = Self::compute_hash(&entry);
+                previous_hash = entry.hash.clone();
+                storage.append_audit_entry(entry);
+            }
+
+            Ok(storage.audit_log_length())
+        })
+    }
+
+    /// SHA-256 over everything in the entry except its own hash
+    pub fn compute_hash(entry: &ChainedAuditEvent) -> String {
+        let contents = serde_json::to_vec(&(
+            entry.sequence,
+            &entry.previous_hash,
+            &entry.event,
+            entry.source_canister,
+            entry.ingested_at,
+        ))
+        .expect("audit entries always serialize");
+
+        hex::encode(Sha256::digest(contents))
+    }
+
+    pub fn head() -> AuditChainHead {
+        with_storage(|storage| AuditChainHead {
+            length: storage.audit_log_length(),
+            head_hash: storage
+                .get_last_audit_entry()
+                .map(|entry| entry.hash)
+                .unwrap_or_else(|| AUDIT_GENESIS_HASH.to_string()),
+        })
+    }
+
+    /// Re-compute every hash from `from` to `to` inclusive and check each entry links
+    /// to the one before it
+    pub fn verify_chain(from: u64, to: u64) -> StudiFiResult<AuditChainVerification> {
+        with_storage(|storage| {
+            let length = storage.audit_log_length();
+            if from > to || from >= length {
+                return Err(StudiFiError::InvalidInput(
+                    format!("Invalid range {}..={} for a chain of {} entries", from, to, length)
+                ));
+            }
+            let to = to.min(length - 1);
+            if to - from + 1 > AUDIT_MAX_VERIFY_RANGE {
+                return Err(StudiFiError::InvalidInput(
+                    format!("At most {} entries can be verified at once", AUDIT_MAX_VERIFY_RANGE)
+                ));
+            }
+
+            let mut report = AuditChainVerification {
+                from,
+                to,
+                entries_checked: 0,
+                is_valid: true,
+                first_invalid_sequence: None,
+                failure_reason: None,
+            };
+
+            let mut expected_previous = match from {
+                0 => AUDIT_GENESIS_HASH.to_string(),
+                _ => match storage.get_audit_entry(from - 1) {
+                    Some(entry) => entry.hash,
+                    None => return Ok(report.fail(from - 1, "entry is missing")),
+                },
+            };
+
+            for sequence in from..=to {
+                let entry = match storage.get_audit_entry(sequence) {
+                    Some(entry) => entry,
+                    None => return Ok(report.fail(sequence, "entry is missing")),
+                };
+                report.entries_checked += 1;
+
+                if entry.sequence != sequence {
+                    return Ok(report.fail(sequence, "entry is stored under the wrong sequence"));
+                }
+                if entry.previous_hash != expected_previous {
+                    return Ok(report.fail(sequence, "previous hash does not match the preceding entry"));
+                }
+                if Self::compute_hash(&entry) != entry.hash {
+                    return Ok(report.fail(sequence, "contents do not match the stored hash"));
+                }
+
+                expected_previous = entry.hash;
+            }
+
+            Ok(report)
+        })
+    }
+
+    /// Entries matching `filter` from `from_sequence` on, oldest first. A page may
+    /// hold fewer than `limit` entries and still have a next page.
+    pub fn query(filter: &AuditEventFilter, from_sequence: Option<u64>, limit: Option<u32>) -> AuditEventPage {
+        let limit = limit
+            .unwrap_or(PaginationParams::default().limit)
+            .clamp(1, AUDIT_QUERY_MAX_LIMIT);
+        let (items, next_sequence) = with_storage(|storage| {
+            storage.query_audit_log(filter, from_sequence.unwrap_or_default(), limit as usize)
+        });
+        AuditEventPage { items, next_sequence }
+    }
+}
+
+impl AuditChainVerification {
+    fn fail(mut self, sequence: u64, reason: &str) -> Self {
+        self.is_valid = false;
+        self.first_invalid_sequence = Some(sequence);
+        self.failure_reason = Some(reason.to_string());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(user: Principal, event_type: AuditEventType, timestamp: Timestamp) -> AuditEvent {
+        AuditEvent {
+            event_id: format!("AUDIT-{}", timestamp),
+            event_type,
+            user_principal: user,
+            canister_id: Principal::anonymous(),
+            function_name: "test".to_string(),
+            details: "details".to_string(),
+            timestamp,
+            success: true,
+        }
+    }
+
+    #[test]
+    fn test_chain_detects_tampering() {
+        let loan_service = Principal::from_slice(&[1]);
+        let credit_service = Principal::from_slice(&[2]);
+        let student = Principal::from_slice(&[3]);
+
+        AuditLog::append(vec![
+            event(student, AuditEventType::LoanCreated, 1),
+            event(student, AuditEventType::PaymentProcessed, 2),
+        ], loan_service, 10).unwrap();
+        let length = AuditLog::append(vec![
+            event(Principal::anonymous(), AuditEventType::LoanApplicationProcessed, 3),
+        ], credit_service, 20).unwrap();
+        assert_eq!(length, 3);
+
+        let filter = AuditEventFilter { user_principal: Some(student), ..Default::default() };
+        assert_eq!(AuditLog::query(&filter, None, None).items.len(), 2);
+        let filter = AuditEventFilter { source_canister: Some(credit_service), ..Default::default() };
+        assert_eq!(AuditLog::query(&filter, None, None).items[0].sequence, 2);
+
+        // Pages continue from the sequence after the last one returned
+        let filter = AuditEventFilter { user_principal: Some(student), ..Default::default() };
+        let page = AuditLog::query(&filter, None, Some(1));
+        assert_eq!((page.items[0].sequence, page.next_sequence), (0, Some(1)));
+        let page = AuditLog::query(&filter, page.next_sequence, Some(1));
+        assert_eq!((page.items[0].sequence, page.next_sequence), (1, None));
+        let filter = AuditEventFilter {
+            event_type: Some(AuditEventType::PaymentProcessed),
+            source_canister: Some(loan_service),
+            ..Default::default()
+        };
+        assert_eq!(AuditLog::query(&filter, None, None).items[0].sequence, 1);
+        assert!(AuditLog::query(&AuditEventFilter::default(), Some(3), None).items.is_empty());
+        assert_eq!(with_storage(|storage| storage.get_user_audit_log(&student)).len(), 2);
+
+        assert!(AuditLog::verify_chain(0, 2).unwrap().is_valid);
+        assert!(AuditLog::verify_chain(3, 5).is_err());
+
+        // Rewriting an entry in place breaks its hash
+        with_storage_mut(|storage| {
+            let mut entry = storage.get_audit_entry(1).unwrap();
+            entry.event.success = false;
+            storage.audit_log.insert(1, entry);
+        });
+        let report = AuditLog::verify_chain(0, 2).unwrap();
+        assert!(!report.is_valid);
+        assert_eq!(report.first_invalid_sequence, Some(1));
+        assert!(AuditLog::verify_chain(2, 2).unwrap().is_valid);
+    }
+}
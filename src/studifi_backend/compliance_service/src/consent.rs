@@ -0,0 +1,210 @@
+use candid::Principal;
+
+use crate::types::*;
+use crate::storage::*;
+use shared::*;
+
+/// Longest notice text that can be published
+const MAX_NOTICE_LENGTH: usize = 10_000;
+
+/// Notice text published for each purpose until compliance publishes its own
+fn default_notice_text(purpose: ConsentPurpose) -> &'static str {
+    match purpose {
+        ConsentPurpose::CreditScoring =>
+            "I agree that StudiFi may use the academic and financial information I submit, \
+             including family income, savings and previous loans, to assess my loan applications.",
+        ConsentPurpose::UniversityVerification =>
+            "I agree that StudiFi may confirm my enrollment and academic standing with my university.",
+        ConsentPurpose::CommunityValidationDisclosure =>
+            "I agree that the evidence supporting my loan application may be shown to community \
+             validators reviewing my credit score.",
+        ConsentPurpose::Marketing =>
+            "I agree to receive news and offers about StudiFi products.",
+    }
+}
+
+/// Purpose-scoped consent ledger
+pub struct ConsentEngine;
+
+impl ConsentEngine {
+    /// Publish version 1 of the default notice for every purpose that has none yet
+    pub fn seed_default_notices(publisher: Principal, now: Timestamp) {
+        with_storage_mut(|storage| {
+            for purpose in ConsentPurpose::ALL {
+                if storage.get_current_consent_notice(purpose).is_none() {
+                    storage.insert_consent_notice(ConsentNotice {
+                        purpose,
+                        version: 1,
+                        text: default_notice_text(purpose).to_string(),
+                        minimum_version: 1,
+                        published_at: now,
+                        published_by: publisher,
+                    });
+                }
+            }
+        });
+    }
+
+    /// Publish a new version of a purpose's notice. With `requires_reconsent` every
+    /// earlier consent stops counting until the subject consents to the new text.
+    pub fn publish_notice(
+        purpose: ConsentPurpose,
+        text: String,
+        requires_reconsent: bool,
+        publisher: Principal,
+        now: Timestamp,
+    ) -> StudiFiResult<ConsentNotice> {
+        let text = text.trim().to_string();
+        if text.is_empty() || text.len() > MAX_NOTICE_LENGTH {
+            return Err(StudiFiError::InvalidInput(
+                format!("Notice text must be between 1 and {} characters", MAX_NOTICE_LENGTH)
+            ));
+        }
+
+        with_storage_mut(|storage| {
+            let current = storage.get_current_consent_notice(purpose);
+            let version = current.as_ref().map_or(1, |notice| notice.version + 1);
+            let minimum_version = match current {
+                Some(notice) if !requires_reconsent => notice.minimum_version,
+                _ => version,
+            };
+
+            let notice = ConsentNotice {
+                purpose,
+                version,
+                text,
+                minimum_version,
+                published_at: now,
+                published_by: publisher,
+            };
+            storage.insert_consent_notice(notice.clone());
+            Ok(notice)
+        })
+    }
+
+    /// Record `subject`'s consent to the notice version they were shown, which must
+    /// be the current one. Consenting again to the same version is a no-op.
+    pub fn grant(
+        subject: Principal,
+        purpose: ConsentPurpose,
+        notice_version: u32,
+        now: Timestamp,
+    ) -> StudiFiResult<ConsentRecord> {
+        if subject == Principal::anonymous() {
+            return Err(StudiFiError::Unauthorized("Anonymous callers cannot give consent".to_string()));
+        }
+
+        with_storage_mut(|storage| {
+            let notice = storage.get_current_consent_notice(purpose).ok_or_else(|| {
+                StudiFiError::NotFound(format!("No {:?} consent notice is published", purpose))
+            })?;
+            if notice.version != notice_version {
+                return Err(StudiFiError::InvalidInput(format!(
+                    "Version {} of the {:?} notice is no longer current; the current version is {}",
+                    notice_version, purpose, notice.version
+                )));
+            }
+
+            if let Some(mut existing) = storage.get_active_consent(&subject, purpose) {
+                if existing.notice_version == notice.version {
+                    return Ok(existing);
+                }
+                // Consenting to newer text supersedes the earlier consent
+                existing.revoked_at = Some(now);
+                storage.insert_consent(existing);
+            }
+
+            let consent = ConsentRecord {
+                id: storage.next_consent_id(),
+                subject,
+                purpose,
+                notice_version: notice.version,
+                text_shown: notice.text,
+                granted_at: now,
+                revoked_at: None,
+            };
+            storage.insert_consent(consent.clone());
+            Ok(consent)
+        })
+    }
+
+    pub fn revoke(subject: Principal, purpose: ConsentPurpose, now: Timestamp) -> StudiFiResult<ConsentRecord> {
+        with_storage_mut(|storage| {
+            let mut consent = storage.get_active_consent(&subject, purpose).ok_or_else(|| {
+                StudiFiError::NotFound(format!("No active {:?} consent to revoke", purpose))
+            })?;
+            consent.revoked_at = Some(now);
+            storage.insert_consent(consent.clone());
+            Ok(consent)
+        })
+    }
+
+    /// Revoke every active consent `subject` holds, returning how many were revoked
+    pub fn revoke_all(subject: &Principal, now: Timestamp) -> u32 {
+        with_storage_mut(|storage| {
+            let active: Vec<ConsentRecord> = storage
+                .get_subject_consents(subject)
+                .into_iter()
+                .filter(|consent| consent.is_active())
+                .collect();
+            for mut consent in active.iter().cloned() {
+                consent.revoked_at = Some(now);
+                storage.insert_consent(consent);
+            }
+            active.len() as u32
+        })
+    }
+
+    /// Whether `subject` has an unrevoked consent to a notice version that is still accepted
+    pub fn has_consent(subject: &Principal, purpose: ConsentPurpose) -> bool {
+        with_storage(|storage| {
+            let minimum_version = match storage.get_current_consent_notice(purpose) {
+                Some(notice) => notice.minimum_version,
+                None => return false,
+            };
+            storage
+                .get_active_consent(subject, purpose)
+                .is_some_and(|consent| consent.notice_version >= minimum_version)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consent_versions_and_revocation() {
+        let officer = Principal::from_slice(&[1]);
+        let student = Principal::from_slice(&[2]);
+        let purpose = ConsentPurpose::CommunityValidationDisclosure;
+        ConsentEngine::seed_default_notices(officer, 1);
+        assert!(!ConsentEngine::has_consent(&student, purpose));
+
+        let consent = ConsentEngine::grant(student, purpose, 1, 2).unwrap();
+        assert_eq!(consent.text_shown, default_notice_text(purpose));
+        assert_eq!(ConsentEngine::grant(student, purpose, 1, 3).unwrap().id, consent.id);
+        assert!(ConsentEngine::has_consent(&student, purpose));
+        assert!(!ConsentEngine::has_consent(&student, ConsentPurpose::Marketing));
+
+        // A wording fix keeps earlier consents; a material change requires them again
+        ConsentEngine::publish_notice(purpose, "Clarified text".to_string(), false, officer, 4).unwrap();
+        assert!(ConsentEngine::has_consent(&student, purpose));
+        assert!(ConsentEngine::grant(student, purpose, 1, 5).is_err());
+        let notice = ConsentEngine::publish_notice(purpose, "New text".to_string(), true, officer, 6).unwrap();
+        assert_eq!((notice.version, notice.minimum_version), (3, 3));
+        assert!(!ConsentEngine::has_consent(&student, purpose));
+
+        let renewed = ConsentEngine::grant(student, purpose, 3, 7).unwrap();
+        assert_eq!(renewed.text_shown, "New text");
+        assert!(ConsentEngine::has_consent(&student, purpose));
+
+        ConsentEngine::revoke(student, purpose, 8).unwrap();
+        assert!(!ConsentEngine::has_consent(&student, purpose));
+        assert!(ConsentEngine::revoke(student, purpose, 9).is_err());
+
+        let history = with_storage(|storage| storage.get_subject_consents(&student));
+        assert_eq!(history.len(), 2);
+        assert!(history.iter().all(|consent| consent.revoked_at.is_some()));
+    }
+}
@@ -0,0 +1,348 @@
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::audit_log::*;
+use crate::consent::*;
+use shared::*;
+
+/// Canisters holding personal data, in the order erasure runs
+const DATA_SUBJECT_SERVICES: [ServiceName; 5] = [
+    ServiceName::LoanManagement,
+    ServiceName::CreditAssessment,
+    ServiceName::StudentIdentity,
+    ServiceName::DaoGovernance,
+    ServiceName::Authentication,
+];
+
+/// Principal class byte reserved by the IC, so pseudonyms never collide with real principals
+const PSEUDONYM_CLASS_TAG: u8 = 0x7f;
+
+thread_local! {
+    static ERASURES_IN_FLIGHT: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Held while an erasure for a subject waits on other canisters, so a second
+/// request for the same subject is turned away instead of racing it
+struct ErasureGuard {
+    subject: Principal,
+}
+
+impl ErasureGuard {
+    fn acquire(subject: Principal) -> StudiFiResult<Self> {
+        if !ERASURES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(subject)) {
+            return Err(StudiFiError::InvalidInput("An erasure for this subject is already in progress".to_string()));
+        }
+        Ok(Self { subject })
+    }
+}
+
+impl Drop for ErasureGuard {
+    fn drop(&mut self) {
+        ERASURES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.subject));
+    }
+}
+
+/// Data subject export and erasure across every StudiFi canister
+pub struct DataSubjectEngine;
+
+impl DataSubjectEngine {
+    /// Collect everything held about `subject` into one JSON bundle. Canisters that
+    /// cannot be reached are listed in the bundle rather than failing the export.
+    pub async fn export(subject: Principal) -> StudiFiResult<String> {
+        Self::require_subject(&subject)?;
+
+        let mut services = serde_json::Map::new();
+        let mut failed_services = Vec::new();
+        for service in DATA_SUBJECT_SERVICES {
+            let export = DataSubjectClient::export(service, subject)
+                .await
+                .and_then(|json| {
+                    serde_json::from_str::<serde_json::Value>(&json)
+                        .map_err(|e| StudiFiError::InternalError(format!("Malformed export: {}", e)))
+                });
+            match export {
+                Ok(data) => {
+                    services.insert(service.as_str().to_string(), data);
+                }
+                Err(e) => {
+                    ic_cdk::println!("Export from {} failed: {}", service.as_str(), e);
+                    failed_services.push(service.as_str().to_string());
+                }
+            }
+        }
+
+        let now = current_time();
+        let mut request = Self::open_request(subject, DataSubjectRequestType::Export, now);
+        request.failed_services = failed_services;
+        Self::finish(&mut request, now);
+
+        let local = serde_json::to_value(Self::export_local(&subject))
+            .map_err(|e| StudiFiError::InternalError(format!("Failed to serialize export: {}", e)))?;
+        services.insert(ServiceName::Compliance.as_str().to_string(), local);
+
+        let bundle = serde_json::json!({
+            "request_id": request.id,
+            "subject": subject.to_text(),
+            "generated_at": now,
+            "services": services,
+            "unavailable_services": request.failed_services,
+        });
+        serde_json::to_string(&bundle)
+            .map_err(|e| StudiFiError::InternalError(format!("Failed to serialize export: {}", e)))
+    }
+
+    /// Erase or pseudonymize `subject`'s records in every canister. Records backing
+    /// outstanding loans and the audit log are retained. Requesting erasure again
+    /// resumes an incomplete request with the same pseudonym, and once the loans are
+    /// repaid runs it again so the records retained for them are pseudonymized too.
+    pub async fn erase(subject: Principal) -> StudiFiResult<DataSubjectRequest> {
+        Self::require_subject(&subject)?;
+        let _guard = ErasureGuard::acquire(subject)?;
+
+        // The request is stored before the first call, so it is on record even if this message traps
+        let mut request = match Self::resumable_erasure(&subject) {
+            Some(mut request) => {
+                if request.status == DataSubjectRequestStatus::PartiallyRetained {
+                    request.receipts.clear();
+                }
+                request
+            }
+            None => Self::open_request(subject, DataSubjectRequestType::Erasure, current_time()),
+        };
+        request.status = DataSubjectRequestStatus::InProgress;
+        request.failed_services.clear();
+        with_storage_mut(|storage| storage.insert_data_subject_request(request.clone()));
+
+        let pseudonym = match Self::prepare_erasure(&mut request).await {
+            Ok(pseudonym) => pseudonym,
+            Err(e) => {
+                request.status = DataSubjectRequestStatus::Incomplete;
+                with_storage_mut(|storage| storage.insert_data_subject_request(request.clone()));
+                return Err(e);
+            }
+        };
+        let has_active_loans = request.has_active_loans;
+
+        for service in DATA_SUBJECT_SERVICES {
+            if request.receipts.iter().any(|receipt| receipt.service == service.as_str()) {
+                continue;
+            }
+            let erasure = DataErasureRequest {
+                request_id: request.id.clone(),
+                subject,
+                pseudonym,
+                has_active_loans,
+            };
+            match DataSubjectClient::erase(service, erasure).await {
+                Ok(receipt) => request.receipts.push(receipt),
+                Err(e) => {
+                    ic_cdk::println!("Erasure in {} failed: {}", service.as_str(), e);
+                    request.failed_services.push(service.as_str().to_string());
+                }
+            }
+        }
+
+        if !request.receipts.iter().any(|receipt| receipt.service == ServiceName::Compliance.as_str()) {
+            request.receipts.push(Self::erase_local(&subject, current_time()));
+        }
+
+        Self::finish(&mut request, current_time());
+        Ok(request)
+    }
+
+    /// Run again every erasure that retained records for loans since repaid
+    pub async fn resume_retained_erasures() -> u32 {
+        let subjects: BTreeSet<Principal> = with_storage(|storage| storage.get_all_data_subject_requests())
+            .into_iter()
+            .filter(|request| request.status == DataSubjectRequestStatus::PartiallyRetained)
+            .map(|request| request.subject)
+            .collect();
+
+        let mut resumed = 0;
+        for subject in subjects {
+            match LoanClient::count_outstanding_loans(subject).await {
+                Ok(0) => match Self::erase(subject).await {
+                    Ok(_) => resumed += 1,
+                    Err(e) => ic_cdk::println!("Resuming erasure for {} failed: {}", subject, e),
+                },
+                Ok(_) => {}
+                Err(e) => ic_cdk::println!("Could not check loans of {}: {}", subject, e),
+            }
+        }
+        resumed
+    }
+
+    /// Whether loans are outstanding, and the request's pseudonym, drawn on its first run.
+    /// Without knowing whether loans are outstanding nothing can safely be erased.
+    async fn prepare_erasure(request: &mut DataSubjectRequest) -> StudiFiResult<Principal> {
+        request.has_active_loans = LoanClient::count_outstanding_loans(request.subject).await? > 0;
+        let pseudonym = match request.pseudonym {
+            Some(pseudonym) => pseudonym,
+            None => Self::new_pseudonym(request.subject).await?,
+        };
+        request.pseudonym = Some(pseudonym);
+        with_storage_mut(|storage| storage.insert_data_subject_request(request.clone()));
+        Ok(pseudonym)
+    }
+
+    /// What this canister holds about `subject`. Everything here is kept under
+    /// AML and audit retention, so erasure only reports it.
+    pub fn export_local(subject: &Principal) -> ComplianceSubjectData {
+        with_storage(|storage| ComplianceSubjectData {
+            kyc_cases: storage.get_subject_cases(subject),
+            screening_profile: storage.get_screening_profile(subject),
+            aml_transactions: storage.get_party_transactions(subject),
+            audit_events: storage.get_user_audit_log(subject),
+            data_subject_requests: storage.get_subject_data_requests(subject),
+            consents: storage.get_subject_consents(subject),
+        })
+    }
+
+    /// Revoke the subject's consents and report what is retained. Consent records
+    /// themselves are kept as evidence of what was agreed to.
+    pub fn erase_local(subject: &Principal, now: Timestamp) -> ErasureReceipt {
+        ConsentEngine::revoke_all(subject, now);
+        let data = Self::export_local(subject);
+        let screenings = with_storage(|storage| storage.get_subject_screenings(subject).len()) as u32;
+        let alerts = with_storage(|storage| storage.get_subject_alerts(subject).len()) as u32;
+
+        let mut receipt = ErasureReceipt::new(ServiceName::Compliance);
+        receipt.retain(
+            data.kyc_cases.len() as u32
+                + data.screening_profile.is_some() as u32
+                + screenings
+                + data.aml_transactions.len() as u32
+                + alerts,
+            "KYC, screening and AML records are kept for the regulatory retention period",
+        );
+        receipt.retain(data.audit_events.len() as u32, "audit log entries are immutable");
+        receipt.retain(data.consents.len() as u32, "consent records are kept, revoked, as evidence of consent");
+        receipt
+    }
+
+    /// Pseudonym derived from the subject and a random seed, so it cannot be
+    /// recomputed from the subject's principal alone
+    pub fn pseudonym_from_seed(subject: &Principal, seed: &[u8]) -> Principal {
+        let digest = Sha256::new()
+            .chain_update(subject.as_slice())
+            .chain_update(seed)
+            .finalize();
+        let mut bytes = digest[..28].to_vec();
+        bytes.push(PSEUDONYM_CLASS_TAG);
+        Principal::from_slice(&bytes)
+    }
+
+    async fn new_pseudonym(subject: Principal) -> StudiFiResult<Principal> {
+        let (seed,) = raw_rand().await.map_err(|(code, message)| {
+            StudiFiError::ExternalServiceError(format!("raw_rand failed ({:?}): {}", code, message))
+        })?;
+        Ok(Self::pseudonym_from_seed(&subject, &seed))
+    }
+
+    fn require_subject(subject: &Principal) -> StudiFiResult<()> {
+        if *subject == Principal::anonymous() {
+            return Err(StudiFiError::Unauthorized("Anonymous callers have no personal data".to_string()));
+        }
+        Ok(())
+    }
+
+    fn resumable_erasure(subject: &Principal) -> Option<DataSubjectRequest> {
+        with_storage(|storage| storage.get_subject_data_requests(subject))
+            .into_iter()
+            .rev()
+            .find(|request| {
+                request.request_type == DataSubjectRequestType::Erasure
+                    && request.status != DataSubjectRequestStatus::Completed
+            })
+    }
+
+    fn open_request(subject: Principal, request_type: DataSubjectRequestType, now: Timestamp) -> DataSubjectRequest {
+        DataSubjectRequest {
+            id: with_storage_mut(|storage| storage.next_data_subject_request_id()),
+            subject,
+            request_type,
+            status: DataSubjectRequestStatus::InProgress,
+            pseudonym: None,
+            has_active_loans: false,
+            receipts: Vec::new(),
+            failed_services: Vec::new(),
+            requested_at: now,
+            completed_at: None,
+        }
+    }
+
+    /// Store the request's outcome and record it in the audit log
+    fn finish(request: &mut DataSubjectRequest, now: Timestamp) {
+        if !request.failed_services.is_empty() {
+            request.status = DataSubjectRequestStatus::Incomplete;
+        } else if request.request_type == DataSubjectRequestType::Erasure && request.has_active_loans {
+            request.status = DataSubjectRequestStatus::PartiallyRetained;
+        } else {
+            request.status = DataSubjectRequestStatus::Completed;
+            request.completed_at = Some(now);
+        }
+        with_storage_mut(|storage| storage.insert_data_subject_request(request.clone()));
+
+        let (event_type, function_name) = match request.request_type {
+            DataSubjectRequestType::Export => (AuditEventType::PersonalDataExported, "export_my_data"),
+            DataSubjectRequestType::Erasure => (AuditEventType::PersonalDataErased, "request_erasure"),
+        };
+        let details = format!(
+            "Data subject request {} {:?}; unavailable services: [{}]",
+            request.id,
+            request.status,
+            request.failed_services.join(", ")
+        );
+        let event = create_audit_event(
+            event_type,
+            request.subject,
+            ic_cdk::id(),
+            function_name,
+            &details,
+            request.failed_services.is_empty(),
+        );
+        if let Err(e) = AuditLog::append(vec![event], ic_cdk::id(), now) {
+            ic_cdk::println!("Failed to log data subject request {}: {}", request.id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pseudonyms_and_retained_records() {
+        let subject = Principal::from_slice(&[7; 29]);
+        let pseudonym = DataSubjectEngine::pseudonym_from_seed(&subject, &[1; 32]);
+        assert_eq!(pseudonym.as_slice().len(), 29);
+        assert_eq!(pseudonym.as_slice()[28], PSEUDONYM_CLASS_TAG);
+        assert_eq!(pseudonym, DataSubjectEngine::pseudonym_from_seed(&subject, &[1; 32]));
+        assert_ne!(pseudonym, DataSubjectEngine::pseudonym_from_seed(&subject, &[2; 32]));
+
+        with_storage_mut(|storage| {
+            let id = storage.next_kyc_case_id();
+            storage.insert_kyc_case(KycCase::new(id, subject, Vec::new(), 1));
+        });
+        let receipt = DataSubjectEngine::erase_local(&subject, 2);
+        assert_eq!(receipt.retained, 1);
+        assert_eq!(receipt.erased, 0);
+        assert_eq!(DataSubjectEngine::export_local(&subject).kyc_cases.len(), 1);
+        assert!(DataSubjectEngine::erase_local(&Principal::from_slice(&[8]), 2).notes.is_empty());
+    }
+
+    #[test]
+    fn test_one_erasure_per_subject_at_a_time() {
+        let subject = Principal::from_slice(&[7; 29]);
+        let guard = ErasureGuard::acquire(subject).unwrap();
+        assert!(ErasureGuard::acquire(subject).is_err());
+        assert!(ErasureGuard::acquire(Principal::from_slice(&[8])).is_ok());
+        drop(guard);
+        assert!(ErasureGuard::acquire(subject).is_ok());
+    }
+}
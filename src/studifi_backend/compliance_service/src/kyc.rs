@@ -0,0 +1,264 @@
+use candid::Principal;
+
+use crate::types::*;
+use crate::storage::*;
+use shared::*;
+
+/// Maximum number of documents attached to a single case
+const MAX_KYC_DOCUMENTS: usize = 10;
+
+/// KYC case management
+pub struct KycEngine;
+
+impl KycEngine {
+    /// Attach documents to the subject's open case, or open a new case.
+    ///
+    /// A new case is opened when the subject has none, or their last case was
+    /// rejected or has expired.
+    pub fn submit_documents(
+        subject: Principal,
+        submissions: Vec<KycDocumentSubmission>,
+        now: Timestamp,
+    ) -> StudiFiResult<KycCase> {
+        if submissions.is_empty() {
+            return Err(StudiFiError::InvalidInput("At least one document is required".to_string()));
+        }
+        if submissions.iter().any(|submission| submission.reference.trim().is_empty()) {
+            return Err(StudiFiError::InvalidInput("Document reference cannot be empty".to_string()));
+        }
+
+        let documents: Vec<KycDocument> = submissions
+            .into_iter()
+            .map(|submission| KycDocument {
+                document_type: submission.document_type,
+                reference: submission.reference,
+                submitted_at: now,
+            })
+            .collect();
+
+        with_storage_mut(|storage| {
+            let case = match storage.get_current_case(&subject) {
+                Some(mut case) if case.status == KycCaseStatus::DocumentsSubmitted => {
+                    case.documents.extend(documents);
+                    case.updated_at = now;
+                    case
+                }
+                Some(case) if case.status == KycCaseStatus::UnderReview => {
+                    return Err(StudiFiError::InvalidInput("KYC case is already under review".to_string()));
+                }
+                Some(case) if case.status == KycCaseStatus::Approved && !case.is_expired_at(now) => {
+                    return Err(StudiFiError::AlreadyExists("KYC is already approved".to_string()));
+                }
+                _ => KycCase::new(storage.next_kyc_case_id(), subject, documents, now),
+            };
+
+            if case.documents.len() > MAX_KYC_DOCUMENTS {
+                return Err(StudiFiError::InvalidInput(
+                    format!("A KYC case holds at most {} documents", MAX_KYC_DOCUMENTS)
+                ));
+            }
+
+            storage.insert_kyc_case(case.clone());
+            Ok(case)
+        })
+    }
+
+    /// Assign a reviewer to a submitted case
+    pub fn start_review(case_id: &str, reviewer: Principal, now: Timestamp) -> StudiFiResult<KycCase> {
+        with_storage_mut(|storage| {
+            let mut case = storage.get_kyc_case(case_id)
+                .ok_or_else(|| StudiFiError::NotFound("KYC case not found".to_string()))?;
+
+            if case.status != KycCaseStatus::DocumentsSubmitted {
+                return Err(StudiFiError::InvalidInput(
+                    format!("Cannot start review of a case in status {:?}", case.status)
+                ));
+            }
+
+            case.reviewer = Some(reviewer);
+            case.transition(KycCaseStatus::UnderReview, now);
+            storage.insert_kyc_case(case.clone());
+            Ok(case)
+        })
+    }
+
+    /// Record a reviewer's decision on a case under review
+    pub fn decide(
+        case_id: &str,
+        reviewer: Principal,
+        decision: KycDecision,
+        reason: String,
+        now: Timestamp,
+    ) -> StudiFiResult<KycCase> {
+        if reason.trim().is_empty() {
+            return Err(StudiFiError::InvalidInput("A reason is required for every decision".to_string()));
+        }
+
+        with_storage_mut(|storage| {
+            let mut case = storage.get_kyc_case(case_id)
+                .ok_or_else(|| StudiFiError::NotFound("KYC case not found".to_string()))?;
+
+            if case.status != KycCaseStatus::UnderReview {
+                return Err(StudiFiError::InvalidInput(
+                    format!("Cannot decide a case in status {:?}", case.status)
+                ));
+            }
+
+            match decision {
+                KycDecision::Approve => {
+                    case.approved_at = Some(now);
+                    case.expires_at = Some(now + KYC_EXPIRY_PERIOD);
+                    case.transition(KycCaseStatus::Approved, now);
+                }
+                KycDecision::Reject => case.transition(KycCaseStatus::Rejected, now),
+            }
+
+            case.reviews.push(KycReview {
+                reviewer,
+                decision,
+                reason,
+                decided_at: now,
+            });
+
+            storage.insert_kyc_case(case.clone());
+            Ok(case)
+        })
+    }
+
+    /// Expire approved cases older than `KYC_EXPIRY_PERIOD`
+    pub fn expire_cases(now: Timestamp) -> u32 {
+        with_storage_mut(|storage| {
+            let expired: Vec<KycCase> = storage
+                .get_cases_by_status(&KycCaseStatus::Approved)
+                .into_iter()
+                .filter(|case| case.is_expired_at(now))
+                .collect();
+
+            let count = expired.len() as u32;
+            for mut case in expired {
+                case.transition(KycCaseStatus::Expired, now);
+                storage.insert_kyc_case(case);
+            }
+            count
+        })
+    }
+
+    /// Check that an entity holds an approved, unexpired case, returning its id
+    pub fn check(entity_id: &Principal, now: Timestamp) -> StudiFiResult<String> {
+        let case = with_storage(|storage| storage.get_current_case(entity_id))
+            .ok_or_else(|| StudiFiError::NotFound("No KYC case for entity".to_string()))?;
+
+        if case.status != KycCaseStatus::Approved {
+            return Err(StudiFiError::Unauthorized(format!("KYC case {} is {:?}", case.id, case.status)));
+        }
+        if case.is_expired_at(now) {
+            return Err(StudiFiError::Expired(format!("KYC case {} has expired", case.id)));
+        }
+
+        Ok(case.id)
+    }
+
+    /// Report a case's current status to student_identity_service
+    pub async fn sync_case(case_id: &str) -> StudiFiResult<()> {
+        let case = with_storage(|storage| storage.get_kyc_case(case_id))
+            .ok_or_else(|| StudiFiError::NotFound("KYC case not found".to_string()))?;
+        if case.identity_synced {
+            return Ok(());
+        }
+
+        let reported = case.status.clone();
+        IdentityClient::apply_kyc_outcome(case.subject, reported.kyc_status(), case.id.clone()).await?;
+
+        // Only acknowledge the status that was reported; it may have moved on during the call
+        with_storage_mut(|storage| {
+            if let Some(mut case) = storage.get_kyc_case(case_id) {
+                if case.status == reported {
+                    case.identity_synced = true;
+                    storage.insert_kyc_case(case);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Retry reporting every case student_identity_service has not acknowledged
+    pub async fn sync_pending_cases() -> u32 {
+        let pending = with_storage(|storage| storage.get_unsynced_cases());
+
+        let mut synced = 0;
+        for case in pending {
+            match Self::sync_case(&case.id).await {
+                Ok(()) => synced += 1,
+                Err(e) => ic_cdk::println!("Failed to sync KYC case {}: {}", case.id, e),
+            }
+        }
+        synced
+    }
+
+    pub fn get_stats() -> KycCaseStats {
+        let cases = with_storage(|storage| storage.get_all_cases());
+
+        let mut stats = KycCaseStats {
+            total_cases: cases.len() as u32,
+            ..KycCaseStats::default()
+        };
+        for case in &cases {
+            match case.status {
+                KycCaseStatus::DocumentsSubmitted => stats.documents_submitted += 1,
+                KycCaseStatus::UnderReview => stats.under_review += 1,
+                KycCaseStatus::Approved => stats.approved += 1,
+                KycCaseStatus::Rejected => stats.rejected += 1,
+                KycCaseStatus::Expired => stats.expired += 1,
+            }
+            if !case.identity_synced {
+                stats.pending_identity_sync += 1;
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Timestamp = 1_000_000_000;
+
+    fn passport() -> Vec<KycDocumentSubmission> {
+        vec![KycDocumentSubmission {
+            document_type: KycDocumentType::Passport,
+            reference: "sha256:0f1e".to_string(),
+        }]
+    }
+
+    #[test]
+    fn test_case_lifecycle() {
+        let subject = Principal::from_slice(&[7]);
+        let reviewer = Principal::from_slice(&[8]);
+
+        let case = KycEngine::submit_documents(subject, passport(), SECOND).unwrap();
+        assert_eq!(case.status, KycCaseStatus::DocumentsSubmitted);
+        assert!(KycEngine::check(&subject, SECOND).is_err());
+
+        // Cannot decide before review starts
+        assert!(KycEngine::decide(&case.id, reviewer, KycDecision::Approve, "ok".to_string(), SECOND).is_err());
+
+        KycEngine::start_review(&case.id, reviewer, 2 * SECOND).unwrap();
+        assert!(KycEngine::submit_documents(subject, passport(), 2 * SECOND).is_err());
+
+        let approved = KycEngine::decide(&case.id, reviewer, KycDecision::Approve, "documents match".to_string(), 3 * SECOND).unwrap();
+        assert_eq!(approved.status, KycCaseStatus::Approved);
+        assert_eq!(approved.reviews.len(), 1);
+        assert_eq!(KycEngine::check(&subject, 4 * SECOND).unwrap(), case.id);
+
+        // Approval lapses after the expiry period, after which a new case can be opened
+        let after_expiry = 3 * SECOND + KYC_EXPIRY_PERIOD;
+        assert_eq!(KycEngine::expire_cases(after_expiry - 1), 0);
+        assert_eq!(KycEngine::expire_cases(after_expiry), 1);
+        assert_eq!(with_storage(|storage| storage.get_kyc_case(&case.id)).unwrap().status, KycCaseStatus::Expired);
+
+        let renewal = KycEngine::submit_documents(subject, passport(), after_expiry).unwrap();
+        assert_ne!(renewal.id, case.id);
+        assert_eq!(with_storage(|storage| storage.get_subject_cases(&subject)).len(), 2);
+    }
+}
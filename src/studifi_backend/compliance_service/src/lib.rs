@@ -1,9 +1,39 @@
+mod types;
+mod storage;
+mod kyc;
+mod aml;
+mod audit_log;
+mod screening;
+mod reporting;
+mod data_subject;
+mod consent;
+
 use candid::{candid_method, Principal};
-use ic_cdk::{query, update, init, pre_upgrade, post_upgrade};
+use ic_cdk::{query, update, init, pre_upgrade, post_upgrade, caller};
+use ic_cdk_timers::set_timer_interval;
+use std::time::Duration;
 use shared::*;
 
+use types::*;
+use storage::*;
+use kyc::*;
+use aml::*;
+use audit_log::*;
+use screening::*;
+use reporting::*;
+use data_subject::*;
+use consent::*;
+
 #[init]
-fn init() {
+fn init(args: Option<ServiceInitArgs>) {
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
+    start_kyc_maintenance();
+    start_report_schedule();
+    start_erasure_maintenance();
+    ConsentEngine::seed_default_notices(ic_cdk::id(), current_time());
     ic_cdk::println!("Compliance Service canister initialized");
 }
 
@@ -13,22 +43,525 @@ fn pre_upgrade() {
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<ServiceInitArgs>) {
+    // Keep the previously registered canisters unless a new registry is supplied
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
+    start_kyc_maintenance();
+    start_report_schedule();
+    start_erasure_maintenance();
+    ConsentEngine::seed_default_notices(ic_cdk::id(), current_time());
     ic_cdk::println!("Compliance Service canister upgraded successfully");
 }
 
+/// Periodically expire lapsed approvals and retry unacknowledged identity updates
+fn start_kyc_maintenance() {
+    set_timer_interval(Duration::from_secs(AUTOMATION_INTERVAL_SECONDS), || {
+        ic_cdk::spawn(async {
+            let expired = KycEngine::expire_cases(current_time());
+            if expired > 0 {
+                ic_cdk::println!("Expired {} KYC cases", expired);
+            }
+            KycEngine::sync_pending_cases().await;
+        });
+    });
+}
+
+/// Periodically generate every regulatory report
+fn start_report_schedule() {
+    set_timer_interval(Duration::from_secs(REPORT_GENERATION_INTERVAL_SECONDS), || {
+        ic_cdk::spawn(ReportEngine::run_schedule());
+    });
+}
+
+/// Periodically finish erasures that were held back by loans since repaid
+fn start_erasure_maintenance() {
+    set_timer_interval(Duration::from_secs(AUTOMATION_INTERVAL_SECONDS), || {
+        ic_cdk::spawn(async {
+            let resumed = DataSubjectEngine::resume_retained_erasures().await;
+            if resumed > 0 {
+                ic_cdk::println!("Resumed {} erasures after loan payoff", resumed);
+            }
+        });
+    });
+}
+
+/// Report a case to student_identity_service, leaving it for the maintenance timer on failure
+async fn sync_case_best_effort(case_id: &str) {
+    if let Err(e) = KycEngine::sync_case(case_id).await {
+        ic_cdk::println!("KYC case {} not yet reported to identity service: {}", case_id, e);
+    }
+}
+
+// ============================================================================
+// KYC CASE MANAGEMENT
+// ============================================================================
+
+/// Submit identity documents, opening a new KYC case if needed
 #[update]
 #[candid_method(update)]
+async fn submit_kyc_documents(documents: Vec<KycDocumentSubmission>) -> StudiFiResult<KycCase> {
+    let case = KycEngine::submit_documents(caller(), documents, current_time())?;
+    sync_case_best_effort(&case.id).await;
+    Ok(case)
+}
+
+/// Take a submitted case into review
+#[update(guard = "require_review_compliance")]
+#[candid_method(update)]
+async fn start_kyc_review(case_id: String) -> StudiFiResult<KycCase> {
+    let case = KycEngine::start_review(&case_id, caller(), current_time())?;
+    sync_case_best_effort(&case.id).await;
+    Ok(case)
+}
+
+/// Approve or reject a case under review
+#[update(guard = "require_review_compliance")]
+#[candid_method(update)]
+async fn decide_kyc_case(case_id: String, decision: KycDecision, reason: String) -> StudiFiResult<KycCase> {
+    let case = KycEngine::decide(&case_id, caller(), decision, reason, current_time())?;
+    sync_case_best_effort(&case.id).await;
+    Ok(case)
+}
+
+/// Check that an entity has passed KYC, returning the approving case id
+#[update(guard = "require_service_or_manage_system")]
+#[candid_method(update)]
 fn perform_kyc_check(entity_id: Principal) -> StudiFiResult<String> {
-    let record_id = generate_id("KYC", 1);
-    ic_cdk::println!("Performed KYC check for: {:?}", entity_id);
-    Ok(record_id)
+    KycEngine::check(&entity_id, current_time())
+}
+
+/// Get a KYC case (its subject or compliance reviewers only)
+#[query]
+#[candid_method(query)]
+fn get_kyc_case(case_id: String) -> StudiFiResult<KycCase> {
+    let caller = caller();
+    let case = with_storage(|storage| storage.get_kyc_case(&case_id))
+        .ok_or_else(|| StudiFiError::NotFound("KYC case not found".to_string()))?;
+
+    if case.subject != caller && !is_authorized(&caller, &Permission::ReviewCompliance) {
+        return Err(StudiFiError::Unauthorized("Cannot view other user's KYC case".to_string()));
+    }
+
+    Ok(case)
+}
+
+/// Get the caller's KYC cases, oldest first
+#[query]
+#[candid_method(query)]
+fn get_my_kyc_cases() -> Vec<KycCase> {
+    let caller = caller();
+    with_storage(|storage| storage.get_subject_cases(&caller))
+}
+
+/// Get a principal's KYC cases, oldest first
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_subject_kyc_cases(subject: Principal) -> Vec<KycCase> {
+    with_storage(|storage| storage.get_subject_cases(&subject))
+}
+
+/// Get KYC cases in a given status, e.g. the review queue
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_kyc_cases_by_status(status: KycCaseStatus) -> Vec<KycCase> {
+    with_storage(|storage| storage.get_cases_by_status(&status))
+}
+
+/// Get KYC case statistics
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_kyc_stats() -> KycCaseStats {
+    KycEngine::get_stats()
+}
+
+/// Manually expire lapsed approvals and retry pending identity updates
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+async fn process_kyc_expirations() -> StudiFiResult<u32> {
+    let expired = KycEngine::expire_cases(current_time());
+    KycEngine::sync_pending_cases().await;
+    Ok(expired)
+}
+
+// ============================================================================
+// AML MONITORING
+// ============================================================================
+
+/// Record a money movement reported by another StudiFi canister, returning the ids of any alerts raised
+#[update(guard = "require_service_or_manage_system")]
+#[candid_method(update)]
+fn report_aml_transaction(report: AmlTransactionReport) -> StudiFiResult<Vec<String>> {
+    let alerts = AmlEngine::record_transaction(report, caller(), current_time())?;
+    Ok(alerts.into_iter().map(|alert| alert.id).collect())
+}
+
+/// Get unresolved alerts, most severe first
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_alert_queue() -> Vec<Alert> {
+    AmlEngine::get_queue()
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_alert(alert_id: String) -> Option<Alert> {
+    with_storage(|storage| storage.get_alert(&alert_id))
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_alerts_by_status(status: AlertStatus) -> Vec<Alert> {
+    with_storage(|storage| storage.get_alerts_by_status(&status))
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_subject_alerts(subject: Principal) -> Vec<Alert> {
+    with_storage(|storage| storage.get_subject_alerts(&subject))
+}
+
+/// Get a party's monitored transactions, oldest first
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_party_aml_transactions(party: Principal) -> Vec<AmlTransaction> {
+    with_storage(|storage| storage.get_party_transactions(&party))
+}
+
+/// Take an open or escalated alert into investigation
+#[update(guard = "require_review_compliance")]
+#[candid_method(update)]
+fn assign_alert(alert_id: String) -> StudiFiResult<Alert> {
+    AmlEngine::assign(&alert_id, caller(), current_time())
+}
+
+#[update(guard = "require_review_compliance")]
+#[candid_method(update)]
+fn add_alert_note(alert_id: String, note: String) -> StudiFiResult<Alert> {
+    AmlEngine::add_note(&alert_id, caller(), note, current_time())
+}
+
+/// Escalate, dismiss or report an alert under investigation
+#[update(guard = "require_review_compliance")]
+#[candid_method(update)]
+fn resolve_alert(alert_id: String, disposition: AlertDisposition, reason: String) -> StudiFiResult<Alert> {
+    AmlEngine::resolve(&alert_id, caller(), disposition, reason, current_time())
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_aml_stats() -> AmlStats {
+    AmlEngine::get_stats()
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_aml_config() -> AmlConfig {
+    with_storage(|storage| storage.get_aml_config())
+}
+
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+fn update_aml_config(config: AmlConfig) -> StudiFiResult<()> {
+    config.validate()?;
+    with_storage_mut(|storage| storage.set_aml_config(config));
+    Ok(())
+}
+
+// ============================================================================
+// SANCTIONS SCREENING
+// ============================================================================
+
+/// Screen a party against the loaded watchlists on behalf of another StudiFi canister
+#[update(guard = "require_service_or_manage_system")]
+#[candid_method(update)]
+fn screen_party(request: ScreeningRequest) -> StudiFiResult<ScreeningDecision> {
+    let result = ScreeningEngine::screen(request, caller(), current_time())?;
+    Ok(ScreeningDecision {
+        screening_id: result.id,
+        outcome: result.outcome,
+    })
+}
+
+/// Submit the caller's own details for screening, e.g. as a donor or cosigner
+#[update]
+#[candid_method(update)]
+fn submit_screening_details(
+    full_name: String,
+    date_of_birth: Option<String>,
+    country: Option<String>,
+) -> StudiFiResult<ScreeningProfile> {
+    ScreeningEngine::submit_profile(caller(), full_name, date_of_birth, country, current_time())
+}
+
+/// Upload a batch of watchlist entries for a list
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+fn upload_watchlist_entries(list_name: String, entries: Vec<WatchlistEntryInput>) -> StudiFiResult<u32> {
+    ScreeningEngine::upload_entries(list_name, entries, caller(), current_time())
+}
+
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+fn deactivate_watchlist_entry(entry_id: String) -> StudiFiResult<()> {
+    ScreeningEngine::deactivate_entry(&entry_id)
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_watchlist_entries(pagination: Option<PaginationParams>) -> PaginatedResponse<WatchlistEntry> {
+    let entries = with_storage(|storage| storage.get_all_watchlist_entries());
+    paginate(&entries, &pagination.unwrap_or_default())
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_screening_result(screening_id: String) -> Option<ScreeningResult> {
+    with_storage(|storage| storage.get_screening_result(&screening_id))
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_subject_screenings(subject: Principal) -> Vec<ScreeningResult> {
+    with_storage(|storage| storage.get_subject_screenings(&subject))
+}
+
+/// Get screenings with matches awaiting analyst review, oldest first
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_screening_review_queue() -> Vec<ScreeningResult> {
+    ScreeningEngine::get_review_queue()
+}
+
+/// Clear a screening's matches as false positives or confirm them
+#[update(guard = "require_review_compliance")]
+#[candid_method(update)]
+fn review_screening(
+    screening_id: String,
+    decision: ScreeningReviewDecision,
+    reason: String,
+) -> StudiFiResult<ScreeningResult> {
+    ScreeningEngine::review(&screening_id, caller(), decision, reason, current_time())
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_screening_stats() -> ScreeningStats {
+    ScreeningEngine::get_stats()
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_screening_config() -> ScreeningConfig {
+    with_storage(|storage| storage.get_screening_config())
+}
+
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+fn update_screening_config(config: ScreeningConfig) -> StudiFiResult<()> {
+    config.validate()?;
+    with_storage_mut(|storage| storage.set_screening_config(config));
+    Ok(())
+}
+
+// ============================================================================
+// AUDIT LOG
+// ============================================================================
+
+/// Append audit events shipped by a StudiFi canister, returning the new chain length
+#[update(guard = "require_studifi_service")]
+#[candid_method(update)]
+fn ingest_audit_events(events: Vec<AuditEvent>) -> StudiFiResult<u64> {
+    AuditLog::append(events, caller(), current_time())
+}
+
+/// Query audit events from `from_sequence` on, oldest first. Pass the page's
+/// `next_sequence` as `from_sequence` to read on.
+#[query(guard = "require_access_audit_logs")]
+#[candid_method(query)]
+fn get_audit_events(filter: AuditEventFilter, from_sequence: Option<u64>, limit: Option<u32>) -> AuditEventPage {
+    AuditLog::query(&filter, from_sequence, limit)
+}
+
+#[query(guard = "require_access_audit_logs")]
+#[candid_method(query)]
+fn get_audit_event(sequence: u64) -> Option<ChainedAuditEvent> {
+    with_storage(|storage| storage.get_audit_entry(sequence))
+}
+
+#[query(guard = "require_access_audit_logs")]
+#[candid_method(query)]
+fn get_audit_chain_head() -> AuditChainHead {
+    AuditLog::head()
+}
+
+/// Re-compute the hash chain between two sequences (inclusive)
+#[query(guard = "require_access_audit_logs")]
+#[candid_method(query)]
+fn verify_chain(from: u64, to: u64) -> StudiFiResult<AuditChainVerification> {
+    AuditLog::verify_chain(from, to)
+}
+
+// ============================================================================
+// REGULATORY REPORTS
+// ============================================================================
+
+/// Generate a report now rather than waiting for the schedule
+#[update(guard = "require_review_compliance")]
+#[candid_method(update)]
+async fn generate_report(report_type: ReportType) -> StudiFiResult<ReportSummary> {
+    ReportEngine::generate(report_type, Some(caller())).await
+}
+
+/// List generated reports, newest first
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn list_reports(report_type: Option<ReportType>, pagination: Option<PaginationParams>) -> PaginatedResponse<ReportSummary> {
+    ReportEngine::list(report_type, pagination.unwrap_or_default())
+}
+
+/// Download a generated report as CSV or JSON
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn download_report(report_id: String, format: ReportFormat) -> StudiFiResult<String> {
+    ReportEngine::download(&report_id, format)
+}
+
+// ============================================================================
+// DATA SUBJECT REQUESTS
+// ============================================================================
+
+/// Export everything the platform holds about the caller as a single JSON bundle
+#[update]
+#[candid_method(update)]
+async fn export_my_data() -> StudiFiResult<String> {
+    DataSubjectEngine::export(caller()).await
+}
+
+/// Erase or pseudonymize the caller's data in every canister, subject to legal retention.
+/// Calling again resumes an erasure that could not reach every canister; one already
+/// running for the caller is refused.
+#[update]
+#[candid_method(update)]
+async fn request_erasure() -> StudiFiResult<DataSubjectRequest> {
+    DataSubjectEngine::erase(caller()).await
+}
+
+#[query]
+#[candid_method(query)]
+fn get_my_data_requests() -> Vec<DataSubjectRequest> {
+    with_storage(|storage| storage.get_subject_data_requests(&caller()))
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_data_subject_requests() -> Vec<DataSubjectRequest> {
+    with_storage(|storage| storage.get_all_data_subject_requests())
+}
+
+// ============================================================================
+// CONSENT
+// ============================================================================
+
+/// Publish a new version of a purpose's consent notice
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+fn publish_consent_notice(purpose: ConsentPurpose, text: String, requires_reconsent: bool) -> StudiFiResult<ConsentNotice> {
+    ConsentEngine::publish_notice(purpose, text, requires_reconsent, caller(), current_time())
+}
+
+/// The notice a subject is asked to consent to for `purpose`
+#[query]
+#[candid_method(query)]
+fn get_consent_notice(purpose: ConsentPurpose) -> Option<ConsentNotice> {
+    with_storage(|storage| storage.get_current_consent_notice(purpose))
+}
+
+/// Every version of a purpose's notice, oldest first
+#[query]
+#[candid_method(query)]
+fn get_consent_notice_history(purpose: ConsentPurpose) -> Vec<ConsentNotice> {
+    with_storage(|storage| storage.get_consent_notices(purpose))
+}
+
+/// Consent to the current version of a purpose's notice
+#[update]
+#[candid_method(update)]
+fn grant_consent(purpose: ConsentPurpose, notice_version: u32) -> StudiFiResult<ConsentRecord> {
+    ConsentEngine::grant(caller(), purpose, notice_version, current_time())
+}
+
+/// Withdraw consent. Evidence already shown to community validators is removed
+/// from credit_assessment_service; if that call fails, its next sweep catches up.
+#[update]
+#[candid_method(update)]
+async fn revoke_consent(purpose: ConsentPurpose) -> StudiFiResult<ConsentRecord> {
+    let subject = caller();
+    let consent = ConsentEngine::revoke(subject, purpose, current_time())?;
+    if purpose == ConsentPurpose::CommunityValidationDisclosure {
+        if let Err(e) = CreditClient::withdraw_validation_disclosure(subject).await {
+            ic_cdk::println!("Could not withdraw validation evidence of {}: {}", subject, e);
+        }
+    }
+    Ok(consent)
+}
+
+/// The caller's consents, including revoked ones, oldest first
+#[query]
+#[candid_method(query)]
+fn get_my_consents() -> Vec<ConsentRecord> {
+    with_storage(|storage| storage.get_subject_consents(&caller()))
+}
+
+/// Checked by other StudiFi canisters before processing a subject's data
+#[query(guard = "require_service_or_view_all_data")]
+#[candid_method(query)]
+fn has_consent(subject: Principal, purpose: ConsentPurpose) -> StudiFiResult<bool> {
+    Ok(ConsentEngine::has_consent(&subject, purpose))
+}
+
+#[query(guard = "require_review_compliance")]
+#[candid_method(query)]
+fn get_subject_consents(subject: Principal) -> Vec<ConsentRecord> {
+    with_storage(|storage| storage.get_subject_consents(&subject))
 }
 
 #[query]
 #[candid_method(query)]
 fn get_platform_stats() -> Statistics {
-    Statistics::default()
+    let stats = KycEngine::get_stats();
+
+    Statistics {
+        total_count: stats.total_cases,
+        active_count: stats.documents_submitted + stats.under_review,
+        completed_count: stats.approved,
+        failed_count: stats.rejected,
+        total_amount: 0,
+        average_amount: 0,
+    }
+}
+
+/// Re-sync the cached role assignments used by authorization guards
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+async fn refresh_role_cache() -> StudiFiResult<u32> {
+    sync_role_cache().await
+}
+
+/// Get the canister registry used for inter-canister calls
+#[query]
+#[candid_method(query)]
+fn get_canister_registry() -> CanisterRegistry {
+    current_canister_registry()
+}
+
+/// Get the health of downstream canisters this canister has called since its last upgrade
+#[query]
+#[candid_method(query)]
+fn get_circuit_breakers() -> Vec<CircuitBreaker> {
+    circuit_breaker_states()
 }
 
 candid::export_service!();
@@ -0,0 +1,418 @@
+use candid::Principal;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::types::*;
+use crate::storage::*;
+use shared::*;
+
+/// Delinquency aging buckets as (label, lowest days past due)
+const AGING_BUCKETS: [(&str, u64); 5] = [
+    ("Current", 0),
+    ("1-29", 1),
+    ("30-59", 30),
+    ("60-89", 60),
+    ("90+", 90),
+];
+
+/// Report contents before they are stored
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportTable {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl ReportTable {
+    fn new(columns: &[&str]) -> Self {
+        Self {
+            columns: columns.iter().map(|column| column.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+}
+
+/// Regulatory report generation
+pub struct ReportEngine;
+
+impl ReportEngine {
+    /// Generate and store a report, pulling loan and credit data from the other services
+    pub async fn generate(report_type: ReportType, requested_by: Option<Principal>) -> StudiFiResult<ReportSummary> {
+        let table = match report_type {
+            ReportType::LoanTape => {
+                let loans = LoanClient::get_loan_tape().await?;
+                let scores = Self::fetch_credit_scores(&loans).await?;
+                Self::build_loan_tape(&loans, &scores)
+            }
+            ReportType::DelinquencyAging => {
+                let loans = LoanClient::get_loan_tape().await?;
+                Self::build_delinquency_aging(&loans)
+            }
+            ReportType::Defaults => {
+                let loans: Vec<LoanTapeRecord> = LoanClient::get_loan_tape()
+                    .await?
+                    .into_iter()
+                    .filter(|loan| loan.status == LoanStatus::Default)
+                    .collect();
+                let scores = Self::fetch_credit_scores(&loans).await?;
+                Self::build_defaults(&loans, &scores)
+            }
+            ReportType::SarDrafts => {
+                let (alerts, transactions) = with_storage(|storage| {
+                    let alerts: Vec<Alert> = storage
+                        .get_all_alerts()
+                        .into_iter()
+                        .filter(|alert| matches!(alert.status, AlertStatus::Escalated | AlertStatus::Reported))
+                        .collect();
+                    let transactions = alerts
+                        .iter()
+                        .flat_map(|alert| alert.transaction_ids.iter())
+                        .filter_map(|id| storage.get_aml_transaction(id))
+                        .map(|transaction| (transaction.id.clone(), transaction))
+                        .collect();
+                    (alerts, transactions)
+                });
+                Self::build_sar_drafts(&alerts, &transactions)
+            }
+        };
+
+        Ok(Self::store(report_type, table, requested_by, current_time()))
+    }
+
+    /// Generate every report type, logging any that fail
+    pub async fn run_schedule() {
+        for report_type in ReportType::ALL {
+            match Self::generate(report_type.clone(), None).await {
+                Ok(summary) => ic_cdk::println!("Generated {:?} report {}", report_type, summary.id),
+                Err(e) => ic_cdk::println!("Failed to generate {:?} report: {}", report_type, e),
+            }
+        }
+    }
+
+    pub fn store(
+        report_type: ReportType,
+        table: ReportTable,
+        requested_by: Option<Principal>,
+        now: Timestamp,
+    ) -> ReportSummary {
+        with_storage_mut(|storage| {
+            let report = Report {
+                id: storage.next_report_id(),
+                report_type,
+                generated_at: now,
+                requested_by,
+                columns: table.columns,
+                rows: table.rows,
+            };
+            let summary = report.summary();
+            storage.insert_report(report);
+            summary
+        })
+    }
+
+    pub fn list(report_type: Option<ReportType>, pagination: PaginationParams) -> PaginatedResponse<ReportSummary> {
+        let summaries = with_storage(|storage| storage.get_report_summaries(report_type.as_ref()));
+        paginate(&summaries, &pagination)
+    }
+
+    pub fn download(report_id: &str, format: ReportFormat) -> StudiFiResult<String> {
+        let report = with_storage(|storage| storage.get_report(report_id))
+            .ok_or_else(|| StudiFiError::NotFound(format!("Report {} not found", report_id)))?;
+
+        match format {
+            ReportFormat::Csv => Ok(Self::to_csv(&report.columns, &report.rows)),
+            ReportFormat::Json => Self::to_json(&report.columns, &report.rows),
+        }
+    }
+
+    async fn fetch_credit_scores(loans: &[LoanTapeRecord]) -> StudiFiResult<HashMap<Principal, Option<u32>>> {
+        let student_ids: BTreeSet<Principal> = loans.iter().map(|loan| loan.student_id).collect();
+        if student_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let snapshots = CreditClient::get_effective_credit_scores(student_ids.into_iter().collect()).await?;
+        Ok(snapshots.into_iter().map(|snapshot| (snapshot.student_id, snapshot.score)).collect())
+    }
+
+    // ========================================================================
+    // REPORT BUILDERS
+    // ========================================================================
+
+    pub fn build_loan_tape(loans: &[LoanTapeRecord], scores: &HashMap<Principal, Option<u32>>) -> ReportTable {
+        let mut table = ReportTable::new(&[
+            "loan_id", "student_id", "cosigner_id", "status", "original_amount", "current_balance",
+            "interest_rate", "term_months", "monthly_payment", "payments_made", "late_payments",
+            "days_past_due", "originated_on", "next_payment_due", "last_payment_on", "credit_score",
+        ]);
+
+        for loan in loans {
+            table.rows.push(vec![
+                loan.loan_id.clone(),
+                loan.student_id.to_text(),
+                loan.cosigner_id.map(|cosigner| cosigner.to_text()).unwrap_or_default(),
+                format!("{:?}", loan.status),
+                format_decimal_amount(loan.original_amount),
+                format_decimal_amount(loan.current_balance),
+                format!("{:.4}", loan.interest_rate),
+                loan.term_months.to_string(),
+                format_decimal_amount(loan.monthly_payment),
+                loan.payments_made.to_string(),
+                loan.late_payments.to_string(),
+                loan.days_past_due.to_string(),
+                format_date(loan.originated_at),
+                format_date(loan.next_payment_due),
+                loan.last_payment_date.map(format_date).unwrap_or_default(),
+                format_score(scores, &loan.student_id),
+            ]);
+        }
+
+        table
+    }
+
+    /// Loans still being repaid, bucketed by days past due. Defaulted loans fall in 90+.
+    pub fn build_delinquency_aging(loans: &[LoanTapeRecord]) -> ReportTable {
+        let mut counts = [0u32; AGING_BUCKETS.len()];
+        let mut balances = [0 as Amount; AGING_BUCKETS.len()];
+
+        for loan in loans {
+            if !matches!(loan.status, LoanStatus::Active | LoanStatus::Late | LoanStatus::Default) {
+                continue;
+            }
+            let days_past_due = if loan.status == LoanStatus::Default {
+                loan.days_past_due.max(AGING_BUCKETS[AGING_BUCKETS.len() - 1].1)
+            } else {
+                loan.days_past_due
+            };
+            let bucket = AGING_BUCKETS
+                .iter()
+                .rposition(|(_, floor)| days_past_due >= *floor)
+                .unwrap_or(0);
+            counts[bucket] += 1;
+            balances[bucket] += loan.current_balance;
+        }
+
+        let total_balance: Amount = balances.iter().sum();
+        let mut table = ReportTable::new(&["bucket", "loan_count", "outstanding_balance", "share_of_balance"]);
+        for (index, (label, _)) in AGING_BUCKETS.iter().enumerate() {
+            let share = if total_balance == 0 {
+                0.0
+            } else {
+                balances[index] as f64 / total_balance as f64
+            };
+            table.rows.push(vec![
+                label.to_string(),
+                counts[index].to_string(),
+                format_decimal_amount(balances[index]),
+                format!("{:.4}", share),
+            ]);
+        }
+
+        table
+    }
+
+    pub fn build_defaults(loans: &[LoanTapeRecord], scores: &HashMap<Principal, Option<u32>>) -> ReportTable {
+        let mut table = ReportTable::new(&[
+            "loan_id", "student_id", "cosigner_id", "original_amount", "current_balance",
+            "amount_repaid", "payments_made", "late_payments", "days_past_due", "originated_on",
+            "last_payment_on", "credit_score",
+        ]);
+
+        for loan in loans.iter().filter(|loan| loan.status == LoanStatus::Default) {
+            table.rows.push(vec![
+                loan.loan_id.clone(),
+                loan.student_id.to_text(),
+                loan.cosigner_id.map(|cosigner| cosigner.to_text()).unwrap_or_default(),
+                format_decimal_amount(loan.original_amount),
+                format_decimal_amount(loan.current_balance),
+                format_decimal_amount(loan.original_amount.saturating_sub(loan.current_balance)),
+                loan.payments_made.to_string(),
+                loan.late_payments.to_string(),
+                loan.days_past_due.to_string(),
+                format_date(loan.originated_at),
+                loan.last_payment_date.map(format_date).unwrap_or_default(),
+                format_score(scores, &loan.student_id),
+            ]);
+        }
+
+        table
+    }
+
+    /// One draft per alert, with the analyst notes as the starting narrative
+    pub fn build_sar_drafts(alerts: &[Alert], transactions: &HashMap<String, AmlTransaction>) -> ReportTable {
+        let mut table = ReportTable::new(&[
+            "alert_id", "subject", "rule", "severity", "status", "raised_on", "activity_start",
+            "activity_end", "transaction_count", "total_amount", "transaction_references",
+            "description", "narrative",
+        ]);
+
+        for alert in alerts {
+            let alert_transactions: Vec<&AmlTransaction> = alert
+                .transaction_ids
+                .iter()
+                .filter_map(|id| transactions.get(id))
+                .collect();
+            let activity_start = alert_transactions.iter().map(|transaction| transaction.occurred_at).min();
+            let activity_end = alert_transactions.iter().map(|transaction| transaction.occurred_at).max();
+            let total_amount: Amount = alert_transactions.iter().map(|transaction| transaction.amount).sum();
+            let references: Vec<&str> = alert_transactions
+                .iter()
+                .map(|transaction| transaction.reference_id.as_str())
+                .collect();
+            let narrative: Vec<&str> = alert.notes.iter().map(|note| note.note.as_str()).collect();
+
+            table.rows.push(vec![
+                alert.id.clone(),
+                alert.subject.to_text(),
+                format!("{:?}", alert.rule),
+                format!("{:?}", alert.severity),
+                format!("{:?}", alert.status),
+                format_date(alert.raised_at),
+                activity_start.map(format_date).unwrap_or_default(),
+                activity_end.map(format_date).unwrap_or_default(),
+                alert_transactions.len().to_string(),
+                format_decimal_amount(total_amount),
+                references.join(";"),
+                alert.description.clone(),
+                narrative.join("\n"),
+            ]);
+        }
+
+        table
+    }
+
+    // ========================================================================
+    // RENDERING
+    // ========================================================================
+
+    /// RFC 4180 CSV with a header row
+    pub fn to_csv(columns: &[String], rows: &[Vec<String>]) -> String {
+        let mut csv = String::new();
+        for line in std::iter::once(columns).chain(rows.iter().map(|row| row.as_slice())) {
+            let fields: Vec<String> = line.iter().map(|field| escape_csv_field(field)).collect();
+            csv.push_str(&fields.join(","));
+            csv.push_str("\r\n");
+        }
+        csv
+    }
+
+    /// JSON array with one object per row, keyed by column
+    pub fn to_json(columns: &[String], rows: &[Vec<String>]) -> StudiFiResult<String> {
+        let records: Vec<serde_json::Map<String, serde_json::Value>> = rows
+            .iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().map(|field| serde_json::Value::String(field.clone())))
+                    .collect()
+            })
+            .collect();
+
+        serde_json::to_string(&records)
+            .map_err(|e| StudiFiError::InternalError(format!("Failed to render report: {}", e)))
+    }
+}
+
+fn escape_csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Amount in cents as a plain decimal, e.g. 1234.56
+fn format_decimal_amount(amount: Amount) -> String {
+    format!("{}.{:02}", amount / 100, amount % 100)
+}
+
+/// Timestamp as an ISO 8601 date
+fn format_date(timestamp: Timestamp) -> String {
+    let days = (timestamp / days_to_nanos(1)) as i64;
+    let (year, month, day) = days_to_civil_date(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn format_score(scores: &HashMap<Principal, Option<u32>>, student_id: &Principal) -> String {
+    scores
+        .get(student_id)
+        .copied()
+        .flatten()
+        .map(|score| score.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loan(id: &str, status: LoanStatus, balance: Amount, days_past_due: u64) -> LoanTapeRecord {
+        LoanTapeRecord {
+            loan_id: id.to_string(),
+            student_id: Principal::from_slice(&[1]),
+            cosigner_id: None,
+            status,
+            original_amount: 1_000_000,
+            current_balance: balance,
+            interest_rate: 0.05,
+            term_months: 60,
+            monthly_payment: 18_871,
+            payments_made: 3,
+            late_payments: 1,
+            days_past_due,
+            originated_at: days_to_nanos(19_723),
+            next_payment_due: days_to_nanos(19_813),
+            last_payment_date: None,
+        }
+    }
+
+    #[test]
+    fn test_aging_buckets_and_rendering() {
+        let loans = vec![
+            loan("LOAN-1", LoanStatus::Active, 400_000, 0),
+            loan("LOAN-2", LoanStatus::Late, 300_000, 45),
+            loan("LOAN-3", LoanStatus::Default, 300_000, 91),
+            loan("LOAN-4", LoanStatus::PaidOff, 0, 0),
+        ];
+
+        let aging = ReportEngine::build_delinquency_aging(&loans);
+        let row = |label: &str| aging.rows.iter().find(|row| row[0] == label).unwrap().clone();
+        assert_eq!(row("Current")[1], "1");
+        assert_eq!(row("30-59")[2], "3000.00");
+        assert_eq!(row("90+")[3], "0.3000");
+        assert_eq!(row("60-89")[1], "0");
+
+        let scores = HashMap::from([(Principal::from_slice(&[1]), Some(710))]);
+        let defaults = ReportEngine::build_defaults(&loans, &scores);
+        assert_eq!(defaults.rows.len(), 1);
+        assert_eq!(defaults.rows[0][0], "LOAN-3");
+        assert_eq!(defaults.rows[0][5], "7000.00");
+        assert_eq!(defaults.rows[0][11], "710");
+
+        let tape = ReportEngine::build_loan_tape(&loans, &scores);
+        assert_eq!(tape.rows.len(), 4);
+        assert_eq!(tape.rows[0][12], "2024-01-01");
+
+        // Only the newest reports of a type are kept
+        for day in 0..=REPORT_RETENTION_PER_TYPE as u64 {
+            ReportEngine::store(ReportType::DelinquencyAging, aging.clone(), None, days_to_nanos(day));
+        }
+        ReportEngine::store(ReportType::Defaults, defaults.clone(), None, 0);
+        let kept = ReportEngine::list(Some(ReportType::DelinquencyAging), PaginationParams { offset: 0, limit: 1_000 });
+        assert_eq!(kept.total_count as usize, REPORT_RETENTION_PER_TYPE);
+        assert_eq!(kept.items.last().unwrap().generated_at, days_to_nanos(1));
+        let oldest = with_storage(|storage| storage.get_report("RPT-00000001"));
+        assert!(oldest.is_none());
+        assert_eq!(ReportEngine::list(Some(ReportType::Defaults), PaginationParams::default()).total_count, 1);
+
+        let columns = vec!["id".to_string(), "note".to_string()];
+        let rows = vec![vec!["1".to_string(), "said \"hi\", twice".to_string()]];
+        assert_eq!(
+            ReportEngine::to_csv(&columns, &rows),
+            "id,note\r\n1,\"said \"\"hi\"\", twice\"\r\n"
+        );
+        assert_eq!(
+            ReportEngine::to_json(&columns, &rows).unwrap(),
+            r#"[{"id":"1","note":"said \"hi\", twice"}]"#
+        );
+    }
+}
@@ -0,0 +1,587 @@
+use candid::Principal;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::types::*;
+use crate::storage::*;
+use shared::*;
+
+/// Maximum number of watchlist entries uploaded in one batch
+const MAX_WATCHLIST_BATCH: usize = 1_000;
+
+/// Discount applied to token-by-token matches, which ignore extra names on one side
+const PARTIAL_NAME_WEIGHT: f64 = 0.95;
+
+/// Sanctions and watchlist screening
+pub struct ScreeningEngine;
+
+impl ScreeningEngine {
+    /// Load a batch of entries for `list_name`, replacing entries with the same source reference
+    pub fn upload_entries(
+        list_name: String,
+        inputs: Vec<WatchlistEntryInput>,
+        added_by: Principal,
+        now: Timestamp,
+    ) -> StudiFiResult<u32> {
+        let list_name = sanitize_text(&list_name);
+        if list_name.is_empty() {
+            return Err(StudiFiError::InvalidInput("List name cannot be empty".to_string()));
+        }
+        if inputs.is_empty() || inputs.len() > MAX_WATCHLIST_BATCH {
+            return Err(StudiFiError::InvalidInput(
+                format!("Upload between 1 and {} entries at a time", MAX_WATCHLIST_BATCH)
+            ));
+        }
+        for input in &inputs {
+            if normalize_name(&input.name).is_empty() {
+                return Err(StudiFiError::InvalidInput(
+                    format!("Entry {} has no usable name", input.source_reference)
+                ));
+            }
+            validate_date_of_birth(&input.date_of_birth)?;
+            validate_country(&input.country)?;
+        }
+
+        with_storage_mut(|storage| {
+            let existing: BTreeMap<String, String> = storage
+                .get_all_watchlist_entries()
+                .into_iter()
+                .filter(|entry| entry.list_name == list_name)
+                .map(|entry| (entry.source_reference, entry.id))
+                .collect();
+
+            let count = inputs.len() as u32;
+            for input in inputs {
+                let id = match existing.get(&input.source_reference) {
+                    Some(id) => id.clone(),
+                    None => storage.next_watchlist_entry_id(),
+                };
+                let normalized_names = std::iter::once(&input.name)
+                    .chain(input.aliases.iter())
+                    .map(|name| normalize_name(name))
+                    .filter(|name| !name.is_empty())
+                    .collect();
+
+                storage.insert_watchlist_entry(WatchlistEntry {
+                    id,
+                    list_name: list_name.clone(),
+                    source_reference: input.source_reference,
+                    name: input.name,
+                    aliases: input.aliases,
+                    date_of_birth: input.date_of_birth,
+                    country: input.country.map(|country| country.to_uppercase()),
+                    normalized_names,
+                    is_active: true,
+                    added_by,
+                    added_at: now,
+                });
+            }
+            Ok(count)
+        })
+    }
+
+    pub fn deactivate_entry(entry_id: &str) -> StudiFiResult<()> {
+        with_storage_mut(|storage| {
+            let mut entry = storage.get_watchlist_entry(entry_id)
+                .ok_or_else(|| StudiFiError::NotFound("Watchlist entry not found".to_string()))?;
+            entry.is_active = false;
+            storage.insert_watchlist_entry(entry);
+            Ok(())
+        })
+    }
+
+    /// Record the identifying details a party is screened under. A party whose
+    /// matches were cleared as false positives is screened again when the details
+    /// change, since the clearance only covers the details that were reviewed.
+    pub fn submit_profile(
+        subject: Principal,
+        full_name: String,
+        date_of_birth: Option<String>,
+        country: Option<String>,
+        now: Timestamp,
+    ) -> StudiFiResult<ScreeningProfile> {
+        let previous = with_storage(|storage| storage.get_screening_profile(&subject));
+        let profile = Self::store_profile(subject, full_name, date_of_birth, country, now)?;
+
+        let changed = previous.is_none_or(|previous| {
+            (&previous.full_name, &previous.date_of_birth, &previous.country)
+                != (&profile.full_name, &profile.date_of_birth, &profile.country)
+        });
+        let last_cleared = with_storage(|storage| storage.get_subject_screenings(&subject))
+            .into_iter()
+            .filter(Self::is_cleared)
+            .max_by_key(|result| result.screened_at);
+        if let Some(cleared) = last_cleared.filter(|_| changed) {
+            Self::screen(ScreeningRequest {
+                subject,
+                role: cleared.role,
+                full_name: None,
+                date_of_birth: None,
+                country: None,
+                context: "Details changed after a false-positive clearance".to_string(),
+            }, subject, now)?;
+        }
+        Ok(profile)
+    }
+
+    fn store_profile(
+        subject: Principal,
+        full_name: String,
+        date_of_birth: Option<String>,
+        country: Option<String>,
+        now: Timestamp,
+    ) -> StudiFiResult<ScreeningProfile> {
+        let full_name = sanitize_text(&full_name);
+        if normalize_name(&full_name).is_empty() {
+            return Err(StudiFiError::InvalidInput("Full name cannot be empty".to_string()));
+        }
+        validate_date_of_birth(&date_of_birth)?;
+        validate_country(&country)?;
+
+        let profile = ScreeningProfile {
+            subject,
+            full_name,
+            date_of_birth,
+            country: country.map(|country| country.to_uppercase()),
+            updated_at: now,
+        };
+        with_storage_mut(|storage| storage.set_screening_profile(profile.clone()));
+        Ok(profile)
+    }
+
+    /// Screen a party and persist the result
+    pub fn screen(request: ScreeningRequest, requested_by: Principal, now: Timestamp) -> StudiFiResult<ScreeningResult> {
+        // Details supplied with the request become the subject's profile
+        if let Some(full_name) = &request.full_name {
+            let on_file = with_storage(|storage| storage.get_screening_profile(&request.subject));
+            Self::store_profile(
+                request.subject,
+                full_name.clone(),
+                request.date_of_birth.clone().or_else(|| on_file.as_ref().and_then(|p| p.date_of_birth.clone())),
+                request.country.clone().or_else(|| on_file.as_ref().and_then(|p| p.country.clone())),
+                now,
+            )?;
+        }
+
+        with_storage_mut(|storage| {
+            let config = storage.get_screening_config();
+            let profile = storage.get_screening_profile(&request.subject);
+            let watchlist = storage.get_active_watchlist();
+
+            let (outcome, matches) = match &profile {
+                Some(profile) => {
+                    let cleared = Self::cleared_entries(storage, profile);
+                    let candidates: Vec<WatchlistEntry> = watchlist
+                        .iter()
+                        .filter(|entry| !cleared.contains(&entry.id))
+                        .cloned()
+                        .collect();
+                    let matches = match_profile(profile, &candidates, &config);
+                    (classify(&matches, &config), matches)
+                }
+                None => (ScreeningOutcome::DetailsRequired, Vec::new()),
+            };
+
+            let result = ScreeningResult {
+                id: storage.next_screening_id(),
+                subject: request.subject,
+                role: request.role,
+                context: sanitize_text(&request.context),
+                screened_name: profile.as_ref().map(|profile| profile.full_name.clone()),
+                date_of_birth: profile.as_ref().and_then(|profile| profile.date_of_birth.clone()),
+                country: profile.as_ref().and_then(|profile| profile.country.clone()),
+                outcome,
+                matches,
+                entries_screened: watchlist.len() as u32,
+                requested_by,
+                screened_at: now,
+                review: None,
+            };
+
+            if result.outcome != ScreeningOutcome::Clear {
+                ic_cdk::println!("Screening {} of {} returned {:?}", result.id, result.subject, result.outcome);
+            }
+            storage.insert_screening_result(result.clone());
+            Ok(result)
+        })
+    }
+
+    /// Watchlist entries an analyst has cleared as false positives for the
+    /// subject under the details now in `profile`
+    fn cleared_entries(storage: &ComplianceStorage, profile: &ScreeningProfile) -> BTreeSet<String> {
+        storage
+            .get_subject_screenings(&profile.subject)
+            .into_iter()
+            .filter(Self::is_cleared)
+            .filter(|result| result.review.as_ref().is_some_and(|review| review.covers(profile)))
+            .flat_map(|result| result.matches.into_iter().map(|m| m.entry_id))
+            .collect()
+    }
+
+    fn is_cleared(result: &ScreeningResult) -> bool {
+        result.review.as_ref().map(|review| &review.decision) == Some(&ScreeningReviewDecision::ClearFalsePositive)
+    }
+
+    /// Record an analyst's decision on a screening held for review
+    pub fn review(
+        screening_id: &str,
+        reviewer: Principal,
+        decision: ScreeningReviewDecision,
+        reason: String,
+        now: Timestamp,
+    ) -> StudiFiResult<ScreeningResult> {
+        let reason = sanitize_text(&reason);
+        if reason.is_empty() {
+            return Err(StudiFiError::InvalidInput("A reason is required for every review".to_string()));
+        }
+
+        with_storage_mut(|storage| {
+            let mut result = storage.get_screening_result(screening_id)
+                .ok_or_else(|| StudiFiError::NotFound("Screening not found".to_string()))?;
+
+            if result.matches.is_empty() {
+                return Err(StudiFiError::InvalidInput("Screening has no matches to review".to_string()));
+            }
+            if result.review.is_some() {
+                return Err(StudiFiError::AlreadyExists("Screening has already been reviewed".to_string()));
+            }
+
+            result.review = Some(ScreeningReview {
+                reviewer,
+                decision,
+                reason,
+                reviewed_at: now,
+                screened_name: result.screened_name.clone(),
+                date_of_birth: result.date_of_birth.clone(),
+                country: result.country.clone(),
+            });
+            storage.insert_screening_result(result.clone());
+            Ok(result)
+        })
+    }
+
+    /// Screenings with matches that no analyst has reviewed, oldest first
+    pub fn get_review_queue() -> Vec<ScreeningResult> {
+        let mut queue: Vec<ScreeningResult> = with_storage(|storage| storage.get_all_screening_results())
+            .into_iter()
+            .filter(|result| !result.matches.is_empty() && result.review.is_none())
+            .collect();
+        queue.sort_by_key(|result| result.screened_at);
+        queue
+    }
+
+    pub fn get_stats() -> ScreeningStats {
+        with_storage(|storage| {
+            let results = storage.get_all_screening_results();
+
+            let mut stats = ScreeningStats {
+                active_watchlist_entries: storage.get_active_watchlist().len() as u32,
+                total_screenings: results.len() as u32,
+                ..ScreeningStats::default()
+            };
+            for result in &results {
+                match result.outcome {
+                    ScreeningOutcome::Clear => stats.clear += 1,
+                    ScreeningOutcome::PotentialMatch => stats.potential_matches += 1,
+                    ScreeningOutcome::Match => stats.matches += 1,
+                    ScreeningOutcome::DetailsRequired => stats.details_required += 1,
+                }
+                if !result.matches.is_empty() && result.review.is_none() {
+                    stats.awaiting_review += 1;
+                }
+            }
+            stats
+        })
+    }
+}
+
+/// Outcome of a screening given its matches
+fn classify(matches: &[WatchlistMatch], config: &ScreeningConfig) -> ScreeningOutcome {
+    let best = matches.iter().map(|m| m.score).fold(0.0, f64::max);
+    if best >= config.match_threshold {
+        ScreeningOutcome::Match
+    } else if best >= config.potential_match_threshold {
+        ScreeningOutcome::PotentialMatch
+    } else {
+        ScreeningOutcome::Clear
+    }
+}
+
+/// Score a profile against every entry, keeping those at or above the review threshold
+pub fn match_profile(profile: &ScreeningProfile, entries: &[WatchlistEntry], config: &ScreeningConfig) -> Vec<WatchlistMatch> {
+    let name = normalize_name(&profile.full_name);
+
+    let mut matches: Vec<WatchlistMatch> = entries
+        .iter()
+        .filter_map(|entry| {
+            let (best_index, name_score) = entry
+                .normalized_names
+                .iter()
+                .map(|candidate| name_similarity(&name, candidate))
+                .enumerate()
+                .fold((0, 0.0), |best, (index, score)| if score > best.1 { (index, score) } else { best });
+
+            let date_of_birth_matches = compare_known(&profile.date_of_birth, &entry.date_of_birth);
+            let country_matches = compare_known(&profile.country, &entry.country);
+
+            let mut score = name_score;
+            if date_of_birth_matches == Some(false) {
+                score -= config.date_of_birth_mismatch_penalty;
+            }
+            if country_matches == Some(false) {
+                score -= config.country_mismatch_penalty;
+            }
+            let score = score.max(0.0);
+
+            (score >= config.potential_match_threshold).then(|| WatchlistMatch {
+                entry_id: entry.id.clone(),
+                list_name: entry.list_name.clone(),
+                matched_name: std::iter::once(&entry.name)
+                    .chain(entry.aliases.iter())
+                    .nth(best_index)
+                    .cloned()
+                    .unwrap_or_else(|| entry.name.clone()),
+                score,
+                date_of_birth_matches,
+                country_matches,
+            })
+        })
+        .collect();
+
+    matches.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    matches
+}
+
+fn compare_known(a: &Option<String>, b: &Option<String>) -> Option<bool> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.eq_ignore_ascii_case(b)),
+        _ => None,
+    }
+}
+
+// ============================================================================
+// NAME MATCHING
+// ============================================================================
+
+/// Lowercase, fold accents, drop punctuation and sort the name's tokens so that
+/// "Doe, John" and "JOHN DOE" compare equal
+pub fn normalize_name(name: &str) -> String {
+    let folded: String = name
+        .chars()
+        .flat_map(|c| c.to_lowercase())
+        .map(fold_accent)
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { ' ' })
+        .collect();
+
+    let mut tokens: Vec<&str> = folded.split_whitespace().collect();
+    tokens.sort_unstable();
+    tokens.join(" ")
+}
+
+fn fold_accent(c: char) -> char {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => 'a',
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => 'c',
+        'ď' | 'đ' => 'd',
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => 'e',
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => 'g',
+        'ĥ' | 'ħ' => 'h',
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => 'i',
+        'ĵ' => 'j',
+        'ķ' => 'k',
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => 'l',
+        'ñ' | 'ń' | 'ņ' | 'ň' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => 'o',
+        'ŕ' | 'ŗ' | 'ř' => 'r',
+        'ś' | 'ŝ' | 'ş' | 'š' | 'ß' => 's',
+        'ţ' | 'ť' | 'ŧ' => 't',
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => 'u',
+        'ŵ' => 'w',
+        'ý' | 'ÿ' | 'ŷ' => 'y',
+        'ź' | 'ż' | 'ž' => 'z',
+        _ => c,
+    }
+}
+
+/// Similarity of two normalized names, 0 to 1.
+///
+/// The whole-name Jaro-Winkler score is used unless matching token by token scores
+/// higher, which catches names with an extra middle name or initial on one side.
+pub fn name_similarity(a: &str, b: &str) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+
+    let whole = jaro_winkler(a, b);
+
+    let a_tokens: Vec<&str> = a.split(' ').collect();
+    let b_tokens: Vec<&str> = b.split(' ').collect();
+    let (shorter, longer) = if a_tokens.len() <= b_tokens.len() { (a_tokens, b_tokens) } else { (b_tokens, a_tokens) };
+
+    // A single shared token, e.g. a common surname, is not enough on its own
+    if shorter.len() < 2 {
+        return whole;
+    }
+
+    let by_token = shorter
+        .iter()
+        .map(|token| longer.iter().map(|other| jaro_winkler(token, other)).fold(0.0, f64::max))
+        .sum::<f64>()
+        / shorter.len() as f64;
+
+    whole.max(by_token * PARTIAL_NAME_WEIGHT)
+}
+
+/// Jaro-Winkler similarity with the standard 0.1 prefix scale over up to 4 characters
+pub fn jaro_winkler(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+
+    let window = (a.len().max(b.len()) / 2).saturating_sub(1);
+    let mut a_matched = vec![false; a.len()];
+    let mut b_matched = vec![false; b.len()];
+    let mut matches = 0usize;
+
+    for (i, a_char) in a.iter().enumerate() {
+        let start = i.saturating_sub(window);
+        let end = (i + window + 1).min(b.len());
+        for j in start..end {
+            if !b_matched[j] && b[j] == *a_char {
+                a_matched[i] = true;
+                b_matched[j] = true;
+                matches += 1;
+                break;
+            }
+        }
+    }
+    if matches == 0 {
+        return 0.0;
+    }
+
+    let a_sequence = a.iter().zip(&a_matched).filter(|(_, matched)| **matched).map(|(c, _)| c);
+    let b_sequence = b.iter().zip(&b_matched).filter(|(_, matched)| **matched).map(|(c, _)| c);
+    let transpositions = a_sequence.zip(b_sequence).filter(|(x, y)| x != y).count() / 2;
+
+    let m = matches as f64;
+    let jaro = (m / a.len() as f64 + m / b.len() as f64 + (m - transpositions as f64) / m) / 3.0;
+
+    let prefix = a.iter().zip(&b).take(4).take_while(|(x, y)| x == y).count() as f64;
+    jaro + prefix * 0.1 * (1.0 - jaro)
+}
+
+// ============================================================================
+// VALIDATION
+// ============================================================================
+
+fn validate_date_of_birth(date_of_birth: &Option<String>) -> StudiFiResult<()> {
+    let Some(date) = date_of_birth else { return Ok(()) };
+
+    let parts: Vec<&str> = date.split('-').collect();
+    let valid = parts.len() == 3
+        && parts[0].len() == 4
+        && parts[1].len() == 2
+        && parts[2].len() == 2
+        && parts.iter().all(|part| part.chars().all(|c| c.is_ascii_digit()))
+        && (1..=12).contains(&parts[1].parse::<u32>().unwrap_or(0))
+        && (1..=31).contains(&parts[2].parse::<u32>().unwrap_or(0));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(StudiFiError::InvalidInput(format!("Date of birth {} is not in YYYY-MM-DD form", date)))
+    }
+}
+
+fn validate_country(country: &Option<String>) -> StudiFiResult<()> {
+    match country {
+        Some(code) if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) => Err(
+            StudiFiError::InvalidInput(format!("Country {} is not an ISO 3166 alpha-2 code", code))
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_matching() {
+        assert_eq!(normalize_name("  Doe, JOHN  "), "doe john");
+        assert_eq!(normalize_name("José Müller-Lüdenscheidt"), "jose ludenscheidt muller");
+        assert!((jaro_winkler("martha", "marhta") - 0.961).abs() < 0.001);
+        assert_eq!(jaro_winkler("abc", "xyz"), 0.0);
+
+        // An extra middle name still matches closely; a shared surname alone does not
+        assert!(name_similarity(&normalize_name("John Smith"), &normalize_name("John Albert Smith")) >= 0.95);
+        assert!(name_similarity(&normalize_name("Smith"), &normalize_name("John Smith")) < 0.85);
+    }
+
+    #[test]
+    fn test_screening_and_false_positive_review() {
+        let admin = Principal::from_slice(&[1]);
+        let identity_service = Principal::from_slice(&[2]);
+        let analyst = Principal::from_slice(&[3]);
+        let student = Principal::from_slice(&[4]);
+        let donor = Principal::from_slice(&[5]);
+
+        ScreeningEngine::upload_entries("TEST SDN".to_string(), vec![WatchlistEntryInput {
+            source_reference: "SDN-1".to_string(),
+            name: "Ivan Petrovich Sidorov".to_string(),
+            aliases: vec!["Ivan Sidorov".to_string()],
+            date_of_birth: Some("1970-05-01".to_string()),
+            country: Some("ru".to_string()),
+        }], admin, 1).unwrap();
+
+        let request = |subject, full_name: Option<&str>, date_of_birth: Option<&str>| ScreeningRequest {
+            subject,
+            role: ScreeningRole::Borrower,
+            full_name: full_name.map(str::to_string),
+            date_of_birth: date_of_birth.map(str::to_string),
+            country: None,
+            context: "test".to_string(),
+        };
+
+        let clear = ScreeningEngine::screen(request(student, Some("Maria Garcia"), None), identity_service, 2).unwrap();
+        assert_eq!(clear.outcome, ScreeningOutcome::Clear);
+
+        // Donors without details on file cannot be screened
+        let missing = ScreeningEngine::screen(request(donor, None, None), identity_service, 3).unwrap();
+        assert_eq!(missing.outcome, ScreeningOutcome::DetailsRequired);
+
+        let hit = ScreeningEngine::screen(request(donor, Some("IVAN SIDOROV"), None), identity_service, 4).unwrap();
+        assert_eq!(hit.outcome, ScreeningOutcome::Match);
+        assert_eq!(hit.matches[0].matched_name, "Ivan Sidorov");
+
+        // A different date of birth lowers the score below the match threshold
+        let namesake = ScreeningEngine::screen(request(donor, Some("Ivan Sidorov"), Some("1999-12-31")), identity_service, 5).unwrap();
+        assert_eq!(namesake.outcome, ScreeningOutcome::PotentialMatch);
+        assert_eq!(ScreeningEngine::get_review_queue().len(), 2);
+
+        ScreeningEngine::review(&namesake.id, analyst, ScreeningReviewDecision::ClearFalsePositive, "different person".to_string(), 6).unwrap();
+        assert!(ScreeningEngine::review(&namesake.id, analyst, ScreeningReviewDecision::ConfirmMatch, "again".to_string(), 7).is_err());
+
+        // Cleared entries are ignored from then on
+        let rescreen = ScreeningEngine::screen(request(donor, None, None), identity_service, 8).unwrap();
+        assert_eq!(rescreen.outcome, ScreeningOutcome::Clear);
+        assert_eq!(ScreeningEngine::get_stats().total_screenings, 5);
+
+        // Resubmitting the same details changes nothing
+        ScreeningEngine::submit_profile(donor, "Ivan Sidorov".to_string(), Some("1999-12-31".to_string()), None, 9).unwrap();
+        assert_eq!(ScreeningEngine::get_stats().total_screenings, 5);
+
+        // The clearance covered the reviewed details only: new ones are screened again and queued
+        ScreeningEngine::submit_profile(donor, "Ivan Sidorov".to_string(), Some("1970-05-01".to_string()), None, 10).unwrap();
+        let queue = ScreeningEngine::get_review_queue();
+        assert_eq!(queue.len(), 2);
+        let lapsed = queue.last().unwrap();
+        assert_eq!((lapsed.outcome.clone(), lapsed.screened_at), (ScreeningOutcome::Match, 10));
+        let rescreen = ScreeningEngine::screen(request(donor, None, None), identity_service, 11).unwrap();
+        assert_eq!(rescreen.outcome, ScreeningOutcome::Match);
+    }
+}
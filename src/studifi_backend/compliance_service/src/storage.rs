@@ -0,0 +1,699 @@
+use candid::Principal;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    DefaultMemoryImpl,
+};
+use std::cell::RefCell;
+
+use crate::types::*;
+use shared::*;
+
+// Memory management for stable storage
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Define memory IDs for different data structures
+const KYC_CASES_MEMORY_ID: MemoryId = MemoryId::new(0);
+const CASES_BY_SUBJECT_MEMORY_ID: MemoryId = MemoryId::new(1);
+const COUNTERS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const CANISTER_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(3);
+const AML_TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const TRANSACTIONS_BY_PARTY_MEMORY_ID: MemoryId = MemoryId::new(5);
+const ALERTS_MEMORY_ID: MemoryId = MemoryId::new(6);
+const AML_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(7);
+const AUDIT_LOG_MEMORY_ID: MemoryId = MemoryId::new(8);
+const WATCHLIST_MEMORY_ID: MemoryId = MemoryId::new(9);
+const SCREENING_RESULTS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const SCREENING_PROFILES_MEMORY_ID: MemoryId = MemoryId::new(11);
+const SCREENING_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(12);
+const REPORTS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const DATA_SUBJECT_REQUESTS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const CONSENT_NOTICES_MEMORY_ID: MemoryId = MemoryId::new(15);
+const CONSENTS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const AUDIT_BY_USER_MEMORY_ID: MemoryId = MemoryId::new(17);
+const AUDIT_BY_EVENT_TYPE_MEMORY_ID: MemoryId = MemoryId::new(18);
+const AUDIT_BY_CANISTER_MEMORY_ID: MemoryId = MemoryId::new(19);
+const REPORT_SUMMARIES_MEMORY_ID: MemoryId = MemoryId::new(20);
+
+// Stable record version for KycCase
+impl VersionedRecord for KycCase {
+    const VERSION: u16 = 1;
+}
+
+// Wrapper type for a subject's case ids, oldest first
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct CaseIdList(pub Vec<String>);
+
+impl VersionedRecord for CaseIdList {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for AmlTransaction
+impl VersionedRecord for AmlTransaction {
+    const VERSION: u16 = 1;
+}
+
+// Wrapper type for a party's transaction ids, oldest first
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TransactionIdList(pub Vec<String>);
+
+impl VersionedRecord for TransactionIdList {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for Alert
+impl VersionedRecord for Alert {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for AmlConfig
+impl VersionedRecord for AmlConfig {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for ChainedAuditEvent
+impl VersionedRecord for ChainedAuditEvent {
+    const VERSION: u16 = 1;
+}
+
+// Value of an audit log index entry; the key carries the sequence
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct AuditIndexEntry;
+
+impl VersionedRecord for AuditIndexEntry {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for WatchlistEntry
+impl VersionedRecord for WatchlistEntry {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for ScreeningResult
+impl VersionedRecord for ScreeningResult {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for ScreeningProfile
+impl VersionedRecord for ScreeningProfile {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for ScreeningConfig
+impl VersionedRecord for ScreeningConfig {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for Report
+impl VersionedRecord for Report {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for ReportSummary
+impl VersionedRecord for ReportSummary {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for DataSubjectRequest
+impl VersionedRecord for DataSubjectRequest {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for ConsentRecord
+impl VersionedRecord for ConsentRecord {
+    const VERSION: u16 = 1;
+}
+
+// Counter structure for ID generation
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Counters {
+    pub kyc_case_counter: u64,
+    #[serde(default)]
+    pub aml_transaction_counter: u64,
+    #[serde(default)]
+    pub alert_counter: u64,
+    #[serde(default)]
+    pub watchlist_entry_counter: u64,
+    #[serde(default)]
+    pub screening_counter: u64,
+    #[serde(default)]
+    pub report_counter: u64,
+    #[serde(default)]
+    pub data_subject_request_counter: u64,
+    #[serde(default)]
+    pub consent_counter: u64,
+}
+
+impl VersionedRecord for Counters {
+    const VERSION: u16 = 1;
+}
+
+// Storage structure
+pub struct ComplianceStorage {
+    pub kyc_cases: VersionedMap<String, KycCase, Memory>,
+    pub cases_by_subject: VersionedMap<Principal, CaseIdList, Memory>,
+    pub counters: VersionedMap<String, Counters, Memory>,
+    pub canister_registry: VersionedMap<String, CanisterRegistry, Memory>,
+    pub aml_transactions: VersionedMap<String, AmlTransaction, Memory>,
+    pub transactions_by_party: VersionedMap<Principal, TransactionIdList, Memory>,
+    pub alerts: VersionedMap<String, Alert, Memory>,
+    pub aml_config: VersionedMap<String, AmlConfig, Memory>,
+    /// Append-only hash chain keyed by sequence
+    pub audit_log: VersionedMap<u64, ChainedAuditEvent, Memory>,
+    /// Audit log sequences by user, event type and source canister
+    pub audit_by_user: VersionedMap<(Principal, u64), AuditIndexEntry, Memory>,
+    pub audit_by_event_type: VersionedMap<(AuditEventTypeKey, u64), AuditIndexEntry, Memory>,
+    pub audit_by_canister: VersionedMap<(Principal, u64), AuditIndexEntry, Memory>,
+    pub watchlist: VersionedMap<String, WatchlistEntry, Memory>,
+    pub screening_results: VersionedMap<String, ScreeningResult, Memory>,
+    pub screening_profiles: VersionedMap<Principal, ScreeningProfile, Memory>,
+    pub screening_config: VersionedMap<String, ScreeningConfig, Memory>,
+    /// Report rows, read only on download
+    pub reports: VersionedMap<String, Report, Memory>,
+    pub report_summaries: VersionedMap<String, ReportSummary, Memory>,
+    pub data_subject_requests: VersionedMap<String, DataSubjectRequest, Memory>,
+    /// Every published notice, keyed by purpose and zero-padded version
+    pub consent_notices: VersionedMap<String, ConsentNotice, Memory>,
+    pub consents: VersionedMap<String, ConsentRecord, Memory>,
+}
+
+impl ComplianceStorage {
+    pub fn init(memory: DefaultMemoryImpl) -> Self {
+        let memory_manager = init_memory_manager(memory, |_| Vec::new());
+
+        let mut storage = Self {
+            kyc_cases: VersionedMap::init(memory_manager.get(KYC_CASES_MEMORY_ID)),
+            cases_by_subject: VersionedMap::init(memory_manager.get(CASES_BY_SUBJECT_MEMORY_ID)),
+            counters: VersionedMap::init(memory_manager.get(COUNTERS_MEMORY_ID)),
+            canister_registry: VersionedMap::init(memory_manager.get(CANISTER_REGISTRY_MEMORY_ID)),
+            aml_transactions: VersionedMap::init(memory_manager.get(AML_TRANSACTIONS_MEMORY_ID)),
+            transactions_by_party: VersionedMap::init(memory_manager.get(TRANSACTIONS_BY_PARTY_MEMORY_ID)),
+            alerts: VersionedMap::init(memory_manager.get(ALERTS_MEMORY_ID)),
+            aml_config: VersionedMap::init(memory_manager.get(AML_CONFIG_MEMORY_ID)),
+            audit_log: VersionedMap::init(memory_manager.get(AUDIT_LOG_MEMORY_ID)),
+            audit_by_user: VersionedMap::init(memory_manager.get(AUDIT_BY_USER_MEMORY_ID)),
+            audit_by_event_type: VersionedMap::init(memory_manager.get(AUDIT_BY_EVENT_TYPE_MEMORY_ID)),
+            audit_by_canister: VersionedMap::init(memory_manager.get(AUDIT_BY_CANISTER_MEMORY_ID)),
+            watchlist: VersionedMap::init(memory_manager.get(WATCHLIST_MEMORY_ID)),
+            screening_results: VersionedMap::init(memory_manager.get(SCREENING_RESULTS_MEMORY_ID)),
+            screening_profiles: VersionedMap::init(memory_manager.get(SCREENING_PROFILES_MEMORY_ID)),
+            screening_config: VersionedMap::init(memory_manager.get(SCREENING_CONFIG_MEMORY_ID)),
+            reports: VersionedMap::init(memory_manager.get(REPORTS_MEMORY_ID)),
+            report_summaries: VersionedMap::init(memory_manager.get(REPORT_SUMMARIES_MEMORY_ID)),
+            data_subject_requests: VersionedMap::init(memory_manager.get(DATA_SUBJECT_REQUESTS_MEMORY_ID)),
+            consent_notices: VersionedMap::init(memory_manager.get(CONSENT_NOTICES_MEMORY_ID)),
+            consents: VersionedMap::init(memory_manager.get(CONSENTS_MEMORY_ID)),
+        };
+
+        // Logs written before the indexes existed are indexed once
+        if storage.audit_by_user.is_empty() {
+            let entries: Vec<ChainedAuditEvent> = storage.audit_log.iter().map(|(_, entry)| entry).collect();
+            entries.iter().for_each(|entry| storage.index_audit_entry(entry));
+        }
+        // Likewise reports stored before their summaries were kept apart
+        if storage.report_summaries.is_empty() {
+            let summaries: Vec<ReportSummary> = storage.reports.iter().map(|(_, report)| report.summary()).collect();
+            summaries.into_iter().for_each(|summary| {
+                storage.report_summaries.insert(summary.id.clone(), summary);
+            });
+        }
+        storage
+    }
+
+    // KYC case operations
+    pub fn get_kyc_case(&self, id: &str) -> Option<KycCase> {
+        self.kyc_cases.get(&id.to_string())
+    }
+
+    pub fn insert_kyc_case(&mut self, case: KycCase) {
+        let mut case_ids = self.cases_by_subject.get(&case.subject).unwrap_or_default();
+        if !case_ids.0.contains(&case.id) {
+            case_ids.0.push(case.id.clone());
+            self.cases_by_subject.insert(case.subject, case_ids);
+        }
+        self.kyc_cases.insert(case.id.clone(), case);
+    }
+
+    /// All of a subject's cases, oldest first
+    pub fn get_subject_cases(&self, subject: &Principal) -> Vec<KycCase> {
+        self.cases_by_subject
+            .get(subject)
+            .unwrap_or_default()
+            .0
+            .iter()
+            .filter_map(|id| self.get_kyc_case(id))
+            .collect()
+    }
+
+    /// A subject's most recent case
+    pub fn get_current_case(&self, subject: &Principal) -> Option<KycCase> {
+        self.cases_by_subject
+            .get(subject)
+            .and_then(|case_ids| case_ids.0.last().cloned())
+            .and_then(|id| self.get_kyc_case(&id))
+    }
+
+    pub fn get_cases_by_status(&self, status: &KycCaseStatus) -> Vec<KycCase> {
+        self.kyc_cases
+            .iter()
+            .filter(|(_, case)| &case.status == status)
+            .map(|(_, case)| case)
+            .collect()
+    }
+
+    pub fn get_unsynced_cases(&self) -> Vec<KycCase> {
+        self.kyc_cases
+            .iter()
+            .filter(|(_, case)| !case.identity_synced)
+            .map(|(_, case)| case)
+            .collect()
+    }
+
+    pub fn get_all_cases(&self) -> Vec<KycCase> {
+        self.kyc_cases.iter().map(|(_, case)| case).collect()
+    }
+
+    pub fn next_kyc_case_id(&mut self) -> String {
+        let mut counters = self.get_counters();
+
+        counters.kyc_case_counter += 1;
+        let id = generate_id("KYC", counters.kyc_case_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    // AML transaction operations
+    pub fn get_aml_transaction(&self, id: &str) -> Option<AmlTransaction> {
+        self.aml_transactions.get(&id.to_string())
+    }
+
+    pub fn insert_aml_transaction(&mut self, transaction: AmlTransaction) {
+        let mut transaction_ids = self.transactions_by_party.get(&transaction.party).unwrap_or_default();
+        if !transaction_ids.0.contains(&transaction.id) {
+            transaction_ids.0.push(transaction.id.clone());
+            self.transactions_by_party.insert(transaction.party, transaction_ids);
+        }
+        self.aml_transactions.insert(transaction.id.clone(), transaction);
+    }
+
+    /// A party's transactions that occurred at or after `since`
+    pub fn get_party_transactions_since(&self, party: &Principal, since: Timestamp) -> Vec<AmlTransaction> {
+        self.get_party_transactions(party)
+            .into_iter()
+            .filter(|transaction| transaction.occurred_at >= since)
+            .collect()
+    }
+
+    /// All of a party's transactions, oldest reported first
+    pub fn get_party_transactions(&self, party: &Principal) -> Vec<AmlTransaction> {
+        self.transactions_by_party
+            .get(party)
+            .unwrap_or_default()
+            .0
+            .iter()
+            .filter_map(|id| self.get_aml_transaction(id))
+            .collect()
+    }
+
+    pub fn has_reported_transaction(
+        &self,
+        party: &Principal,
+        reference_id: &str,
+        transaction_type: &AmlTransactionType,
+    ) -> bool {
+        self.get_party_transactions(party)
+            .iter()
+            .any(|transaction| {
+                transaction.reference_id == reference_id && &transaction.transaction_type == transaction_type
+            })
+    }
+
+    pub fn count_aml_transactions(&self) -> u32 {
+        self.aml_transactions.len() as u32
+    }
+
+    pub fn next_aml_transaction_id(&mut self) -> String {
+        let mut counters = self.get_counters();
+        counters.aml_transaction_counter += 1;
+        let id = generate_id("TXN", counters.aml_transaction_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    // Alert operations
+    pub fn get_alert(&self, id: &str) -> Option<Alert> {
+        self.alerts.get(&id.to_string())
+    }
+
+    pub fn insert_alert(&mut self, alert: Alert) {
+        self.alerts.insert(alert.id.clone(), alert);
+    }
+
+    /// The still-open alert a rule has raised on a subject, if any
+    pub fn get_active_alert(&self, subject: &Principal, rule: &AmlRule) -> Option<Alert> {
+        self.alerts
+            .iter()
+            .map(|(_, alert)| alert)
+            .find(|alert| &alert.subject == subject && &alert.rule == rule && !alert.status.is_closed())
+    }
+
+    pub fn get_alerts_by_status(&self, status: &AlertStatus) -> Vec<Alert> {
+        self.alerts
+            .iter()
+            .filter(|(_, alert)| &alert.status == status)
+            .map(|(_, alert)| alert)
+            .collect()
+    }
+
+    pub fn get_subject_alerts(&self, subject: &Principal) -> Vec<Alert> {
+        self.alerts
+            .iter()
+            .filter(|(_, alert)| &alert.subject == subject)
+            .map(|(_, alert)| alert)
+            .collect()
+    }
+
+    pub fn get_all_alerts(&self) -> Vec<Alert> {
+        self.alerts.iter().map(|(_, alert)| alert).collect()
+    }
+
+    pub fn next_alert_id(&mut self) -> String {
+        let mut counters = self.get_counters();
+        counters.alert_counter += 1;
+        let id = generate_id("ALERT", counters.alert_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    // AML configuration
+    pub fn get_aml_config(&self) -> AmlConfig {
+        self.aml_config
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_aml_config(&mut self, config: AmlConfig) {
+        self.aml_config.insert("default".to_string(), config);
+    }
+
+    // Audit log operations
+    pub fn get_audit_entry(&self, sequence: u64) -> Option<ChainedAuditEvent> {
+        self.audit_log.get(&sequence)
+    }
+
+    pub fn get_last_audit_entry(&self) -> Option<ChainedAuditEvent> {
+        self.audit_log.iter().next_back().map(|(_, entry)| entry)
+    }
+
+    pub fn audit_log_length(&self) -> u64 {
+        self.audit_log.len()
+    }
+
+    /// Append the next entry; entries are never updated or removed
+    pub fn append_audit_entry(&mut self, entry: ChainedAuditEvent) {
+        debug_assert_eq!(entry.sequence, self.audit_log.len());
+        self.index_audit_entry(&entry);
+        self.audit_log.insert(entry.sequence, entry);
+    }
+
+    fn index_audit_entry(&mut self, entry: &ChainedAuditEvent) {
+        self.audit_by_user.insert((entry.event.user_principal, entry.sequence), AuditIndexEntry);
+        self.audit_by_event_type.insert((audit_event_type_key(&entry.event.event_type), entry.sequence), AuditIndexEntry);
+        self.audit_by_canister.insert((entry.source_canister, entry.sequence), AuditIndexEntry);
+    }
+
+    /// Up to `limit` entries matching `filter` from `from_sequence` on, oldest first.
+    /// Walks the index of the filter's user, canister or event type when it has one,
+    /// and looks at no more than `AUDIT_QUERY_MAX_SCAN` candidates. Returns the
+    /// entries and the sequence to continue from, if the log goes on.
+    pub fn query_audit_log(
+        &self,
+        filter: &AuditEventFilter,
+        from_sequence: u64,
+        limit: usize,
+    ) -> (Vec<ChainedAuditEvent>, Option<u64>) {
+        let sequences: Box<dyn Iterator<Item = u64> + '_> = if let Some(user) = filter.user_principal {
+            Box::new(self.audit_by_user.range((user, from_sequence)..=(user, u64::MAX)).map(|((_, sequence), _)| sequence))
+        } else if let Some(canister) = filter.source_canister {
+            Box::new(self.audit_by_canister.range((canister, from_sequence)..=(canister, u64::MAX)).map(|((_, sequence), _)| sequence))
+        } else if let Some(event_type) = &filter.event_type {
+            let key = audit_event_type_key(event_type);
+            Box::new(self.audit_by_event_type.range((key, from_sequence)..=(key, u64::MAX)).map(|((_, sequence), _)| sequence))
+        } else {
+            Box::new(self.audit_log.range(from_sequence..).map(|(sequence, _)| sequence))
+        };
+
+        let mut entries = Vec::new();
+        let mut sequences = sequences.peekable();
+        let mut scanned = 0;
+        while let Some(sequence) = sequences.next() {
+            scanned += 1;
+            if let Some(entry) = self.get_audit_entry(sequence).filter(|entry| filter.matches(entry)) {
+                entries.push(entry);
+            }
+            if entries.len() >= limit || scanned >= AUDIT_QUERY_MAX_SCAN {
+                return (entries, sequences.peek().map(|_| sequence + 1));
+            }
+        }
+        (entries, None)
+    }
+
+    /// Every entry about `user`, oldest first
+    pub fn get_user_audit_log(&self, user: &Principal) -> Vec<ChainedAuditEvent> {
+        self.audit_by_user
+            .range((*user, 0)..=(*user, u64::MAX))
+            .filter_map(|((_, sequence), _)| self.get_audit_entry(sequence))
+            .collect()
+    }
+
+    // Watchlist operations
+    pub fn get_watchlist_entry(&self, id: &str) -> Option<WatchlistEntry> {
+        self.watchlist.get(&id.to_string())
+    }
+
+    pub fn insert_watchlist_entry(&mut self, entry: WatchlistEntry) {
+        self.watchlist.insert(entry.id.clone(), entry);
+    }
+
+    pub fn get_active_watchlist(&self) -> Vec<WatchlistEntry> {
+        self.watchlist
+            .iter()
+            .filter(|(_, entry)| entry.is_active)
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+
+    pub fn get_all_watchlist_entries(&self) -> Vec<WatchlistEntry> {
+        self.watchlist.iter().map(|(_, entry)| entry).collect()
+    }
+
+    pub fn next_watchlist_entry_id(&mut self) -> String {
+        let mut counters = self.get_counters();
+        counters.watchlist_entry_counter += 1;
+        let id = generate_id("WL", counters.watchlist_entry_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    // Screening operations
+    pub fn get_screening_result(&self, id: &str) -> Option<ScreeningResult> {
+        self.screening_results.get(&id.to_string())
+    }
+
+    pub fn insert_screening_result(&mut self, result: ScreeningResult) {
+        self.screening_results.insert(result.id.clone(), result);
+    }
+
+    pub fn get_subject_screenings(&self, subject: &Principal) -> Vec<ScreeningResult> {
+        self.screening_results
+            .iter()
+            .filter(|(_, result)| &result.subject == subject)
+            .map(|(_, result)| result)
+            .collect()
+    }
+
+    pub fn get_all_screening_results(&self) -> Vec<ScreeningResult> {
+        self.screening_results.iter().map(|(_, result)| result).collect()
+    }
+
+    pub fn next_screening_id(&mut self) -> String {
+        let mut counters = self.get_counters();
+        counters.screening_counter += 1;
+        let id = generate_id("SCR", counters.screening_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    pub fn get_screening_profile(&self, subject: &Principal) -> Option<ScreeningProfile> {
+        self.screening_profiles.get(subject)
+    }
+
+    pub fn set_screening_profile(&mut self, profile: ScreeningProfile) {
+        self.screening_profiles.insert(profile.subject, profile);
+    }
+
+    pub fn get_screening_config(&self) -> ScreeningConfig {
+        self.screening_config
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_screening_config(&mut self, config: ScreeningConfig) {
+        self.screening_config.insert("default".to_string(), config);
+    }
+
+    // Report operations
+    pub fn get_report(&self, id: &str) -> Option<Report> {
+        self.reports.get(&id.to_string())
+    }
+
+    /// Store a report, dropping the oldest of its type beyond `REPORT_RETENTION_PER_TYPE`
+    pub fn insert_report(&mut self, report: Report) {
+        let report_type = report.report_type.clone();
+        self.report_summaries.insert(report.id.clone(), report.summary());
+        self.reports.insert(report.id.clone(), report);
+
+        let expired: Vec<String> = self.get_report_summaries(Some(&report_type))
+            .into_iter()
+            .skip(REPORT_RETENTION_PER_TYPE)
+            .map(|summary| summary.id)
+            .collect();
+        for id in expired {
+            self.report_summaries.remove(&id);
+            self.reports.remove(&id);
+        }
+    }
+
+    /// Summaries of stored reports, optionally of one type, newest first
+    pub fn get_report_summaries(&self, report_type: Option<&ReportType>) -> Vec<ReportSummary> {
+        let mut summaries: Vec<ReportSummary> = self.report_summaries
+            .iter()
+            .map(|(_, summary)| summary)
+            .filter(|summary| report_type.is_none_or(|report_type| &summary.report_type == report_type))
+            .collect();
+        summaries.sort_by(|a, b| b.generated_at.cmp(&a.generated_at).then_with(|| b.id.cmp(&a.id)));
+        summaries
+    }
+
+    pub fn next_report_id(&mut self) -> String {
+        let mut counters = self.get_counters();
+        counters.report_counter += 1;
+        let id = generate_id(REPORT_PREFIX, counters.report_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    // Data subject request operations
+    pub fn insert_data_subject_request(&mut self, request: DataSubjectRequest) {
+        self.data_subject_requests.insert(request.id.clone(), request);
+    }
+
+    /// A subject's requests, oldest first
+    pub fn get_subject_data_requests(&self, subject: &Principal) -> Vec<DataSubjectRequest> {
+        self.data_subject_requests
+            .iter()
+            .filter(|(_, request)| &request.subject == subject)
+            .map(|(_, request)| request)
+            .collect()
+    }
+
+    pub fn get_all_data_subject_requests(&self) -> Vec<DataSubjectRequest> {
+        self.data_subject_requests.iter().map(|(_, request)| request).collect()
+    }
+
+    pub fn next_data_subject_request_id(&mut self) -> String {
+        let mut counters = self.get_counters();
+        counters.data_subject_request_counter += 1;
+        let id = generate_id("DSR", counters.data_subject_request_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    // Consent operations
+    pub fn insert_consent_notice(&mut self, notice: ConsentNotice) {
+        let key = format!("{:?}-{:010}", notice.purpose, notice.version);
+        self.consent_notices.insert(key, notice);
+    }
+
+    /// Every notice published for `purpose`, oldest first
+    pub fn get_consent_notices(&self, purpose: ConsentPurpose) -> Vec<ConsentNotice> {
+        let prefix = format!("{:?}-", purpose);
+        self.consent_notices
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, notice)| notice)
+            .collect()
+    }
+
+    pub fn get_current_consent_notice(&self, purpose: ConsentPurpose) -> Option<ConsentNotice> {
+        self.get_consent_notices(purpose).pop()
+    }
+
+    pub fn insert_consent(&mut self, consent: ConsentRecord) {
+        self.consents.insert(consent.id.clone(), consent);
+    }
+
+    /// A subject's consent records, oldest first
+    pub fn get_subject_consents(&self, subject: &Principal) -> Vec<ConsentRecord> {
+        self.consents
+            .iter()
+            .filter(|(_, consent)| &consent.subject == subject)
+            .map(|(_, consent)| consent)
+            .collect()
+    }
+
+    /// The subject's unrevoked consent for `purpose`, if any
+    pub fn get_active_consent(&self, subject: &Principal, purpose: ConsentPurpose) -> Option<ConsentRecord> {
+        self.get_subject_consents(subject)
+            .into_iter()
+            .find(|consent| consent.purpose == purpose && consent.is_active())
+    }
+
+    pub fn next_consent_id(&mut self) -> String {
+        let mut counters = self.get_counters();
+        counters.consent_counter += 1;
+        let id = generate_id("CNS", counters.consent_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    fn get_counters(&self) -> Counters {
+        self.counters
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn get_canister_registry(&self) -> CanisterRegistry {
+        self.canister_registry
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_canister_registry(&mut self, registry: CanisterRegistry) {
+        self.canister_registry.insert("default".to_string(), registry);
+    }
+}
+
+/// Event type name, zero-padded; index keys must have a bounded size
+type AuditEventTypeKey = [u8; 32];
+
+fn audit_event_type_key(event_type: &AuditEventType) -> AuditEventTypeKey {
+    let name = format!("{:?}", event_type);
+    let mut key = [0u8; 32];
+    let len = name.len().min(key.len());
+    key[..len].copy_from_slice(&name.as_bytes()[..len]);
+    key
+}
+
+// Thread-local storage
+thread_local! {
+    static STORAGE: RefCell<ComplianceStorage> = RefCell::new(ComplianceStorage::init(DefaultMemoryImpl::default()));
+}
+
+// Storage access functions
+pub fn with_storage<R>(f: impl FnOnce(&ComplianceStorage) -> R) -> R {
+    STORAGE.with(|storage| f(&storage.borrow()))
+}
+
+pub fn with_storage_mut<R>(f: impl FnOnce(&mut ComplianceStorage) -> R) -> R {
+    STORAGE.with(|storage| f(&mut storage.borrow_mut()))
+}
@@ -0,0 +1,659 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use shared::*;
+
+/// Lifecycle of a KYC case
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum KycCaseStatus {
+    DocumentsSubmitted,
+    UnderReview,
+    Approved,
+    Rejected,
+    Expired,
+}
+
+impl KycCaseStatus {
+    /// Status reported to student_identity_service
+    pub fn kyc_status(&self) -> KycStatus {
+        match self {
+            KycCaseStatus::DocumentsSubmitted | KycCaseStatus::UnderReview => KycStatus::InProgress,
+            KycCaseStatus::Approved => KycStatus::Verified,
+            KycCaseStatus::Rejected => KycStatus::Rejected,
+            KycCaseStatus::Expired => KycStatus::Expired,
+        }
+    }
+}
+
+/// Kind of identity document attached to a case
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum KycDocumentType {
+    GovernmentId,
+    Passport,
+    ProofOfAddress,
+    StudentId,
+    Other(String),
+}
+
+/// Document submitted by the case subject
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct KycDocumentSubmission {
+    pub document_type: KycDocumentType,
+    /// Off-chain reference to the document, e.g. a content hash or storage URL
+    pub reference: String,
+}
+
+/// Document recorded on a case
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct KycDocument {
+    pub document_type: KycDocumentType,
+    pub reference: String,
+    pub submitted_at: Timestamp,
+}
+
+/// Reviewer decision on a case
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum KycDecision {
+    Approve,
+    Reject,
+}
+
+/// Recorded reviewer decision
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct KycReview {
+    pub reviewer: Principal,
+    pub decision: KycDecision,
+    pub reason: String,
+    pub decided_at: Timestamp,
+}
+
+/// KYC case for a single principal
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct KycCase {
+    pub id: String,
+    pub subject: Principal,
+    pub status: KycCaseStatus,
+    pub documents: Vec<KycDocument>,
+    pub reviewer: Option<Principal>,
+    pub reviews: Vec<KycReview>,
+    pub opened_at: Timestamp,
+    pub updated_at: Timestamp,
+    pub approved_at: Option<Timestamp>,
+    pub expires_at: Option<Timestamp>,
+    /// Whether student_identity_service has acknowledged the current status
+    pub identity_synced: bool,
+}
+
+impl KycCase {
+    pub fn new(id: String, subject: Principal, documents: Vec<KycDocument>, now: Timestamp) -> Self {
+        Self {
+            id,
+            subject,
+            status: KycCaseStatus::DocumentsSubmitted,
+            documents,
+            reviewer: None,
+            reviews: Vec::new(),
+            opened_at: now,
+            updated_at: now,
+            approved_at: None,
+            expires_at: None,
+            identity_synced: false,
+        }
+    }
+
+    /// Move to `status`, marking the new status as not yet reported to student_identity_service
+    pub fn transition(&mut self, status: KycCaseStatus, now: Timestamp) {
+        self.status = status;
+        self.updated_at = now;
+        self.identity_synced = false;
+    }
+
+    pub fn is_expired_at(&self, now: Timestamp) -> bool {
+        self.status == KycCaseStatus::Approved
+            && self.expires_at.map(|expires_at| now >= expires_at).unwrap_or(false)
+    }
+}
+
+/// KYC case counts by status
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Serialize)]
+pub struct KycCaseStats {
+    pub total_cases: u32,
+    pub documents_submitted: u32,
+    pub under_review: u32,
+    pub approved: u32,
+    pub rejected: u32,
+    pub expired: u32,
+    pub pending_identity_sync: u32,
+}
+
+// ============================================================================
+// AML MONITORING
+// ============================================================================
+
+/// Tunable parameters for the AML rules
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AmlConfig {
+    /// Single transactions at or above this amount raise a threshold alert
+    pub threshold: Amount,
+    /// Transactions at or above this share of the threshold (in basis points) count
+    /// towards structuring when they stay below it
+    pub structuring_floor_bps: u32,
+    /// Number of near-threshold payments within the window that indicates structuring
+    pub structuring_min_count: u32,
+    pub structuring_window: Timestamp,
+    /// Transactions by one party within the window above which velocity alerts are raised
+    pub velocity_max_count: u32,
+    pub velocity_max_amount: Amount,
+    pub velocity_window: Timestamp,
+    /// How far back to look for a reverse flow between a borrower and their cosigner
+    pub circular_flow_window: Timestamp,
+}
+
+impl Default for AmlConfig {
+    fn default() -> Self {
+        Self {
+            threshold: AML_CHECK_THRESHOLD,
+            structuring_floor_bps: 8_000, // 80% of the threshold
+            structuring_min_count: 3,
+            structuring_window: 7 * 24 * 60 * 60 * 1_000_000_000, // 7 days
+            velocity_max_count: 10,
+            velocity_max_amount: 2 * AML_CHECK_THRESHOLD,
+            velocity_window: 24 * 60 * 60 * 1_000_000_000, // 1 day
+            circular_flow_window: 30 * 24 * 60 * 60 * 1_000_000_000, // 30 days
+        }
+    }
+}
+
+impl AmlConfig {
+    pub fn validate(&self) -> StudiFiResult<()> {
+        validate_amount(self.threshold)?;
+        validate_amount(self.velocity_max_amount)?;
+        if self.structuring_floor_bps == 0 || self.structuring_floor_bps >= 10_000 {
+            return Err(StudiFiError::InvalidInput(
+                "Structuring floor must be between 1 and 9999 basis points".to_string()
+            ));
+        }
+        if self.structuring_min_count < 2 || self.velocity_max_count == 0 {
+            return Err(StudiFiError::InvalidInput("Rule counts are too low".to_string()));
+        }
+        if self.structuring_window == 0 || self.velocity_window == 0 || self.circular_flow_window == 0 {
+            return Err(StudiFiError::InvalidInput("Rule windows cannot be empty".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Smallest amount counted towards structuring
+    pub fn structuring_floor(&self) -> Amount {
+        (self.threshold as u128 * self.structuring_floor_bps as u128 / 10_000) as Amount
+    }
+}
+
+/// Transaction recorded for AML monitoring
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AmlTransaction {
+    pub id: String,
+    pub reference_id: String,
+    pub transaction_type: AmlTransactionType,
+    pub amount: Amount,
+    pub party: Principal,
+    pub counterparty: Option<Principal>,
+    pub loan_id: Option<String>,
+    pub occurred_at: Timestamp,
+    /// Canister that reported the transaction
+    pub reported_by: Principal,
+    pub recorded_at: Timestamp,
+}
+
+/// AML rule that raised an alert
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum AmlRule {
+    /// A single transaction at or above the reporting threshold
+    Threshold,
+    /// Repeated payments just under the threshold
+    Structuring,
+    /// Unusually many or large transactions in a short window
+    Velocity,
+    /// Funds moving back and forth between a borrower and their cosigner
+    CircularFlow,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, PartialOrd, Serialize)]
+pub enum AlertSeverity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+/// Triage state of an alert
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum AlertStatus {
+    Open,
+    UnderInvestigation,
+    Escalated,
+    /// Closed as a false positive
+    Dismissed,
+    /// Closed with a suspicious activity report filed
+    Reported,
+}
+
+impl AlertStatus {
+    pub fn is_closed(&self) -> bool {
+        matches!(self, AlertStatus::Dismissed | AlertStatus::Reported)
+    }
+}
+
+/// Analyst decision on an alert under investigation
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum AlertDisposition {
+    Escalate,
+    Dismiss,
+    Report,
+}
+
+/// Analyst note on an alert
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AlertNote {
+    pub author: Principal,
+    pub note: String,
+    pub created_at: Timestamp,
+}
+
+/// Suspicious activity raised by an AML rule
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct Alert {
+    pub id: String,
+    pub rule: AmlRule,
+    pub severity: AlertSeverity,
+    pub status: AlertStatus,
+    pub subject: Principal,
+    /// Transactions that triggered the alert; later hits of the same rule on the
+    /// same subject are added while the alert is still open
+    pub transaction_ids: Vec<String>,
+    pub description: String,
+    pub assigned_to: Option<Principal>,
+    pub notes: Vec<AlertNote>,
+    pub raised_at: Timestamp,
+    pub updated_at: Timestamp,
+    pub closed_at: Option<Timestamp>,
+}
+
+/// AML alert counts by status
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Serialize)]
+pub struct AmlStats {
+    pub total_transactions: u32,
+    pub total_alerts: u32,
+    pub open: u32,
+    pub under_investigation: u32,
+    pub escalated: u32,
+    pub dismissed: u32,
+    pub reported: u32,
+}
+
+// ============================================================================
+// AUDIT LOG
+// ============================================================================
+
+/// Audit event as appended to the hash chain
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ChainedAuditEvent {
+    /// Position in the chain, starting at 0
+    pub sequence: u64,
+    pub event: AuditEvent,
+    /// Canister that shipped the event
+    pub source_canister: Principal,
+    pub ingested_at: Timestamp,
+    /// Hash of the preceding entry, or `AUDIT_GENESIS_HASH` for the first
+    pub previous_hash: String,
+    /// Hex-encoded SHA-256 over this entry's contents and `previous_hash`
+    pub hash: String,
+}
+
+/// Criteria for audit log queries; unset fields match everything
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct AuditEventFilter {
+    pub user_principal: Option<Principal>,
+    pub event_type: Option<AuditEventType>,
+    pub source_canister: Option<Principal>,
+    /// Inclusive event timestamp range
+    pub from_timestamp: Option<Timestamp>,
+    pub to_timestamp: Option<Timestamp>,
+}
+
+impl AuditEventFilter {
+    pub fn matches(&self, entry: &ChainedAuditEvent) -> bool {
+        self.user_principal.is_none_or(|user| entry.event.user_principal == user)
+            && self.event_type.as_ref().is_none_or(|event_type| &entry.event.event_type == event_type)
+            && self.source_canister.is_none_or(|canister| entry.source_canister == canister)
+            && self.from_timestamp.is_none_or(|from| entry.event.timestamp >= from)
+            && self.to_timestamp.is_none_or(|to| entry.event.timestamp <= to)
+    }
+}
+
+/// One page of an audit log query
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditEventPage {
+    pub items: Vec<ChainedAuditEvent>,
+    /// `from_sequence` for the next page; None once no entries are left to look at
+    pub next_sequence: Option<u64>,
+}
+
+/// Latest state of the audit chain
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditChainHead {
+    pub length: u64,
+    pub head_hash: String,
+}
+
+/// Result of re-computing part of the audit chain
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditChainVerification {
+    pub from: u64,
+    pub to: u64,
+    pub entries_checked: u64,
+    pub is_valid: bool,
+    /// First sequence whose stored hash or link does not match
+    pub first_invalid_sequence: Option<u64>,
+    pub failure_reason: Option<String>,
+}
+
+// ============================================================================
+// SANCTIONS SCREENING
+// ============================================================================
+
+/// Watchlist entry as uploaded by an administrator
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WatchlistEntryInput {
+    /// Identifier of the entry on the source list
+    pub source_reference: String,
+    pub name: String,
+    pub aliases: Vec<String>,
+    /// ISO 8601 date, e.g. "1990-01-31"
+    pub date_of_birth: Option<String>,
+    /// ISO 3166 alpha-2 country code
+    pub country: Option<String>,
+}
+
+/// Watchlist entry loaded for screening
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct WatchlistEntry {
+    pub id: String,
+    /// Source list, e.g. "OFAC SDN"
+    pub list_name: String,
+    pub source_reference: String,
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub date_of_birth: Option<String>,
+    pub country: Option<String>,
+    /// Normalized name and aliases used for matching
+    pub normalized_names: Vec<String>,
+    pub is_active: bool,
+    pub added_by: Principal,
+    pub added_at: Timestamp,
+}
+
+/// Name matching thresholds and adjustments
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ScreeningConfig {
+    /// Scores at or above this are held for review
+    pub potential_match_threshold: f64,
+    /// Scores at or above this are treated as matches
+    pub match_threshold: f64,
+    /// Subtracted from the name score when both dates of birth are known and differ
+    pub date_of_birth_mismatch_penalty: f64,
+    /// Subtracted from the name score when both countries are known and differ
+    pub country_mismatch_penalty: f64,
+}
+
+impl Default for ScreeningConfig {
+    fn default() -> Self {
+        Self {
+            potential_match_threshold: 0.85,
+            match_threshold: 0.95,
+            date_of_birth_mismatch_penalty: 0.15,
+            country_mismatch_penalty: 0.05,
+        }
+    }
+}
+
+impl ScreeningConfig {
+    pub fn validate(&self) -> StudiFiResult<()> {
+        let in_unit_range = |value: f64| (0.0..=1.0).contains(&value);
+        if !in_unit_range(self.potential_match_threshold)
+            || !in_unit_range(self.match_threshold)
+            || !in_unit_range(self.date_of_birth_mismatch_penalty)
+            || !in_unit_range(self.country_mismatch_penalty)
+        {
+            return Err(StudiFiError::InvalidInput("Thresholds and penalties must be between 0 and 1".to_string()));
+        }
+        if self.potential_match_threshold > self.match_threshold {
+            return Err(StudiFiError::InvalidInput(
+                "Potential match threshold cannot exceed the match threshold".to_string()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Identifying details a party has on file for screening
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ScreeningProfile {
+    pub subject: Principal,
+    pub full_name: String,
+    pub date_of_birth: Option<String>,
+    pub country: Option<String>,
+    pub updated_at: Timestamp,
+}
+
+/// Watchlist entry that scored above the review threshold
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct WatchlistMatch {
+    pub entry_id: String,
+    pub list_name: String,
+    /// Entry name or alias that scored highest
+    pub matched_name: String,
+    /// Name similarity after date of birth and country adjustments, 0 to 1
+    pub score: f64,
+    pub date_of_birth_matches: Option<bool>,
+    pub country_matches: Option<bool>,
+}
+
+/// Analyst decision on a screening held for review
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum ScreeningReviewDecision {
+    /// The matches are not the subject; later screenings ignore these entries
+    ClearFalsePositive,
+    ConfirmMatch,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ScreeningReview {
+    pub reviewer: Principal,
+    pub decision: ScreeningReviewDecision,
+    pub reason: String,
+    pub reviewed_at: Timestamp,
+    /// Details the reviewed screening ran on; a clearance only holds while the
+    /// subject's profile still carries them
+    #[serde(default)]
+    pub screened_name: Option<String>,
+    #[serde(default)]
+    pub date_of_birth: Option<String>,
+    #[serde(default)]
+    pub country: Option<String>,
+}
+
+impl ScreeningReview {
+    pub fn covers(&self, profile: &ScreeningProfile) -> bool {
+        self.screened_name.as_ref() == Some(&profile.full_name)
+            && self.date_of_birth == profile.date_of_birth
+            && self.country == profile.country
+    }
+}
+
+/// Persisted result of one screening
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ScreeningResult {
+    pub id: String,
+    pub subject: Principal,
+    pub role: ScreeningRole,
+    pub context: String,
+    pub screened_name: Option<String>,
+    pub date_of_birth: Option<String>,
+    pub country: Option<String>,
+    pub outcome: ScreeningOutcome,
+    pub matches: Vec<WatchlistMatch>,
+    /// Active watchlist entries at the time of screening
+    pub entries_screened: u32,
+    pub requested_by: Principal,
+    pub screened_at: Timestamp,
+    pub review: Option<ScreeningReview>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Serialize)]
+pub struct ScreeningStats {
+    pub active_watchlist_entries: u32,
+    pub total_screenings: u32,
+    pub clear: u32,
+    pub potential_matches: u32,
+    pub matches: u32,
+    pub details_required: u32,
+    pub awaiting_review: u32,
+}
+
+// ============================================================================
+// REGULATORY REPORTS
+// ============================================================================
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum ReportType {
+    /// Every loan with its status and balance
+    LoanTape,
+    /// Outstanding balances by days past due
+    DelinquencyAging,
+    /// Loans in default
+    Defaults,
+    /// Suspicious activity report drafts for escalated and reported AML alerts
+    SarDrafts,
+}
+
+impl ReportType {
+    pub const ALL: [ReportType; 4] = [
+        ReportType::LoanTape,
+        ReportType::DelinquencyAging,
+        ReportType::Defaults,
+        ReportType::SarDrafts,
+    ];
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+/// Generated report, kept as a table and rendered to CSV or JSON on download
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct Report {
+    pub id: String,
+    pub report_type: ReportType,
+    pub generated_at: Timestamp,
+    /// None when generated by the report schedule
+    pub requested_by: Option<Principal>,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// Report metadata without its rows
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ReportSummary {
+    pub id: String,
+    pub report_type: ReportType,
+    pub generated_at: Timestamp,
+    pub requested_by: Option<Principal>,
+    pub row_count: u32,
+}
+
+impl Report {
+    pub fn summary(&self) -> ReportSummary {
+        ReportSummary {
+            id: self.id.clone(),
+            report_type: self.report_type.clone(),
+            generated_at: self.generated_at,
+            requested_by: self.requested_by,
+            row_count: self.rows.len() as u32,
+        }
+    }
+}
+
+// ============================================================================
+// DATA SUBJECT REQUESTS
+// ============================================================================
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum DataSubjectRequestType {
+    Export,
+    Erasure,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum DataSubjectRequestStatus {
+    InProgress,
+    Completed,
+    /// Some canisters could not be reached; an erasure resumes when requested again
+    Incomplete,
+    /// Records backing outstanding loans were retained; the erasure runs again once they are repaid
+    PartiallyRetained,
+}
+
+/// Compliance log entry for a data subject's export or erasure request
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct DataSubjectRequest {
+    pub id: String,
+    pub subject: Principal,
+    pub request_type: DataSubjectRequestType,
+    pub status: DataSubjectRequestStatus,
+    /// Stands in for the subject on pseudonymized records (erasure only)
+    pub pseudonym: Option<Principal>,
+    /// Whether the subject had outstanding loans when the erasure last ran
+    pub has_active_loans: bool,
+    /// What each canister erased, pseudonymized and retained (erasure only)
+    pub receipts: Vec<ErasureReceipt>,
+    pub failed_services: Vec<String>,
+    pub requested_at: Timestamp,
+    pub completed_at: Option<Timestamp>,
+}
+
+/// Everything this service discloses about a data subject. AML alerts and
+/// screening results are withheld so investigations are not revealed to the subject.
+#[derive(Clone, Debug, Serialize)]
+pub struct ComplianceSubjectData {
+    pub kyc_cases: Vec<KycCase>,
+    pub screening_profile: Option<ScreeningProfile>,
+    pub aml_transactions: Vec<AmlTransaction>,
+    pub audit_events: Vec<ChainedAuditEvent>,
+    pub data_subject_requests: Vec<DataSubjectRequest>,
+    pub consents: Vec<ConsentRecord>,
+}
+
+// ============================================================================
+// CONSENT
+// ============================================================================
+
+/// A data subject's consent to one version of a purpose's notice. Records are
+/// never deleted; revoking stamps `revoked_at`.
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ConsentRecord {
+    pub id: String,
+    pub subject: Principal,
+    pub purpose: ConsentPurpose,
+    pub notice_version: u32,
+    /// The notice text exactly as it was shown when consent was given
+    pub text_shown: String,
+    pub granted_at: Timestamp,
+    pub revoked_at: Option<Timestamp>,
+}
+
+impl ConsentRecord {
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none()
+    }
+}
@@ -228,6 +228,35 @@ impl CommunityValidationEngine {
         with_storage(|storage| storage.get_active_validations())
     }
 
+    /// Remove the evidence from a student's requests after they withdraw disclosure
+    /// consent, returning how many requests changed
+    pub fn withdraw_disclosure(student_id: Principal, now: Timestamp) -> u32 {
+        with_storage_mut(|storage| {
+            let mut withdrawn = 0;
+            for mut request in storage.get_validations_for_student(&student_id) {
+                if request.disclosure_withdrawn_at.is_some() {
+                    continue;
+                }
+                request.withdraw_disclosure(now);
+                storage.insert_validation_request(request.id.clone(), request);
+                withdrawn += 1;
+            }
+            withdrawn
+        })
+    }
+
+    /// Students whose evidence is still disclosed to validators
+    pub fn students_with_disclosed_evidence() -> Vec<Principal> {
+        let mut students: Vec<Principal> = with_storage(|storage| storage.get_all_validation_requests())
+            .into_iter()
+            .filter(|request| request.disclosure_withdrawn_at.is_none() && !request.evidence.is_empty())
+            .map(|request| request.student_id)
+            .collect();
+        students.sort();
+        students.dedup();
+        students
+    }
+
     /// Get validation requests that need processing
     pub fn get_validations_to_process() -> Vec<CommunityValidationRequest> {
         with_storage(|storage| storage.get_validations_to_process())
@@ -20,6 +20,9 @@ pub struct CommunityValidationRequest {
     pub votes: Vec<CommunityVote>,
     pub final_adjustment: Option<i32>,
     pub processed_at: Option<Timestamp>,
+    /// When the student withdrew consent to disclose the evidence, which was then removed
+    #[serde(default)]
+    pub disclosure_withdrawn_at: Option<Timestamp>,
 }
 
 impl CommunityValidationRequest {
@@ -49,6 +52,18 @@ impl CommunityValidationRequest {
             votes: Vec::new(),
             final_adjustment: None,
             processed_at: None,
+            disclosure_withdrawn_at: None,
+        }
+    }
+
+    /// Remove the evidence once the student withdraws disclosure consent. Requests
+    /// whose adjustment has not been applied yet are rejected, since it rests on that evidence.
+    pub fn withdraw_disclosure(&mut self, now: Timestamp) {
+        self.evidence.clear();
+        self.disclosure_withdrawn_at = Some(now);
+        if matches!(self.status, ValidationStatus::Active | ValidationStatus::Completed) {
+            self.status = ValidationStatus::Rejected;
+            self.processed_at = Some(now);
         }
     }
 
@@ -18,7 +18,11 @@ use shared::*;
 
 /// Initialize the canister
 #[init]
-fn init() {
+fn init(args: Option<ServiceInitArgs>) {
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("Credit Assessment Service canister initialized");
 }
 
@@ -30,7 +34,12 @@ fn pre_upgrade() {
 
 /// Post-upgrade hook
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<ServiceInitArgs>) {
+    // Keep the previously registered canisters unless a new registry is supplied
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("Credit Assessment Service canister upgraded successfully");
 }
 
@@ -42,8 +51,11 @@ async fn submit_loan_application(
     purpose: LoanPurpose,
     academic_info: AcademicInfo,
     financial_info: FinancialInfo,
+    session_id: Option<String>,
 ) -> StudiFiResult<LoanApplication> {
     let caller = caller();
+    authorize_caller_session(session_id, Permission::SubmitLoanApplication).await?;
+    ComplianceClient::require_consent(caller, ConsentPurpose::CreditScoring).await?;
 
     // Validate inputs
     validate_amount(requested_amount)?;
@@ -104,6 +116,9 @@ async fn process_application(application_id: String) -> StudiFiResult<LoanApplic
         return Err(StudiFiError::InvalidInput("Application already processed".to_string()));
     }
 
+    // Consent may have been revoked since the application was submitted
+    ComplianceClient::require_consent(application.student_id, ConsentPurpose::CreditScoring).await?;
+
     // Calculate credit score
     let credit_score = CreditScoringEngine::calculate_enhanced_score(
         &application.academic_info,
@@ -126,7 +141,7 @@ async fn process_application(application_id: String) -> StudiFiResult<LoanApplic
             application.requested_amount,
             &effective_credit_score,
             &application.purpose,
-        );
+        )?;
         (ApplicationStatus::Approved, Some(terms))
     } else {
         (ApplicationStatus::Rejected, None)
@@ -178,7 +193,7 @@ fn get_application_stats() -> ApplicationStats {
 }
 
 /// Search applications with filters
-#[query]
+#[query(guard = "require_view_all_data")]
 #[candid_method(query)]
 fn search_applications(
     filters: ApplicationFilters,
@@ -204,15 +219,13 @@ fn generate_loan_terms(
     }
 
     let mock_credit_score = CreditScore::new(credit_score, Vec::new(), 0.9);
-    let terms = CreditScoringEngine::generate_loan_terms(amount, &mock_credit_score, &purpose);
-    Ok(terms)
+    CreditScoringEngine::generate_loan_terms(amount, &mock_credit_score, &purpose)
 }
 
 /// Update scoring configuration (admin only)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 fn update_scoring_config(config: ScoringConfig) -> StudiFiResult<()> {
-    // In production, check admin authorization
     with_storage_mut(|storage| {
         storage.set_scoring_config(config);
     });
@@ -242,6 +255,8 @@ async fn create_validation_request(
     evidence: Vec<ValidationEvidence>,
 ) -> StudiFiResult<CommunityValidationRequest> {
     let caller = caller();
+    // Validators see the evidence, so the student must have agreed to disclose it
+    ComplianceClient::require_consent(student_id, ConsentPurpose::CommunityValidationDisclosure).await?;
     CommunityValidationEngine::create_validation_request(
         student_id,
         application_id,
@@ -264,6 +279,11 @@ async fn cast_community_vote(
     justification: String,
 ) -> StudiFiResult<CommunityVote> {
     let caller = caller();
+    let student_id = with_storage(|storage| storage.get_validation_request(&validation_id))
+        .ok_or_else(|| StudiFiError::NotFound("Validation request not found".to_string()))?
+        .student_id;
+    // Stop reviews of students who have since withdrawn their consent
+    ComplianceClient::require_consent(student_id, ConsentPurpose::CommunityValidationDisclosure).await?;
     CommunityValidationEngine::cast_community_vote(
         validation_id,
         caller,
@@ -298,6 +318,19 @@ fn get_effective_credit_score(student_id: Principal) -> Option<u32> {
     CommunityValidationEngine::get_effective_credit_score(student_id)
 }
 
+/// Get effective credit scores for many students at once (for regulatory reporting)
+#[query(guard = "require_service_or_view_all_data")]
+#[candid_method(query)]
+fn get_effective_credit_scores(student_ids: Vec<Principal>) -> Vec<CreditScoreSnapshot> {
+    student_ids
+        .into_iter()
+        .map(|student_id| CreditScoreSnapshot {
+            student_id,
+            score: CommunityValidationEngine::get_effective_credit_score(student_id),
+        })
+        .collect()
+}
+
 /// Get validation requests for a student
 #[query]
 #[candid_method(query)]
@@ -340,13 +373,27 @@ fn check_validation_eligibility(user: Principal) -> bool {
     CommunityValidationEngine::check_validation_eligibility(user)
 }
 
-/// Process expired validations (automation function)
-#[update]
+/// Process expired validations (automation function). Evidence of students whose
+/// disclosure consent lapsed without compliance_service reaching us is removed as well.
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 async fn process_expired_validations() -> StudiFiResult<u32> {
+    for student_id in CommunityValidationEngine::students_with_disclosed_evidence() {
+        if let Ok(false) = ComplianceClient::has_consent(student_id, ConsentPurpose::CommunityValidationDisclosure).await {
+            CommunityValidationEngine::withdraw_disclosure(student_id, current_time());
+        }
+    }
     CommunityValidationEngine::process_expired_validations()
 }
 
+/// Remove a student's evidence from validation requests once they revoke disclosure
+/// consent. Called by compliance_service; returns how many requests changed.
+#[update(guard = "require_compliance_service")]
+#[candid_method(update)]
+fn withdraw_validation_disclosure(student_id: Principal) -> StudiFiResult<u32> {
+    Ok(CommunityValidationEngine::withdraw_disclosure(student_id, current_time()))
+}
+
 /// Create loan from approved application (inter-canister call)
 #[update]
 #[candid_method(update)]
@@ -357,17 +404,20 @@ async fn create_loan_from_application(application_id: String) -> StudiFiResult<S
     let application = with_storage(|storage| storage.get_application(&application_id))
         .ok_or_else(|| StudiFiError::NotFound("Application not found".to_string()))?;
 
-    // Verify application is approved
-    if application.status != ApplicationStatus::Approved {
-        return Err(StudiFiError::InvalidInput("Application not approved".to_string()));
+    if application.student_id != caller {
+        return Err(StudiFiError::Unauthorized("Only the applicant can create a loan from this application".to_string()));
     }
 
-    // Verify student identity with identity service
-    let identity_verified = validate_student_identity(application.student_id).await?;
-    if !identity_verified {
-        return Err(StudiFiError::Unauthorized("Student identity not verified".to_string()));
+    // Verify application is approved. A pending one whose last attempt had an unknown
+    // outcome is retried: the loan service returns the loan if that attempt created it.
+    if !matches!(application.status, ApplicationStatus::Approved | ApplicationStatus::LoanCreationPending) {
+        return Err(StudiFiError::InvalidInput("Application not approved".to_string()));
     }
 
+    // Fail fast if the downstream services are not registered
+    service_config(ServiceName::StudentIdentity)?;
+    service_config(ServiceName::LoanManagement)?;
+
     // Get effective credit score
     let credit_score = get_effective_credit_score(application.student_id)
         .unwrap_or_else(|| application.credit_score.as_ref().map(|cs| cs.score).unwrap_or(500));
@@ -377,22 +427,54 @@ async fn create_loan_from_application(application_id: String) -> StudiFiResult<S
         application.requested_amount,
         &CreditScore::new(credit_score, Vec::new(), 0.9),
         &application.purpose,
-    );
+    )?;
+
+    // Reserve the application before awaiting so a concurrent call cannot create a second loan
+    set_application_status(&application_id, ApplicationStatus::LoanCreationPending, None);
+
+    // Verify student identity with identity service
+    let identity_verified = match IdentityClient::is_student_verified(application.student_id).await {
+        Ok(verified) => verified,
+        Err(e) => {
+            compensate_failed_loan_creation(&application_id, &e);
+            return Err(e);
+        }
+    };
+    if !identity_verified {
+        let error = StudiFiError::Unauthorized("Student identity not verified".to_string());
+        compensate_failed_loan_creation(&application_id, &error);
+        return Err(error);
+    }
 
     // Create loan via loan management service
-    let loan_result = create_loan_via_service(
-        application.student_id,
-        application.requested_amount,
-        terms.interest_rate,
-        terms.term_months,
-        application_id.clone(),
-    ).await?;
+    let request = loan_request(&application_id, application.student_id, &terms, &application.purpose);
+    let loan = match LoanClient::create_loan(request).await {
+        Ok(loan) => loan,
+        Err(e) if is_definite_rejection(&e) => {
+            compensate_failed_loan_creation(&application_id, &e);
+            return Err(e);
+        }
+        Err(e) => {
+            // The loan may exist; stay pending so a retry reconciles it
+            settle_pending_application(
+                &application_id,
+                ApplicationStatus::LoanCreationPending,
+                format!("Loan creation outcome unknown, retry to reconcile: {}", e),
+            );
+            return Err(e);
+        }
+    };
 
     // Update application status
+    set_application_status(
+        &application_id,
+        ApplicationStatus::LoanCreated,
+        Some(format!("Loan {} created", loan.id)),
+    );
     with_storage_mut(|storage| {
         if let Some(mut app) = storage.get_application(&application_id) {
-            app.status = ApplicationStatus::LoanCreated;
-            app.loan_id = Some(loan_result.clone());
+            app.loan_id = Some(loan.id.clone());
+            app.loan_terms = Some(terms.clone());
             storage.update_application(application_id.clone(), app);
         }
     });
@@ -403,43 +485,84 @@ async fn create_loan_from_application(application_id: String) -> StudiFiResult<S
         application.student_id,
         ic_cdk::id(),
         "create_loan_from_application",
-        &format!("Loan created from application {}", application_id),
+        &format!("Loan {} created from application {}", loan.id, application_id),
         true,
     ));
 
-    Ok(loan_result)
+    Ok(loan.id)
 }
 
-/// Validate student identity with identity service
-async fn validate_student_identity(student_id: Principal) -> StudiFiResult<bool> {
-    // This would be an actual inter-canister call in production
-    // For now, we'll simulate the call
-    ic_cdk::println!("Validating student identity for: {:?}", student_id);
+/// Update an application's status, optionally appending a note
+fn set_application_status(application_id: &str, status: ApplicationStatus, note: Option<String>) {
+    with_storage_mut(|storage| {
+        if let Some(mut app) = storage.get_application(application_id) {
+            app.status = status;
+            if let Some(note) = note {
+                app.notes.push(note);
+            }
+            app.set_updated_at(current_time());
+            storage.update_application(application_id.to_string(), app);
+        }
+    });
+}
 
-    // Simulate identity verification
-    // In real implementation: call_canister(identity_service_id, "is_student_verified", student_id, 3).await
-    Ok(true)
+/// Update an application still awaiting its loan. A concurrent attempt may have
+/// created the loan in the meantime, and that outcome is kept.
+fn settle_pending_application(application_id: &str, status: ApplicationStatus, note: String) {
+    let pending = with_storage(|storage| storage.get_application(application_id))
+        .is_some_and(|app| app.status == ApplicationStatus::LoanCreationPending);
+    if pending {
+        set_application_status(application_id, status, Some(note));
+    }
 }
 
-/// Create loan via loan management service
-async fn create_loan_via_service(
-    student_id: Principal,
-    amount: Amount,
-    interest_rate: Percentage,
-    term_months: u32,
-    application_id: String,
-) -> StudiFiResult<String> {
-    // This would be an actual inter-canister call in production
-    // For now, we'll simulate the call
-    ic_cdk::println!(
-        "Creating loan via service: student={:?}, amount={}, rate={}, term={}",
-        student_id, amount, interest_rate, term_months
+/// Return an application to `Approved` after a failed loan creation so it can be retried
+fn compensate_failed_loan_creation(application_id: &str, error: &StudiFiError) {
+    settle_pending_application(
+        application_id,
+        ApplicationStatus::Approved,
+        format!("Loan creation failed: {}", error),
     );
 
-    // Simulate loan creation
-    // In real implementation: call_canister(loan_service_id, "create_loan", args, 3).await
-    let loan_id = generate_id("LOAN", current_time());
-    Ok(loan_id)
+    log_audit_event(create_audit_event(
+        shared::AuditEventType::LoanCreated,
+        caller(),
+        ic_cdk::id(),
+        "create_loan_from_application",
+        &format!("Loan creation failed for application {}: {}", application_id, error),
+        false,
+    ));
+}
+
+/// Whether a failed loan creation certainly created nothing: the loan service answered
+/// with its own error. Transport failures and rejected or trapped calls are ambiguous,
+/// since the call may have run before the reply was lost.
+fn is_definite_rejection(error: &StudiFiError) -> bool {
+    !matches!(
+        error,
+        StudiFiError::NetworkError(_) | StudiFiError::ExternalServiceError(_) | StudiFiError::SystemError(_)
+    )
+}
+
+/// Build the loan management service request for approved loan terms
+fn loan_request(application_id: &str, student_id: Principal, terms: &LoanTerms, purpose: &LoanPurpose) -> CreateLoanRequest {
+    let mut special_conditions = terms.special_conditions.clone();
+    if !terms.prepayment_penalty {
+        special_conditions.push("no_prepayment_penalty".to_string());
+    }
+
+    CreateLoanRequest {
+        student_id,
+        principal_amount: terms.approved_amount,
+        interest_rate: terms.interest_rate,
+        term_months: terms.term_months,
+        grace_period_months: terms.grace_period_months,
+        purpose: format!("{:?}", purpose),
+        collateral_required: terms.collateral_required,
+        cosigner_id: None,
+        special_conditions,
+        application_id: application_id.to_string(),
+    }
 }
 
 /// Get platform statistics
@@ -467,6 +590,45 @@ fn get_platform_stats() -> Statistics {
     })
 }
 
+/// Re-sync the cached role assignments used by authorization guards
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+async fn refresh_role_cache() -> StudiFiResult<u32> {
+    sync_role_cache().await
+}
+
+/// Get the canister registry used for inter-canister calls
+#[query]
+#[candid_method(query)]
+fn get_canister_registry() -> CanisterRegistry {
+    current_canister_registry()
+}
+
+/// Get the health of downstream canisters this canister has called since its last upgrade
+#[query]
+#[candid_method(query)]
+fn get_circuit_breakers() -> Vec<CircuitBreaker> {
+    circuit_breaker_states()
+}
+
+// ============================================================================
+// DATA SUBJECT REQUESTS
+// ============================================================================
+
+/// Everything held about a principal, as JSON
+#[query(guard = "require_compliance_service")]
+#[candid_method(query)]
+fn export_subject_data(subject: Principal) -> StudiFiResult<String> {
+    to_export_json(&with_storage(|storage| storage.export_subject_data(&subject)))
+}
+
+/// Remove a principal's applications and scores and pseudonymize their validation activity
+#[update(guard = "require_compliance_service")]
+#[candid_method(update)]
+fn erase_subject_data(request: DataErasureRequest) -> StudiFiResult<ErasureReceipt> {
+    Ok(with_storage_mut(|storage| storage.erase_subject_data(&request)))
+}
+
 // Export Candid interface
 candid::export_service!();
 
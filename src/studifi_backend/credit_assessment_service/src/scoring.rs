@@ -317,7 +317,7 @@ impl CreditScoringEngine {
         requested_amount: Amount,
         credit_score: &CreditScore,
         purpose: &LoanPurpose,
-    ) -> LoanTerms {
+    ) -> StudiFiResult<LoanTerms> {
         let risk_multiplier = purpose.risk_multiplier();
         let base_rate = credit_score.risk_level.to_interest_rate();
         let adjusted_rate = (base_rate * risk_multiplier).min(MAX_INTEREST_RATE);
@@ -332,18 +332,18 @@ impl CreditScoringEngine {
             _ => 0.50,
         };
         
-        let approved_amount = ((requested_amount as f64 * approval_percentage) as Amount)
-            .min(MAX_LOAN_AMOUNT)
-            .max(MIN_LOAN_AMOUNT);
+        let approved_amount = apply_percentage(requested_amount, approval_percentage, RoundingMode::Down)?
+            .clamp(MIN_LOAN_AMOUNT, MAX_LOAN_AMOUNT);
         
         // Standard term: 5 years for most loans
         let term_months = 60;
         let grace_period = if credit_score.score >= 700 { 6 } else { 3 };
         
-        let monthly_payment = calculate_monthly_payment(approved_amount, adjusted_rate, term_months);
-        let origination_fee = (approved_amount as f64 * 0.01) as Amount; // 1% origination fee
-        
-        LoanTerms {
+        let amortized_principal = capitalize_grace_interest(approved_amount, adjusted_rate, grace_period)?;
+        let monthly_payment = calculate_monthly_payment(amortized_principal, adjusted_rate, term_months)?;
+        let origination_fee = calculate_fee(approved_amount, ORIGINATION_FEE_BPS)?;
+
+        Ok(LoanTerms {
             approved_amount,
             interest_rate: adjusted_rate,
             term_months,
@@ -354,7 +354,7 @@ impl CreditScoringEngine {
             cosigner_required: credit_score.score < 600,
             collateral_required: credit_score.score < 500,
             special_conditions: Self::generate_special_conditions(credit_score),
-        }
+        })
     }
 
     /// Generate special conditions based on risk assessment
@@ -1,130 +1,110 @@
 use candid::Principal;
-use ic_stable_structures::{DefaultMemoryImpl, RestrictedMemory, StableBTreeMap, Storable, storable::Bound};
-use std::borrow::Cow;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    DefaultMemoryImpl,
+};
 use std::cell::RefCell;
 
 use crate::types::*;
 use crate::community_validation::*;
-use shared::current_time;
-
-type Memory = RestrictedMemory<DefaultMemoryImpl>;
-
-const APPLICATIONS_MEMORY_ID: u64 = 0;
-const CREDIT_SCORES_MEMORY_ID: u64 = 1;
-const CONFIG_MEMORY_ID: u64 = 2;
-const VALIDATION_REQUESTS_MEMORY_ID: u64 = 3;
-const HYBRID_SCORES_MEMORY_ID: u64 = 4;
-const VALIDATOR_REPUTATION_MEMORY_ID: u64 = 5;
-
-impl Storable for LoanApplication {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+use shared::{
+    current_time, init_memory_manager, max_generated_id, read_legacy_map, CanisterRegistry, DataErasureRequest,
+    ErasureReceipt, ServiceName, VersionedMap, VersionedRecord, LOAN_APPLICATION_PREFIX,
+};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const APPLICATIONS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const CREDIT_SCORES_MEMORY_ID: MemoryId = MemoryId::new(1);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(2);
+const VALIDATION_REQUESTS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const HYBRID_SCORES_MEMORY_ID: MemoryId = MemoryId::new(4);
+const VALIDATOR_REPUTATION_MEMORY_ID: MemoryId = MemoryId::new(5);
+const CANISTER_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(6);
+const COUNTERS_MEMORY_ID: MemoryId = MemoryId::new(7);
+
+impl VersionedRecord for LoanApplication {
+    const VERSION: u16 = 1;
 }
 
-impl Storable for CreditScore {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+impl VersionedRecord for CreditScore {
+    const VERSION: u16 = 1;
 }
 
-impl Storable for ScoringConfig {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+impl VersionedRecord for ScoringConfig {
+    const VERSION: u16 = 1;
 }
 
-impl Storable for CommunityValidationRequest {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+impl VersionedRecord for CommunityValidationRequest {
+    const VERSION: u16 = 1;
 }
 
-impl Storable for HybridCreditScore {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+impl VersionedRecord for HybridCreditScore {
+    const VERSION: u16 = 1;
 }
 
-impl Storable for ValidatorReputation {
-    const BOUND: Bound = Bound::Unbounded;
+impl VersionedRecord for ValidatorReputation {
+    const VERSION: u16 = 1;
+}
 
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
+// Counter structure for ID generation
+#[derive(candid::CandidType, candid::Deserialize, Clone, Debug, serde::Serialize)]
+pub struct Counters {
+    pub next_application_id: u64,
+    pub next_validation_id: u64,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+impl VersionedRecord for Counters {
+    const VERSION: u16 = 1;
 }
 
 pub struct CreditStorage {
-    pub applications: StableBTreeMap<String, LoanApplication, Memory>,
-    pub credit_scores: StableBTreeMap<Principal, CreditScore, Memory>,
-    pub scoring_config: StableBTreeMap<String, ScoringConfig, Memory>,
-    pub validation_requests: StableBTreeMap<String, CommunityValidationRequest, Memory>,
-    pub hybrid_scores: StableBTreeMap<Principal, HybridCreditScore, Memory>,
-    pub validator_reputation: StableBTreeMap<Principal, ValidatorReputation, Memory>,
-    pub next_application_id: u64,
-    pub next_validation_id: u64,
+    pub applications: VersionedMap<String, LoanApplication, Memory>,
+    pub credit_scores: VersionedMap<Principal, CreditScore, Memory>,
+    pub scoring_config: VersionedMap<String, ScoringConfig, Memory>,
+    pub validation_requests: VersionedMap<String, CommunityValidationRequest, Memory>,
+    pub hybrid_scores: VersionedMap<Principal, HybridCreditScore, Memory>,
+    pub validator_reputation: VersionedMap<Principal, ValidatorReputation, Memory>,
+    pub canister_registry: VersionedMap<String, CanisterRegistry, Memory>,
+    pub counters: VersionedMap<String, Counters, Memory>,
 }
 
 impl CreditStorage {
-    pub fn new() -> Self {
+    pub fn init(memory: DefaultMemoryImpl) -> Self {
+        // Canisters installed before the MemoryManager layout kept each map in its own page
+        let memory_manager = init_memory_manager(memory, |memory| vec![
+            read_legacy_map::<String, _>(memory, 0, APPLICATIONS_MEMORY_ID),
+            read_legacy_map::<Principal, _>(memory, 1, CREDIT_SCORES_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 2, CONFIG_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 3, VALIDATION_REQUESTS_MEMORY_ID),
+            read_legacy_map::<Principal, _>(memory, 4, HYBRID_SCORES_MEMORY_ID),
+            read_legacy_map::<Principal, _>(memory, 5, VALIDATOR_REPUTATION_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 6, CANISTER_REGISTRY_MEMORY_ID),
+        ]);
+
         Self {
-            applications: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), APPLICATIONS_MEMORY_ID..APPLICATIONS_MEMORY_ID + 1)
-            ),
-            credit_scores: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), CREDIT_SCORES_MEMORY_ID..CREDIT_SCORES_MEMORY_ID + 1)
-            ),
-            scoring_config: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), CONFIG_MEMORY_ID..CONFIG_MEMORY_ID + 1)
-            ),
-            validation_requests: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), VALIDATION_REQUESTS_MEMORY_ID..VALIDATION_REQUESTS_MEMORY_ID + 1)
-            ),
-            hybrid_scores: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), HYBRID_SCORES_MEMORY_ID..HYBRID_SCORES_MEMORY_ID + 1)
-            ),
-            validator_reputation: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), VALIDATOR_REPUTATION_MEMORY_ID..VALIDATOR_REPUTATION_MEMORY_ID + 1)
-            ),
-            next_application_id: 1,
-            next_validation_id: 1,
+            applications: VersionedMap::init(memory_manager.get(APPLICATIONS_MEMORY_ID)),
+            credit_scores: VersionedMap::init(memory_manager.get(CREDIT_SCORES_MEMORY_ID)),
+            scoring_config: VersionedMap::init(memory_manager.get(CONFIG_MEMORY_ID)),
+            validation_requests: VersionedMap::init(memory_manager.get(VALIDATION_REQUESTS_MEMORY_ID)),
+            hybrid_scores: VersionedMap::init(memory_manager.get(HYBRID_SCORES_MEMORY_ID)),
+            validator_reputation: VersionedMap::init(memory_manager.get(VALIDATOR_REPUTATION_MEMORY_ID)),
+            canister_registry: VersionedMap::init(memory_manager.get(CANISTER_REGISTRY_MEMORY_ID)),
+            counters: VersionedMap::init(memory_manager.get(COUNTERS_MEMORY_ID)),
         }
     }
 
+    /// ID counters, seeded from existing records for canisters that kept them on the heap
+    fn get_counters(&self) -> Counters {
+        self.counters.get(&"default".to_string()).unwrap_or_else(|| Counters {
+            next_application_id: max_generated_id(
+                LOAN_APPLICATION_PREFIX,
+                self.applications.iter().map(|(id, _)| id),
+            ) + 1,
+            next_validation_id: max_generated_id("VAL", self.validation_requests.iter().map(|(id, _)| id)) + 1,
+        })
+    }
+
     pub fn get_application(&self, id: &str) -> Option<LoanApplication> {
         self.applications.get(&id.to_string())
     }
@@ -229,12 +209,24 @@ impl CreditStorage {
         self.scoring_config.insert("default".to_string(), config);
     }
 
+    pub fn get_canister_registry(&self) -> CanisterRegistry {
+        self.canister_registry
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_canister_registry(&mut self, registry: CanisterRegistry) {
+        self.canister_registry.insert("default".to_string(), registry);
+    }
+
     pub fn get_next_application_id(&self) -> u64 {
-        self.next_application_id
+        self.get_counters().next_application_id
     }
 
     pub fn increment_application_id(&mut self) {
-        self.next_application_id += 1;
+        let mut counters = self.get_counters();
+        counters.next_application_id += 1;
+        self.counters.insert("default".to_string(), counters);
     }
 
     // Community validation methods
@@ -254,6 +246,10 @@ impl CreditStorage {
             .collect()
     }
 
+    pub fn get_all_validation_requests(&self) -> Vec<CommunityValidationRequest> {
+        self.validation_requests.iter().map(|(_, request)| request).collect()
+    }
+
     pub fn get_active_validations(&self) -> Vec<CommunityValidationRequest> {
         self.validation_requests
             .iter()
@@ -274,11 +270,13 @@ impl CreditStorage {
     }
 
     pub fn get_next_validation_id(&self) -> u64 {
-        self.next_validation_id
+        self.get_counters().next_validation_id
     }
 
     pub fn increment_validation_id(&mut self) {
-        self.next_validation_id += 1;
+        let mut counters = self.get_counters();
+        counters.next_validation_id += 1;
+        self.counters.insert("default".to_string(), counters);
     }
 
     // Hybrid score methods
@@ -299,6 +297,82 @@ impl CreditStorage {
         self.validator_reputation.insert(validator, reputation);
     }
 
+    // Data subject requests
+    /// Validations of other students the subject created or voted on
+    fn get_validations_participated(&self, subject: &Principal) -> Vec<CommunityValidationRequest> {
+        self.validation_requests
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| {
+                request.student_id != *subject
+                    && (request.created_by == *subject || request.votes.iter().any(|vote| vote.voter == *subject))
+            })
+            .collect()
+    }
+
+    pub fn export_subject_data(&self, subject: &Principal) -> CreditSubjectData {
+        CreditSubjectData {
+            applications: self.get_student_applications(subject),
+            credit_score: self.get_credit_score(subject),
+            hybrid_score: self.get_hybrid_score(subject),
+            validations: self.get_validations_for_student(subject),
+            votes_cast: self
+                .get_validations_participated(subject)
+                .into_iter()
+                .flat_map(|request| request.votes)
+                .filter(|vote| vote.voter == *subject)
+                .collect(),
+            validator_reputation: self.get_validator_reputation(subject),
+        }
+    }
+
+    /// Remove the subject's applications, scores and validations, keeping them while
+    /// the subject has outstanding loans. The subject's part in validating other
+    /// students is pseudonymized so those validations stay intact.
+    pub fn erase_subject_data(&mut self, request: &DataErasureRequest) -> ErasureReceipt {
+        let subject = request.subject;
+        let mut receipt = ErasureReceipt::new(ServiceName::CreditAssessment);
+
+        let applications = self.get_student_applications(&subject);
+        let validations = self.get_validations_for_student(&subject);
+        let scores = self.get_credit_score(&subject).is_some() as u32 + self.get_hybrid_score(&subject).is_some() as u32;
+        if request.has_active_loans {
+            receipt.retain(
+                applications.len() as u32 + validations.len() as u32 + scores,
+                "underwriting records of outstanding loans",
+            );
+        } else {
+            receipt.erased += applications.len() as u32 + validations.len() as u32 + scores;
+            for application in applications {
+                self.applications.remove(&application.id);
+            }
+            for validation in validations {
+                self.validation_requests.remove(&validation.id);
+            }
+            self.credit_scores.remove(&subject);
+            self.hybrid_scores.remove(&subject);
+        }
+
+        for mut validation in self.get_validations_participated(&subject) {
+            if validation.created_by == subject {
+                validation.created_by = request.pseudonym;
+            }
+            for vote in validation.votes.iter_mut().filter(|vote| vote.voter == subject) {
+                vote.voter = request.pseudonym;
+            }
+            self.validation_requests.insert(validation.id.clone(), validation);
+            receipt.pseudonymized += 1;
+        }
+
+        if let Some(mut reputation) = self.validator_reputation.remove(&subject) {
+            reputation.validator = request.pseudonym;
+            self.validator_reputation.insert(request.pseudonym, reputation);
+            receipt.pseudonymized += 1;
+        }
+
+        receipt
+    }
+
     // Statistics
     pub fn calculate_validation_stats(&self) -> CommunityValidationStats {
         let all_validations: Vec<_> = self.validation_requests.iter().map(|(_, v)| v).collect();
@@ -359,7 +433,7 @@ impl CreditStorage {
 }
 
 thread_local! {
-    static STORAGE: RefCell<CreditStorage> = RefCell::new(CreditStorage::new());
+    static STORAGE: RefCell<CreditStorage> = RefCell::new(CreditStorage::init(DefaultMemoryImpl::default()));
 }
 
 pub fn with_storage<R>(f: impl FnOnce(&CreditStorage) -> R) -> R {
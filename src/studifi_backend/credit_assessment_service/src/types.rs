@@ -274,6 +274,7 @@ pub enum ApplicationStatus {
     Rejected,
     Withdrawn,
     Expired,
+    LoanCreationPending,
     LoanCreated,
 }
 
@@ -377,3 +378,16 @@ pub struct ApplicationFilters {
     pub date_from: Option<Timestamp>,
     pub date_to: Option<Timestamp>,
 }
+
+/// Everything this service holds about a data subject, for export
+#[derive(Clone, Debug, Serialize)]
+pub struct CreditSubjectData {
+    pub applications: Vec<LoanApplication>,
+    pub credit_score: Option<CreditScore>,
+    pub hybrid_score: Option<crate::community_validation::HybridCreditScore>,
+    /// Community validations of the subject's credit
+    pub validations: Vec<crate::community_validation::CommunityValidationRequest>,
+    /// Votes the subject cast on other students' validations
+    pub votes_cast: Vec<crate::community_validation::CommunityVote>,
+    pub validator_reputation: Option<crate::community_validation::ValidatorReputation>,
+}
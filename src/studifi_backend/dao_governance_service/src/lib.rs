@@ -11,7 +11,11 @@ use storage::*;
 use voting::*;
 
 #[init]
-fn init() {
+fn init(args: Option<ServiceInitArgs>) {
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("Governance Engine canister initialized");
 }
 
@@ -21,7 +25,12 @@ fn pre_upgrade() {
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<ServiceInitArgs>) {
+    // Keep the previously registered canisters unless a new registry is supplied
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("Governance Engine canister upgraded successfully");
 }
 
@@ -30,7 +39,7 @@ fn post_upgrade() {
 // ============================================================================
 
 /// Issue governance tokens to a user
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 async fn issue_tokens(
     recipient: Principal,
@@ -38,9 +47,17 @@ async fn issue_tokens(
     source: TokenSource,
     stakeholder_type: StakeholderType,
 ) -> StudiFiResult<GovernanceToken> {
-    let _caller = caller();
-
-    // TODO: Add authorization check (only admin or governance can issue tokens)
+    // Donors are screened against sanctions watchlists on every issuance
+    if stakeholder_type == StakeholderType::Donor || source == TokenSource::ScholarshipDonation {
+        ComplianceClient::screen_party(ScreeningRequest {
+            subject: recipient,
+            role: ScreeningRole::Donor,
+            full_name: None,
+            date_of_birth: None,
+            country: None,
+            context: "issue_tokens".to_string(),
+        }).await?.into_clearance()?;
+    }
 
     // Create or update token
     let token = with_storage_mut(|storage| {
@@ -130,8 +147,10 @@ async fn create_proposal(
     description: String,
     proposal_type: ProposalType,
     voting_period_seconds: Option<u64>,
+    session_id: Option<String>,
 ) -> StudiFiResult<Proposal> {
     let caller = caller();
+    authorize_caller_session(session_id, Permission::CreateProposals).await?;
 
     // Validate inputs
     if title.len() > MAX_TITLE_LENGTH {
@@ -221,8 +240,10 @@ fn get_proposals_by_status(status: ProposalStatus) -> Vec<Proposal> {
 async fn vote_on_proposal(
     proposal_id: String,
     vote_type: VoteType,
+    session_id: Option<String>,
 ) -> StudiFiResult<Vote> {
     let caller = caller();
+    authorize_caller_session(session_id, Permission::VoteOnProposals).await?;
     VotingEngine::cast_vote(proposal_id, caller, vote_type)
 }
 
@@ -353,6 +374,24 @@ fn get_my_voting_power() -> u64 {
     with_storage(|storage| storage.get_effective_voting_power(&caller))
 }
 
+// ============================================================================
+// DATA SUBJECT REQUESTS
+// ============================================================================
+
+/// Everything held about a principal, as JSON
+#[query(guard = "require_compliance_service")]
+#[candid_method(query)]
+fn export_subject_data(subject: Principal) -> StudiFiResult<String> {
+    to_export_json(&with_storage(|storage| storage.export_subject_data(&subject)))
+}
+
+/// Pseudonymize a principal's governance activity and remove their empty holdings
+#[update(guard = "require_compliance_service")]
+#[candid_method(update)]
+fn erase_subject_data(request: DataErasureRequest) -> StudiFiResult<ErasureReceipt> {
+    Ok(with_storage_mut(|storage| storage.erase_subject_data(&request)))
+}
+
 // ============================================================================
 // INITIALIZATION AND DEMO FUNCTIONS
 // ============================================================================
@@ -493,6 +532,27 @@ async fn create_demo_proposal() -> StudiFiResult<Proposal> {
     Ok(proposal)
 }
 
+/// Re-sync the cached role assignments used by authorization guards
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+async fn refresh_role_cache() -> StudiFiResult<u32> {
+    sync_role_cache().await
+}
+
+/// Get the canister registry used for inter-canister calls
+#[query]
+#[candid_method(query)]
+fn get_canister_registry() -> CanisterRegistry {
+    current_canister_registry()
+}
+
+/// Get the health of downstream canisters this canister has called since its last upgrade
+#[query]
+#[candid_method(query)]
+fn get_circuit_breakers() -> Vec<CircuitBreaker> {
+    circuit_breaker_states()
+}
+
 candid::export_service!();
 
 #[query(name = "__get_candid_interface_tmp_hack")]
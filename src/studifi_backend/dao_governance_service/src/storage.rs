@@ -1,28 +1,79 @@
-use std::collections::BTreeMap;
-use std::cell::RefCell;
 use candid::Principal;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl,
+};
+use std::cell::RefCell;
+
 use crate::types::*;
 use shared::*;
 
-/// Governance storage structure
-#[derive(Default)]
-pub struct GovernanceStorage {
-    pub proposals: BTreeMap<String, Proposal>,
-    pub votes: BTreeMap<String, Vec<Vote>>, // proposal_id -> votes
-    pub tokens: BTreeMap<Principal, GovernanceToken>,
-    pub delegations: BTreeMap<Principal, Vec<Delegation>>, // delegator -> delegations
-    pub stakeholders: BTreeMap<Principal, Stakeholder>,
-    pub counters: Counters,
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const PROPOSALS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const VOTES_MEMORY_ID: MemoryId = MemoryId::new(1);
+const TOKENS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const DELEGATIONS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const STAKEHOLDERS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const COUNTERS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const CANISTER_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(6);
+
+impl VersionedRecord for Proposal {
+    const VERSION: u16 = 1;
+}
+
+impl VersionedRecord for GovernanceToken {
+    const VERSION: u16 = 1;
 }
 
-#[derive(Default)]
+impl VersionedRecord for Stakeholder {
+    const VERSION: u16 = 1;
+}
+
+// Wrapper type for a proposal's votes to store as a single record
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct VoteList(pub Vec<Vote>);
+
+impl VersionedRecord for VoteList {
+    const VERSION: u16 = 1;
+}
+
+// Wrapper type for a delegator's delegations to store as a single record
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct DelegationList(pub Vec<Delegation>);
+
+impl VersionedRecord for DelegationList {
+    const VERSION: u16 = 1;
+}
+
+// Counter structure for ID generation
+#[derive(candid::CandidType, candid::Deserialize, Clone, Debug, Default, serde::Serialize)]
 pub struct Counters {
     pub proposal_counter: u64,
     pub vote_counter: u64,
 }
 
+impl VersionedRecord for Counters {
+    const VERSION: u16 = 1;
+}
+
+/// Governance storage structure
+pub struct GovernanceStorage {
+    pub proposals: VersionedMap<String, Proposal, Memory>,
+    pub votes: VersionedMap<String, VoteList, Memory>, // proposal_id -> votes
+    pub tokens: VersionedMap<Principal, GovernanceToken, Memory>,
+    pub delegations: VersionedMap<Principal, DelegationList, Memory>, // delegator -> delegations
+    pub stakeholders: VersionedMap<Principal, Stakeholder, Memory>,
+    pub counters: VersionedMap<String, Counters, Memory>,
+    pub canister_registry: VersionedMap<String, CanisterRegistry, Memory>,
+}
+
 thread_local! {
-    static STORAGE: RefCell<GovernanceStorage> = RefCell::new(GovernanceStorage::default());
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STORAGE: RefCell<GovernanceStorage> =
+        RefCell::new(MEMORY_MANAGER.with(|manager| GovernanceStorage::init(&manager.borrow())));
 }
 
 /// Storage access functions
@@ -35,13 +86,26 @@ pub fn with_storage_mut<R>(f: impl FnOnce(&mut GovernanceStorage) -> R) -> R {
 }
 
 impl GovernanceStorage {
+    /// Open (or reopen after an upgrade) every governance map in the given memory manager
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>) -> Self {
+        Self {
+            proposals: VersionedMap::init(memory_manager.get(PROPOSALS_MEMORY_ID)),
+            votes: VersionedMap::init(memory_manager.get(VOTES_MEMORY_ID)),
+            tokens: VersionedMap::init(memory_manager.get(TOKENS_MEMORY_ID)),
+            delegations: VersionedMap::init(memory_manager.get(DELEGATIONS_MEMORY_ID)),
+            stakeholders: VersionedMap::init(memory_manager.get(STAKEHOLDERS_MEMORY_ID)),
+            counters: VersionedMap::init(memory_manager.get(COUNTERS_MEMORY_ID)),
+            canister_registry: VersionedMap::init(memory_manager.get(CANISTER_REGISTRY_MEMORY_ID)),
+        }
+    }
+
     // Proposal operations
     pub fn insert_proposal(&mut self, id: String, proposal: Proposal) {
         self.proposals.insert(id, proposal);
     }
 
     pub fn get_proposal(&self, id: &str) -> Option<Proposal> {
-        self.proposals.get(id).cloned()
+        self.proposals.get(&id.to_string())
     }
 
     pub fn update_proposal(&mut self, id: String, proposal: Proposal) {
@@ -49,46 +113,51 @@ impl GovernanceStorage {
     }
 
     pub fn get_all_proposals(&self) -> Vec<Proposal> {
-        self.proposals.values().cloned().collect()
+        self.proposals.iter().map(|(_, p)| p).collect()
     }
 
     pub fn get_active_proposals(&self) -> Vec<Proposal> {
         self.proposals
-            .values()
+            .iter()
+            .map(|(_, p)| p)
             .filter(|p| p.is_active())
-            .cloned()
             .collect()
     }
 
     pub fn get_proposals_by_status(&self, status: ProposalStatus) -> Vec<Proposal> {
         self.proposals
-            .values()
+            .iter()
+            .map(|(_, p)| p)
             .filter(|p| p.status == status)
-            .cloned()
             .collect()
     }
 
     // Vote operations
     pub fn add_vote(&mut self, proposal_id: String, vote: Vote) {
-        self.votes
-            .entry(proposal_id)
-            .or_insert_with(Vec::new)
-            .push(vote);
+        let mut votes = self.votes.get(&proposal_id).unwrap_or_default();
+        votes.0.push(vote);
+        self.votes.insert(proposal_id, votes);
     }
 
     pub fn get_votes_for_proposal(&self, proposal_id: &str) -> Vec<Vote> {
         self.votes
-            .get(proposal_id)
-            .cloned()
+            .get(&proposal_id.to_string())
+            .map(|votes| votes.0)
             .unwrap_or_default()
     }
 
+    pub fn get_votes_by_voter(&self, voter: &Principal) -> Vec<Vote> {
+        self.votes
+            .iter()
+            .flat_map(|(_, votes)| votes.0)
+            .filter(|v| v.voter == *voter)
+            .collect()
+    }
+
     pub fn has_voted(&self, proposal_id: &str, voter: &Principal) -> bool {
-        if let Some(votes) = self.votes.get(proposal_id) {
-            votes.iter().any(|v| v.voter == *voter)
-        } else {
-            false
-        }
+        self.get_votes_for_proposal(proposal_id)
+            .iter()
+            .any(|v| v.voter == *voter)
     }
 
     // Token operations
@@ -97,7 +166,7 @@ impl GovernanceStorage {
     }
 
     pub fn get_token(&self, holder: &Principal) -> Option<GovernanceToken> {
-        self.tokens.get(holder).cloned()
+        self.tokens.get(holder)
     }
 
     pub fn update_token(&mut self, holder: Principal, token: GovernanceToken) {
@@ -105,40 +174,39 @@ impl GovernanceStorage {
     }
 
     pub fn get_all_tokens(&self) -> Vec<GovernanceToken> {
-        self.tokens.values().cloned().collect()
+        self.tokens.iter().map(|(_, t)| t).collect()
     }
 
     pub fn get_total_voting_power(&self) -> u64 {
-        self.tokens.values().map(|t| t.voting_power).sum()
+        self.tokens.iter().map(|(_, t)| t.voting_power).sum()
     }
 
     // Delegation operations
     pub fn add_delegation(&mut self, delegator: Principal, delegation: Delegation) {
-        self.delegations
-            .entry(delegator)
-            .or_insert_with(Vec::new)
-            .push(delegation);
+        let mut delegations = self.delegations.get(&delegator).unwrap_or_default();
+        delegations.0.push(delegation);
+        self.delegations.insert(delegator, delegations);
     }
 
     pub fn get_delegations_by_delegator(&self, delegator: &Principal) -> Vec<Delegation> {
         self.delegations
             .get(delegator)
-            .cloned()
+            .map(|delegations| delegations.0)
             .unwrap_or_default()
     }
 
     pub fn get_delegations_to_delegate(&self, delegate: &Principal) -> Vec<Delegation> {
         self.delegations
-            .values()
-            .flatten()
+            .iter()
+            .flat_map(|(_, delegations)| delegations.0)
             .filter(|d| d.delegate == *delegate)
-            .cloned()
             .collect()
     }
 
     pub fn remove_delegation(&mut self, delegator: &Principal, delegate: &Principal) {
-        if let Some(delegations) = self.delegations.get_mut(delegator) {
-            delegations.retain(|d| d.delegate != *delegate);
+        if let Some(mut delegations) = self.delegations.get(delegator) {
+            delegations.0.retain(|d| d.delegate != *delegate);
+            self.delegations.insert(*delegator, delegations);
         }
     }
 
@@ -148,7 +216,7 @@ impl GovernanceStorage {
     }
 
     pub fn get_stakeholder(&self, id: &Principal) -> Option<Stakeholder> {
-        self.stakeholders.get(id).cloned()
+        self.stakeholders.get(id)
     }
 
     pub fn update_stakeholder(&mut self, id: Principal, stakeholder: Stakeholder) {
@@ -156,30 +224,134 @@ impl GovernanceStorage {
     }
 
     pub fn get_all_stakeholders(&self) -> Vec<Stakeholder> {
-        self.stakeholders.values().cloned().collect()
+        self.stakeholders.iter().map(|(_, s)| s).collect()
     }
 
     pub fn get_stakeholders_by_type(&self, stakeholder_type: StakeholderType) -> Vec<Stakeholder> {
         self.stakeholders
-            .values()
+            .iter()
+            .map(|(_, s)| s)
             .filter(|s| s.stakeholder_type == stakeholder_type)
-            .cloned()
             .collect()
     }
 
     // Counter operations
     pub fn get_next_proposal_id(&mut self) -> String {
-        let id = generate_id(PROPOSAL_PREFIX, self.counters.proposal_counter);
-        self.counters.proposal_counter += 1;
+        let mut counters = self.counters
+            .get(&"default".to_string())
+            .unwrap_or_default();
+
+        let id = generate_id(PROPOSAL_PREFIX, counters.proposal_counter);
+        counters.proposal_counter += 1;
+        self.counters.insert("default".to_string(), counters);
         id
     }
 
     pub fn get_next_vote_id(&mut self) -> u64 {
-        let id = self.counters.vote_counter;
-        self.counters.vote_counter += 1;
+        let mut counters = self.counters
+            .get(&"default".to_string())
+            .unwrap_or_default();
+
+        let id = counters.vote_counter;
+        counters.vote_counter += 1;
+        self.counters.insert("default".to_string(), counters);
         id
     }
 
+    // Canister registry
+    // Data subject requests
+    pub fn export_subject_data(&self, subject: &Principal) -> GovernanceSubjectData {
+        GovernanceSubjectData {
+            token: self.get_token(subject),
+            stakeholder: self.get_stakeholder(subject),
+            proposals: self
+                .get_all_proposals()
+                .into_iter()
+                .filter(|proposal| proposal.proposer == *subject)
+                .collect(),
+            votes: self.get_votes_by_voter(subject),
+            delegations_given: self.get_delegations_by_delegator(subject),
+            delegations_received: self.get_delegations_to_delegate(subject),
+        }
+    }
+
+    /// Pseudonymize the subject's proposals, votes and received delegations so
+    /// governance results stay verifiable. Token holdings, with the stakeholder
+    /// record and delegations given, are kept while a balance remains.
+    pub fn erase_subject_data(&mut self, request: &DataErasureRequest) -> ErasureReceipt {
+        let subject = request.subject;
+        let pseudonym = request.pseudonym;
+        let mut receipt = ErasureReceipt::new(ServiceName::DaoGovernance);
+
+        for mut proposal in self.get_all_proposals() {
+            if proposal.proposer == subject {
+                proposal.proposer = pseudonym;
+                self.proposals.insert(proposal.id.clone(), proposal);
+                receipt.pseudonymized += 1;
+            }
+        }
+
+        let vote_lists: Vec<(String, VoteList)> = self.votes.iter().collect();
+        for (proposal_id, mut votes) in vote_lists {
+            let mut changed = 0;
+            for vote in votes.0.iter_mut() {
+                if vote.voter == subject || vote.delegated_from == Some(subject) {
+                    if vote.voter == subject {
+                        vote.voter = pseudonym;
+                    }
+                    if vote.delegated_from == Some(subject) {
+                        vote.delegated_from = Some(pseudonym);
+                    }
+                    changed += 1;
+                }
+            }
+            if changed > 0 {
+                self.votes.insert(proposal_id, votes);
+                receipt.pseudonymized += changed;
+            }
+        }
+
+        let delegation_lists: Vec<(Principal, DelegationList)> = self.delegations.iter().collect();
+        for (delegator, mut delegations) in delegation_lists {
+            if delegator == subject {
+                continue;
+            }
+            let received = delegations.0.iter().filter(|delegation| delegation.delegate == subject).count() as u32;
+            if received > 0 {
+                for delegation in delegations.0.iter_mut().filter(|delegation| delegation.delegate == subject) {
+                    delegation.delegate = pseudonym;
+                }
+                self.delegations.insert(delegator, delegations);
+                receipt.pseudonymized += received;
+            }
+        }
+
+        let holdings = self.get_token(&subject).map(|token| token.balance).unwrap_or(0);
+        let holder_records = self.tokens.contains_key(&subject) as u32
+            + self.stakeholders.contains_key(&subject) as u32
+            + self.delegations.contains_key(&subject) as u32;
+        if holdings > 0 {
+            receipt.retain(holder_records, "governance tokens are still held");
+        } else {
+            self.tokens.remove(&subject);
+            self.stakeholders.remove(&subject);
+            self.delegations.remove(&subject);
+            receipt.erased += holder_records;
+        }
+
+        receipt
+    }
+
+    pub fn get_canister_registry(&self) -> CanisterRegistry {
+        self.canister_registry
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_canister_registry(&mut self, registry: CanisterRegistry) {
+        self.canister_registry.insert("default".to_string(), registry);
+    }
+
     // Statistics
     pub fn get_governance_stats(&self) -> GovernanceStats {
         let total_proposals = self.proposals.len() as u32;
@@ -187,12 +359,13 @@ impl GovernanceStorage {
         let passed_proposals = self.get_proposals_by_status(ProposalStatus::Passed).len() as u32;
         let rejected_proposals = self.get_proposals_by_status(ProposalStatus::Rejected).len() as u32;
         
-        let total_votes_cast = self.votes.values().map(|v| v.len() as u64).sum();
+        let total_votes_cast = self.votes.iter().map(|(_, v)| v.0.len() as u64).sum();
         let total_voting_power = self.get_total_voting_power();
         let total_token_holders = self.tokens.len() as u32;
-        let total_tokens_issued = self.tokens.values().map(|t| t.balance).sum();
+        let total_tokens_issued = self.tokens.iter().map(|(_, t)| t.balance).sum();
         let total_tokens_locked = self.tokens
-            .values()
+            .iter()
+            .map(|(_, t)| t)
             .filter(|t| t.is_locked())
             .map(|t| t.balance)
             .sum();
@@ -242,3 +415,107 @@ impl GovernanceStorage {
         total_power
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    fn token(holder: Principal, balance: u64) -> GovernanceToken {
+        GovernanceToken {
+            holder,
+            balance,
+            locked_until: None,
+            voting_power: balance,
+            earned_from: TokenSource::InitialDistribution,
+            created_at: 1,
+            last_updated: 1,
+        }
+    }
+
+    #[test]
+    fn test_state_survives_upgrade() {
+        // The same backing memory is handed to a fresh MemoryManager, as happens after an upgrade
+        let memory = DefaultMemoryImpl::default();
+        let proposer = principal(1);
+        let delegate = principal(2);
+
+        let proposal_id = {
+            let mut storage = GovernanceStorage::init(&MemoryManager::init(memory.clone()));
+
+            let proposal_id = storage.get_next_proposal_id();
+            storage.insert_proposal(proposal_id.clone(), Proposal {
+                id: proposal_id.clone(),
+                proposer,
+                title: "Raise loan cap".to_string(),
+                description: "Raise the maximum loan amount".to_string(),
+                proposal_type: ProposalType::ParameterChange {
+                    parameter: "max_loan_amount".to_string(),
+                    current_value: "50000".to_string(),
+                    new_value: "60000".to_string(),
+                },
+                status: ProposalStatus::Active,
+                voting_starts_at: 1,
+                voting_ends_at: 2,
+                votes_for: 150,
+                votes_against: 0,
+                votes_abstain: 0,
+                total_voting_power: 150,
+                quorum_required: 100,
+                execution_delay: 0,
+                executed_at: None,
+                created_at: 1,
+                updated_at: 1,
+            });
+            storage.add_vote(proposal_id.clone(), Vote {
+                proposal_id: proposal_id.clone(),
+                voter: proposer,
+                vote_type: VoteType::For,
+                voting_power: 150,
+                delegated_from: None,
+                cast_at: 1,
+            });
+            storage.get_next_vote_id();
+            storage.insert_token(proposer, token(proposer, 100));
+            storage.insert_token(delegate, token(delegate, 50));
+            storage.add_delegation(delegate, Delegation {
+                delegator: delegate,
+                delegate: proposer,
+                voting_power: 50,
+                expires_at: None,
+                created_at: 1,
+            });
+            storage.insert_stakeholder(proposer, Stakeholder {
+                id: proposer,
+                stakeholder_type: StakeholderType::Student,
+                tokens: token(proposer, 100),
+                delegations_received: Vec::new(),
+                delegations_given: Vec::new(),
+                proposals_created: 1,
+                votes_cast: 1,
+                reputation_score: 0,
+                joined_at: 1,
+            });
+            proposal_id
+        };
+
+        let mut storage = GovernanceStorage::init(&MemoryManager::init(memory));
+
+        let proposal = storage.get_proposal(&proposal_id).expect("proposal should survive upgrade");
+        assert_eq!(proposal.title, "Raise loan cap");
+        assert_eq!(proposal.votes_for, 150);
+        assert!(storage.has_voted(&proposal_id, &proposer));
+        assert_eq!(storage.get_votes_by_voter(&proposer).len(), 1);
+        assert_eq!(storage.get_total_voting_power(), 150);
+        assert_eq!(storage.get_delegations_by_delegator(&delegate).len(), 1);
+        assert_eq!(storage.get_effective_voting_power(&proposer), 150);
+        assert_eq!(storage.get_stakeholder(&proposer).map(|s| s.proposals_created), Some(1));
+
+        // Counters carry on from where they were instead of reissuing ids
+        assert_ne!(storage.get_next_proposal_id(), proposal_id);
+        assert_eq!(storage.get_next_vote_id(), 1);
+    }
+}
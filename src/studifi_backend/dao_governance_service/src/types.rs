@@ -296,3 +296,14 @@ impl StakeholderType {
         }
     }
 }
+
+/// Everything this service holds about a data subject, for export
+#[derive(Clone, Debug, Serialize)]
+pub struct GovernanceSubjectData {
+    pub token: Option<GovernanceToken>,
+    pub stakeholder: Option<Stakeholder>,
+    pub proposals: Vec<Proposal>,
+    pub votes: Vec<Vote>,
+    pub delegations_given: Vec<Delegation>,
+    pub delegations_received: Vec<Delegation>,
+}
@@ -263,13 +263,6 @@ impl VotingEngine {
 
     /// Get voting history for a user
     pub fn get_voting_history(voter: &Principal) -> Vec<Vote> {
-        with_storage(|storage| {
-            storage.votes
-                .values()
-                .flatten()
-                .filter(|v| v.voter == *voter)
-                .cloned()
-                .collect()
-        })
+        with_storage(|storage| storage.get_votes_by_voter(voter))
     }
 }
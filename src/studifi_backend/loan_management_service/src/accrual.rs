@@ -0,0 +1,298 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::ledger::*;
+use crate::token::*;
+use shared::*;
+
+/// How a day's share of the annual rate is counted
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub enum DayCountConvention {
+    /// Every calendar day is 1/365 of a year, leap years included
+    #[default]
+    Actual365,
+    /// Every month is 30 days of a 360-day year (US bond basis)
+    Thirty360,
+}
+
+impl DayCountConvention {
+    /// Days of the year basis accrued over calendar day `day` (days since the epoch)
+    pub fn accrued_days(&self, day: i64) -> u64 {
+        match self {
+            DayCountConvention::Actual365 => 1,
+            DayCountConvention::Thirty360 => days_360(day, day + 1).max(0) as u64,
+        }
+    }
+
+    /// Days in the year the annual rate is spread over
+    pub fn year_basis(&self) -> u64 {
+        match self {
+            DayCountConvention::Actual365 => 365,
+            DayCountConvention::Thirty360 => 360,
+        }
+    }
+}
+
+/// Days between two dates under 30/360: the 31st counts as the 30th, so a
+/// 31st accrues nothing and the last day of February makes up the month
+fn days_360(from_day: i64, to_day: i64) -> i64 {
+    let (from_year, from_month, from_date) = days_to_civil_date(from_day);
+    let (to_year, to_month, to_date) = days_to_civil_date(to_day);
+    let from_date = from_date.min(30) as i64;
+    let to_date = if to_date == 31 && from_date == 30 { 30 } else { to_date as i64 };
+
+    360 * (to_year - from_year) + 30 * (to_month as i64 - from_month as i64) + (to_date - from_date)
+}
+
+/// One day of interest accrued on a loan
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct InterestAccrual {
+    pub loan_id: String,
+    /// Start of the accrued day (UTC)
+    pub accrual_date: Timestamp,
+    pub convention: DayCountConvention,
+    pub principal_balance: Amount,
+    pub interest_rate: Percentage,
+    pub amount: Amount,
+    /// The loan's unpaid accrued interest after this day
+    pub accrued_interest: Amount,
+    /// Grace-period interest added to principal at the start of this day
+    #[serde(default)]
+    pub capitalized: Amount,
+    pub recorded_at: Timestamp,
+}
+
+/// Daily interest accrual, kept apart from principal
+pub struct AccrualEngine;
+
+impl AccrualEngine {
+    /// Loans accruing interest: in repayment or grace, but not deferred or defaulted
+    pub fn is_accruing(loan: &Loan) -> bool {
+        matches!(loan.status, LoanStatus::Active | LoanStatus::Late | LoanStatus::InGracePeriod)
+    }
+
+    /// Accrue every full day since the last accrual, record each one and post
+    /// the total to the ledger. Days already accrued are skipped, so running
+    /// this more than once a day is harmless.
+    pub fn accrue_loan(loan: &mut Loan, now: Timestamp) -> StudiFiResult<Vec<InterestAccrual>> {
+        let accruals = Self::accrue(loan, now)?;
+        if !accruals.is_empty() {
+            with_storage_mut(|storage| {
+                for accrual in accruals.iter().cloned() {
+                    storage.insert_accrual(accrual);
+                }
+            });
+        }
+
+        let amount: Amount = accruals.iter().map(|accrual| accrual.amount).sum();
+        if amount > 0 {
+            LedgerEngine::record_interest_accrual(&loan.id, amount, now)?;
+        }
+        let capitalized: Amount = accruals.iter().map(|accrual| accrual.capitalized).sum();
+        if capitalized > 0 {
+            LedgerEngine::record_interest_capitalization(&loan.id, capitalized, now)?;
+        }
+        Ok(accruals)
+    }
+
+    /// Accrue interest on every accruing loan, returning how many loans changed.
+    /// Loans with a payment in flight are accrued by that payment instead.
+    pub fn accrue_all(now: Timestamp) -> StudiFiResult<u32> {
+        let loans: Vec<Loan> = with_storage(|storage| storage.get_all_loans())
+            .into_iter()
+            .filter(|loan| Self::is_accruing(loan) && !InFlightGuard::is_held(&loan.id))
+            .collect();
+
+        let mut accrued = 0;
+        for mut loan in loans {
+            if !Self::accrue_loan(&mut loan, now)?.is_empty() {
+                with_storage_mut(|storage| {
+                    let _ = storage.update_loan(loan.id.clone(), loan);
+                });
+                accrued += 1;
+            }
+        }
+        Ok(accrued)
+    }
+
+    /// Accrue days up to, but not including, the day containing `now`, without recording them.
+    /// Interest accrued during the grace period is capitalized on each monthly anniversary.
+    pub fn accrue(loan: &mut Loan, now: Timestamp) -> StudiFiResult<Vec<InterestAccrual>> {
+        if !Self::is_accruing(loan) {
+            return Ok(Vec::new());
+        }
+
+        let day_nanos = days_to_nanos(1);
+        let first_day = match loan.last_accrual_date {
+            Some(last) => (last / day_nanos) as i64 + 1,
+            None => (loan.accrual_start() / day_nanos) as i64,
+        };
+        let today = (now / day_nanos) as i64;
+        let capitalization_days: Vec<i64> = loan.grace_capitalization_dates()
+            .into_iter()
+            .map(|date| (date / day_nanos) as i64)
+            .collect();
+        let rate = Rate::from_percentage(loan.interest_rate)?;
+        let mut annual_interest = Money::from_cents(loan.current_balance).checked_mul_rate(rate)?;
+        let convention = loan.day_count_convention;
+
+        let mut accruals = Vec::new();
+        for day in first_day..today {
+            let mut capitalized = 0;
+            if capitalization_days.contains(&day) && loan.accrued_interest > 0 {
+                capitalized = loan.accrued_interest;
+                loan.current_balance += capitalized;
+                loan.accrued_interest = 0;
+                annual_interest = Money::from_cents(loan.current_balance).checked_mul_rate(rate)?;
+            }
+
+            // Sub-cent remainders carry over so rounding does not drift over the term
+            let interest = annual_interest
+                .checked_mul(convention.accrued_days(day))?
+                .checked_div(convention.year_basis(), RoundingMode::HalfEven)?
+                .checked_add(Money::from_raw(loan.accrual_remainder as i128))?;
+            let amount = interest.to_cents(RoundingMode::Down)?;
+            loan.accrual_remainder = interest.checked_sub(Money::from_cents(amount))?.raw() as u64;
+            loan.accrued_interest += amount;
+            loan.last_accrual_date = Some(day as u64 * day_nanos);
+
+            accruals.push(InterestAccrual {
+                loan_id: loan.id.clone(),
+                accrual_date: day as u64 * day_nanos,
+                convention: loan.day_count_convention,
+                principal_balance: loan.current_balance,
+                interest_rate: loan.interest_rate,
+                amount,
+                accrued_interest: loan.accrued_interest,
+                capitalized,
+                recorded_at: now,
+            });
+        }
+        Ok(accruals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2025-01-01
+    const JAN_1_2025: i64 = 20_089;
+
+    #[test]
+    fn test_day_count_conventions() {
+        let days: Vec<u64> = (0..365)
+            .map(|offset| DayCountConvention::Thirty360.accrued_days(JAN_1_2025 + offset))
+            .collect();
+        assert_eq!(days.iter().sum::<u64>(), DayCountConvention::Thirty360.year_basis());
+        // January 30th to 31st accrues nothing; February 28th makes up three days
+        assert_eq!(days[29], 0);
+        assert_eq!(days[58], 3);
+        assert_eq!(days[59], 1);
+
+        let actual: u64 = (0..365).map(|offset| DayCountConvention::Actual365.accrued_days(JAN_1_2025 + offset)).sum();
+        assert_eq!(actual, DayCountConvention::Actual365.year_basis());
+    }
+
+    fn loan(activated_at: Timestamp, grace_period_months: u32) -> Loan {
+        Loan {
+            id: "LOAN-1".to_string(),
+            student_id: candid::Principal::anonymous(),
+            original_amount: 1_000_000,
+            current_balance: 1_000_000,
+            interest_rate: 0.0365,
+            term_months: 12,
+            monthly_payment: 85_000,
+            grace_period_months,
+            origination_fee: 0,
+            status: LoanStatus::Active,
+            created_at: activated_at,
+            first_payment_due: add_months(activated_at, grace_period_months.max(1)),
+            last_payment_date: None,
+            payments_made: 0,
+            late_payments: 0,
+            purpose: "Tuition".to_string(),
+            collateral_required: false,
+            cosigner_id: None,
+            special_conditions: Vec::new(),
+            activated_at: Some(activated_at),
+            overpayment_preference: Default::default(),
+            day_count_convention: DayCountConvention::Actual365,
+            accrued_interest: 0,
+            last_accrual_date: None,
+            accrual_remainder: 0,
+            application_id: None,
+        }
+    }
+
+    #[test]
+    fn test_accrual_is_idempotent_per_day() {
+        let day = days_to_nanos(1);
+        let activated_at = JAN_1_2025 as u64 * day + day / 2;
+        let mut loan = loan(activated_at, 0);
+
+        // Nothing accrues until the first day is over, and each day accrues once
+        assert!(AccrualEngine::accrue(&mut loan, activated_at + day / 4).unwrap().is_empty());
+        let accruals = AccrualEngine::accrue(&mut loan, activated_at + 3 * day).unwrap();
+        assert_eq!(accruals.len(), 3);
+        assert!(accruals.iter().all(|accrual| accrual.amount == 100));
+        assert!(AccrualEngine::accrue(&mut loan, activated_at + 3 * day + day / 4).unwrap().is_empty());
+        assert_eq!((loan.accrued_interest, loan.current_balance), (300, 1_000_000));
+
+        // Sub-cent interest is carried rather than dropped
+        loan.current_balance = 1_000;
+        let small: Amount = AccrualEngine::accrue(&mut loan, activated_at + 103 * day)
+            .unwrap()
+            .iter()
+            .map(|accrual| accrual.amount)
+            .sum();
+        assert_eq!(small, 10);
+
+        // A full year at an awkward balance and rate accrues the annual interest to the cent:
+        // 123,457 cents at 4.99% is 6,160.5043 cents
+        loan.current_balance = 123_457;
+        loan.interest_rate = 0.0499;
+        loan.accrued_interest = 0;
+        loan.accrual_remainder = 0;
+        let year: Amount = AccrualEngine::accrue(&mut loan, activated_at + 468 * day)
+            .unwrap()
+            .iter()
+            .map(|accrual| accrual.amount)
+            .sum();
+        assert_eq!(year, 6_160);
+        assert!(loan.accrual_remainder < Money::UNITS_PER_CENT as u64);
+
+        loan.status = LoanStatus::Default;
+        assert!(AccrualEngine::accrue(&mut loan, activated_at + 600 * day).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_grace_interest_is_capitalized_monthly() {
+        let day = days_to_nanos(1);
+        let activated_at = JAN_1_2025 as u64 * day + day / 2;
+        let mut loan = loan(activated_at, 3);
+        loan.status = LoanStatus::InGracePeriod;
+
+        // Interest accrues from activation: January's 31 days at $1 a day
+        AccrualEngine::accrue(&mut loan, activated_at + 31 * day).unwrap();
+        assert_eq!((loan.current_balance, loan.accrued_interest), (1_000_000, 3_100));
+
+        // On February 1st it joins the principal and starts earning interest itself
+        let accruals = AccrualEngine::accrue(&mut loan, activated_at + 32 * day).unwrap();
+        assert_eq!(accruals[0].capitalized, 3_100);
+        assert_eq!(accruals[0].principal_balance, 1_003_100);
+        assert_eq!((loan.current_balance, loan.accrued_interest), (1_003_100, 100));
+
+        // March 1st capitalizes February; the last grace month is left for the first installment
+        let accruals = AccrualEngine::accrue(&mut loan, activated_at + 120 * day).unwrap();
+        let capitalized: Vec<Amount> = accruals
+            .iter()
+            .map(|accrual| accrual.capitalized)
+            .filter(|capitalized| *capitalized > 0)
+            .collect();
+        assert_eq!(capitalized.len(), 1);
+        assert_eq!(loan.current_balance, 1_003_100 + capitalized[0]);
+    }
+}
@@ -0,0 +1,327 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::types::*;
+use crate::storage::*;
+use crate::disbursement::*;
+use crate::accrual::DayCountConvention;
+use shared::*;
+
+/// Version of the document layout below. Bump it whenever the rendered text
+/// changes so stored agreements can still be matched to the template that made them.
+const AGREEMENT_TEMPLATE_VERSION: u32 = 3;
+
+/// Special condition recorded by credit_assessment_service when the terms waive the penalty
+const NO_PREPAYMENT_PENALTY: &str = "no_prepayment_penalty";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum SignerRole {
+    Borrower,
+    Cosigner,
+}
+
+/// A party's acceptance of one agreement version, identified by its hash
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AgreementSignature {
+    pub signer: Principal,
+    pub role: SignerRole,
+    pub document_hash: String,
+    pub signed_at: Timestamp,
+}
+
+/// Canonical loan agreement document. A new version is issued whenever the
+/// terms change before signing; earlier versions are kept, marked superseded.
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct LoanAgreement {
+    pub loan_id: String,
+    pub version: u32,
+    pub template_version: u32,
+    pub borrower: Principal,
+    pub cosigner: Option<Principal>,
+    /// UTF-8 text with `\n` line endings; the hash is taken over exactly these bytes
+    pub document: String,
+    /// Hex-encoded SHA-256 of `document`
+    pub document_hash: String,
+    pub generated_at: Timestamp,
+    pub superseded_at: Option<Timestamp>,
+    pub signatures: Vec<AgreementSignature>,
+}
+
+impl LoanAgreement {
+    pub fn is_signed_by(&self, signer: &Principal) -> bool {
+        self.signatures.iter().any(|signature| signature.signer == *signer)
+    }
+
+    /// Whether the borrower and, if there is one, the cosigner have signed
+    pub fn is_fully_signed(&self) -> bool {
+        self.is_signed_by(&self.borrower) && self.cosigner.is_none_or(|cosigner| self.is_signed_by(&cosigner))
+    }
+}
+
+/// Loan agreement generation and signing
+pub struct AgreementEngine;
+
+impl AgreementEngine {
+    /// Issue a new agreement version for an unsigned loan, superseding the previous one
+    pub fn generate(loan: &Loan, now: Timestamp) -> StudiFiResult<LoanAgreement> {
+        if loan.status != LoanStatus::PendingSignature {
+            return Err(StudiFiError::InvalidInput("Agreements can only be issued before the loan is signed".to_string()));
+        }
+
+        let disbursements = with_storage(|storage| storage.get_loan_disbursements(&loan.id));
+        let previous = with_storage(|storage| storage.get_current_agreement(&loan.id));
+        let version = previous.as_ref().map_or(1, |agreement| agreement.version + 1);
+        let document = Self::render(loan, &disbursements, version)?;
+
+        let agreement = LoanAgreement {
+            loan_id: loan.id.clone(),
+            version,
+            template_version: AGREEMENT_TEMPLATE_VERSION,
+            borrower: loan.student_id,
+            cosigner: loan.cosigner_id,
+            document_hash: Self::hash(&document),
+            document,
+            generated_at: now,
+            superseded_at: None,
+            signatures: Vec::new(),
+        };
+
+        with_storage_mut(|storage| {
+            if let Some(mut previous) = previous {
+                previous.superseded_at = Some(now);
+                storage.insert_agreement(previous);
+            }
+            storage.insert_agreement(agreement.clone());
+        });
+        Ok(agreement)
+    }
+
+    /// Record `signer`'s acceptance of the current agreement. The hash must match
+    /// the version the signer was shown. Returns the agreement and whether every
+    /// required party has now signed.
+    pub fn sign(loan: &Loan, signer: Principal, document_hash: &str, now: Timestamp) -> StudiFiResult<(LoanAgreement, bool)> {
+        let role = if signer == loan.student_id {
+            SignerRole::Borrower
+        } else if loan.cosigner_id == Some(signer) {
+            SignerRole::Cosigner
+        } else {
+            return Err(StudiFiError::Unauthorized("Only the borrower and cosigner can sign the agreement".to_string()));
+        };
+        if loan.status != LoanStatus::PendingSignature {
+            return Err(StudiFiError::InvalidInput(format!("Loan is {:?}, not awaiting signature", loan.status)));
+        }
+
+        let mut agreement = with_storage(|storage| storage.get_current_agreement(&loan.id))
+            .ok_or_else(|| StudiFiError::NotFound("No agreement has been issued for this loan".to_string()))?;
+        if !agreement.document_hash.eq_ignore_ascii_case(document_hash) {
+            return Err(StudiFiError::InvalidInput(format!(
+                "Hash does not match agreement version {}; review the current agreement and sign again",
+                agreement.version
+            )));
+        }
+        if agreement.is_signed_by(&signer) {
+            return Err(StudiFiError::AlreadyExists("Agreement already signed".to_string()));
+        }
+
+        agreement.signatures.push(AgreementSignature {
+            signer,
+            role,
+            document_hash: agreement.document_hash.clone(),
+            signed_at: now,
+        });
+        with_storage_mut(|storage| storage.insert_agreement(agreement.clone()));
+
+        let fully_signed = agreement.is_fully_signed();
+        Ok((agreement, fully_signed))
+    }
+
+    pub fn hash(document: &str) -> String {
+        hex::encode(Sha256::digest(document.as_bytes()))
+    }
+
+    fn render(loan: &Loan, disbursements: &[Disbursement], version: u32) -> StudiFiResult<String> {
+        // Grace-period interest is capitalized, so it is part of the payments disclosed here
+        let schedule = build_amortization_schedule(loan.amortized_principal()?, loan.interest_rate, loan.term_months)?;
+        let total_payments: Amount = schedule.iter().map(|line| line.payment).sum();
+        let total_interest = total_payments.saturating_sub(loan.original_amount);
+        let payments: Vec<Amount> = schedule.iter().map(|line| line.payment).collect();
+        let apr = annual_percentage_rate(
+            loan.original_amount.saturating_sub(loan.origination_fee),
+            &payments,
+            loan.grace_period_months,
+        )?;
+        let prepayment_penalty = if loan.special_conditions.iter().any(|condition| condition == NO_PREPAYMENT_PENALTY) {
+            "none".to_string()
+        } else {
+            format!("{} of the remaining balance", format_bps(PREPAYMENT_PENALTY_BPS))
+        };
+
+        let mut lines = vec![
+            "STUDIFI STUDENT LOAN AGREEMENT".to_string(),
+            format!("Template version: {}", AGREEMENT_TEMPLATE_VERSION),
+            format!("Agreement version: {}", version),
+            format!("Loan: {}", loan.id),
+            format!("Borrower: {}", loan.student_id),
+            format!("Cosigner: {}", loan.cosigner_id.map_or("none".to_string(), |cosigner| cosigner.to_text())),
+            format!("Purpose: {}", loan.purpose),
+            String::new(),
+            "TERMS".to_string(),
+            format!("Principal: {}", format_currency(loan.original_amount)),
+            format!("Interest rate: {:.4}% per year", loan.interest_rate * 100.0),
+            format!("Interest accrual: daily, {}", match loan.day_count_convention {
+                DayCountConvention::Actual365 => "actual/365",
+                DayCountConvention::Thirty360 => "30/360",
+            }),
+            "Grace period interest: accrues from disbursement and is added to the principal monthly until repayment begins".to_string(),
+            format!("Annual percentage rate: {}", format_rate(apr)),
+            format!("Term: {} months", loan.term_months),
+            format!("Monthly payment: {}", format_currency(schedule.first().map_or(0, |line| line.payment))),
+            format!("Origination fee: {}", format_currency(loan.origination_fee)),
+            format!("Total of payments: {}", format_currency(total_payments)),
+            format!("Total interest: {}", format_currency(total_interest)),
+            format!("Prepayment penalty: {}", prepayment_penalty),
+            format!("Collateral required: {}", if loan.collateral_required { "yes" } else { "no" }),
+            String::new(),
+            "REPAYMENT SCHEDULE".to_string(),
+            format!("Installments: {}", schedule.len()),
+            format!("First payment: {} months after the final disbursement", loan.grace_period_months),
+            "Payments are due on the same day of each following month.".to_string(),
+            String::new(),
+            "DISBURSEMENT PLAN".to_string(),
+        ];
+
+        for disbursement in disbursements {
+            let payee = match &disbursement.payee {
+                DisbursementPayee::Student => "the borrower".to_string(),
+                DisbursementPayee::University { name, reference, .. } => format!("{} (account {})", name, reference),
+            };
+            let term = if disbursement.academic_term.is_empty() {
+                String::new()
+            } else {
+                format!(" for {}", disbursement.academic_term)
+            };
+            lines.push(format!(
+                "{}. {} to {}{}, on or after {}",
+                disbursement.tranche_number,
+                format_currency(disbursement.amount),
+                payee,
+                term,
+                format_date(disbursement.scheduled_for),
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push("SPECIAL CONDITIONS".to_string());
+        let conditions: Vec<&String> = loan.special_conditions
+            .iter()
+            .filter(|condition| condition.as_str() != NO_PREPAYMENT_PENALTY)
+            .collect();
+        if conditions.is_empty() {
+            lines.push("none".to_string());
+        }
+        lines.extend(conditions.into_iter().map(|condition| format!("- {}", condition)));
+
+        let mut document = lines.join("\n");
+        document.push('\n');
+        Ok(document)
+    }
+}
+
+/// APR as an annual rate: twelve times the monthly rate at which the payments,
+/// starting `first_payment_month` months out, are worth the amount financed
+fn annual_percentage_rate(amount_financed: Amount, payments: &[Amount], first_payment_month: u32) -> StudiFiResult<Rate> {
+    // Discounting by 1 / (1 + r) keeps every power below one, so long terms cannot overflow
+    let present_value = |monthly_rate: Rate| -> StudiFiResult<Money> {
+        let discount = Rate::ONE.checked_div(Rate::ONE.checked_add(monthly_rate)?)?;
+        let mut factor = discount.checked_pow(first_payment_month)?;
+        let mut total = Money::ZERO;
+        for payment in payments {
+            total = total.checked_add(Money::from_cents(*payment).checked_mul_rate(factor)?)?;
+            factor = factor.checked_mul(discount)?;
+        }
+        Ok(total)
+    };
+
+    let target = Money::from_cents(amount_financed);
+    if amount_financed == 0 || present_value(Rate::ZERO)? <= target {
+        return Ok(Rate::ZERO);
+    }
+
+    // Present value falls as the rate rises, so bisect for the matching rate
+    let (mut low, mut high) = (Rate::ZERO, Rate::ONE);
+    while high.raw() - low.raw() > 1 {
+        let mid = Rate::from_raw(low.raw() + (high.raw() - low.raw()) / 2);
+        if present_value(mid)? > target {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(Rate::from_raw(low.raw() * 12))
+}
+
+/// A rate as a percentage to four decimal places, rounded half up
+fn format_rate(rate: Rate) -> String {
+    let unit = Rate::SCALE / 1_000_000;
+    let scaled = (rate.raw() + unit / 2) / unit;
+    format!("{}.{:04}%", scaled / 10_000, scaled % 10_000)
+}
+
+fn format_bps(basis_points: u32) -> String {
+    format!("{}.{:02}%", basis_points / 100, basis_points % 100)
+}
+
+/// ISO 8601 calendar date (UTC)
+fn format_date(timestamp: Timestamp) -> String {
+    let (year, month, day) = days_to_civil_date((timestamp / days_to_nanos(1)) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apr_and_document_hash() {
+        // $12,000 at 6% over 12 months pays $1,032.80 a month. Rounding the payment up
+        // to the cent puts the APR just above the note rate, at 6.000516%
+        let payment = calculate_monthly_payment(1_200_000, 0.06, 12).unwrap();
+        assert_eq!(payment, 103_280);
+        let apr = annual_percentage_rate(1_200_000, &[payment; 12], 1).unwrap();
+        assert_eq!(format_rate(apr), "6.0005%");
+
+        // A 1% fee raises it to 7.886646%
+        let with_fee = annual_percentage_rate(1_188_000, &[payment; 12], 1).unwrap();
+        assert_eq!(format_rate(with_fee), "7.8866%");
+        assert_eq!(annual_percentage_rate(1_200_000, &[100_000; 12], 1).unwrap(), Rate::ZERO);
+
+        // Grace interest is capitalized into the payments, so deferring the first payment
+        // never brings the APR below the note rate; a fee keeps it above
+        let note_rate = Rate::from_percentage(0.06).unwrap();
+        for (grace_period_months, expected, expected_with_fee) in [
+            (1, "6.0002%", "7.8864%"),
+            (6, "6.0003%", "7.0607%"),
+            (36, "6.0000%", "6.2925%"),
+        ] {
+            let principal = capitalize_grace_interest(1_200_000, 0.06, grace_period_months).unwrap();
+            let payments: Vec<Amount> = build_amortization_schedule(principal, 0.06, 12)
+                .unwrap()
+                .iter()
+                .map(|line| line.payment)
+                .collect();
+            let apr = annual_percentage_rate(1_200_000, &payments, grace_period_months).unwrap();
+            let with_fee = annual_percentage_rate(1_188_000, &payments, grace_period_months).unwrap();
+            assert_eq!(format_rate(apr), expected);
+            assert_eq!(format_rate(with_fee), expected_with_fee);
+            assert!(with_fee > note_rate);
+        }
+
+        assert_eq!(
+            AgreementEngine::hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(format_bps(PREPAYMENT_PENALTY_BPS), "2.00%");
+        assert_eq!(format_date(0), "1970-01-01");
+    }
+}
@@ -0,0 +1,403 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+use crate::types::*;
+use crate::storage::*;
+use shared::*;
+
+/// Single installment of a loan's amortization schedule
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct Installment {
+    pub loan_id: String,
+    pub number: u32,
+    pub due_date: Timestamp,
+    pub payment: Amount,
+    pub principal: Amount,
+    pub interest: Amount,
+    pub remaining_balance: Amount,
+    pub amount_paid: Amount,
+    pub status: InstallmentStatus,
+    pub paid_at: Option<Timestamp>,
+}
+
+impl Installment {
+    pub fn amount_outstanding(&self) -> Amount {
+        self.payment.saturating_sub(self.amount_paid)
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.status == InstallmentStatus::Paid
+    }
+
+    /// Scheduled interest not yet covered; amounts paid count toward interest first
+    pub fn interest_outstanding(&self) -> Amount {
+        self.interest.saturating_sub(self.amount_paid)
+    }
+
+    pub fn principal_outstanding(&self) -> Amount {
+        self.amount_outstanding() - self.interest_outstanding().min(self.amount_outstanding())
+    }
+
+    /// Settled at least a day after its due date
+    pub fn was_paid_late(&self) -> bool {
+        self.paid_at
+            .is_some_and(|paid_at| paid_at.saturating_sub(self.due_date) >= days_to_nanos(1))
+    }
+}
+
+/// What happens to the part of a payment beyond the installments currently due
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Serialize)]
+pub enum OverpaymentPreference {
+    /// Cover upcoming installments in order, moving the next due date out
+    #[default]
+    PrepayInstallments,
+    /// Reduce principal and re-amortize the remaining installments at a lower payment
+    Reamortize,
+}
+
+/// Installment status enumeration
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum InstallmentStatus {
+    Scheduled,
+    PartiallyPaid,
+    Paid,
+    Overdue,
+}
+
+/// Persisted amortization schedule for a loan
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AmortizationSchedule {
+    pub loan_id: String,
+    pub student_id: Principal,
+    pub principal: Amount,
+    pub interest_rate: Percentage,
+    pub term_months: u32,
+    pub monthly_payment: Amount,
+    pub total_interest: Amount,
+    pub total_payments: Amount,
+    pub installments: Vec<Installment>,
+    pub generated_at: Timestamp,
+}
+
+impl AmortizationSchedule {
+    /// Installments paid in full; they are settled in order, so this is also
+    /// the number of due dates already behind the borrower
+    pub fn settled_count(&self) -> u32 {
+        self.installments.iter().filter(|installment| installment.is_settled()).count() as u32
+    }
+
+    pub fn late_count(&self) -> u32 {
+        self.installments.iter().filter(|installment| installment.was_paid_late()).count() as u32
+    }
+
+    /// Interest and principal currently due: the next open installment, plus any
+    /// other open installment whose due date has passed
+    pub fn amounts_due(&self, now: Timestamp) -> (Amount, Amount) {
+        self.installments
+            .iter()
+            .filter(|installment| !installment.is_settled())
+            .enumerate()
+            .filter(|(index, installment)| *index == 0 || installment.due_date <= now)
+            .fold((0, 0), |(interest, principal), (_, installment)| {
+                (interest + installment.interest_outstanding(), principal + installment.principal_outstanding())
+            })
+    }
+
+    /// Mark every open installment paid, e.g. once the loan is paid off
+    pub fn settle_remaining(&mut self, paid_at: Timestamp) {
+        for installment in self.installments.iter_mut().filter(|installment| !installment.is_settled()) {
+            installment.status = InstallmentStatus::Paid;
+            installment.paid_at = Some(paid_at);
+        }
+    }
+
+    /// Credit `amount` to open installments in due-date order
+    fn apply_amount(&mut self, amount: Amount, paid_at: Timestamp) {
+        let mut remaining = amount;
+        for installment in self.installments.iter_mut() {
+            if remaining == 0 {
+                break;
+            }
+            if installment.is_settled() {
+                continue;
+            }
+
+            let applied = remaining.min(installment.amount_outstanding());
+            installment.amount_paid += applied;
+            remaining -= applied;
+
+            if installment.amount_outstanding() == 0 {
+                installment.status = InstallmentStatus::Paid;
+                installment.paid_at = Some(paid_at);
+            } else {
+                installment.status = InstallmentStatus::PartiallyPaid;
+            }
+        }
+    }
+
+    /// Spread `balance` over the open installments as a new level payment, keeping their due dates.
+    /// Credit already sitting on those installments is part of the reduced balance, so it is cleared.
+    fn reamortize(&mut self, balance: Amount) -> StudiFiResult<()> {
+        let open: Vec<&mut Installment> = self.installments
+            .iter_mut()
+            .filter(|installment| !installment.is_settled())
+            .collect();
+        if open.is_empty() || balance == 0 {
+            return Ok(());
+        }
+
+        let lines = build_amortization_schedule(balance, self.interest_rate, open.len() as u32)?;
+        self.monthly_payment = lines.first().map_or(0, |line| line.payment);
+        for (index, installment) in open.into_iter().enumerate() {
+            let line = lines.get(index);
+            installment.payment = line.map_or(0, |line| line.payment);
+            installment.principal = line.map_or(0, |line| line.principal);
+            installment.interest = line.map_or(0, |line| line.interest);
+            installment.remaining_balance = line.map_or(0, |line| line.remaining_balance);
+            installment.amount_paid = 0;
+            // Rounding can pay the balance off before the last installment
+            installment.status = if line.is_some() { InstallmentStatus::Scheduled } else { InstallmentStatus::Paid };
+        }
+
+        self.total_interest = self.installments.iter().map(|installment| installment.interest).sum();
+        self.total_payments = self.installments.iter().map(|installment| installment.payment).sum();
+        Ok(())
+    }
+}
+
+/// Amortization engine for schedule generation and installment tracking
+pub struct AmortizationEngine;
+
+impl AmortizationEngine {
+    /// Generate the installment schedule for a loan.
+    ///
+    /// Due dates fall on the same day of each calendar month as the loan's
+    /// first payment, clamped to the last day of shorter months. The schedule
+    /// amortizes the principal with grace-period interest capitalized.
+    pub fn generate_schedule(loan: &Loan) -> StudiFiResult<AmortizationSchedule> {
+        let principal = loan.amortized_principal()?;
+        let lines = build_amortization_schedule(principal, loan.interest_rate, loan.term_months)?;
+
+        let installments: Vec<Installment> = lines
+            .iter()
+            .map(|line| Installment {
+                loan_id: loan.id.clone(),
+                number: line.period,
+                due_date: add_months(loan.first_payment_due, line.period - 1),
+                payment: line.payment,
+                principal: line.principal,
+                interest: line.interest,
+                remaining_balance: line.remaining_balance,
+                amount_paid: 0,
+                status: InstallmentStatus::Scheduled,
+                paid_at: None,
+            })
+            .collect();
+
+        let total_interest = installments.iter().map(|installment| installment.interest).sum();
+        let total_payments = installments.iter().map(|installment| installment.payment).sum();
+
+        Ok(AmortizationSchedule {
+            loan_id: loan.id.clone(),
+            student_id: loan.student_id,
+            principal,
+            interest_rate: loan.interest_rate,
+            term_months: loan.term_months,
+            monthly_payment: loan.monthly_payment,
+            total_interest,
+            total_payments,
+            installments,
+            generated_at: current_time(),
+        })
+    }
+
+    /// Generate and persist the schedule for a newly created loan
+    pub fn create_schedule(loan: &Loan) -> StudiFiResult<AmortizationSchedule> {
+        let schedule = Self::generate_schedule(loan)?;
+        with_storage_mut(|storage| {
+            storage.insert_schedule(loan.id.clone(), schedule.clone());
+        });
+        Ok(schedule)
+    }
+
+    /// Load a loan's stored schedule, regenerating it for loans created before schedules were persisted
+    fn load_schedule(loan_id: &str) -> StudiFiResult<AmortizationSchedule> {
+        if let Some(schedule) = with_storage(|storage| storage.get_schedule(loan_id)) {
+            return Ok(schedule);
+        }
+
+        let loan = with_storage(|storage| storage.get_loan(loan_id))
+            .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
+        Self::generate_schedule(&loan)
+    }
+
+    /// The installments of `loan` after a received payment, whose amount has
+    /// already been taken off the loan's balance. `scheduled` covers the installments
+    /// currently due; `prepayment` is handled according to the loan's overpayment
+    /// preference. Nothing is stored, so this can run before the payment is collected.
+    pub fn schedule_after_payment(
+        loan: &Loan,
+        scheduled: Amount,
+        prepayment: Amount,
+        paid_at: Timestamp,
+    ) -> StudiFiResult<AmortizationSchedule> {
+        let mut schedule = Self::load_schedule(&loan.id)?;
+
+        // Interest accrued beyond the schedule, e.g. while overdue, does not settle later installments
+        let (interest_due, principal_due) = schedule.amounts_due(paid_at);
+        let scheduled = scheduled.min(interest_due + principal_due);
+
+        match loan.overpayment_preference {
+            OverpaymentPreference::PrepayInstallments => schedule.apply_amount(scheduled + prepayment, paid_at),
+            OverpaymentPreference::Reamortize => {
+                schedule.apply_amount(scheduled, paid_at);
+                if prepayment > 0 {
+                    schedule.reamortize(loan.current_balance)?;
+                }
+            }
+        }
+
+        Self::refresh_statuses(&mut schedule, paid_at);
+        Ok(schedule)
+    }
+
+    /// Interest and principal due on a loan's schedule as of `now`
+    pub fn amounts_due(loan_id: &str, now: Timestamp) -> StudiFiResult<(Amount, Amount)> {
+        Ok(Self::load_schedule(loan_id)?.amounts_due(now))
+    }
+
+    /// The loan's schedule with every open installment settled, e.g. after an
+    /// early payoff. Like `schedule_after_payment`, nothing is stored.
+    pub fn settled_schedule(loan_id: &str, paid_at: Timestamp) -> StudiFiResult<AmortizationSchedule> {
+        let mut schedule = Self::load_schedule(loan_id)?;
+        schedule.settle_remaining(paid_at);
+        Ok(schedule)
+    }
+
+    /// Recompute overdue flags for open installments as of `now`
+    pub fn refresh_statuses(schedule: &mut AmortizationSchedule, now: Timestamp) {
+        for installment in schedule.installments.iter_mut().filter(|installment| !installment.is_settled()) {
+            installment.status = if installment.due_date < now {
+                InstallmentStatus::Overdue
+            } else if installment.amount_paid > 0 {
+                InstallmentStatus::PartiallyPaid
+            } else {
+                InstallmentStatus::Scheduled
+            };
+        }
+    }
+
+    /// Persist refreshed installment statuses for every stored schedule
+    pub fn update_installment_statuses() -> StudiFiResult<()> {
+        let now = current_time();
+        let schedules = with_storage(|storage| storage.get_all_schedules());
+
+        for mut schedule in schedules {
+            Self::refresh_statuses(&mut schedule, now);
+            with_storage_mut(|storage| {
+                storage.insert_schedule(schedule.loan_id.clone(), schedule);
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Get a loan's schedule with statuses current as of now
+    pub fn get_schedule(loan_id: &str) -> StudiFiResult<AmortizationSchedule> {
+        let mut schedule = Self::load_schedule(loan_id)?;
+        Self::refresh_statuses(&mut schedule, current_time());
+        Ok(schedule)
+    }
+
+    /// Open installments across all of a student's loans, soonest first
+    pub fn get_upcoming_installments(student_id: &Principal, pagination: PaginationParams) -> PaginatedResponse<Installment> {
+        let now = current_time();
+        let schedules = with_storage(|storage| storage.get_schedules_by_student(student_id));
+
+        let mut upcoming: Vec<Installment> = schedules
+            .into_iter()
+            .flat_map(|mut schedule| {
+                Self::refresh_statuses(&mut schedule, now);
+                schedule.installments
+            })
+            .filter(|installment| !installment.is_settled())
+            .collect();
+
+        upcoming.sort_by_key(|installment| installment.due_date);
+        paginate(&upcoming, &pagination)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONTH: Timestamp = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+    fn schedule(principal: Amount, term_months: u32) -> AmortizationSchedule {
+        let lines = build_amortization_schedule(principal, 0.06, term_months).unwrap();
+        let installments: Vec<Installment> = lines
+            .iter()
+            .map(|line| Installment {
+                loan_id: "LOAN-1".to_string(),
+                number: line.period,
+                due_date: line.period as u64 * MONTH,
+                payment: line.payment,
+                principal: line.principal,
+                interest: line.interest,
+                remaining_balance: line.remaining_balance,
+                amount_paid: 0,
+                status: InstallmentStatus::Scheduled,
+                paid_at: None,
+            })
+            .collect();
+
+        AmortizationSchedule {
+            loan_id: "LOAN-1".to_string(),
+            student_id: Principal::anonymous(),
+            principal,
+            interest_rate: 0.06,
+            term_months,
+            monthly_payment: lines[0].payment,
+            total_interest: lines.iter().map(|line| line.interest).sum(),
+            total_payments: lines.iter().map(|line| line.payment).sum(),
+            installments,
+            generated_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_partial_prepaid_and_reamortized_payments() {
+        let mut prepaid = schedule(1_200_000, 12);
+        let first = prepaid.installments[0].clone();
+
+        // A partial payment covers interest first and leaves the installment open
+        prepaid.apply_amount(first.interest + 100, MONTH / 2);
+        assert_eq!(prepaid.settled_count(), 0);
+        assert_eq!(prepaid.amounts_due(MONTH / 2), (0, first.principal - 100));
+
+        // Paying ahead settles the following installment too
+        let second = prepaid.installments[1].payment;
+        prepaid.apply_amount(first.principal - 100 + second, MONTH / 2);
+        assert_eq!(prepaid.settled_count(), 2);
+        assert_eq!(prepaid.late_count(), 0);
+
+        // Everything overdue is due at once, and settling it late is counted
+        let mut overdue = schedule(1_200_000, 12);
+        let (interest, principal) = overdue.amounts_due(2 * MONTH + 1);
+        assert_eq!(interest + principal, overdue.installments[0].payment + overdue.installments[1].payment);
+        overdue.apply_amount(interest + principal, 3 * MONTH);
+        assert_eq!((overdue.settled_count(), overdue.late_count()), (2, 2));
+
+        // Re-amortizing keeps the due dates and lowers the payment
+        let mut reamortized = schedule(1_200_000, 12);
+        reamortized.apply_amount(first.payment, MONTH);
+        let balance = first.remaining_balance - 300_000;
+        reamortized.reamortize(balance).unwrap();
+        assert_eq!(reamortized.installments.len(), 12);
+        assert!(reamortized.monthly_payment < first.payment);
+        let open_principal: Amount = reamortized.installments[1..].iter().map(|installment| installment.principal).sum();
+        assert_eq!(open_principal, balance);
+        assert_eq!(reamortized.installments[11].due_date, 12 * MONTH);
+    }
+}
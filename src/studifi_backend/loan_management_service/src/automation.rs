@@ -1,6 +1,11 @@
 use crate::types::*;
 use crate::storage::*;
 use crate::treasury::*;
+use crate::amortization::*;
+use crate::disbursement::*;
+use crate::fees::*;
+use crate::accrual::*;
+use crate::token::*;
 use shared::*;
 
 /// Automation engine for scheduled tasks and loan management
@@ -11,12 +16,21 @@ impl AutomationEngine {
     pub async fn run_scheduled_tasks() -> StudiFiResult<()> {
         ic_cdk::println!("Running scheduled automation tasks...");
 
+        // Pay out tranches that have fallen due
+        let disbursed = DisbursementEngine::disburse_due(current_time()).await;
+        if disbursed > 0 {
+            ic_cdk::println!("Disbursed {} tranches", disbursed);
+        }
+
         // Update loan statuses
         Self::update_loan_statuses().await?;
 
         // Process overdue loans
         Self::process_overdue_loans().await?;
 
+        // Refresh installment statuses on amortization schedules
+        AmortizationEngine::update_installment_statuses()?;
+
         // Send payment reminders
         Self::send_payment_reminders().await?;
 
@@ -26,6 +40,9 @@ impl AutomationEngine {
         // Update interest accruals
         Self::update_interest_accruals().await?;
 
+        // Retry AML reports compliance_service has not acknowledged
+        Self::flush_aml_reports().await;
+
         ic_cdk::println!("Completed scheduled automation tasks");
         Ok(())
     }
@@ -34,7 +51,8 @@ impl AutomationEngine {
     async fn update_loan_statuses() -> StudiFiResult<()> {
         let active_loans = with_storage(|storage| storage.get_active_loans());
 
-        for mut loan in active_loans {
+        // Loans with a payment waiting on the token ledger are picked up next run
+        for mut loan in active_loans.into_iter().filter(|loan| !InFlightGuard::is_held(&loan.id)) {
             let old_status = loan.status.clone();
             let new_status = Self::determine_loan_status(&loan);
 
@@ -77,8 +95,8 @@ impl AutomationEngine {
             return LoanStatus::InGracePeriod;
         }
 
-        // Check if paid off
-        if loan.current_balance == 0 {
+        // Check if paid off, interest and fees included
+        if loan.total_owed() == 0 && with_storage(|storage| storage.get_outstanding_fees(&loan.id)) == 0 {
             return LoanStatus::PaidOff;
         }
 
@@ -103,7 +121,8 @@ impl AutomationEngine {
     async fn process_overdue_loans() -> StudiFiResult<()> {
         let overdue_loans = with_storage(|storage| storage.get_overdue_loans());
 
-        for loan in overdue_loans {
+        // Loans with a payment or tranche in flight are picked up next run
+        for loan in overdue_loans.into_iter().filter(|loan| !InFlightGuard::is_held(&loan.id)) {
             let days_overdue = loan.days_overdue();
 
             ic_cdk::println!(
@@ -169,50 +188,14 @@ impl AutomationEngine {
         Ok(())
     }
 
-    /// Apply late fees to overdue loan
+    /// Assess the current period's late fee against the loan, once per period
     async fn apply_late_fees(loan: &Loan) -> StudiFiResult<()> {
-        // Check if late fee already applied for current period
-        let payments = with_storage(|storage| storage.get_payments_by_loan(&loan.id));
-        let current_period_start = loan.next_payment_due() - months_to_nanos(1);
-        
-        let late_fee_already_applied = payments.iter().any(|payment| {
-            payment.payment_type == PaymentType::LateFee &&
-            payment.created_at >= current_period_start
-        });
-
-        if late_fee_already_applied {
-            return Ok(());
+        if let Some(fee) = FeeEngine::assess_late_fee(loan, current_time())? {
+            ic_cdk::println!(
+                "Assessed late fee {} of {} on loan {}",
+                fee.id, format_currency(fee.amount), loan.id
+            );
         }
-
-        // Calculate late fee (5% of monthly payment, minimum $25)
-        let late_fee = std::cmp::max(
-            (loan.monthly_payment as f64 * 0.05) as Amount,
-            25_00 // $25 minimum
-        );
-
-        // Create late fee payment record
-        let payment_id = with_storage_mut(|storage| storage.get_next_payment_id());
-        let late_fee_payment = Payment::new(
-            payment_id,
-            loan.id.clone(),
-            loan.student_id,
-            late_fee,
-            0, // No principal portion
-            0, // No interest portion
-            late_fee,
-            PaymentType::LateFee,
-            PaymentMethod::ICP,
-        );
-
-        with_storage_mut(|storage| {
-            storage.insert_payment(late_fee_payment.id.clone(), late_fee_payment);
-        });
-
-        ic_cdk::println!(
-            "Applied late fee of {} to loan {}",
-            format_currency(late_fee), loan.id
-        );
-
         Ok(())
     }
 
@@ -245,8 +228,8 @@ impl AutomationEngine {
             loan.id, format_currency(loan.current_balance)
         );
 
-        // Update treasury to reflect the loss
-        TreasuryEngine::handle_loan_default(loan.current_balance)?;
+        // Charge the loss off in the ledger
+        TreasuryEngine::handle_loan_default(loan)?;
 
         // TODO: Integrate with compliance gateway for regulatory reporting
         // TODO: Integrate with governance for default handling policies
@@ -254,89 +237,87 @@ impl AutomationEngine {
         Ok(())
     }
 
-    /// Update interest accruals for all active loans
+    /// Accrue interest for every full day since each loan's last accrual
     async fn update_interest_accruals() -> StudiFiResult<()> {
-        let active_loans = with_storage(|storage| storage.get_active_loans());
+        let accrued = AccrualEngine::accrue_all(current_time())?;
+        if accrued > 0 {
+            ic_cdk::println!("Accrued interest on {} loans", accrued);
+        }
+        Ok(())
+    }
 
-        for mut loan in active_loans {
-            // Calculate daily interest accrual
-            let daily_rate = loan.interest_rate / 365.0;
-            let daily_interest = (loan.current_balance as f64 * daily_rate) as Amount;
+    /// Queue a transaction for AML monitoring and try to deliver it straight away.
+    /// Undelivered reports stay queued for the next scheduled run.
+    pub async fn report_aml_transaction(report: AmlTransactionReport) {
+        with_storage_mut(|storage| storage.queue_aml_report(report.clone()));
+        Self::deliver_aml_report(report).await;
+    }
 
-            // Add accrued interest to balance (compound daily)
-            loan.current_balance += daily_interest;
-            loan.set_updated_at(current_time());
+    /// Deliver every queued AML report, returning how many were acknowledged
+    pub async fn flush_aml_reports() -> u32 {
+        let pending = with_storage(|storage| storage.get_pending_aml_reports());
 
-            with_storage_mut(|storage| {
-                let _ = storage.update_loan(loan.id.clone(), loan);
-            });
+        let mut delivered = 0;
+        for report in pending {
+            if Self::deliver_aml_report(report).await {
+                delivered += 1;
+            }
         }
-
-        Ok(())
+        delivered
     }
 
-    /// Calculate payment breakdown for a loan payment
-    pub fn calculate_payment_breakdown(
-        loan: &Loan,
-        payment_amount: Amount,
-    ) -> PaymentBreakdown {
-        // Calculate interest portion (current balance * monthly rate)
-        let monthly_rate = loan.interest_rate / 12.0;
-        let interest_portion = (loan.current_balance as f64 * monthly_rate) as Amount;
-        
-        // Remaining goes to principal
-        let principal_portion = payment_amount.saturating_sub(interest_portion);
-        
-        // Ensure we don't pay more principal than remaining balance
-        let actual_principal = std::cmp::min(principal_portion, loan.current_balance);
-        let actual_interest = payment_amount.saturating_sub(actual_principal);
-
-        PaymentBreakdown {
-            total_amount: payment_amount,
-            principal_portion: actual_principal,
-            interest_portion: actual_interest,
-            late_fee: 0, // Late fees are handled separately
-            remaining_balance: loan.current_balance.saturating_sub(actual_principal),
+    async fn deliver_aml_report(report: AmlTransactionReport) -> bool {
+        let reference_id = report.reference_id.clone();
+        match ComplianceClient::report_aml_transaction(report).await {
+            Ok(alert_ids) => {
+                with_storage_mut(|storage| storage.remove_aml_report(&reference_id));
+                if !alert_ids.is_empty() {
+                    ic_cdk::println!("AML alerts raised for {}: {:?}", reference_id, alert_ids);
+                }
+                true
+            }
+            Err(e) => {
+                ic_cdk::println!("AML report {} not yet delivered: {}", reference_id, e);
+                false
+            }
         }
     }
 
     /// Check if loan is eligible for early payoff
-    pub fn check_early_payoff_eligibility(loan: &Loan) -> EarlyPayoffInfo {
+    pub fn check_early_payoff_eligibility(loan: &Loan) -> StudiFiResult<EarlyPayoffInfo> {
         let remaining_balance = loan.current_balance;
         let prepayment_penalty = if loan.special_conditions.contains(&"no_prepayment_penalty".to_string()) {
             0
         } else {
             // 2% of remaining balance as prepayment penalty
-            (remaining_balance as f64 * 0.02) as Amount
+            calculate_fee(remaining_balance, PREPAYMENT_PENALTY_BPS)?
         };
 
-        let total_payoff_amount = remaining_balance + prepayment_penalty;
-        let interest_savings = loan.calculate_total_interest() - loan.total_interest_paid();
+        let (outstanding_fees, interest_paid) = with_storage(|storage| {
+            (storage.get_outstanding_fees(&loan.id), storage.get_interest_paid(&loan.id))
+        });
+        let total_payoff_amount = remaining_balance + loan.accrued_interest + outstanding_fees + prepayment_penalty;
+        let interest_savings = loan.calculate_total_interest()?
+            .saturating_sub(interest_paid);
 
-        EarlyPayoffInfo {
+        Ok(EarlyPayoffInfo {
             remaining_balance,
+            accrued_interest: loan.accrued_interest,
+            outstanding_fees,
             prepayment_penalty,
             total_payoff_amount,
             interest_savings,
             is_eligible: true, // Most loans are eligible for early payoff
-        }
+        })
     }
 }
 
-/// Payment breakdown structure
-#[derive(candid::CandidType, candid::Deserialize, Clone, Debug, serde::Serialize)]
-pub struct PaymentBreakdown {
-    pub total_amount: Amount,
-    pub principal_portion: Amount,
-    pub interest_portion: Amount,
-    pub late_fee: Amount,
-    pub remaining_balance: Amount,
-}
-
 /// Early payoff information
 #[derive(candid::CandidType, candid::Deserialize, Clone, Debug, serde::Serialize)]
 pub struct EarlyPayoffInfo {
     pub remaining_balance: Amount,
+    pub accrued_interest: Amount,
+    pub outstanding_fees: Amount,
     pub prepayment_penalty: Amount,
     pub total_payoff_amount: Amount,
     pub interest_savings: Amount,
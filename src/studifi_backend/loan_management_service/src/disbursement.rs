@@ -0,0 +1,501 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::treasury::*;
+use crate::automation::*;
+use crate::amortization::*;
+use crate::agreement::*;
+use crate::ledger::*;
+use crate::token::*;
+use shared::*;
+
+/// Most tranches a loan can be split into
+const MAX_DISBURSEMENT_TRANCHES: usize = 12;
+
+/// Who a tranche is paid to
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum DisbursementPayee {
+    /// Paid to the borrower for living costs and other expenses
+    Student,
+    /// Paid directly to the university against the student's tuition account
+    University {
+        name: String,
+        account: Option<Principal>,
+        /// Student account or invoice number the university credits
+        reference: String,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum DisbursementStatus {
+    Scheduled,
+    Disbursed,
+    /// Cancelled before payout; its funds went back to the loan treasury
+    Cancelled,
+}
+
+/// One tranche of a disbursement plan as requested by the borrower or the platform
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TrancheRequest {
+    pub amount: Amount,
+    pub payee: DisbursementPayee,
+    /// Academic term the tranche funds, e.g. "Fall 2026"
+    pub academic_term: String,
+    /// Earliest time the tranche may be paid out
+    pub scheduled_for: Timestamp,
+}
+
+/// A single payout of loan principal
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct Disbursement {
+    pub id: String,
+    pub loan_id: String,
+    pub student_id: Principal,
+    pub tranche_number: u32,
+    pub amount: Amount,
+    pub payee: DisbursementPayee,
+    pub academic_term: String,
+    pub scheduled_for: Timestamp,
+    pub status: DisbursementStatus,
+    pub created_at: Timestamp,
+    pub disbursed_at: Option<Timestamp>,
+    pub cancelled_at: Option<Timestamp>,
+    pub cancellation_reason: Option<String>,
+    /// Token ledger block index of the payout; None when paid off-chain
+    #[serde(default)]
+    pub transaction_hash: Option<String>,
+    /// `created_at_time` of the first token payout attempt. Retries reuse it,
+    /// with the disbursement id as memo, so the ledger deduplicates them.
+    #[serde(default)]
+    pub payout_attempted_at: Option<Timestamp>,
+}
+
+/// Disbursement workflow: a loan moves from `PendingSignature` to
+/// `PendingDisbursement` once accepted, pays out its tranches, and becomes
+/// `Active` when none remain scheduled. Repayment terms run from activation, so
+/// students are not charged while later terms are still to be funded.
+pub struct DisbursementEngine;
+
+impl DisbursementEngine {
+    /// Pay the whole principal to the student in one tranche. Used until a plan is set.
+    pub fn create_default_plan(loan: &Loan, now: Timestamp) -> Vec<Disbursement> {
+        let tranche = TrancheRequest {
+            amount: loan.original_amount,
+            payee: DisbursementPayee::Student,
+            academic_term: String::new(),
+            scheduled_for: now,
+        };
+        with_storage_mut(|storage| Self::store_plan(storage, loan, vec![tranche], now))
+    }
+
+    /// Replace an unsigned loan's tranches. Amounts must add up to the principal.
+    pub fn set_plan(loan_id: &str, tranches: Vec<TrancheRequest>, now: Timestamp) -> StudiFiResult<Vec<Disbursement>> {
+        let loan = Self::get_loan(loan_id)?;
+        if loan.status != LoanStatus::PendingSignature {
+            return Err(StudiFiError::InvalidInput(
+                "The disbursement plan can only change before the loan is signed".to_string()
+            ));
+        }
+        Self::validate_plan(&loan, &tranches)?;
+
+        let plan = with_storage_mut(|storage| {
+            for disbursement in storage.get_loan_disbursements(loan_id) {
+                storage.remove_disbursement(&disbursement.id);
+            }
+            Self::store_plan(storage, &loan, tranches, now)
+        });
+
+        // The plan is part of the agreement, so it must be reissued for signing
+        AgreementEngine::generate(&loan, now)?;
+        Ok(plan)
+    }
+
+    fn validate_plan(loan: &Loan, tranches: &[TrancheRequest]) -> StudiFiResult<()> {
+        if tranches.is_empty() || tranches.len() > MAX_DISBURSEMENT_TRANCHES {
+            return Err(StudiFiError::InvalidInput(
+                format!("A plan needs between 1 and {} tranches", MAX_DISBURSEMENT_TRANCHES)
+            ));
+        }
+
+        let mut total: Amount = 0;
+        for tranche in tranches {
+            validate_amount(tranche.amount)?;
+            total = total.checked_add(tranche.amount)
+                .ok_or_else(|| StudiFiError::InvalidInput("Tranche amounts overflow".to_string()))?;
+            if let DisbursementPayee::University { name, reference, .. } = &tranche.payee {
+                if name.trim().is_empty() || reference.trim().is_empty() {
+                    return Err(StudiFiError::InvalidInput(
+                        "University payments need the university's name and the student's account reference".to_string()
+                    ));
+                }
+            }
+        }
+        if total != loan.original_amount {
+            return Err(StudiFiError::InvalidInput(format!(
+                "Tranches total {} but the loan principal is {}",
+                format_currency(total),
+                format_currency(loan.original_amount)
+            )));
+        }
+        Ok(())
+    }
+
+    fn store_plan(
+        storage: &mut FinanceStorage,
+        loan: &Loan,
+        mut tranches: Vec<TrancheRequest>,
+        now: Timestamp,
+    ) -> Vec<Disbursement> {
+        tranches.sort_by_key(|tranche| tranche.scheduled_for);
+        tranches
+            .into_iter()
+            .enumerate()
+            .map(|(index, tranche)| {
+                let disbursement = Disbursement {
+                    id: storage.get_next_disbursement_id(),
+                    loan_id: loan.id.clone(),
+                    student_id: loan.student_id,
+                    tranche_number: index as u32 + 1,
+                    amount: tranche.amount,
+                    payee: tranche.payee,
+                    academic_term: sanitize_text(&tranche.academic_term),
+                    scheduled_for: tranche.scheduled_for,
+                    status: DisbursementStatus::Scheduled,
+                    created_at: now,
+                    disbursed_at: None,
+                    cancelled_at: None,
+                    cancellation_reason: None,
+                    transaction_hash: None,
+                    payout_attempted_at: None,
+                };
+                storage.insert_disbursement(disbursement.clone());
+                disbursement
+            })
+            .collect()
+    }
+
+    /// Release a loan for disbursement once every party has signed its agreement
+    pub fn mark_signed(loan_id: &str) -> StudiFiResult<Loan> {
+        let mut loan = Self::get_loan(loan_id)?;
+        if loan.status != LoanStatus::PendingSignature {
+            return Err(StudiFiError::InvalidInput(format!("Loan is {:?}, not awaiting signature", loan.status)));
+        }
+
+        loan.status = LoanStatus::PendingDisbursement;
+        with_storage_mut(|storage| storage.update_loan(loan.id.clone(), loan))
+    }
+
+    /// Pay out a scheduled tranche once it is due, activating the loan after the last one
+    pub async fn disburse(disbursement_id: &str, now: Timestamp) -> StudiFiResult<Disbursement> {
+        let _guard = InFlightGuard::acquire(disbursement_id)?;
+        let mut disbursement = Self::get_disbursement(disbursement_id)?;
+        // The loan's new terms are worked out below from its tranches as they stand now,
+        // so no sibling tranche may be paid out or cancelled until this one is booked
+        let _loan_guard = InFlightGuard::acquire(&disbursement.loan_id)?;
+        let loan = Self::get_loan(&disbursement.loan_id)?;
+
+        if loan.status != LoanStatus::PendingDisbursement {
+            return Err(StudiFiError::InvalidInput(format!("Loan is {:?}, not pending disbursement", loan.status)));
+        }
+        if disbursement.status != DisbursementStatus::Scheduled {
+            return Err(StudiFiError::InvalidInput(format!("Disbursement is already {:?}", disbursement.status)));
+        }
+        if disbursement.scheduled_for > now {
+            return Err(StudiFiError::InvalidInput("Disbursement is not due yet".to_string()));
+        }
+
+        // Work out what the payout books before sending it, so that booking it
+        // once the tokens have moved cannot fail
+        let mut paid = disbursement.clone();
+        paid.status = DisbursementStatus::Disbursed;
+        paid.disbursed_at = Some(now);
+        let ledger_entry = LedgerEngine::disbursement_entry(&loan.id, &paid.id, paid.amount)?;
+        let disbursements: Vec<Disbursement> = with_storage(|storage| storage.get_loan_disbursements(&loan.id))
+            .into_iter()
+            .map(|other| if other.id == paid.id { paid.clone() } else { other })
+            .collect();
+        let settled = Self::settled_terms(loan.clone(), &disbursements, now)?;
+        let schedule = match &settled {
+            Some(settled) if settled.status == LoanStatus::Active => Some(AmortizationEngine::generate_schedule(settled)?),
+            _ => None,
+        };
+
+        // Pay out on the token ledger when it is configured and the payee has an account there
+        if let Some(config) = TokenEngine::active_config() {
+            let payee = match &disbursement.payee {
+                DisbursementPayee::Student => Some(loan.student_id),
+                DisbursementPayee::University { account, .. } => *account,
+            };
+            if payee.is_some() {
+                // Keep the first attempt's time so a retry after an interrupted payout
+                // sends the identical transfer and the ledger deduplicates it
+                let attempted_at = *disbursement.payout_attempted_at.get_or_insert(now);
+                with_storage_mut(|storage| storage.insert_disbursement(disbursement.clone()));
+                paid.payout_attempted_at = Some(attempted_at);
+            }
+            let created_at = paid.payout_attempted_at.unwrap_or(now);
+            let transfer = TokenEngine::pay_out(&config, &loan.id, &paid.id, payee, paid.amount, created_at, now).await?;
+            paid.transaction_hash = transfer.map(|transfer| transfer.block_index.to_string());
+        }
+
+        let disbursement = paid;
+        with_storage_mut(|storage| storage.insert_disbursement(disbursement.clone()));
+        LedgerEngine::post_prepared(ledger_entry, now);
+        if let Some(settled) = settled {
+            with_storage_mut(|storage| {
+                let _ = storage.update_loan(settled.id.clone(), settled.clone());
+                if let Some(schedule) = schedule {
+                    storage.insert_schedule(settled.id.clone(), schedule);
+                }
+            });
+            if settled.status == LoanStatus::Active {
+                ic_cdk::println!("Loan {} activated with {} disbursed", settled.id, format_currency(settled.original_amount));
+            }
+        }
+
+        AutomationEngine::report_aml_transaction(AmlTransactionReport {
+            reference_id: disbursement.id.clone(),
+            transaction_type: AmlTransactionType::LoanDisbursement,
+            amount: disbursement.amount,
+            party: loan.student_id,
+            counterparty: loan.cosigner_id,
+            loan_id: Some(loan.id.clone()),
+            occurred_at: now,
+        }).await;
+
+        ic_cdk::println!(
+            "Disbursed tranche {} of loan {}: {}",
+            disbursement.tranche_number, loan.id, format_currency(disbursement.amount)
+        );
+        Ok(disbursement)
+    }
+
+    /// Pay out every due tranche of signed loans, returning how many were disbursed
+    pub async fn disburse_due(now: Timestamp) -> u32 {
+        let due = with_storage(|storage| storage.get_due_disbursements(now));
+        let mut disbursed = 0;
+        for disbursement in due {
+            match Self::disburse(&disbursement.id, now).await {
+                Ok(_) => disbursed += 1,
+                // Tranches of loans not yet signed stay scheduled
+                Err(StudiFiError::InvalidInput(_)) => {}
+                Err(e) => ic_cdk::println!("Failed to disburse {}: {}", disbursement.id, e),
+            }
+        }
+        disbursed
+    }
+
+    /// Cancel a tranche before payout and return its funds to the loan treasury
+    pub fn cancel(disbursement_id: &str, reason: String, now: Timestamp) -> StudiFiResult<Disbursement> {
+        let disbursement = Self::get_disbursement(disbursement_id)?;
+        let loan = Self::cancellable_loan(&disbursement.loan_id)?;
+
+        let disbursement = Self::cancel_tranche(disbursement, &reason, now)?;
+        Self::settle_loan(&loan.id, now)?;
+        Ok(disbursement)
+    }
+
+    /// Cancel every tranche still scheduled. A loan with nothing paid out is
+    /// cancelled; otherwise it goes into repayment on what was already disbursed.
+    pub fn cancel_loan(loan_id: &str, reason: String, now: Timestamp) -> StudiFiResult<Loan> {
+        Self::cancellable_loan(loan_id)?;
+
+        let scheduled = with_storage(|storage| storage.get_loan_disbursements(loan_id))
+            .into_iter()
+            .filter(|disbursement| disbursement.status == DisbursementStatus::Scheduled);
+        for disbursement in scheduled {
+            Self::cancel_tranche(disbursement, &reason, now)?;
+        }
+        Self::settle_loan(loan_id, now)
+    }
+
+    /// A loan whose tranches may still be cancelled: not yet in repayment, and
+    /// with no tranche payout in flight whose booking would settle the loan
+    fn cancellable_loan(loan_id: &str) -> StudiFiResult<Loan> {
+        let loan = Self::get_loan(loan_id)?;
+        if !matches!(loan.status, LoanStatus::PendingSignature | LoanStatus::PendingDisbursement) {
+            return Err(StudiFiError::InvalidInput(format!("Loan is already {:?}", loan.status)));
+        }
+        if InFlightGuard::is_held(loan_id) {
+            return Err(StudiFiError::InvalidInput("Loan has a disbursement being paid out".to_string()));
+        }
+        Ok(loan)
+    }
+
+    fn cancel_tranche(mut disbursement: Disbursement, reason: &str, now: Timestamp) -> StudiFiResult<Disbursement> {
+        if disbursement.status != DisbursementStatus::Scheduled {
+            return Err(StudiFiError::InvalidInput(format!("Disbursement is already {:?}", disbursement.status)));
+        }
+        if InFlightGuard::is_held(&disbursement.id) {
+            return Err(StudiFiError::InvalidInput("Disbursement is being paid out".to_string()));
+        }
+
+        TreasuryEngine::return_treasury_funds(TreasuryType::Loan, disbursement.amount, Some(disbursement.id.clone()))?;
+
+        disbursement.status = DisbursementStatus::Cancelled;
+        disbursement.cancelled_at = Some(now);
+        disbursement.cancellation_reason = Some(sanitize_text(reason));
+        with_storage_mut(|storage| storage.insert_disbursement(disbursement.clone()));
+        Ok(disbursement)
+    }
+
+    /// Once no tranche is scheduled, cancel a loan that paid nothing out or put
+    /// it into repayment on the amount actually disbursed
+    fn settle_loan(loan_id: &str, now: Timestamp) -> StudiFiResult<Loan> {
+        let loan = Self::get_loan(loan_id)?;
+        let disbursements = with_storage(|storage| storage.get_loan_disbursements(loan_id));
+        let Some(loan) = Self::settled_terms(loan.clone(), &disbursements, now)? else {
+            return Ok(loan);
+        };
+
+        let loan = with_storage_mut(|storage| storage.update_loan(loan.id.clone(), loan))?;
+        if loan.status == LoanStatus::Active {
+            AmortizationEngine::create_schedule(&loan)?;
+            ic_cdk::println!("Loan {} activated with {} disbursed", loan.id, format_currency(loan.original_amount));
+        }
+        Ok(loan)
+    }
+
+    /// The loan's terms after its last scheduled tranche, or `None` while any remain
+    fn settled_terms(mut loan: Loan, disbursements: &[Disbursement], now: Timestamp) -> StudiFiResult<Option<Loan>> {
+        if disbursements.iter().any(|disbursement| disbursement.status == DisbursementStatus::Scheduled) {
+            return Ok(None);
+        }
+
+        let disbursed: Amount = disbursements
+            .iter()
+            .filter(|disbursement| disbursement.status == DisbursementStatus::Disbursed)
+            .map(|disbursement| disbursement.amount)
+            .sum();
+
+        if disbursed == 0 {
+            loan.status = LoanStatus::Cancelled;
+            loan.current_balance = 0;
+            return Ok(Some(loan));
+        }
+
+        if disbursed != loan.original_amount {
+            loan.origination_fee = calculate_fee(disbursed, ORIGINATION_FEE_BPS)?;
+        }
+        loan.original_amount = disbursed;
+        loan.monthly_payment = calculate_monthly_payment(loan.amortized_principal()?, loan.interest_rate, loan.term_months)?;
+        loan.current_balance = disbursed;
+        loan.status = LoanStatus::Active;
+        loan.activated_at = Some(now);
+        loan.first_payment_due = add_months(now, loan.grace_period_months);
+        Ok(Some(loan))
+    }
+
+    fn get_loan(loan_id: &str) -> StudiFiResult<Loan> {
+        with_storage(|storage| storage.get_loan(loan_id))
+            .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))
+    }
+
+    fn get_disbursement(disbursement_id: &str) -> StudiFiResult<Disbursement> {
+        with_storage(|storage| storage.get_disbursement(disbursement_id))
+            .ok_or_else(|| StudiFiError::NotFound("Disbursement not found".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accrual::DayCountConvention;
+
+    fn loan(amount: Amount) -> Loan {
+        Loan {
+            id: "LOAN-1".to_string(),
+            student_id: Principal::from_slice(&[1]),
+            original_amount: amount,
+            current_balance: amount,
+            interest_rate: 0.05,
+            term_months: 24,
+            monthly_payment: calculate_monthly_payment(amount, 0.05, 24).unwrap(),
+            grace_period_months: 6,
+            origination_fee: 0,
+            status: LoanStatus::PendingDisbursement,
+            created_at: 0,
+            first_payment_due: add_months(0, 6),
+            last_payment_date: None,
+            payments_made: 0,
+            late_payments: 0,
+            purpose: "Tuition".to_string(),
+            collateral_required: false,
+            cosigner_id: None,
+            special_conditions: Vec::new(),
+            activated_at: None,
+            overpayment_preference: OverpaymentPreference::default(),
+            day_count_convention: DayCountConvention::default(),
+            accrued_interest: 0,
+            last_accrual_date: None,
+            accrual_remainder: 0,
+            application_id: None,
+        }
+    }
+
+    fn tranche(number: u32, amount: Amount, status: DisbursementStatus) -> Disbursement {
+        Disbursement {
+            id: format!("DSB-{}", number),
+            loan_id: "LOAN-1".to_string(),
+            student_id: Principal::from_slice(&[1]),
+            tranche_number: number,
+            amount,
+            payee: DisbursementPayee::University {
+                name: "State University".to_string(),
+                account: None,
+                reference: "STU-42".to_string(),
+            },
+            academic_term: format!("Term {}", number),
+            scheduled_for: 0,
+            status,
+            created_at: 0,
+            disbursed_at: None,
+            cancelled_at: None,
+            cancellation_reason: None,
+            transaction_hash: None,
+            payout_attempted_at: None,
+        }
+    }
+
+    #[test]
+    fn test_loan_settles_on_disbursed_amount() {
+        let loan = loan(1_000_000);
+        let request = |amount| TrancheRequest {
+            amount,
+            payee: DisbursementPayee::Student,
+            academic_term: String::new(),
+            scheduled_for: 0,
+        };
+        assert!(DisbursementEngine::validate_plan(&loan, &[request(600_000), request(400_000)]).is_ok());
+        assert!(DisbursementEngine::validate_plan(&loan, &[request(600_000)]).is_err());
+        assert!(DisbursementEngine::validate_plan(&loan, &[]).is_err());
+
+        // Nothing changes while a tranche is still scheduled
+        let pending = [
+            tranche(1, 400_000, DisbursementStatus::Disbursed),
+            tranche(2, 600_000, DisbursementStatus::Scheduled),
+        ];
+        assert!(DisbursementEngine::settled_terms(loan.clone(), &pending, 10).unwrap().is_none());
+
+        // A cancelled second term re-bases repayment on the first
+        let partial = [
+            tranche(1, 400_000, DisbursementStatus::Disbursed),
+            tranche(2, 600_000, DisbursementStatus::Cancelled),
+        ];
+        let active = DisbursementEngine::settled_terms(loan.clone(), &partial, 10).unwrap().unwrap();
+        assert_eq!(active.status, LoanStatus::Active);
+        assert_eq!((active.original_amount, active.current_balance), (400_000, 400_000));
+        // Five months of the six-month grace period are capitalized before repayment
+        let amortized = capitalize_grace_interest(400_000, 0.05, 6).unwrap();
+        assert!(amortized > 400_000);
+        assert_eq!(active.monthly_payment, calculate_monthly_payment(amortized, 0.05, 24).unwrap());
+        assert_eq!(active.activated_at, Some(10));
+        assert_eq!(active.first_payment_due, add_months(10, 6));
+
+        let cancelled = [tranche(1, 1_000_000, DisbursementStatus::Cancelled)];
+        let cancelled = DisbursementEngine::settled_terms(loan, &cancelled, 10).unwrap().unwrap();
+        assert_eq!((cancelled.status, cancelled.current_balance), (LoanStatus::Cancelled, 0));
+    }
+}
@@ -0,0 +1,126 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::ledger::*;
+use shared::*;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum FeeType {
+    LateFee,
+}
+
+/// A fee assessed against a loan, collected through the payment waterfall
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct FeeReceivable {
+    pub id: String,
+    pub loan_id: String,
+    pub fee_type: FeeType,
+    pub amount: Amount,
+    pub amount_paid: Amount,
+    pub assessed_at: Timestamp,
+    pub paid_at: Option<Timestamp>,
+}
+
+impl FeeReceivable {
+    pub fn amount_outstanding(&self) -> Amount {
+        self.amount.saturating_sub(self.amount_paid)
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.amount_outstanding() == 0
+    }
+}
+
+/// Per-loan fee receivables
+pub struct FeeEngine;
+
+impl FeeEngine {
+    /// Assess the late fee for the loan's current period (5% of the monthly
+    /// payment, minimum $25). Returns None if the period has already been charged.
+    pub fn assess_late_fee(loan: &Loan, now: Timestamp) -> StudiFiResult<Option<FeeReceivable>> {
+        let current_period_start = loan.current_period_start();
+        let already_assessed = with_storage(|storage| storage.get_loan_fees(&loan.id))
+            .iter()
+            .any(|fee| fee.fee_type == FeeType::LateFee && fee.assessed_at >= current_period_start);
+        if already_assessed {
+            return Ok(None);
+        }
+
+        let amount = std::cmp::max(calculate_fee(loan.monthly_payment, LATE_FEE_BPS)?, MIN_LATE_FEE);
+        let fee = with_storage_mut(|storage| {
+            let fee = FeeReceivable {
+                id: storage.get_next_fee_id(),
+                loan_id: loan.id.clone(),
+                fee_type: FeeType::LateFee,
+                amount,
+                amount_paid: 0,
+                assessed_at: now,
+                paid_at: None,
+            };
+            storage.insert_fee(fee.clone());
+            fee
+        });
+        LedgerEngine::record_fee_assessed(&loan.id, &fee.id, fee.amount, now)?;
+        Ok(Some(fee))
+    }
+
+    /// Apply a payment's fee portion to the loan's open receivables, oldest first
+    pub fn apply_payment(loan_id: &str, amount: Amount, paid_at: Timestamp) {
+        with_storage_mut(|storage| {
+            let mut remaining = amount;
+            for mut fee in storage.get_loan_fees(loan_id) {
+                if remaining == 0 {
+                    break;
+                }
+                if fee.is_settled() {
+                    continue;
+                }
+
+                let applied = remaining.min(fee.amount_outstanding());
+                fee.amount_paid += applied;
+                remaining -= applied;
+                if fee.is_settled() {
+                    fee.paid_at = Some(paid_at);
+                }
+                storage.insert_fee(fee);
+            }
+        });
+    }
+
+    /// Move late fees recorded as pending `PaymentType::LateFee` payments, which
+    /// were never tied to a balance, onto the loan's receivables
+    pub fn migrate_legacy_late_fees(now: Timestamp) -> StudiFiResult<u32> {
+        let legacy: Vec<Payment> = with_storage(|storage| storage.get_all_payments())
+            .into_iter()
+            .filter(|payment| payment.payment_type == PaymentType::LateFee && payment.status == PaymentStatus::Pending)
+            .collect();
+
+        let fees = with_storage_mut(|storage| {
+            let mut fees = Vec::new();
+            for mut payment in legacy.iter().cloned() {
+                let fee = FeeReceivable {
+                    id: storage.get_next_fee_id(),
+                    loan_id: payment.loan_id.clone(),
+                    fee_type: FeeType::LateFee,
+                    amount: payment.late_fee,
+                    amount_paid: 0,
+                    assessed_at: payment.created_at,
+                    paid_at: None,
+                };
+                payment.status = PaymentStatus::Cancelled;
+                payment.processed_at = Some(now);
+                payment.notes = format!("Moved to fee receivable {}", fee.id);
+                storage.insert_fee(fee.clone());
+                storage.insert_payment(payment.id.clone(), payment);
+                fees.push(fee);
+            }
+            fees
+        });
+        for fee in &fees {
+            LedgerEngine::record_fee_assessed(&fee.loan_id, &fee.id, fee.amount, now)?;
+        }
+        Ok(fees.len() as u32)
+    }
+}
@@ -0,0 +1,558 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::treasury::*;
+use shared::*;
+
+/// Chart of accounts
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum LedgerAccount {
+    /// Unallocated cash held by a treasury
+    TreasuryCash(TreasuryType),
+    /// Cash set aside for approved loans, grants or protocol spending not yet paid out
+    TreasuryReserved(TreasuryType),
+    /// Principal paid out to borrowers and not yet repaid
+    LoansReceivable,
+    /// Interest accrued and not yet paid
+    InterestReceivable,
+    /// Fees assessed and not yet paid
+    FeesReceivable,
+    /// Provision against defaulted receivables
+    AllowanceForLosses,
+    /// Funds contributed to the treasuries
+    ContributedCapital,
+    InterestIncome,
+    FeeIncome,
+    /// Defaulted balances provided for, net of later recoveries
+    CreditLossExpense,
+    ScholarshipGrants,
+    /// Fees charged by the token ledger on transfers the service sends
+    NetworkFees,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum AccountType {
+    Asset,
+    ContraAsset,
+    Equity,
+    Income,
+    Expense,
+}
+
+impl LedgerAccount {
+    pub fn account_type(&self) -> AccountType {
+        match self {
+            LedgerAccount::TreasuryCash(_)
+            | LedgerAccount::TreasuryReserved(_)
+            | LedgerAccount::LoansReceivable
+            | LedgerAccount::InterestReceivable
+            | LedgerAccount::FeesReceivable => AccountType::Asset,
+            LedgerAccount::AllowanceForLosses => AccountType::ContraAsset,
+            LedgerAccount::ContributedCapital => AccountType::Equity,
+            LedgerAccount::InterestIncome | LedgerAccount::FeeIncome => AccountType::Income,
+            LedgerAccount::CreditLossExpense
+            | LedgerAccount::ScholarshipGrants
+            | LedgerAccount::NetworkFees => AccountType::Expense,
+        }
+    }
+
+    /// Whether debits increase the account
+    pub fn is_debit_normal(&self) -> bool {
+        matches!(self.account_type(), AccountType::Asset | AccountType::Expense)
+    }
+
+    pub fn key(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// One side of a journal entry; exactly one of `debit` and `credit` is non-zero
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct JournalLine {
+    pub account: LedgerAccount,
+    pub debit: Amount,
+    pub credit: Amount,
+}
+
+impl JournalLine {
+    pub fn debit(account: LedgerAccount, amount: Amount) -> Self {
+        Self { account, debit: amount, credit: 0 }
+    }
+
+    pub fn credit(account: LedgerAccount, amount: Amount) -> Self {
+        Self { account, debit: 0, credit: amount }
+    }
+}
+
+/// Balanced, immutable record of one money movement. Mistakes are corrected by
+/// posting a further entry, never by editing this one.
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct JournalEntry {
+    pub id: String,
+    pub posted_at: Timestamp,
+    pub description: String,
+    /// Id of the loan, payment, disbursement or fee the entry records
+    pub reference: Option<String>,
+    pub lines: Vec<JournalLine>,
+}
+
+/// Running debit and credit totals for an account
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AccountBalance {
+    pub account: LedgerAccount,
+    pub total_debits: Amount,
+    pub total_credits: Amount,
+}
+
+impl AccountBalance {
+    pub fn new(account: LedgerAccount) -> Self {
+        Self {
+            account,
+            total_debits: 0,
+            total_credits: 0,
+        }
+    }
+
+    /// Balance on the account's normal side; negative if it has gone the other way
+    pub fn balance(&self) -> i128 {
+        let net = self.total_debits as i128 - self.total_credits as i128;
+        if self.account.is_debit_normal() { net } else { -net }
+    }
+}
+
+/// A posting to one account, with the account's balance after it
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AccountPosting {
+    pub entry_id: String,
+    pub posted_at: Timestamp,
+    pub description: String,
+    pub reference: Option<String>,
+    pub debit: Amount,
+    pub credit: Amount,
+    pub balance: i128,
+}
+
+/// How the cash from a loan payment is split
+#[derive(Clone, Debug, Default)]
+pub struct PaymentAllocation {
+    pub principal: Amount,
+    pub interest: Amount,
+    /// Settles fee receivables
+    pub fees: Amount,
+    /// Charged with the payment itself, e.g. a prepayment penalty
+    pub penalty: Amount,
+}
+
+impl PaymentAllocation {
+    pub fn total(&self) -> Amount {
+        self.principal + self.interest + self.fees + self.penalty
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct TrialBalance {
+    pub accounts: Vec<AccountBalance>,
+    pub total_debits: Amount,
+    pub total_credits: Amount,
+    pub is_balanced: bool,
+    pub generated_at: Timestamp,
+}
+
+/// A journal entry that has been validated but not yet posted. Preparing an
+/// entry before an irreversible step, such as a token transfer, means posting
+/// it afterwards cannot fail.
+#[derive(Clone, Debug)]
+pub struct PreparedEntry {
+    description: String,
+    reference: Option<String>,
+    lines: Vec<JournalLine>,
+}
+
+/// Double-entry general ledger for every money movement in the service
+pub struct LedgerEngine;
+
+impl LedgerEngine {
+    /// Validate and post an entry, updating account totals in the same step
+    pub fn post(
+        description: impl Into<String>,
+        reference: Option<String>,
+        lines: Vec<JournalLine>,
+        now: Timestamp,
+    ) -> StudiFiResult<JournalEntry> {
+        Ok(Self::post_prepared(Self::prepare(description, reference, lines)?, now))
+    }
+
+    /// Validate an entry for posting later with `post_prepared`
+    pub fn prepare(
+        description: impl Into<String>,
+        reference: Option<String>,
+        lines: Vec<JournalLine>,
+    ) -> StudiFiResult<PreparedEntry> {
+        let lines: Vec<JournalLine> = lines.into_iter().filter(|line| line.debit > 0 || line.credit > 0).collect();
+        Self::validate(&lines)?;
+        Ok(PreparedEntry {
+            description: description.into(),
+            reference,
+            lines,
+        })
+    }
+
+    /// Post an entry validated by `prepare`
+    pub fn post_prepared(entry: PreparedEntry, now: Timestamp) -> JournalEntry {
+        with_storage_mut(|storage| {
+            let entry = JournalEntry {
+                id: storage.get_next_journal_entry_id(),
+                posted_at: now,
+                description: entry.description,
+                reference: entry.reference,
+                lines: entry.lines,
+            };
+            storage.insert_journal_entry(entry.clone());
+            entry
+        })
+    }
+
+    fn validate(lines: &[JournalLine]) -> StudiFiResult<()> {
+        if lines.is_empty() {
+            return Err(StudiFiError::InvalidInput("Journal entry has no lines".to_string()));
+        }
+        if lines.iter().any(|line| line.debit > 0 && line.credit > 0) {
+            return Err(StudiFiError::InvalidInput(
+                "Each journal line must be either a debit or a credit".to_string()
+            ));
+        }
+        let debits: Amount = lines.iter().map(|line| line.debit).sum();
+        let credits: Amount = lines.iter().map(|line| line.credit).sum();
+        if debits != credits {
+            return Err(StudiFiError::InternalError(format!(
+                "Unbalanced journal entry: debits {} != credits {}",
+                format_currency(debits),
+                format_currency(credits)
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn balance(account: &LedgerAccount) -> i128 {
+        with_storage(|storage| storage.get_account_balance(account)).balance()
+    }
+
+    /// Normal-side balance floored at zero, for figures reported as amounts
+    fn amount(account: LedgerAccount) -> Amount {
+        Self::balance(&account).max(0) as Amount
+    }
+
+    /// A treasury's available and reserved funds. Reserved funds include the
+    /// loan book for the loan treasury and grants awarded for the scholarship treasury.
+    pub fn treasury_funds(treasury_type: &TreasuryType) -> (Amount, Amount) {
+        let available = Self::amount(LedgerAccount::TreasuryCash(treasury_type.clone()));
+        let mut reserved = Self::balance(&LedgerAccount::TreasuryReserved(treasury_type.clone()));
+        match treasury_type {
+            TreasuryType::Loan => {
+                reserved += Self::balance(&LedgerAccount::LoansReceivable)
+                    - Self::balance(&LedgerAccount::AllowanceForLosses);
+            }
+            TreasuryType::Scholarship => reserved += Self::balance(&LedgerAccount::ScholarshipGrants),
+            TreasuryType::Protocol => {}
+        }
+        (available, reserved.max(0) as Amount)
+    }
+
+    pub fn trial_balance(now: Timestamp) -> TrialBalance {
+        let accounts = with_storage(|storage| storage.get_all_account_balances());
+        let total_debits = accounts.iter().map(|account| account.total_debits).sum();
+        let total_credits = accounts.iter().map(|account| account.total_credits).sum();
+        TrialBalance {
+            accounts,
+            total_debits,
+            total_credits,
+            is_balanced: total_debits == total_credits,
+            generated_at: now,
+        }
+    }
+
+    /// Every posting to `account`, oldest first
+    pub fn account_history(account: &LedgerAccount) -> Vec<AccountPosting> {
+        let mut running = AccountBalance::new(account.clone());
+        with_storage(|storage| storage.get_journal_entries())
+            .into_iter()
+            .flat_map(|entry| {
+                entry.lines
+                    .iter()
+                    .filter(|line| line.account == *account)
+                    .map(|line| (entry.clone(), line.debit, line.credit))
+                    .collect::<Vec<_>>()
+            })
+            .map(|(entry, debit, credit)| {
+                running.total_debits += debit;
+                running.total_credits += credit;
+                AccountPosting {
+                    entry_id: entry.id,
+                    posted_at: entry.posted_at,
+                    description: entry.description,
+                    reference: entry.reference,
+                    debit,
+                    credit,
+                    balance: running.balance(),
+                }
+            })
+            .collect()
+    }
+
+    // ------------------------------------------------------------------------
+    // Postings for each kind of movement
+    // ------------------------------------------------------------------------
+
+    pub fn record_contribution(treasury_type: &TreasuryType, amount: Amount, source: &str, now: Timestamp) -> StudiFiResult<JournalEntry> {
+        Self::post(
+            format!("Funds added to {:?} treasury from {}", treasury_type, source),
+            None,
+            vec![
+                JournalLine::debit(LedgerAccount::TreasuryCash(treasury_type.clone()), amount),
+                JournalLine::credit(LedgerAccount::ContributedCapital, amount),
+            ],
+            now,
+        )
+    }
+
+    /// Scholarship allocations are grants and are expensed; other treasuries set the cash aside
+    pub fn record_allocation(treasury_type: &TreasuryType, amount: Amount, purpose: &str, reference: Option<String>, now: Timestamp) -> StudiFiResult<JournalEntry> {
+        let destination = match treasury_type {
+            TreasuryType::Scholarship => LedgerAccount::ScholarshipGrants,
+            _ => LedgerAccount::TreasuryReserved(treasury_type.clone()),
+        };
+        Self::post(
+            format!("{:?} treasury allocation: {}", treasury_type, purpose),
+            reference,
+            vec![
+                JournalLine::debit(destination, amount),
+                JournalLine::credit(LedgerAccount::TreasuryCash(treasury_type.clone()), amount),
+            ],
+            now,
+        )
+    }
+
+    pub fn record_release(treasury_type: &TreasuryType, amount: Amount, reference: Option<String>, now: Timestamp) -> StudiFiResult<JournalEntry> {
+        Self::post(
+            format!("{:?} treasury allocation released", treasury_type),
+            reference,
+            vec![
+                JournalLine::debit(LedgerAccount::TreasuryCash(treasury_type.clone()), amount),
+                JournalLine::credit(LedgerAccount::TreasuryReserved(treasury_type.clone()), amount),
+            ],
+            now,
+        )
+    }
+
+    pub fn record_transfer(from: &TreasuryType, to: &TreasuryType, amount: Amount, now: Timestamp) -> StudiFiResult<JournalEntry> {
+        Self::post(
+            format!("Transfer from {:?} to {:?} treasury", from, to),
+            None,
+            vec![
+                JournalLine::debit(LedgerAccount::TreasuryCash(to.clone()), amount),
+                JournalLine::credit(LedgerAccount::TreasuryCash(from.clone()), amount),
+            ],
+            now,
+        )
+    }
+
+    pub fn disbursement_entry(loan_id: &str, disbursement_id: &str, amount: Amount) -> StudiFiResult<PreparedEntry> {
+        Self::prepare(
+            format!("Loan {} tranche paid out", loan_id),
+            Some(disbursement_id.to_string()),
+            vec![
+                JournalLine::debit(LedgerAccount::LoansReceivable, amount),
+                JournalLine::credit(LedgerAccount::TreasuryReserved(TreasuryType::Loan), amount),
+            ],
+        )
+    }
+
+    pub fn network_fee_entry(reference: &str, amount: Amount) -> StudiFiResult<PreparedEntry> {
+        Self::prepare(
+            "Token ledger transfer fee",
+            Some(reference.to_string()),
+            vec![
+                JournalLine::debit(LedgerAccount::NetworkFees, amount),
+                JournalLine::credit(LedgerAccount::TreasuryCash(TreasuryType::Loan), amount),
+            ],
+        )
+    }
+
+    pub fn record_interest_accrual(loan_id: &str, amount: Amount, now: Timestamp) -> StudiFiResult<JournalEntry> {
+        Self::post(
+            format!("Interest accrued on loan {}", loan_id),
+            Some(loan_id.to_string()),
+            vec![
+                JournalLine::debit(LedgerAccount::InterestReceivable, amount),
+                JournalLine::credit(LedgerAccount::InterestIncome, amount),
+            ],
+            now,
+        )
+    }
+
+    /// Grace-period interest added to the loan's principal
+    pub fn record_interest_capitalization(loan_id: &str, amount: Amount, now: Timestamp) -> StudiFiResult<JournalEntry> {
+        Self::post(
+            format!("Interest capitalized on loan {}", loan_id),
+            Some(loan_id.to_string()),
+            vec![
+                JournalLine::debit(LedgerAccount::LoansReceivable, amount),
+                JournalLine::credit(LedgerAccount::InterestReceivable, amount),
+            ],
+            now,
+        )
+    }
+
+    pub fn record_fee_assessed(loan_id: &str, fee_id: &str, amount: Amount, now: Timestamp) -> StudiFiResult<JournalEntry> {
+        Self::post(
+            format!("Fee assessed on loan {}", loan_id),
+            Some(fee_id.to_string()),
+            vec![
+                JournalLine::debit(LedgerAccount::FeesReceivable, amount),
+                JournalLine::credit(LedgerAccount::FeeIncome, amount),
+            ],
+            now,
+        )
+    }
+
+    /// Cash received on a loan. The receivables of a loan that was in default
+    /// have been charged off, so what it collects is recorded as a recovery instead.
+    pub fn payment_entry(
+        loan_id: &str,
+        payment_id: &str,
+        charged_off: bool,
+        allocation: &PaymentAllocation,
+    ) -> StudiFiResult<PreparedEntry> {
+        let mut lines = vec![JournalLine::debit(LedgerAccount::TreasuryCash(TreasuryType::Loan), allocation.total())];
+        if charged_off {
+            lines.push(JournalLine::credit(
+                LedgerAccount::CreditLossExpense,
+                allocation.principal + allocation.interest + allocation.fees,
+            ));
+        } else {
+            lines.push(JournalLine::credit(LedgerAccount::LoansReceivable, allocation.principal));
+            lines.push(JournalLine::credit(LedgerAccount::InterestReceivable, allocation.interest));
+            lines.push(JournalLine::credit(LedgerAccount::FeesReceivable, allocation.fees));
+        }
+        lines.push(JournalLine::credit(LedgerAccount::FeeIncome, allocation.penalty));
+
+        Self::prepare(format!("Payment on loan {}", loan_id), Some(payment_id.to_string()), lines)
+    }
+
+    /// Provide for a defaulted loan's receivables in full and charge them off
+    pub fn record_default(loan: &Loan, fees: Amount, now: Timestamp) -> StudiFiResult<()> {
+        let total = loan.current_balance + loan.accrued_interest + fees;
+        Self::post(
+            format!("Loss provision for defaulted loan {}", loan.id),
+            Some(loan.id.clone()),
+            vec![
+                JournalLine::debit(LedgerAccount::CreditLossExpense, total),
+                JournalLine::credit(LedgerAccount::AllowanceForLosses, total),
+            ],
+            now,
+        )?;
+        Self::post(
+            format!("Charge-off of defaulted loan {}", loan.id),
+            Some(loan.id.clone()),
+            vec![
+                JournalLine::debit(LedgerAccount::AllowanceForLosses, total),
+                JournalLine::credit(LedgerAccount::LoansReceivable, loan.current_balance),
+                JournalLine::credit(LedgerAccount::InterestReceivable, loan.accrued_interest),
+                JournalLine::credit(LedgerAccount::FeesReceivable, fees),
+            ],
+            now,
+        )?;
+        Ok(())
+    }
+
+    /// Open the ledger from the balances held before it existed: treasury configs,
+    /// outstanding loans, accrued interest and unpaid fees. Does nothing once any
+    /// entry has been posted.
+    pub fn post_opening_balances(now: Timestamp) -> StudiFiResult<Option<JournalEntry>> {
+        if with_storage(|storage| storage.has_journal_entries()) {
+            return Ok(None);
+        }
+
+        let (treasuries, loans) = with_storage(|storage| {
+            (storage.get_all_separate_treasuries(), storage.get_all_loans())
+        });
+        let book: Vec<&Loan> = loans
+            .iter()
+            .filter(|loan| loan.status.is_in_repayment() && loan.status != LoanStatus::Default)
+            .collect();
+        let loans_receivable: Amount = book.iter().map(|loan| loan.current_balance).sum();
+        let interest_receivable: Amount = book.iter().map(|loan| loan.accrued_interest).sum();
+        let fees_receivable: Amount = book
+            .iter()
+            .map(|loan| with_storage(|storage| storage.get_outstanding_fees(&loan.id)))
+            .sum();
+
+        let mut lines = Vec::new();
+        let mut capital = 0;
+        for treasury in treasuries {
+            let mut reserved = treasury.reserved_funds;
+            if treasury.treasury_type == TreasuryType::Loan {
+                // The loan treasury's reservations include the principal now carried as receivables
+                reserved = reserved.saturating_sub(loans_receivable);
+            }
+            capital += treasury.available_funds + reserved;
+            lines.push(JournalLine::debit(LedgerAccount::TreasuryCash(treasury.treasury_type.clone()), treasury.available_funds));
+            lines.push(JournalLine::debit(LedgerAccount::TreasuryReserved(treasury.treasury_type.clone()), reserved));
+        }
+        lines.push(JournalLine::debit(LedgerAccount::LoansReceivable, loans_receivable));
+        lines.push(JournalLine::debit(LedgerAccount::InterestReceivable, interest_receivable));
+        lines.push(JournalLine::debit(LedgerAccount::FeesReceivable, fees_receivable));
+        lines.push(JournalLine::credit(LedgerAccount::ContributedCapital, capital + loans_receivable));
+        lines.push(JournalLine::credit(LedgerAccount::InterestIncome, interest_receivable));
+        lines.push(JournalLine::credit(LedgerAccount::FeeIncome, fees_receivable));
+
+        if lines.iter().all(|line| line.debit == 0 && line.credit == 0) {
+            return Ok(None);
+        }
+        Self::post("Opening balances", None, lines, now).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_balance_and_drive_treasury_funds() {
+        assert!(LedgerEngine::validate(&[
+            JournalLine::debit(LedgerAccount::LoansReceivable, 100),
+            JournalLine::credit(LedgerAccount::TreasuryCash(TreasuryType::Loan), 90),
+        ]).is_err());
+        assert!(LedgerEngine::validate(&[JournalLine { account: LedgerAccount::FeeIncome, debit: 5, credit: 5 }]).is_err());
+        assert!(LedgerEngine::post("Nothing", None, Vec::new(), 0).is_err());
+
+        let loan = TreasuryType::Loan;
+        LedgerEngine::record_contribution(&loan, 100_000, "test", 1).unwrap();
+        LedgerEngine::record_allocation(&loan, 40_000, "LOAN-1", None, 2).unwrap();
+        LedgerEngine::post_prepared(LedgerEngine::disbursement_entry("LOAN-1", "DSB-1", 30_000).unwrap(), 3);
+        LedgerEngine::record_release(&loan, 10_000, None, 4).unwrap();
+        LedgerEngine::record_interest_accrual("LOAN-1", 500, 5).unwrap();
+        assert_eq!(LedgerEngine::treasury_funds(&loan), (70_000, 30_000));
+
+        // Interest is added to the treasury when it is collected, not when it accrues
+        let allocation = PaymentAllocation { principal: 5_000, interest: 500, ..Default::default() };
+        let entry = LedgerEngine::post_prepared(LedgerEngine::payment_entry("LOAN-1", "PAY-1", false, &allocation).unwrap(), 6);
+        assert_eq!(entry.lines.len(), 3);
+        assert_eq!(LedgerEngine::treasury_funds(&loan), (75_500, 25_000));
+        assert_eq!(LedgerEngine::balance(&LedgerAccount::InterestIncome), 500);
+
+        LedgerEngine::record_allocation(&TreasuryType::Scholarship, 2_000, "grant", None, 7).unwrap();
+        assert_eq!(LedgerEngine::balance(&LedgerAccount::ScholarshipGrants), 2_000);
+        assert_eq!(LedgerEngine::balance(&LedgerAccount::TreasuryCash(TreasuryType::Scholarship)), -2_000);
+
+        let trial_balance = LedgerEngine::trial_balance(8);
+        assert!(trial_balance.is_balanced);
+        let history = LedgerEngine::account_history(&LedgerAccount::LoansReceivable);
+        assert_eq!(history.iter().map(|posting| posting.balance).collect::<Vec<_>>(), vec![30_000, 25_000]);
+
+        // Once entries exist the opening balances are not posted again
+        assert!(LedgerEngine::post_opening_balances(9).unwrap().is_none());
+    }
+}
@@ -2,6 +2,14 @@ mod types;
 mod storage;
 mod treasury;
 mod automation;
+mod amortization;
+mod disbursement;
+mod agreement;
+mod fees;
+mod waterfall;
+mod accrual;
+mod ledger;
+pub mod token;
 
 use candid::{candid_method, Principal};
 use ic_cdk::{query, update, init, pre_upgrade, post_upgrade, caller};
@@ -13,12 +21,24 @@ use types::*;
 use storage::*;
 use treasury::*;
 use automation::*;
+use amortization::*;
+use disbursement::*;
+use agreement::*;
+use fees::*;
+use waterfall::*;
+use accrual::*;
+use ledger::*;
+use token::*;
 
 // Global timer for automation
 static mut AUTOMATION_TIMER: Option<TimerId> = None;
 
 #[init]
-fn init() {
+fn init(args: Option<ServiceInitArgs>) {
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("Loan Management Service canister initialized");
 
     // Initialize treasury with default configuration
@@ -27,8 +47,11 @@ fn init() {
         storage.set_treasury_config(treasury_config);
     });
 
-    // Initialize separate treasuries
+    // Initialize separate treasuries and open the ledger with their funds
     let _ = TreasuryEngine::initialize_treasuries();
+    if let Err(e) = LedgerEngine::post_opening_balances(current_time()) {
+        ic_cdk::println!("Failed to post opening balances: {}", e);
+    }
 
     // Start automation timer (runs every hour)
     unsafe {
@@ -51,9 +74,27 @@ fn pre_upgrade() {
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<ServiceInitArgs>) {
+    // Keep the previously registered canisters unless a new registry is supplied
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("Loan Management Service canister upgraded successfully");
 
+    // Canisters upgraded from before the ledger existed open it from their stored balances
+    match LedgerEngine::post_opening_balances(current_time()) {
+        Ok(Some(entry)) => ic_cdk::println!("Posted opening balances as {}", entry.id),
+        Ok(None) => {}
+        Err(e) => ic_cdk::println!("Failed to post opening balances: {}", e),
+    }
+
+    match FeeEngine::migrate_legacy_late_fees(current_time()) {
+        Ok(0) => {}
+        Ok(migrated) => ic_cdk::println!("Moved {} late fees onto fee receivables", migrated),
+        Err(e) => ic_cdk::println!("Failed to migrate legacy late fees: {}", e),
+    }
+
     // Restart automation timer after upgrade
     unsafe {
         AUTOMATION_TIMER = Some(set_timer_interval(
@@ -73,8 +114,11 @@ fn post_upgrade() {
 // LOAN MANAGEMENT FUNCTIONS
 // ============================================================================
 
-/// Create a new loan from approved application
-#[update]
+/// Create a new loan from approved application. The principal is reserved in the
+/// loan treasury and paid out in a single tranche to the student unless the plan
+/// is changed before the agreement is signed. Requests carrying the same
+/// `application_id` create one loan: later ones return it unchanged.
+#[update(guard = "require_service_or_manage_system")]
 #[candid_method(update)]
 async fn create_loan(
     student_id: Principal,
@@ -86,7 +130,24 @@ async fn create_loan(
     collateral_required: bool,
     cosigner_id: Option<Principal>,
     special_conditions: Vec<String>,
+    application_id: Option<String>,
 ) -> StudiFiResult<Loan> {
+    // A retried request for an application returns the loan already created for it
+    if let Some(application_id) = &application_id {
+        if let Some(loan) = with_storage(|storage| storage.get_loan_for_application(application_id)) {
+            if loan.student_id != student_id {
+                return Err(StudiFiError::AlreadyExists(
+                    format!("Application {} already has a loan for another student", application_id)
+                ));
+            }
+            return Ok(loan);
+        }
+    }
+    let _application_guard = application_id
+        .as_deref()
+        .map(|application_id| InFlightGuard::acquire(&format!("application:{}", application_id)))
+        .transpose()?;
+
     // Validate inputs
     validate_amount(principal_amount)?;
     validate_percentage(interest_rate)?;
@@ -100,14 +161,30 @@ async fn create_loan(
     // Check treasury eligibility
     TreasuryEngine::check_loan_eligibility(principal_amount)?;
 
+    // Screen the cosigner against sanctions watchlists before attaching them
+    if let Some(cosigner) = cosigner_id {
+        ComplianceClient::screen_party(ScreeningRequest {
+            subject: cosigner,
+            role: ScreeningRole::Cosigner,
+            full_name: None,
+            date_of_birth: None,
+            country: None,
+            context: "create_loan".to_string(),
+        }).await?.into_clearance()?;
+
+        // Treasury balances may have moved during the call
+        TreasuryEngine::check_loan_eligibility(principal_amount)?;
+    }
+
     // Calculate loan terms
-    let monthly_payment = calculate_monthly_payment(principal_amount, interest_rate, term_months);
-    let origination_fee = (principal_amount as f64 * 0.01) as Amount; // 1% origination fee
+    let amortized_principal = capitalize_grace_interest(principal_amount, interest_rate, grace_period_months)?;
+    let monthly_payment = calculate_monthly_payment(amortized_principal, interest_rate, term_months)?;
+    let origination_fee = calculate_fee(principal_amount, ORIGINATION_FEE_BPS)?;
 
     // Generate loan ID and create loan
     let loan_id = with_storage_mut(|storage| storage.get_next_loan_id());
 
-    let loan = Loan::new(
+    let mut loan = Loan::new(
         loan_id.clone(),
         student_id,
         principal_amount,
@@ -121,15 +198,21 @@ async fn create_loan(
         cosigner_id,
         special_conditions,
     );
+    loan.application_id = application_id;
 
     // Allocate treasury funds
-    TreasuryEngine::allocate_loan_funds(principal_amount)?;
+    TreasuryEngine::allocate_loan_funds(&loan_id, principal_amount)?;
 
     // Store the loan
     with_storage_mut(|storage| {
         storage.insert_loan(loan_id.clone(), loan.clone());
     });
 
+    // Generate and persist the projected amortization schedule
+    AmortizationEngine::create_schedule(&loan)?;
+    DisbursementEngine::create_default_plan(&loan, loan.created_at);
+    AgreementEngine::generate(&loan, loan.created_at)?;
+
     ic_cdk::println!("Created loan {} for student {:?}", loan_id, student_id);
     Ok(loan)
 }
@@ -157,99 +240,369 @@ fn get_my_loans() -> Vec<Loan> {
 }
 
 /// Get all active loans (for admin/monitoring)
-#[query]
+#[query(guard = "require_view_all_data")]
 #[candid_method(query)]
 fn get_all_active_loans() -> Vec<Loan> {
     with_storage(|storage| storage.get_active_loans())
 }
 
+/// Get loans with their status and balance (for regulatory reporting), a page at
+/// a time in id order. Pass the page's `next_after` as `after_loan_id` to read on.
+#[query(guard = "require_service_or_view_all_data")]
+#[candid_method(query)]
+fn get_loan_tape(after_loan_id: Option<String>, limit: Option<u32>) -> LoanTapePage {
+    let now = current_time();
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT) as usize;
+    let loans = with_storage(|storage| storage.get_loans_after(after_loan_id.as_deref(), limit));
+    LoanTapePage {
+        next_after: loans.last().filter(|_| loans.len() == limit).map(|loan| loan.id.clone()),
+        records: loans.iter().map(|loan| loan.to_tape_record(now)).collect(),
+    }
+}
+
 /// Get overdue loans (for admin/monitoring)
-#[query]
+#[query(guard = "require_view_all_data")]
 #[candid_method(query)]
 fn get_overdue_loans() -> Vec<Loan> {
     with_storage(|storage| storage.get_overdue_loans())
 }
 
+// ============================================================================
+// DISBURSEMENTS
+// ============================================================================
+
+/// Split an unsigned loan's payout into tranches, e.g. one per academic term,
+/// paid to the student or directly to the university
+#[update]
+#[candid_method(update)]
+fn set_disbursement_plan(loan_id: String, tranches: Vec<TrancheRequest>) -> StudiFiResult<Vec<Disbursement>> {
+    require_borrower_or_operator(&loan_id)?;
+    DisbursementEngine::set_plan(&loan_id, tranches, current_time())
+}
+
+/// Sign the current agreement as the borrower or cosigner. `document_hash` must be
+/// the hash of the version the signer reviewed. Once every party has signed, the
+/// loan is released for disbursement.
+#[update]
+#[candid_method(update)]
+fn sign_agreement(loan_id: String, document_hash: String) -> StudiFiResult<LoanAgreement> {
+    let loan = with_storage(|storage| storage.get_loan(&loan_id))
+        .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
+
+    let (agreement, fully_signed) = AgreementEngine::sign(&loan, caller(), &document_hash, current_time())?;
+    if fully_signed {
+        DisbursementEngine::mark_signed(&loan_id)?;
+    }
+    Ok(agreement)
+}
+
+/// The agreement version currently offered or signed
+#[query]
+#[candid_method(query)]
+fn get_loan_agreement(loan_id: String) -> Option<LoanAgreement> {
+    with_storage(|storage| storage.get_current_agreement(&loan_id))
+}
+
+/// Every agreement version issued for a loan, oldest first
+#[query]
+#[candid_method(query)]
+fn get_loan_agreement_versions(loan_id: String) -> Vec<LoanAgreement> {
+    with_storage(|storage| storage.get_agreement_versions(&loan_id))
+}
+
+/// Pay out a due tranche now rather than waiting for the automation run
+#[update(guard = "require_service_or_manage_system")]
+#[candid_method(update)]
+async fn disburse_tranche(disbursement_id: String) -> StudiFiResult<Disbursement> {
+    DisbursementEngine::disburse(&disbursement_id, current_time()).await
+}
+
+/// Cancel a tranche that has not been paid out, returning its funds to the loan treasury
+#[update]
+#[candid_method(update)]
+fn cancel_disbursement(disbursement_id: String, reason: String) -> StudiFiResult<Disbursement> {
+    let disbursement = with_storage(|storage| storage.get_disbursement(&disbursement_id))
+        .ok_or_else(|| StudiFiError::NotFound("Disbursement not found".to_string()))?;
+    require_borrower_or_operator(&disbursement.loan_id)?;
+    DisbursementEngine::cancel(&disbursement_id, reason, current_time())
+}
+
+/// Cancel every tranche not yet paid out. A loan with nothing disbursed is cancelled.
+#[update]
+#[candid_method(update)]
+fn cancel_loan(loan_id: String, reason: String) -> StudiFiResult<Loan> {
+    require_borrower_or_operator(&loan_id)?;
+    DisbursementEngine::cancel_loan(&loan_id, reason, current_time())
+}
+
+/// A loan's tranches in payout order
+#[query]
+#[candid_method(query)]
+fn get_loan_disbursements(loan_id: String) -> Vec<Disbursement> {
+    with_storage(|storage| storage.get_loan_disbursements(&loan_id))
+}
+
+/// Scheduled tranches whose payout date has been reached
+#[query(guard = "require_service_or_view_all_data")]
+#[candid_method(query)]
+fn get_due_disbursements() -> Vec<Disbursement> {
+    with_storage(|storage| storage.get_due_disbursements(current_time()))
+}
+
+/// The loan's borrower, a StudiFi canister or a system manager
+fn require_borrower_or_operator(loan_id: &str) -> StudiFiResult<Loan> {
+    let loan = with_storage(|storage| storage.get_loan(loan_id))
+        .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
+    if loan.student_id != caller() && require_service_or_manage_system().is_err() {
+        return Err(StudiFiError::Unauthorized("Not authorized to manage this loan's disbursements".to_string()));
+    }
+    Ok(loan)
+}
+
 // ============================================================================
 // PAYMENT PROCESSING FUNCTIONS
 // ============================================================================
 
-/// Process a loan payment
+/// Process a loan payment in tokens pulled from the caller
 #[update]
 #[candid_method(update)]
 async fn process_payment(
     loan_id: String,
     payment_amount: Amount,
     payment_method: PaymentMethod,
+    session_id: Option<String>,
 ) -> StudiFiResult<Payment> {
     let caller = caller();
+    authorize_caller_session(session_id, Permission::MakePayment).await?;
 
     // Validate payment amount
     validate_amount(payment_amount)?;
+    let token_config = token_config_for(&payment_method)?;
+
+    receive_payment(loan_id, caller, payment_amount, payment_method, Settlement::Token(token_config)).await
+}
+
+/// Record a payment the borrower or cosigner made off-chain, e.g. by bank
+/// transfer, once it has been received. `external_reference` identifies it with
+/// the payment provider and can only be recorded once per loan.
+#[update(guard = "require_service_or_manage_system")]
+#[candid_method(update)]
+async fn record_external_payment(
+    loan_id: String,
+    payer: Principal,
+    payment_amount: Amount,
+    payment_method: PaymentMethod,
+    external_reference: String,
+) -> StudiFiResult<Payment> {
+    validate_amount(payment_amount)?;
+    if payment_method == PaymentMethod::ICP {
+        return Err(StudiFiError::InvalidInput("Token payments are collected through process_payment".to_string()));
+    }
+    let external_reference = sanitize_text(&external_reference);
+    if external_reference.is_empty() {
+        return Err(StudiFiError::InvalidInput("External reference is required".to_string()));
+    }
+
+    receive_payment(loan_id, payer, payment_amount, payment_method, Settlement::External(external_reference)).await
+}
+
+/// How the funds for a payment reach the loan treasury
+enum Settlement {
+    /// Pulled from the payer on the token ledger
+    Token(TokenLedgerConfig),
+    /// Already received off-chain under this reference
+    External(String),
+}
+
+/// A token payment on the loan whose collection has not been confirmed, such as one
+/// whose reply from the ledger was lost. It must be retried, with the same amount,
+/// before anything else is collected on the loan.
+fn pending_collection(loan_id: &str, payer: Principal, amount: Amount) -> StudiFiResult<Option<Payment>> {
+    let pending = with_storage(|storage| storage.get_payments_by_loan(loan_id))
+        .into_iter()
+        .find(|payment| payment.status == PaymentStatus::Processing && payment.collection_attempted_at.is_some());
+    match pending {
+        Some(pending) if pending.student_id != payer || pending.amount != amount => Err(StudiFiError::InvalidInput(format!(
+            "Payment {} of {} is awaiting confirmation from the token ledger and must be retried first",
+            pending.id,
+            format_currency(pending.amount)
+        ))),
+        pending => Ok(pending),
+    }
+}
+
+/// Collect a payment on the token ledger. The payment is stored as `Processing`
+/// with its first attempt's time before the transfer is sent, so a retry after a
+/// lost reply sends the identical transfer. A transfer the ledger refuses fails
+/// the payment and the next attempt starts afresh.
+async fn collect_tokens(config: &TokenLedgerConfig, payment: &mut Payment, now: Timestamp) -> StudiFiResult<TokenTransfer> {
+    let attempted_at = *payment.collection_attempted_at.get_or_insert(now);
+    payment.status = PaymentStatus::Processing;
+    with_storage_mut(|storage| storage.insert_payment(payment.id.clone(), payment.clone()));
+
+    let result = TokenEngine::collect_repayment(
+        config, &payment.loan_id, &payment.id, payment.student_id, payment.amount, attempted_at, now
+    ).await;
+    if let Err(StudiFiError::InvalidInput(reason)) = &result {
+        payment.status = PaymentStatus::Failed;
+        payment.notes = reason.clone();
+        with_storage_mut(|storage| storage.insert_payment(payment.id.clone(), payment.clone()));
+    }
+    result
+}
+
+/// Apply a payment from `payer` to a loan, collecting it first when it settles in tokens
+async fn receive_payment(
+    loan_id: String,
+    payer: Principal,
+    payment_amount: Amount,
+    payment_method: PaymentMethod,
+    settlement: Settlement,
+) -> StudiFiResult<Payment> {
+    // Nothing else may change the loan while its tokens are in transit
+    let _guard = InFlightGuard::acquire(&loan_id)?;
 
     // Get the loan
     let mut loan = with_storage(|storage| storage.get_loan(&loan_id))
         .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
 
-    // Verify caller is the loan holder or cosigner
-    if loan.student_id != caller && loan.cosigner_id != Some(caller) {
+    // Verify the payer is the loan holder or cosigner
+    if loan.student_id != payer && loan.cosigner_id != Some(payer) {
         return Err(StudiFiError::Unauthorized("Not authorized to make payments on this loan".to_string()));
     }
+    if let Settlement::External(reference) = &settlement {
+        let recorded = with_storage(|storage| storage.get_payments_by_loan(&loan_id))
+            .iter()
+            .any(|payment| payment.transaction_hash.as_ref() == Some(reference));
+        if recorded {
+            return Err(StudiFiError::InvalidInput("Payment has already been recorded".to_string()));
+        }
+    }
 
     // Check if loan is in a payable state
-    if loan.status == LoanStatus::PaidOff || loan.status == LoanStatus::Cancelled {
+    if !loan.status.is_in_repayment() {
         return Err(StudiFiError::InvalidInput("Loan is not in a payable state".to_string()));
     }
 
-    // Calculate payment breakdown
-    let breakdown = AutomationEngine::calculate_payment_breakdown(&loan, payment_amount);
+    // Bring interest up to date, then allocate the payment across fees, interest and principal
+    let now = current_time();
+    AccrualEngine::accrue_loan(&mut loan, now)?;
+    // Keep the accrual even if the payment is rejected, since it is already in the ledger
+    with_storage_mut(|storage| storage.update_loan(loan_id.clone(), loan.clone()))?;
+    let breakdown = PaymentWaterfall::breakdown(&loan, payment_amount, now)?;
+    if breakdown.unapplied_amount > 0 {
+        return Err(StudiFiError::InvalidInput(format!(
+            "Payment exceeds the amount owed by {}",
+            format_currency(breakdown.unapplied_amount)
+        )));
+    }
+    let borrower = loan.student_id;
+    let late_payments_before = loan.late_payments;
+
+    // Work out everything the payment changes before collecting it, so that
+    // booking it once the tokens have moved cannot fail
+    let pending = match &settlement {
+        Settlement::Token(_) => pending_collection(&loan_id, payer, payment_amount)?,
+        Settlement::External(_) => None,
+    };
+    let payment_id = match &pending {
+        Some(pending) => pending.id.clone(),
+        None => with_storage_mut(|storage| storage.get_next_payment_id()),
+    };
+    let ledger_entry = TreasuryEngine::prepare_payment_to_treasury(&loan, &payment_id, &PaymentAllocation {
+        principal: breakdown.total_principal(),
+        interest: breakdown.interest_portion,
+        fees: breakdown.fee_portion,
+        penalty: 0,
+    })?;
+
+    // Update loan balance
+    loan.current_balance = breakdown.remaining_balance;
+    loan.accrued_interest = breakdown.remaining_interest;
+    loan.set_updated_at(now);
+
+    // Fees sit outside the schedule; the rest settles installments in due-date order
+    let mut schedule = AmortizationEngine::schedule_after_payment(
+        &loan,
+        breakdown.scheduled_portion(),
+        breakdown.prepayment_portion,
+        now,
+    )?;
+
+    // Update loan status if paid off
+    if loan.total_owed() == 0 && breakdown.remaining_fees == 0 {
+        loan.status = LoanStatus::PaidOff;
+        schedule.settle_remaining(now);
+    }
+
+    // Only settled installments move the next due date
+    loan.payments_made = schedule.settled_count();
+    loan.late_payments = schedule.late_count();
+    loan.monthly_payment = schedule.monthly_payment;
+
+    let payment_type = if breakdown.prepayment_portion > 0 {
+        PaymentType::EarlyPayment
+    } else if breakdown.is_partial() {
+        PaymentType::PartialPayment
+    } else if loan.late_payments > late_payments_before {
+        PaymentType::LatePayment
+    } else {
+        PaymentType::Regular
+    };
 
     // Create payment record
-    let payment_id = with_storage_mut(|storage| storage.get_next_payment_id());
     let mut payment = Payment::new(
         payment_id.clone(),
         loan_id.clone(),
-        caller,
+        payer,
         payment_amount,
-        breakdown.principal_portion,
+        breakdown.total_principal(),
         breakdown.interest_portion,
-        0, // Late fees handled separately
-        PaymentType::Regular,
+        breakdown.fee_portion,
+        payment_type,
         payment_method,
     );
-
-    // Update loan balance
-    loan.current_balance = breakdown.remaining_balance;
-    loan.payments_made += 1;
-    loan.set_updated_at(current_time());
-
-    // Update loan status if paid off
-    if loan.current_balance == 0 {
-        loan.status = LoanStatus::PaidOff;
+    if let Some(pending) = pending {
+        payment.created_at = pending.created_at;
+        payment.collection_attempted_at = pending.collection_attempted_at;
     }
 
-    // Process payment to treasury
-    TreasuryEngine::process_payment_to_treasury(
-        breakdown.principal_portion,
-        breakdown.interest_portion,
-        0,
-    )?;
+    // Collect the tokens, or note where the funds were received
+    payment.transaction_hash = Some(match settlement {
+        Settlement::Token(config) => collect_tokens(&config, &mut payment, now).await?.block_index.to_string(),
+        Settlement::External(reference) => reference,
+    });
 
     // Mark payment as completed
     payment.status = PaymentStatus::Completed;
-    payment.set_updated_at(current_time());
+    payment.set_updated_at(now);
 
     // Store updates
+    TreasuryEngine::process_payment_to_treasury(ledger_entry, now);
     with_storage_mut(|storage| {
+        storage.insert_schedule(loan_id.clone(), schedule);
         let _ = storage.update_loan(loan_id.clone(), loan);
         storage.insert_payment(payment_id.clone(), payment.clone());
     });
+    FeeEngine::apply_payment(&loan_id, breakdown.fee_portion, now);
+
+    AutomationEngine::report_aml_transaction(AmlTransactionReport {
+        reference_id: payment_id.clone(),
+        transaction_type: AmlTransactionType::LoanPayment,
+        amount: payment_amount,
+        party: payer,
+        counterparty: Some(borrower).filter(|borrower| *borrower != payer),
+        loan_id: Some(loan_id.clone()),
+        occurred_at: payment.created_at,
+    }).await;
 
     ic_cdk::println!(
-        "Processed payment {} for loan {}: principal={}, interest={}",
+        "Processed payment {} for loan {}: fees={}, interest={}, principal={}, prepayment={}",
         payment_id, loan_id,
+        format_currency(breakdown.fee_portion),
+        format_currency(breakdown.interest_portion),
         format_currency(breakdown.principal_portion),
-        format_currency(breakdown.interest_portion)
+        format_currency(breakdown.prepayment_portion)
     );
 
     Ok(payment)
@@ -258,11 +611,14 @@ async fn process_payment(
 /// Make early payoff of entire loan
 #[update]
 #[candid_method(update)]
-async fn make_early_payoff(loan_id: String, payment_method: PaymentMethod) -> StudiFiResult<Payment> {
+async fn make_early_payoff(loan_id: String, payment_method: PaymentMethod, session_id: Option<String>) -> StudiFiResult<Payment> {
     let caller = caller();
+    authorize_caller_session(session_id, Permission::MakePayment).await?;
+    let token_config = token_config_for(&payment_method)?;
+    let _guard = InFlightGuard::acquire(&loan_id)?;
 
     // Get the loan
-    let loan = with_storage(|storage| storage.get_loan(&loan_id))
+    let mut loan = with_storage(|storage| storage.get_loan(&loan_id))
         .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
 
     // Verify caller is the loan holder or cosigner
@@ -270,49 +626,87 @@ async fn make_early_payoff(loan_id: String, payment_method: PaymentMethod) -> St
         return Err(StudiFiError::Unauthorized("Not authorized to make payments on this loan".to_string()));
     }
 
+    if !loan.status.is_in_repayment() {
+        return Err(StudiFiError::InvalidInput("Loan is not in a payable state".to_string()));
+    }
+    AccrualEngine::accrue_loan(&mut loan, current_time())?;
+    with_storage_mut(|storage| storage.update_loan(loan_id.clone(), loan.clone()))?;
+
     // Get early payoff information
-    let payoff_info = AutomationEngine::check_early_payoff_eligibility(&loan);
+    let payoff_info = AutomationEngine::check_early_payoff_eligibility(&loan)?;
 
     if !payoff_info.is_eligible {
         return Err(StudiFiError::InvalidInput("Loan is not eligible for early payoff".to_string()));
     }
 
-    // Process the payoff payment
-    let payment_id = with_storage_mut(|storage| storage.get_next_payment_id());
+    // Work out everything the payoff changes before collecting it
+    let now = current_time();
+    let pending = pending_collection(&loan_id, caller, payoff_info.total_payoff_amount)?;
+    let payment_id = match &pending {
+        Some(pending) => pending.id.clone(),
+        None => with_storage_mut(|storage| storage.get_next_payment_id()),
+    };
+    let ledger_entry = TreasuryEngine::prepare_payment_to_treasury(&loan, &payment_id, &PaymentAllocation {
+        principal: payoff_info.remaining_balance,
+        interest: payoff_info.accrued_interest,
+        fees: payoff_info.outstanding_fees,
+        penalty: payoff_info.prepayment_penalty,
+    })?;
+
+    // Close out the remaining installments
+    let schedule = AmortizationEngine::settled_schedule(&loan_id, now)?;
+
+    // Update loan
+    let mut updated_loan = loan.clone();
+    updated_loan.current_balance = 0;
+    updated_loan.accrued_interest = 0;
+    updated_loan.status = LoanStatus::PaidOff;
+    updated_loan.payments_made = schedule.settled_count();
+    updated_loan.late_payments = schedule.late_count();
+    updated_loan.set_updated_at(now);
+
     let mut payment = Payment::new(
         payment_id.clone(),
         loan_id.clone(),
         caller,
         payoff_info.total_payoff_amount,
         payoff_info.remaining_balance,
-        0, // No interest for payoff
-        payoff_info.prepayment_penalty,
+        payoff_info.accrued_interest,
+        payoff_info.outstanding_fees + payoff_info.prepayment_penalty,
         PaymentType::FullPayoff,
         payment_method,
     );
+    if let Some(pending) = pending {
+        payment.created_at = pending.created_at;
+        payment.collection_attempted_at = pending.collection_attempted_at;
+    }
 
-    // Update loan
-    let mut updated_loan = loan.clone();
-    updated_loan.current_balance = 0;
-    updated_loan.status = LoanStatus::PaidOff;
-    updated_loan.set_updated_at(current_time());
-
-    // Process payment to treasury
-    TreasuryEngine::process_payment_to_treasury(
-        payoff_info.remaining_balance,
-        0,
-        payoff_info.prepayment_penalty,
-    )?;
+    // Collect the tokens
+    let transfer = collect_tokens(&token_config, &mut payment, now).await?;
+    payment.transaction_hash = Some(transfer.block_index.to_string());
 
     // Mark payment as completed
     payment.status = PaymentStatus::Completed;
-    payment.set_updated_at(current_time());
+    payment.set_updated_at(now);
 
     // Store updates
+    TreasuryEngine::process_payment_to_treasury(ledger_entry, now);
     with_storage_mut(|storage| {
+        storage.insert_schedule(loan_id.clone(), schedule);
         let _ = storage.update_loan(loan_id.clone(), updated_loan);
         storage.insert_payment(payment_id.clone(), payment.clone());
     });
+    FeeEngine::apply_payment(&loan_id, payoff_info.outstanding_fees, now);
+
+    AutomationEngine::report_aml_transaction(AmlTransactionReport {
+        reference_id: payment_id.clone(),
+        transaction_type: AmlTransactionType::EarlyPayoff,
+        amount: payoff_info.total_payoff_amount,
+        party: caller,
+        counterparty: Some(loan.student_id).filter(|borrower| *borrower != caller),
+        loan_id: Some(loan_id.clone()),
+        occurred_at: payment.created_at,
+    }).await;
 
     ic_cdk::println!(
         "Processed early payoff {} for loan {}: amount={}",
@@ -322,6 +716,58 @@ async fn make_early_payoff(loan_id: String, payment_method: PaymentMethod) -> St
     Ok(payment)
 }
 
+/// Token ledger to collect a payment through. Borrowers can only pay in tokens;
+/// payments made off-chain are recorded with `record_external_payment` once received.
+fn token_config_for(payment_method: &PaymentMethod) -> StudiFiResult<TokenLedgerConfig> {
+    if *payment_method != PaymentMethod::ICP {
+        return Err(StudiFiError::InvalidInput(format!(
+            "{:?} payments are recorded by StudiFi once received",
+            payment_method
+        )));
+    }
+    TokenEngine::active_config()
+        .ok_or_else(|| StudiFiError::InvalidInput("Token payments are not enabled".to_string()))
+}
+
+/// Choose what happens to payments beyond the installments currently due
+#[update]
+#[candid_method(update)]
+async fn set_overpayment_preference(
+    loan_id: String,
+    preference: OverpaymentPreference,
+    session_id: Option<String>,
+) -> StudiFiResult<Loan> {
+    let caller = caller();
+    authorize_caller_session(session_id, Permission::MakePayment).await?;
+
+    let mut loan = with_storage(|storage| storage.get_loan(&loan_id))
+        .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
+    if loan.student_id != caller {
+        return Err(StudiFiError::Unauthorized("Only the borrower can change the overpayment preference".to_string()));
+    }
+
+    loan.overpayment_preference = preference;
+    with_storage_mut(|storage| storage.update_loan(loan_id, loan))
+}
+
+// ============================================================================
+// AMORTIZATION SCHEDULE FUNCTIONS
+// ============================================================================
+
+/// Get the full amortization schedule for a loan
+#[query]
+#[candid_method(query)]
+fn get_amortization_schedule(loan_id: String) -> StudiFiResult<AmortizationSchedule> {
+    AmortizationEngine::get_schedule(&loan_id)
+}
+
+/// Get a student's open installments across all loans, soonest first
+#[query]
+#[candid_method(query)]
+fn get_upcoming_installments(student_id: Principal, pagination: Option<PaginationParams>) -> PaginatedResponse<Installment> {
+    AmortizationEngine::get_upcoming_installments(&student_id, pagination.unwrap_or_default())
+}
+
 /// Get payment by ID
 #[query]
 #[candid_method(query)]
@@ -353,17 +799,71 @@ fn calculate_payment_breakdown(loan_id: String, payment_amount: Amount) -> Studi
 
     validate_amount(payment_amount)?;
 
-    Ok(AutomationEngine::calculate_payment_breakdown(&loan, payment_amount))
+    let mut loan = loan;
+    let now = current_time();
+    AccrualEngine::accrue(&mut loan, now)?;
+    PaymentWaterfall::breakdown(&loan, payment_amount, now)
 }
 
 /// Get early payoff information for a loan
 #[query]
 #[candid_method(query)]
 fn get_early_payoff_info(loan_id: String) -> StudiFiResult<EarlyPayoffInfo> {
-    let loan = with_storage(|storage| storage.get_loan(&loan_id))
+    let mut loan = with_storage(|storage| storage.get_loan(&loan_id))
+        .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
+
+    AccrualEngine::accrue(&mut loan, current_time())?;
+    AutomationEngine::check_early_payoff_eligibility(&loan)
+}
+
+/// Daily interest accrued on a loan, oldest first
+#[query]
+#[candid_method(query)]
+fn get_interest_accruals(loan_id: String) -> Vec<InterestAccrual> {
+    with_storage(|storage| storage.get_loan_accruals(&loan_id))
+}
+
+/// Set how interest accrues on a loan; only before the agreement is signed
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+fn set_day_count_convention(loan_id: String, convention: DayCountConvention) -> StudiFiResult<LoanAgreement> {
+    let mut loan = with_storage(|storage| storage.get_loan(&loan_id))
         .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
+    if loan.status != LoanStatus::PendingSignature {
+        return Err(StudiFiError::InvalidInput("Terms can only be changed before the loan is signed".to_string()));
+    }
 
-    Ok(AutomationEngine::check_early_payoff_eligibility(&loan))
+    loan.day_count_convention = convention;
+    with_storage_mut(|storage| storage.update_loan(loan_id, loan.clone()))?;
+    AgreementEngine::generate(&loan, current_time())
+}
+
+/// Fees assessed against a loan, oldest first
+#[query]
+#[candid_method(query)]
+fn get_loan_fees(loan_id: String) -> Vec<FeeReceivable> {
+    with_storage(|storage| storage.get_loan_fees(&loan_id))
+}
+
+/// Allocation order applied to a loan product's payments
+#[query]
+#[candid_method(query)]
+fn get_payment_waterfall(product: String) -> Vec<AllocationBucket> {
+    PaymentWaterfall::order_for(&product)
+}
+
+/// Products with their own allocation order
+#[query]
+#[candid_method(query)]
+fn get_payment_waterfall_configs() -> Vec<PaymentWaterfallConfig> {
+    with_storage(|storage| storage.get_all_waterfall_configs())
+}
+
+/// Set the allocation order for a loan product, or for "default"
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+fn set_payment_waterfall(product: String, order: Vec<AllocationBucket>) -> StudiFiResult<PaymentWaterfallConfig> {
+    PaymentWaterfall::set_order(product, order, caller(), current_time())
 }
 
 // ============================================================================
@@ -377,21 +877,18 @@ fn get_treasury_health() -> TreasuryHealth {
     TreasuryEngine::get_treasury_health()
 }
 
-/// Get treasury configuration
+/// Get treasury configuration, with funds taken from the general ledger
 #[query]
 #[candid_method(query)]
 fn get_treasury_config() -> TreasuryConfig {
-    with_storage(|storage| storage.get_treasury_config())
+    TreasuryEngine::get_platform_treasury_config()
 }
 
-/// Update treasury configuration (admin only)
-#[update]
+/// Update treasury policy (admin only); fund fields are derived from the ledger and ignored
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 fn update_treasury_config(config: TreasuryConfig) -> StudiFiResult<()> {
-    // TODO: Add admin authorization check
-    with_storage_mut(|storage| {
-        storage.set_treasury_config(config);
-    });
+    TreasuryEngine::update_platform_treasury_config(config);
     Ok(())
 }
 
@@ -410,39 +907,44 @@ fn get_separate_treasury_config(treasury_type: TreasuryType) -> StudiFiResult<Se
 #[query]
 #[candid_method(query)]
 fn get_all_separate_treasuries() -> Vec<SeparateTreasuryConfig> {
-    with_storage(|storage| storage.get_all_separate_treasuries())
+    TreasuryEngine::get_all_treasury_configs()
 }
 
 /// Add funds to a specific treasury (governance approved)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
-fn add_treasury_funds(treasury_type: TreasuryType, amount: Amount, source: String) -> StudiFiResult<()> {
-    // TODO: Add governance authorization check
-    TreasuryEngine::add_treasury_funds(treasury_type, amount, source)
+async fn add_treasury_funds(treasury_type: TreasuryType, amount: Amount, source: String) -> StudiFiResult<()> {
+    let entry = TreasuryEngine::add_treasury_funds(treasury_type, amount, source)?;
+    report_treasury_movement(&entry, amount).await;
+    Ok(())
 }
 
 /// Allocate funds from a specific treasury (with governance check)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
-fn allocate_treasury_funds(
+async fn allocate_treasury_funds(
     treasury_type: TreasuryType,
     amount: Amount,
     purpose: String,
     governance_approved: bool,
 ) -> StudiFiResult<()> {
-    TreasuryEngine::allocate_treasury_funds(treasury_type, amount, purpose, governance_approved)
+    let entry = TreasuryEngine::allocate_treasury_funds(treasury_type, amount, purpose, governance_approved)?;
+    report_treasury_movement(&entry, amount).await;
+    Ok(())
 }
 
 /// Transfer funds between treasuries (requires governance approval)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
-fn transfer_between_treasuries(
+async fn transfer_between_treasuries(
     from_treasury: TreasuryType,
     to_treasury: TreasuryType,
     amount: Amount,
     governance_approved: bool,
 ) -> StudiFiResult<()> {
-    TreasuryEngine::transfer_between_treasuries(from_treasury, to_treasury, amount, governance_approved)
+    let entry = TreasuryEngine::transfer_between_treasuries(from_treasury, to_treasury, amount, governance_approved)?;
+    report_treasury_movement(&entry, amount).await;
+    Ok(())
 }
 
 /// Get multi-treasury health overview
@@ -460,21 +962,124 @@ fn get_treasury_health_for_type(treasury_type: TreasuryType) -> StudiFiResult<Tr
 }
 
 /// Legacy treasury functions (for backward compatibility)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
-fn add_legacy_treasury_funds(amount: Amount, source: String) -> StudiFiResult<()> {
+async fn add_legacy_treasury_funds(amount: Amount, source: String) -> StudiFiResult<()> {
     // Default to loan treasury for backward compatibility
-    TreasuryEngine::add_treasury_funds(TreasuryType::Loan, amount, source)
+    let entry = TreasuryEngine::add_treasury_funds(TreasuryType::Loan, amount, source)?;
+    report_treasury_movement(&entry, amount).await;
+    Ok(())
+}
+
+/// Report a treasury movement made by the caller for AML monitoring. The journal
+/// entry id stays unique when several movements land in the same round.
+async fn report_treasury_movement(entry: &JournalEntry, amount: Amount) {
+    AutomationEngine::report_aml_transaction(AmlTransactionReport {
+        reference_id: entry.id.clone(),
+        transaction_type: AmlTransactionType::TreasuryMovement,
+        amount,
+        party: caller(),
+        counterparty: None,
+        loan_id: None,
+        occurred_at: entry.posted_at,
+    }).await;
 }
 
 /// Manually trigger treasury rebalancing
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 fn rebalance_treasury() -> StudiFiResult<()> {
     // TODO: Implement cross-treasury rebalancing logic
     Ok(())
 }
 
+// ============================================================================
+// GENERAL LEDGER FUNCTIONS
+// ============================================================================
+
+/// Debit and credit totals for every ledger account
+#[query(guard = "require_service_or_view_all_data")]
+#[candid_method(query)]
+fn get_trial_balance() -> TrialBalance {
+    LedgerEngine::trial_balance(current_time())
+}
+
+/// Every posting to an account, oldest first, with its running balance
+#[query(guard = "require_service_or_view_all_data")]
+#[candid_method(query)]
+fn get_account_history(account: LedgerAccount) -> Vec<AccountPosting> {
+    LedgerEngine::account_history(&account)
+}
+
+/// Journal entries in posting order, starting at `offset`
+#[query(guard = "require_service_or_view_all_data")]
+#[candid_method(query)]
+fn get_journal_entries(offset: u64, limit: u64) -> Vec<JournalEntry> {
+    with_storage(|storage| storage.get_journal_entries())
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .collect()
+}
+
+// ============================================================================
+// TOKEN LEDGER FUNCTIONS
+// ============================================================================
+
+/// The ICRC ledger used for payouts and repayments, if one is configured
+#[query]
+#[candid_method(query)]
+fn get_token_ledger_config() -> Option<TokenLedgerConfig> {
+    with_storage(|storage| storage.get_token_ledger_config())
+}
+
+/// Point the service at an ICRC ledger, or switch token transfers off
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+fn set_token_ledger_config(
+    ledger_canister_id: Principal,
+    token_symbol: String,
+    units_per_cent: u64,
+    transfer_fee: u64,
+    is_active: bool,
+) -> StudiFiResult<TokenLedgerConfig> {
+    TokenEngine::set_config(ledger_canister_id, token_symbol, units_per_cent, transfer_fee, is_active, caller())
+}
+
+/// Ledger account a loan's token repayments are collected in
+#[query]
+#[candid_method(query)]
+fn get_loan_token_account(loan_id: String) -> Account {
+    TokenEngine::loan_account(&loan_id)
+}
+
+/// Token transfers made for a loan, in ledger order
+#[query]
+#[candid_method(query)]
+fn get_loan_token_transfers(loan_id: String) -> StudiFiResult<Vec<TokenTransfer>> {
+    require_borrower_or_operator(&loan_id)?;
+    Ok(with_storage(|storage| storage.get_loan_token_transfers(&loan_id)))
+}
+
+/// Move a loan's collected repayments to the treasury account
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+async fn sweep_loan_repayments(loan_id: String) -> StudiFiResult<Option<TokenTransfer>> {
+    let config = TokenEngine::active_config()
+        .ok_or_else(|| StudiFiError::InvalidInput("Token ledger is not configured".to_string()))?;
+    let _guard = InFlightGuard::acquire(&loan_id)?;
+    TokenEngine::sweep(&config, &loan_id, current_time()).await
+}
+
+/// Compare the loan treasury's books with balances on the token ledger
+#[update(guard = "require_service_or_view_all_data")]
+#[candid_method(update)]
+async fn reconcile_token_balances() -> StudiFiResult<ReconciliationReport> {
+    let config = with_storage(|storage| storage.get_token_ledger_config())
+        .ok_or_else(|| StudiFiError::InvalidInput("Token ledger is not configured".to_string()))?;
+    TokenEngine::reconcile(&config, current_time()).await
+}
+
 // ============================================================================
 // STATISTICS AND REPORTING FUNCTIONS
 // ============================================================================
@@ -521,7 +1126,7 @@ fn get_student_loan_stats(student_id: Principal) -> StudentLoanStats {
     ).count() as u32;
 
     let on_time_payments = payments.iter()
-        .filter(|payment| payment.payment_type == PaymentType::Regular)
+        .filter(|payment| payment.payment_type == PaymentType::Regular && payment.status == PaymentStatus::Completed)
         .count() as u32;
 
     let late_payments = loans.iter().map(|loan| loan.late_payments).sum();
@@ -551,19 +1156,16 @@ fn get_my_loan_stats() -> StudentLoanStats {
 // ============================================================================
 
 /// Manually trigger automation tasks (admin only)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 async fn run_automation_tasks() -> StudiFiResult<()> {
-    // TODO: Add admin authorization check
     AutomationEngine::run_scheduled_tasks().await
 }
 
 /// Update loan status manually (admin only)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 fn update_loan_status(loan_id: String, new_status: LoanStatus) -> StudiFiResult<Loan> {
-    // TODO: Add admin authorization check
-
     let mut loan = with_storage(|storage| storage.get_loan(&loan_id))
         .ok_or_else(|| StudiFiError::NotFound("Loan not found".to_string()))?;
 
@@ -575,6 +1177,31 @@ fn update_loan_status(loan_id: String, new_status: LoanStatus) -> StudiFiResult<
     })
 }
 
+// ============================================================================
+// DATA SUBJECT REQUESTS
+// ============================================================================
+
+/// Number of loans a principal still owes or cosigns
+#[query(guard = "require_compliance_service")]
+#[candid_method(query)]
+fn count_outstanding_loans(subject: Principal) -> u32 {
+    with_storage(|storage| storage.count_outstanding_loans(&subject))
+}
+
+/// Everything held about a principal, as JSON
+#[query(guard = "require_compliance_service")]
+#[candid_method(query)]
+fn export_subject_data(subject: Principal) -> StudiFiResult<String> {
+    to_export_json(&with_storage(|storage| storage.export_subject_data(&subject)))
+}
+
+/// Pseudonymize a principal's closed loans, keeping outstanding ones for servicing
+#[update(guard = "require_compliance_service")]
+#[candid_method(update)]
+fn erase_subject_data(request: DataErasureRequest) -> StudiFiResult<ErasureReceipt> {
+    Ok(with_storage_mut(|storage| storage.erase_subject_data(&request)))
+}
+
 // ============================================================================
 // HELPER FUNCTIONS
 // ============================================================================
@@ -594,7 +1221,7 @@ fn calculate_credit_impact(loans: &[Loan], payments: &[Payment]) -> i32 {
 
     // Positive impact for on-time payments
     impact += payments.iter()
-        .filter(|payment| payment.payment_type == PaymentType::Regular)
+        .filter(|payment| payment.payment_type == PaymentType::Regular && payment.status == PaymentStatus::Completed)
         .count() as i32 * 2;
 
     impact
@@ -613,6 +1240,27 @@ pub struct StudentLoanStats {
     pub credit_score_impact: i32,
 }
 
+/// Re-sync the cached role assignments used by authorization guards
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+async fn refresh_role_cache() -> StudiFiResult<u32> {
+    sync_role_cache().await
+}
+
+/// Get the canister registry used for inter-canister calls
+#[query]
+#[candid_method(query)]
+fn get_canister_registry() -> CanisterRegistry {
+    current_canister_registry()
+}
+
+/// Get the health of downstream canisters this canister has called since its last upgrade
+#[query]
+#[candid_method(query)]
+fn get_circuit_breakers() -> Vec<CircuitBreaker> {
+    circuit_breaker_states()
+}
+
 // ============================================================================
 // CANDID EXPORT
 // ============================================================================
@@ -1,75 +1,113 @@
 use candid::Principal;
 use ic_stable_structures::{
-    DefaultMemoryImpl, RestrictedMemory, StableBTreeMap, Storable,
-    storable::Bound,
+    memory_manager::{MemoryId, VirtualMemory},
+    DefaultMemoryImpl,
 };
-use std::borrow::Cow;
 use std::cell::RefCell;
+use std::ops::Bound;
 
 use crate::types::*;
 use crate::treasury::{TreasuryType, SeparateTreasuryConfig};
+use crate::amortization::AmortizationSchedule;
+use crate::disbursement::{Disbursement, DisbursementPayee, DisbursementStatus};
+use crate::agreement::LoanAgreement;
+use crate::fees::FeeReceivable;
+use crate::waterfall::PaymentWaterfallConfig;
+use crate::accrual::InterestAccrual;
+use crate::ledger::{AccountBalance, JournalEntry, LedgerAccount};
+use crate::token::{TokenLedgerConfig, TokenTransfer};
 use shared::*;
 
 // Memory management for stable storage
-type Memory = RestrictedMemory<DefaultMemoryImpl>;
+type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 // Define memory IDs for different data structures
-const LOANS_MEMORY_ID: u64 = 0;
-const PAYMENTS_MEMORY_ID: u64 = 1;
-const TREASURY_CONFIG_MEMORY_ID: u64 = 2;
-const SEPARATE_TREASURY_MEMORY_ID: u64 = 3;
-const COUNTERS_MEMORY_ID: u64 = 4;
+const LOANS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const PAYMENTS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const TREASURY_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(2);
+const SEPARATE_TREASURY_MEMORY_ID: MemoryId = MemoryId::new(3);
+const COUNTERS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const SCHEDULES_MEMORY_ID: MemoryId = MemoryId::new(5);
+const CANISTER_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(6);
+const AML_OUTBOX_MEMORY_ID: MemoryId = MemoryId::new(7);
+const DISBURSEMENTS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const AGREEMENTS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const FEES_MEMORY_ID: MemoryId = MemoryId::new(10);
+const WATERFALL_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(11);
+const ACCRUALS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const JOURNAL_MEMORY_ID: MemoryId = MemoryId::new(13);
+const ACCOUNT_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(14);
+const TOKEN_LEDGER_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(15);
+const TOKEN_TRANSFERS_MEMORY_ID: MemoryId = MemoryId::new(16);
+
+// Stable record version for Loan
+impl VersionedRecord for Loan {
+    const VERSION: u16 = 1;
+}
 
-// Implement Storable for Loan
-impl Storable for Loan {
-    const BOUND: Bound = Bound::Unbounded;
+// Stable record version for Payment
+impl VersionedRecord for Payment {
+    const VERSION: u16 = 1;
+}
 
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
+// Stable record version for TreasuryConfig
+impl VersionedRecord for TreasuryConfig {
+    const VERSION: u16 = 1;
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for SeparateTreasuryConfig
+impl VersionedRecord for SeparateTreasuryConfig {
+    const VERSION: u16 = 1;
 }
 
-// Implement Storable for Payment
-impl Storable for Payment {
-    const BOUND: Bound = Bound::Unbounded;
+// Stable record version for AmortizationSchedule
+impl VersionedRecord for AmortizationSchedule {
+    const VERSION: u16 = 1;
+}
 
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
+// Stable record version for Disbursement
+impl VersionedRecord for Disbursement {
+    const VERSION: u16 = 1;
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for LoanAgreement
+impl VersionedRecord for LoanAgreement {
+    const VERSION: u16 = 1;
 }
 
-// Implement Storable for TreasuryConfig
-impl Storable for TreasuryConfig {
-    const BOUND: Bound = Bound::Unbounded;
+// Stable record version for FeeReceivable
+impl VersionedRecord for FeeReceivable {
+    const VERSION: u16 = 1;
+}
 
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
+// Stable record version for PaymentWaterfallConfig
+impl VersionedRecord for PaymentWaterfallConfig {
+    const VERSION: u16 = 1;
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for InterestAccrual
+impl VersionedRecord for InterestAccrual {
+    const VERSION: u16 = 1;
 }
 
-// Implement Storable for SeparateTreasuryConfig
-impl Storable for SeparateTreasuryConfig {
-    const BOUND: Bound = Bound::Unbounded;
+// Stable record version for JournalEntry
+impl VersionedRecord for JournalEntry {
+    const VERSION: u16 = 1;
+}
 
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
+// Stable record version for AccountBalance
+impl VersionedRecord for AccountBalance {
+    const VERSION: u16 = 1;
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for TokenLedgerConfig
+impl VersionedRecord for TokenLedgerConfig {
+    const VERSION: u16 = 1;
+}
+
+// Stable record version for TokenTransfer
+impl VersionedRecord for TokenTransfer {
+    const VERSION: u16 = 1;
 }
 
 // Counter structure for ID generation
@@ -77,6 +115,12 @@ impl Storable for SeparateTreasuryConfig {
 pub struct Counters {
     pub loan_counter: u64,
     pub payment_counter: u64,
+    #[serde(default)]
+    pub disbursement_counter: u64,
+    #[serde(default)]
+    pub fee_counter: u64,
+    #[serde(default)]
+    pub journal_counter: u64,
 }
 
 impl Default for Counters {
@@ -84,52 +128,76 @@ impl Default for Counters {
         Self {
             loan_counter: 1,
             payment_counter: 1,
+            disbursement_counter: 0,
+            fee_counter: 0,
+            journal_counter: 0,
         }
     }
 }
 
-impl Storable for Counters {
-    const BOUND: Bound = Bound::Bounded {
-        max_size: 100,
-        is_fixed_size: false,
-    };
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+impl VersionedRecord for Counters {
+    const VERSION: u16 = 1;
 }
 
 // Storage structure
 pub struct FinanceStorage {
-    pub loans: StableBTreeMap<String, Loan, Memory>,
-    pub payments: StableBTreeMap<String, Payment, Memory>,
-    pub treasury_config: StableBTreeMap<String, TreasuryConfig, Memory>,
-    pub separate_treasuries: StableBTreeMap<String, SeparateTreasuryConfig, Memory>,
-    pub counters: StableBTreeMap<String, Counters, Memory>,
+    pub loans: VersionedMap<String, Loan, Memory>,
+    pub payments: VersionedMap<String, Payment, Memory>,
+    pub treasury_config: VersionedMap<String, TreasuryConfig, Memory>,
+    pub separate_treasuries: VersionedMap<String, SeparateTreasuryConfig, Memory>,
+    pub counters: VersionedMap<String, Counters, Memory>,
+    pub schedules: VersionedMap<String, AmortizationSchedule, Memory>,
+    pub canister_registry: VersionedMap<String, CanisterRegistry, Memory>,
+    /// Transactions not yet acknowledged by compliance_service, keyed by reference id
+    pub aml_outbox: VersionedMap<String, AmlTransactionReport, Memory>,
+    pub disbursements: VersionedMap<String, Disbursement, Memory>,
+    /// Every agreement version, keyed by loan id and zero-padded version
+    pub agreements: VersionedMap<String, LoanAgreement, Memory>,
+    pub fees: VersionedMap<String, FeeReceivable, Memory>,
+    /// Payment allocation order per loan product
+    pub waterfall_configs: VersionedMap<String, PaymentWaterfallConfig, Memory>,
+    /// Daily interest accruals, keyed by loan id and accrual date
+    pub accruals: VersionedMap<String, InterestAccrual, Memory>,
+    /// General ledger journal, keyed by entry id so iteration follows posting order
+    pub journal: VersionedMap<String, JournalEntry, Memory>,
+    /// Running totals per ledger account
+    pub account_balances: VersionedMap<String, AccountBalance, Memory>,
+    pub token_ledger_config: VersionedMap<String, TokenLedgerConfig, Memory>,
+    /// Completed token ledger transfers, keyed by loan id and block index
+    pub token_transfers: VersionedMap<String, TokenTransfer, Memory>,
 }
 
 impl FinanceStorage {
-    pub fn new() -> Self {
+    pub fn init(memory: DefaultMemoryImpl) -> Self {
+        // Canisters installed before the MemoryManager layout kept each map in its own page
+        let memory_manager = init_memory_manager(memory, |memory| vec![
+            read_legacy_map::<String, _>(memory, 0, LOANS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 1, PAYMENTS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 2, TREASURY_CONFIG_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 3, SEPARATE_TREASURY_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 4, COUNTERS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 5, SCHEDULES_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 6, CANISTER_REGISTRY_MEMORY_ID),
+        ]);
+
         Self {
-            loans: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), LOANS_MEMORY_ID..LOANS_MEMORY_ID + 1)
-            ),
-            payments: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), PAYMENTS_MEMORY_ID..PAYMENTS_MEMORY_ID + 1)
-            ),
-            treasury_config: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), TREASURY_CONFIG_MEMORY_ID..TREASURY_CONFIG_MEMORY_ID + 1)
-            ),
-            separate_treasuries: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), SEPARATE_TREASURY_MEMORY_ID..SEPARATE_TREASURY_MEMORY_ID + 1)
-            ),
-            counters: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), COUNTERS_MEMORY_ID..COUNTERS_MEMORY_ID + 1)
-            ),
+            loans: VersionedMap::init(memory_manager.get(LOANS_MEMORY_ID)),
+            payments: VersionedMap::init(memory_manager.get(PAYMENTS_MEMORY_ID)),
+            treasury_config: VersionedMap::init(memory_manager.get(TREASURY_CONFIG_MEMORY_ID)),
+            separate_treasuries: VersionedMap::init(memory_manager.get(SEPARATE_TREASURY_MEMORY_ID)),
+            counters: VersionedMap::init(memory_manager.get(COUNTERS_MEMORY_ID)),
+            schedules: VersionedMap::init(memory_manager.get(SCHEDULES_MEMORY_ID)),
+            canister_registry: VersionedMap::init(memory_manager.get(CANISTER_REGISTRY_MEMORY_ID)),
+            aml_outbox: VersionedMap::init(memory_manager.get(AML_OUTBOX_MEMORY_ID)),
+            disbursements: VersionedMap::init(memory_manager.get(DISBURSEMENTS_MEMORY_ID)),
+            agreements: VersionedMap::init(memory_manager.get(AGREEMENTS_MEMORY_ID)),
+            fees: VersionedMap::init(memory_manager.get(FEES_MEMORY_ID)),
+            waterfall_configs: VersionedMap::init(memory_manager.get(WATERFALL_CONFIG_MEMORY_ID)),
+            accruals: VersionedMap::init(memory_manager.get(ACCRUALS_MEMORY_ID)),
+            journal: VersionedMap::init(memory_manager.get(JOURNAL_MEMORY_ID)),
+            account_balances: VersionedMap::init(memory_manager.get(ACCOUNT_BALANCES_MEMORY_ID)),
+            token_ledger_config: VersionedMap::init(memory_manager.get(TOKEN_LEDGER_CONFIG_MEMORY_ID)),
+            token_transfers: VersionedMap::init(memory_manager.get(TOKEN_TRANSFERS_MEMORY_ID)),
         }
     }
 
@@ -165,10 +233,31 @@ impl FinanceStorage {
             .collect()
     }
 
+    /// The loan created from a credit application, if any
+    pub fn get_loan_for_application(&self, application_id: &str) -> Option<Loan> {
+        self.loans
+            .iter()
+            .map(|(_, loan)| loan)
+            .find(|loan| loan.application_id.as_deref() == Some(application_id))
+    }
+
     pub fn get_all_loans(&self) -> Vec<Loan> {
         self.loans.iter().map(|(_, loan)| loan).collect()
     }
 
+    /// Up to `limit` loans with ids after `after`, in id order
+    pub fn get_loans_after(&self, after: Option<&str>, limit: usize) -> Vec<Loan> {
+        let start = match after {
+            Some(id) => Bound::Excluded(id.to_string()),
+            None => Bound::Unbounded,
+        };
+        self.loans
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, loan)| loan)
+            .collect()
+    }
+
     pub fn get_active_loans(&self) -> Vec<Loan> {
         self.loans
             .iter()
@@ -227,6 +316,19 @@ impl FinanceStorage {
             .collect()
     }
 
+    /// Interest actually collected on a loan, from its completed payments
+    pub fn get_interest_paid(&self, loan_id: &str) -> Amount {
+        self.get_payments_by_loan(loan_id)
+            .iter()
+            .filter(|payment| payment.status == PaymentStatus::Completed)
+            .map(|payment| payment.interest_portion)
+            .sum()
+    }
+
+    pub fn get_all_payments(&self) -> Vec<Payment> {
+        self.payments.iter().map(|(_, payment)| payment).collect()
+    }
+
     pub fn get_payments_by_student(&self, student_id: &Principal) -> Vec<Payment> {
         self.payments
             .iter()
@@ -240,6 +342,32 @@ impl FinanceStorage {
             .collect()
     }
 
+    // Amortization schedule operations
+    pub fn get_schedule(&self, loan_id: &str) -> Option<AmortizationSchedule> {
+        self.schedules.get(&loan_id.to_string())
+    }
+
+    pub fn insert_schedule(&mut self, loan_id: String, schedule: AmortizationSchedule) {
+        self.schedules.insert(loan_id, schedule);
+    }
+
+    pub fn get_all_schedules(&self) -> Vec<AmortizationSchedule> {
+        self.schedules.iter().map(|(_, schedule)| schedule).collect()
+    }
+
+    pub fn get_schedules_by_student(&self, student_id: &Principal) -> Vec<AmortizationSchedule> {
+        self.schedules
+            .iter()
+            .filter_map(|(_, schedule)| {
+                if schedule.student_id == *student_id {
+                    Some(schedule)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
     // Treasury operations
     pub fn get_treasury_config(&self) -> TreasuryConfig {
         self.treasury_config
@@ -270,7 +398,7 @@ impl FinanceStorage {
     pub fn get_next_loan_id(&mut self) -> String {
         let mut counters = self.counters
             .get(&"default".to_string())
-            .unwrap_or_else(Counters::default);
+            .unwrap_or_default();
         
         let id = generate_id(LOAN_PREFIX, counters.loan_counter);
         counters.loan_counter += 1;
@@ -281,7 +409,7 @@ impl FinanceStorage {
     pub fn get_next_payment_id(&mut self) -> String {
         let mut counters = self.counters
             .get(&"default".to_string())
-            .unwrap_or_else(Counters::default);
+            .unwrap_or_default();
         
         let id = generate_id(PAYMENT_PREFIX, counters.payment_counter);
         counters.payment_counter += 1;
@@ -289,10 +417,214 @@ impl FinanceStorage {
         id
     }
 
+    pub fn get_next_disbursement_id(&mut self) -> String {
+        let mut counters = self.counters
+            .get(&"default".to_string())
+            .unwrap_or_default();
+
+        counters.disbursement_counter += 1;
+        let id = generate_id(DISBURSEMENT_PREFIX, counters.disbursement_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    pub fn get_next_fee_id(&mut self) -> String {
+        let mut counters = self.counters
+            .get(&"default".to_string())
+            .unwrap_or_default();
+
+        counters.fee_counter += 1;
+        let id = generate_id(FEE_PREFIX, counters.fee_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    pub fn get_next_journal_entry_id(&mut self) -> String {
+        let mut counters = self.counters
+            .get(&"default".to_string())
+            .unwrap_or_default();
+
+        counters.journal_counter += 1;
+        let id = generate_id(JOURNAL_ENTRY_PREFIX, counters.journal_counter);
+        self.counters.insert("default".to_string(), counters);
+        id
+    }
+
+    // Disbursement operations
+    pub fn get_disbursement(&self, id: &str) -> Option<Disbursement> {
+        self.disbursements.get(&id.to_string())
+    }
+
+    pub fn insert_disbursement(&mut self, disbursement: Disbursement) {
+        self.disbursements.insert(disbursement.id.clone(), disbursement);
+    }
+
+    pub fn remove_disbursement(&mut self, id: &str) {
+        self.disbursements.remove(&id.to_string());
+    }
+
+    /// A loan's tranches in payout order
+    pub fn get_loan_disbursements(&self, loan_id: &str) -> Vec<Disbursement> {
+        let mut disbursements: Vec<Disbursement> = self.disbursements
+            .iter()
+            .map(|(_, disbursement)| disbursement)
+            .filter(|disbursement| disbursement.loan_id == loan_id)
+            .collect();
+        disbursements.sort_by_key(|disbursement| disbursement.tranche_number);
+        disbursements
+    }
+
+    pub fn get_disbursements_by_student(&self, student_id: &Principal) -> Vec<Disbursement> {
+        self.disbursements
+            .iter()
+            .map(|(_, disbursement)| disbursement)
+            .filter(|disbursement| disbursement.student_id == *student_id)
+            .collect()
+    }
+
+    /// Scheduled tranches whose payout date has been reached
+    pub fn get_due_disbursements(&self, now: Timestamp) -> Vec<Disbursement> {
+        self.disbursements
+            .iter()
+            .map(|(_, disbursement)| disbursement)
+            .filter(|disbursement| {
+                disbursement.status == DisbursementStatus::Scheduled && disbursement.scheduled_for <= now
+            })
+            .collect()
+    }
+
+    // Agreement operations
+    pub fn insert_agreement(&mut self, agreement: LoanAgreement) {
+        let key = format!("{}#{:06}", agreement.loan_id, agreement.version);
+        self.agreements.insert(key, agreement);
+    }
+
+    /// Every agreement version issued for a loan, oldest first
+    pub fn get_agreement_versions(&self, loan_id: &str) -> Vec<LoanAgreement> {
+        let prefix = format!("{}#", loan_id);
+        self.agreements
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, agreement)| agreement)
+            .collect()
+    }
+
+    pub fn get_current_agreement(&self, loan_id: &str) -> Option<LoanAgreement> {
+        self.get_agreement_versions(loan_id).pop()
+    }
+
+    // Fee receivable operations
+    pub fn insert_fee(&mut self, fee: FeeReceivable) {
+        self.fees.insert(fee.id.clone(), fee);
+    }
+
+    /// A loan's fees, oldest first
+    pub fn get_loan_fees(&self, loan_id: &str) -> Vec<FeeReceivable> {
+        let mut fees: Vec<FeeReceivable> = self.fees
+            .iter()
+            .map(|(_, fee)| fee)
+            .filter(|fee| fee.loan_id == loan_id)
+            .collect();
+        fees.sort_by_key(|fee| fee.assessed_at);
+        fees
+    }
+
+    pub fn get_outstanding_fees(&self, loan_id: &str) -> Amount {
+        self.get_loan_fees(loan_id).iter().map(|fee| fee.amount_outstanding()).sum()
+    }
+
+    // Payment waterfall operations
+    pub fn get_waterfall_config(&self, product: &str) -> Option<PaymentWaterfallConfig> {
+        self.waterfall_configs.get(&product.to_string())
+    }
+
+    pub fn set_waterfall_config(&mut self, config: PaymentWaterfallConfig) {
+        self.waterfall_configs.insert(config.product.clone(), config);
+    }
+
+    pub fn get_all_waterfall_configs(&self) -> Vec<PaymentWaterfallConfig> {
+        self.waterfall_configs.iter().map(|(_, config)| config).collect()
+    }
+
+    // Interest accrual operations
+    pub fn insert_accrual(&mut self, accrual: InterestAccrual) {
+        let key = format!("{}#{:020}", accrual.loan_id, accrual.accrual_date);
+        self.accruals.insert(key, accrual);
+    }
+
+    /// A loan's accrual history, oldest first
+    pub fn get_loan_accruals(&self, loan_id: &str) -> Vec<InterestAccrual> {
+        let prefix = format!("{}#", loan_id);
+        self.accruals
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, accrual)| accrual)
+            .collect()
+    }
+
+    // General ledger operations
+    /// Store a validated entry and add its lines to the account totals
+    pub fn insert_journal_entry(&mut self, entry: JournalEntry) {
+        for line in &entry.lines {
+            let mut balance = self.get_account_balance(&line.account);
+            balance.total_debits += line.debit;
+            balance.total_credits += line.credit;
+            self.account_balances.insert(line.account.key(), balance);
+        }
+        self.journal.insert(entry.id.clone(), entry);
+    }
+
+    pub fn has_journal_entries(&self) -> bool {
+        self.journal.iter().next().is_some()
+    }
+
+    /// Every journal entry, in posting order
+    pub fn get_journal_entries(&self) -> Vec<JournalEntry> {
+        self.journal.iter().map(|(_, entry)| entry).collect()
+    }
+
+    pub fn get_account_balance(&self, account: &LedgerAccount) -> AccountBalance {
+        self.account_balances
+            .get(&account.key())
+            .unwrap_or_else(|| AccountBalance::new(account.clone()))
+    }
+
+    pub fn get_all_account_balances(&self) -> Vec<AccountBalance> {
+        self.account_balances.iter().map(|(_, balance)| balance).collect()
+    }
+
+    // Token ledger operations
+    pub fn get_token_ledger_config(&self) -> Option<TokenLedgerConfig> {
+        self.token_ledger_config.get(&"default".to_string())
+    }
+
+    pub fn set_token_ledger_config(&mut self, config: TokenLedgerConfig) {
+        self.token_ledger_config.insert("default".to_string(), config);
+    }
+
+    pub fn insert_token_transfer(&mut self, transfer: TokenTransfer) {
+        let key = format!("{}#{:020}", transfer.loan_id, transfer.block_index);
+        self.token_transfers.insert(key, transfer);
+    }
+
+    pub fn get_token_transfers(&self) -> Vec<TokenTransfer> {
+        self.token_transfers.iter().map(|(_, transfer)| transfer).collect()
+    }
+
+    /// A loan's token transfers in ledger order
+    pub fn get_loan_token_transfers(&self, loan_id: &str) -> Vec<TokenTransfer> {
+        let prefix = format!("{}#", loan_id);
+        self.token_transfers
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, transfer)| transfer)
+            .collect()
+    }
+
     // Statistics
     pub fn calculate_treasury_stats(&self) -> TreasuryStats {
         let all_loans = self.get_all_loans();
-        let all_payments: Vec<Payment> = self.payments.iter().map(|(_, payment)| payment).collect();
+        let all_payments = self.get_all_payments();
 
         let total_loans_outstanding = all_loans
             .iter()
@@ -300,9 +632,19 @@ impl FinanceStorage {
             .map(|loan| loan.current_balance)
             .sum();
 
+        // Activated loans were re-based on what they paid out; pending loans count their paid tranches
         let total_loans_disbursed = all_loans
             .iter()
-            .map(|loan| loan.original_amount)
+            .map(|loan| match loan.status {
+                LoanStatus::PendingSignature | LoanStatus::Cancelled => 0,
+                LoanStatus::PendingDisbursement => self
+                    .get_loan_disbursements(&loan.id)
+                    .iter()
+                    .filter(|disbursement| disbursement.status == DisbursementStatus::Disbursed)
+                    .map(|disbursement| disbursement.amount)
+                    .sum(),
+                _ => loan.original_amount,
+            })
             .sum();
 
         let total_payments_received = all_payments
@@ -359,11 +701,153 @@ impl FinanceStorage {
             portfolio_yield,
         }
     }
+
+    // AML report outbox operations
+    pub fn queue_aml_report(&mut self, report: AmlTransactionReport) {
+        self.aml_outbox.insert(report.reference_id.clone(), report);
+    }
+
+    pub fn remove_aml_report(&mut self, reference_id: &str) {
+        self.aml_outbox.remove(&reference_id.to_string());
+    }
+
+    pub fn get_pending_aml_reports(&self) -> Vec<AmlTransactionReport> {
+        self.aml_outbox.iter().map(|(_, report)| report).collect()
+    }
+
+    // Data subject requests
+    /// Loans the subject borrowed or cosigned
+    fn get_subject_loans(&self, subject: &Principal) -> Vec<Loan> {
+        self.loans
+            .iter()
+            .map(|(_, loan)| loan)
+            .filter(|loan| loan.student_id == *subject || loan.cosigner_id == Some(*subject))
+            .collect()
+    }
+
+    /// Agreements naming the subject as borrower or cosigner
+    fn get_subject_agreements(&self, subject: &Principal) -> Vec<LoanAgreement> {
+        self.agreements
+            .iter()
+            .map(|(_, agreement)| agreement)
+            .filter(|agreement| agreement.borrower == *subject || agreement.cosigner == Some(*subject))
+            .collect()
+    }
+
+    pub fn count_outstanding_loans(&self, subject: &Principal) -> u32 {
+        self.get_subject_loans(subject)
+            .iter()
+            .filter(|loan| loan.status.is_outstanding())
+            .count() as u32
+    }
+
+    pub fn export_subject_data(&self, subject: &Principal) -> LoanSubjectData {
+        LoanSubjectData {
+            loans: self.get_subject_loans(subject),
+            payments: self.get_payments_by_student(subject),
+            schedules: self.get_schedules_by_student(subject),
+            pending_aml_reports: self
+                .get_pending_aml_reports()
+                .into_iter()
+                .filter(|report| report.party == *subject || report.counterparty == Some(*subject))
+                .collect(),
+            disbursements: self.get_disbursements_by_student(subject),
+            agreements: self.get_subject_agreements(subject),
+            fees: self
+                .get_subject_loans(subject)
+                .iter()
+                .flat_map(|loan| self.get_loan_fees(&loan.id))
+                .collect(),
+            interest_accruals: self
+                .get_subject_loans(subject)
+                .iter()
+                .flat_map(|loan| self.get_loan_accruals(&loan.id))
+                .collect(),
+        }
+    }
+
+    /// Pseudonymize the subject on closed loans and their payments, schedules and disbursements.
+    /// Outstanding loans are kept as they are until repaid, as are pending AML reports.
+    pub fn erase_subject_data(&mut self, request: &DataErasureRequest) -> ErasureReceipt {
+        let subject = request.subject;
+        let mut receipt = ErasureReceipt::new(ServiceName::LoanManagement);
+        let mut outstanding_loan_ids = Vec::new();
+        let mut outstanding_records = 0;
+
+        for mut loan in self.get_subject_loans(&subject) {
+            if loan.status.is_outstanding() {
+                outstanding_loan_ids.push(loan.id.clone());
+                outstanding_records += 1;
+                continue;
+            }
+            if loan.student_id == subject {
+                loan.student_id = request.pseudonym;
+            }
+            if loan.cosigner_id == Some(subject) {
+                loan.cosigner_id = Some(request.pseudonym);
+            }
+            self.loans.insert(loan.id.clone(), loan);
+            receipt.pseudonymized += 1;
+        }
+
+        for mut payment in self.get_payments_by_student(&subject) {
+            if outstanding_loan_ids.contains(&payment.loan_id) {
+                outstanding_records += 1;
+                continue;
+            }
+            payment.student_id = request.pseudonym;
+            self.payments.insert(payment.id.clone(), payment);
+            receipt.pseudonymized += 1;
+        }
+
+        for mut schedule in self.get_schedules_by_student(&subject) {
+            if outstanding_loan_ids.contains(&schedule.loan_id) {
+                outstanding_records += 1;
+                continue;
+            }
+            schedule.student_id = request.pseudonym;
+            self.schedules.insert(schedule.loan_id.clone(), schedule);
+            receipt.pseudonymized += 1;
+        }
+
+        for mut disbursement in self.get_disbursements_by_student(&subject) {
+            if outstanding_loan_ids.contains(&disbursement.loan_id) {
+                outstanding_records += 1;
+                continue;
+            }
+            disbursement.student_id = request.pseudonym;
+            // The university's account reference identifies the student
+            if let DisbursementPayee::University { reference, .. } = &mut disbursement.payee {
+                reference.clear();
+            }
+            self.disbursements.insert(disbursement.id.clone(), disbursement);
+            receipt.pseudonymized += 1;
+        }
+
+        receipt.retain(outstanding_records, "outstanding loans are kept until repaid");
+        receipt.retain(
+            self.get_subject_agreements(&subject).len() as u32,
+            "signed agreements are kept as evidence of the contract",
+        );
+        let pending_reports = self.export_subject_data(&subject).pending_aml_reports.len() as u32;
+        receipt.retain(pending_reports, "AML reports awaiting delivery to compliance_service");
+        receipt
+    }
+
+    pub fn get_canister_registry(&self) -> CanisterRegistry {
+        self.canister_registry
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_canister_registry(&mut self, registry: CanisterRegistry) {
+        self.canister_registry.insert("default".to_string(), registry);
+    }
 }
 
 // Thread-local storage
 thread_local! {
-    static STORAGE: RefCell<FinanceStorage> = RefCell::new(FinanceStorage::new());
+    static STORAGE: RefCell<FinanceStorage> = RefCell::new(FinanceStorage::init(DefaultMemoryImpl::default()));
 }
 
 // Storage access functions
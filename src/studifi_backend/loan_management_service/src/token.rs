@@ -0,0 +1,902 @@
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::marker::PhantomData;
+
+use crate::storage::*;
+use crate::ledger::*;
+use crate::treasury::*;
+use shared::*;
+
+// ============================================================================
+// ICRC-1 / ICRC-2 INTERFACE
+// ============================================================================
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Block index of a completed transfer, or what the ledger rejected it with.
+/// A duplicate means an earlier attempt of the same transfer went through.
+fn block_index(result: Result<Nat, (String, Option<Nat>)>) -> StudiFiResult<u64> {
+    match result {
+        Ok(block) | Err((_, Some(block))) => nat_to_u64(&block),
+        Err((reason, None)) => Err(StudiFiError::ExternalServiceError(format!("Token transfer failed: {}", reason))),
+    }
+}
+
+impl TransferError {
+    fn into_parts(self) -> (String, Option<Nat>) {
+        match self {
+            TransferError::Duplicate { duplicate_of } => ("duplicate".to_string(), Some(duplicate_of)),
+            TransferError::InsufficientFunds { balance } => (format!("insufficient funds, balance {}", balance), None),
+            TransferError::BadFee { expected_fee } => (format!("ledger fee is now {}", expected_fee), None),
+            other => (format!("{:?}", other), None),
+        }
+    }
+}
+
+impl TransferFromError {
+    fn into_parts(self) -> (String, Option<Nat>) {
+        match self {
+            TransferFromError::Duplicate { duplicate_of } => ("duplicate".to_string(), Some(duplicate_of)),
+            TransferFromError::InsufficientFunds { balance } => (format!("insufficient funds, balance {}", balance), None),
+            TransferFromError::InsufficientAllowance { allowance } => (
+                format!("allowance of {} is too low; approve this canister as spender first", allowance),
+                None,
+            ),
+            TransferFromError::BadFee { expected_fee } => (format!("ledger fee is now {}", expected_fee), None),
+            other => (format!("{:?}", other), None),
+        }
+    }
+}
+
+fn nat_to_u64(value: &Nat) -> StudiFiResult<u64> {
+    u64::try_from(&value.0)
+        .map_err(|_| StudiFiError::ExternalServiceError(format!("Ledger amount {} does not fit in 64 bits", value)))
+}
+
+// ============================================================================
+// CONFIGURATION AND RECORDS
+// ============================================================================
+
+/// The ICRC ledger loans are paid out and repaid through (e.g. ckUSDC)
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct TokenLedgerConfig {
+    pub ledger_canister_id: Principal,
+    pub token_symbol: String,
+    /// Ledger base units per cent of loan currency, e.g. 10_000 for ckUSDC's 6 decimals
+    pub units_per_cent: u64,
+    /// Fee the ledger charges per transfer, in base units
+    pub transfer_fee: u64,
+    /// When off, payments and payouts are recorded without moving tokens
+    pub is_active: bool,
+    pub updated_at: Timestamp,
+    pub updated_by: Principal,
+}
+
+impl TokenLedgerConfig {
+    pub fn to_units(&self, amount: Amount) -> StudiFiResult<u64> {
+        amount.checked_mul(self.units_per_cent)
+            .ok_or_else(|| StudiFiError::InvalidInput(format!("{} is too large to transfer", format_currency(amount))))
+    }
+
+    /// Fee in cents as booked to the general ledger, rounded up
+    pub fn fee_in_cents(&self) -> Amount {
+        self.transfer_fee.div_ceil(self.units_per_cent)
+    }
+
+    fn canister_config(&self) -> CanisterConfig {
+        CanisterConfig {
+            canister_id: self.ledger_canister_id,
+            name: format!("{} ledger", self.token_symbol),
+            is_active: self.is_active,
+            // Transfers carry a creation time, so a retried transfer is deduplicated by the ledger
+            retry_count: 2,
+            timeout_seconds: 60,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum TokenTransferKind {
+    /// Treasury account to the payee of a tranche
+    Disbursement,
+    /// Borrower to the loan's subaccount
+    Repayment,
+    /// Loan subaccount to the treasury account
+    Sweep,
+}
+
+/// A completed transfer on the token ledger
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct TokenTransfer {
+    pub loan_id: String,
+    pub kind: TokenTransferKind,
+    /// Disbursement or payment id; None for sweeps
+    pub reference: Option<String>,
+    /// Base units received by the destination
+    pub amount: u64,
+    /// Base units the ledger charged on top
+    pub fee: u64,
+    pub block_index: u64,
+    pub recorded_at: Timestamp,
+}
+
+/// Expected against actual balance of one ledger account
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AccountReconciliation {
+    pub account: Account,
+    /// None for the treasury account
+    pub loan_id: Option<String>,
+    pub expected_balance: u64,
+    pub ledger_balance: u64,
+    /// Ledger balance minus expected balance, in base units
+    pub discrepancy: i128,
+}
+
+impl AccountReconciliation {
+    pub fn new(account: Account, loan_id: Option<String>, expected_balance: u64, ledger_balance: u64) -> Self {
+        Self {
+            account,
+            loan_id,
+            expected_balance,
+            ledger_balance,
+            discrepancy: ledger_balance as i128 - expected_balance as i128,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ReconciliationReport {
+    pub ledger_canister_id: Principal,
+    pub token_symbol: String,
+    pub accounts: Vec<AccountReconciliation>,
+    pub total_discrepancy: i128,
+    pub is_reconciled: bool,
+    pub reconciled_at: Timestamp,
+}
+
+impl ReconciliationReport {
+    pub fn new(config: &TokenLedgerConfig, accounts: Vec<AccountReconciliation>, now: Timestamp) -> Self {
+        let total_discrepancy = accounts.iter().map(|account| account.discrepancy).sum();
+        Self {
+            ledger_canister_id: config.ledger_canister_id,
+            token_symbol: config.token_symbol.clone(),
+            is_reconciled: accounts.iter().all(|account| account.discrepancy == 0),
+            accounts,
+            total_discrepancy,
+            reconciled_at: now,
+        }
+    }
+}
+
+// ============================================================================
+// IN-FLIGHT GUARD
+// ============================================================================
+
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Held while a loan payment, a tranche payout or a loan creation waits on another
+/// canister, so the same loan, tranche or application cannot be changed by another
+/// message in the meantime
+pub struct InFlightGuard {
+    key: String,
+}
+
+impl InFlightGuard {
+    pub fn acquire(key: &str) -> StudiFiResult<Self> {
+        let acquired = IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(key.to_string()));
+        if !acquired {
+            return Err(StudiFiError::InvalidInput(format!("{} has an operation in progress", key)));
+        }
+        Ok(Self { key: key.to_string() })
+    }
+
+    pub fn is_held(key: &str) -> bool {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(key))
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.key));
+    }
+}
+
+// ============================================================================
+// LEDGER TRANSPORT
+// ============================================================================
+
+/// How the token engine reaches the ledger canister and whose accounts it moves
+pub trait LedgerTransport {
+    /// Owner of the treasury account and the loan subaccounts
+    fn canister_id() -> Principal;
+
+    fn call<T, R>(config: &TokenLedgerConfig, method: &str, args: T) -> impl Future<Output = StudiFiResult<R>>
+    where
+        T: ArgumentEncoder + Clone,
+        R: for<'de> Deserialize<'de> + CandidType;
+}
+
+/// Inter-canister calls from this canister to the configured ledger
+pub struct IcLedger;
+
+impl LedgerTransport for IcLedger {
+    fn canister_id() -> Principal {
+        ic_cdk::id()
+    }
+
+    async fn call<T, R>(config: &TokenLedgerConfig, method: &str, args: T) -> StudiFiResult<R>
+    where
+        T: ArgumentEncoder + Clone,
+        R: for<'de> Deserialize<'de> + CandidType,
+    {
+        call_canister(&config.canister_config(), method, args).await
+    }
+}
+
+// ============================================================================
+// TOKEN ENGINE
+// ============================================================================
+
+/// Moves loan funds on the configured ICRC ledger. Tranches are paid from the
+/// canister's default account; each loan's repayments collect in its own subaccount
+/// until they are swept back to the treasury account.
+pub struct TokenEngineWith<L>(PhantomData<L>);
+
+/// The token engine as deployed, talking to the ledger canister
+pub type TokenEngine = TokenEngineWith<IcLedger>;
+
+impl<L: LedgerTransport> TokenEngineWith<L> {
+    /// The ledger configuration, if token transfers are switched on
+    pub fn active_config() -> Option<TokenLedgerConfig> {
+        with_storage(|storage| storage.get_token_ledger_config()).filter(|config| config.is_active)
+    }
+
+    pub fn set_config(
+        ledger_canister_id: Principal,
+        token_symbol: String,
+        units_per_cent: u64,
+        transfer_fee: u64,
+        is_active: bool,
+        updated_by: Principal,
+    ) -> StudiFiResult<TokenLedgerConfig> {
+        if units_per_cent == 0 {
+            return Err(StudiFiError::InvalidInput("Units per cent must be positive".to_string()));
+        }
+        let config = TokenLedgerConfig {
+            ledger_canister_id,
+            token_symbol: sanitize_text(&token_symbol),
+            units_per_cent,
+            transfer_fee,
+            is_active,
+            updated_at: current_time(),
+            updated_by,
+        };
+        with_storage_mut(|storage| storage.set_token_ledger_config(config.clone()));
+        Ok(config)
+    }
+
+    /// Subaccount a loan's repayments are collected in
+    pub fn loan_subaccount(loan_id: &str) -> Vec<u8> {
+        Sha256::digest(format!("studifi-loan:{}", loan_id).as_bytes()).to_vec()
+    }
+
+    pub fn loan_account(loan_id: &str) -> Account {
+        Account {
+            owner: L::canister_id(),
+            subaccount: Some(Self::loan_subaccount(loan_id)),
+        }
+    }
+
+    pub fn treasury_account() -> Account {
+        Account {
+            owner: L::canister_id(),
+            subaccount: None,
+        }
+    }
+
+    /// Pull a repayment from `payer` into the loan's subaccount. The payer must
+    /// have approved this canister for the amount plus the ledger fee.
+    ///
+    /// `created_at` is the time of the payment's first collection attempt, so a
+    /// retry after a lost reply is deduplicated like a tranche payout. A transfer the
+    /// ledger refuses, for a missing allowance say, fails with `InvalidInput`.
+    pub async fn collect_repayment(
+        config: &TokenLedgerConfig,
+        loan_id: &str,
+        payment_id: &str,
+        payer: Principal,
+        amount: Amount,
+        created_at: Timestamp,
+        now: Timestamp,
+    ) -> StudiFiResult<TokenTransfer> {
+        let units = config.to_units(amount)?;
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from: Account { owner: payer, subaccount: None },
+            to: Self::loan_account(loan_id),
+            amount: Nat::from(units),
+            fee: Some(Nat::from(config.transfer_fee)),
+            memo: Some(payment_id.as_bytes().to_vec()),
+            created_at_time: Some(created_at),
+        };
+        let result: Result<Nat, TransferFromError> = L::call(config, "icrc2_transfer_from", (args,)).await?;
+        let block_index = match result.map_err(TransferFromError::into_parts) {
+            Err((reason, None)) => {
+                return Err(StudiFiError::InvalidInput(format!("Token transfer refused: {}", reason)));
+            }
+            reply => block_index(reply)?,
+        };
+
+        // The payer bears the fee on transfer_from, so the subaccount receives the full amount
+        Ok(Self::record(TokenTransfer {
+            loan_id: loan_id.to_string(),
+            kind: TokenTransferKind::Repayment,
+            reference: Some(payment_id.to_string()),
+            amount: units,
+            fee: 0,
+            block_index,
+            recorded_at: now,
+        }))
+    }
+
+    /// Pay a tranche out of the treasury account. Returns None for payees
+    /// without a ledger account, which are paid off-chain as before.
+    ///
+    /// `created_at` is the time of the tranche's first payout attempt. With the
+    /// disbursement id as memo, a retry is the same transaction and the ledger
+    /// returns the original block instead of paying twice. Once the ledger's
+    /// deduplication window has passed it rejects the transfer as too old, and
+    /// the tranche has to be reconciled against the ledger by hand.
+    pub async fn pay_out(
+        config: &TokenLedgerConfig,
+        loan_id: &str,
+        disbursement_id: &str,
+        payee: Option<Principal>,
+        amount: Amount,
+        created_at: Timestamp,
+        now: Timestamp,
+    ) -> StudiFiResult<Option<TokenTransfer>> {
+        let Some(payee) = payee else {
+            return Ok(None);
+        };
+
+        let units = config.to_units(amount)?;
+        let fee_entry = Self::fee_entry(config, disbursement_id)?;
+        let block_index = Self::transfer(config, None, Account { owner: payee, subaccount: None }, units, disbursement_id, created_at).await?;
+        Self::book_fee(fee_entry, now);
+        Ok(Some(Self::record(TokenTransfer {
+            loan_id: loan_id.to_string(),
+            kind: TokenTransferKind::Disbursement,
+            reference: Some(disbursement_id.to_string()),
+            amount: units,
+            fee: config.transfer_fee,
+            block_index,
+            recorded_at: now,
+        })))
+    }
+
+    /// Move everything collected in a loan's subaccount to the treasury account
+    pub async fn sweep(config: &TokenLedgerConfig, loan_id: &str, now: Timestamp) -> StudiFiResult<Option<TokenTransfer>> {
+        let balance = Self::balance_of(config, Self::loan_account(loan_id)).await?;
+        if balance <= config.transfer_fee {
+            return Ok(None);
+        }
+
+        let units = balance - config.transfer_fee;
+        let subaccount = Self::loan_subaccount(loan_id);
+        let fee_entry = Self::fee_entry(config, loan_id)?;
+        let block_index = Self::transfer(config, Some(subaccount), Self::treasury_account(), units, loan_id, now).await?;
+        Self::book_fee(fee_entry, now);
+        Ok(Some(Self::record(TokenTransfer {
+            loan_id: loan_id.to_string(),
+            kind: TokenTransferKind::Sweep,
+            reference: None,
+            amount: units,
+            fee: config.transfer_fee,
+            block_index,
+            recorded_at: now,
+        })))
+    }
+
+    /// Compare the loan treasury's books with the ledger. The treasury account
+    /// and loan subaccounts together should hold the loan treasury's cash and
+    /// reserves; each subaccount should hold its repayments not yet swept.
+    /// Payments taken off-chain show as a shortfall until deposited on the ledger.
+    pub async fn reconcile(config: &TokenLedgerConfig, now: Timestamp) -> StudiFiResult<ReconciliationReport> {
+        let transfers = with_storage(|storage| storage.get_token_transfers());
+        let mut loan_ids: Vec<String> = transfers.iter().map(|transfer| transfer.loan_id.clone()).collect();
+        loan_ids.sort();
+        loan_ids.dedup();
+
+        let mut accounts = Vec::new();
+        let mut held_in_subaccounts: u64 = 0;
+        for loan_id in loan_ids {
+            let loan_transfers: Vec<&TokenTransfer> = transfers.iter().filter(|transfer| transfer.loan_id == loan_id).collect();
+            let expected = Self::expected_subaccount_balance(&loan_transfers);
+            held_in_subaccounts += expected;
+
+            let account = Self::loan_account(&loan_id);
+            let ledger_balance = Self::balance_of(config, account.clone()).await?;
+            accounts.push(AccountReconciliation::new(account, Some(loan_id), expected, ledger_balance));
+        }
+
+        let (available, _) = LedgerEngine::treasury_funds(&TreasuryType::Loan);
+        let reserved = LedgerEngine::balance(&LedgerAccount::TreasuryReserved(TreasuryType::Loan)).max(0) as Amount;
+        let expected = config.to_units(available + reserved)?.saturating_sub(held_in_subaccounts);
+        let account = Self::treasury_account();
+        let ledger_balance = Self::balance_of(config, account.clone()).await?;
+        accounts.insert(0, AccountReconciliation::new(account, None, expected, ledger_balance));
+
+        Ok(ReconciliationReport::new(config, accounts, now))
+    }
+
+    /// Repayments collected in a loan's subaccount less what was swept out
+    pub fn expected_subaccount_balance(transfers: &[&TokenTransfer]) -> u64 {
+        transfers.iter().fold(0u64, |balance, transfer| match transfer.kind {
+            TokenTransferKind::Repayment => balance + transfer.amount,
+            TokenTransferKind::Sweep => balance.saturating_sub(transfer.amount + transfer.fee),
+            TokenTransferKind::Disbursement => balance,
+        })
+    }
+
+    async fn transfer(
+        config: &TokenLedgerConfig,
+        from_subaccount: Option<Vec<u8>>,
+        to: Account,
+        units: u64,
+        memo: &str,
+        created_at: Timestamp,
+    ) -> StudiFiResult<u64> {
+        let args = TransferArg {
+            from_subaccount,
+            to,
+            amount: Nat::from(units),
+            fee: Some(Nat::from(config.transfer_fee)),
+            memo: Some(memo.as_bytes().to_vec()),
+            created_at_time: Some(created_at),
+        };
+        let result: Result<Nat, TransferError> = L::call(config, "icrc1_transfer", (args,)).await?;
+        block_index(result.map_err(TransferError::into_parts))
+    }
+
+    async fn balance_of(config: &TokenLedgerConfig, account: Account) -> StudiFiResult<u64> {
+        let balance: Nat = L::call(config, "icrc1_balance_of", (account,)).await?;
+        nat_to_u64(&balance)
+    }
+
+    /// Fees on transfers the canister sends come out of the loan treasury. The
+    /// entry is prepared before the transfer so that booking it afterwards cannot fail.
+    fn fee_entry(config: &TokenLedgerConfig, reference: &str) -> StudiFiResult<Option<PreparedEntry>> {
+        let fee = config.fee_in_cents();
+        if fee == 0 {
+            return Ok(None);
+        }
+        LedgerEngine::network_fee_entry(reference, fee).map(Some)
+    }
+
+    fn book_fee(entry: Option<PreparedEntry>, now: Timestamp) {
+        if let Some(entry) = entry {
+            LedgerEngine::post_prepared(entry, now);
+        }
+    }
+
+    fn record(transfer: TokenTransfer) -> TokenTransfer {
+        with_storage_mut(|storage| storage.insert_token_transfer(transfer.clone()));
+        transfer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::api::call::RejectionCode;
+    use std::collections::BTreeMap;
+
+    const TRANSACTION_WINDOW: Timestamp = 24 * 60 * 60 * 1_000_000_000;
+
+    /// In-memory ICRC-1/ICRC-2 ledger following the reference ledger's rules
+    /// for fees, allowances, the transaction window and deduplication
+    #[derive(Default)]
+    struct TestLedger {
+        now: Timestamp,
+        fee: u64,
+        balances: BTreeMap<(Principal, Vec<u8>), u64>,
+        allowances: BTreeMap<(Principal, Principal), u64>,
+        /// Block of each transaction that carried a creation time
+        transactions: BTreeMap<Vec<u8>, u64>,
+        blocks: u64,
+        /// Replies to drop after the call has executed, as when a reply is lost
+        lost_replies: u32,
+    }
+
+    thread_local! {
+        static LEDGER: RefCell<TestLedger> = RefCell::new(TestLedger::default());
+    }
+
+    fn account_key(account: &Account) -> (Principal, Vec<u8>) {
+        (account.owner, account.subaccount.clone().unwrap_or_else(|| vec![0; 32]))
+    }
+
+    impl TestLedger {
+        fn balance(&self, account: &Account) -> u64 {
+            self.balances.get(&account_key(account)).copied().unwrap_or_default()
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn execute(
+            &mut self,
+            transaction: Vec<u8>,
+            spender: Option<Principal>,
+            from: Account,
+            to: Account,
+            amount: &Nat,
+            fee: &Option<Nat>,
+            created_at_time: Option<u64>,
+        ) -> Result<Nat, TransferFromError> {
+            if let Some(created_at) = created_at_time {
+                if created_at + TRANSACTION_WINDOW < self.now {
+                    return Err(TransferFromError::TooOld);
+                }
+                if created_at > self.now {
+                    return Err(TransferFromError::CreatedInFuture { ledger_time: self.now });
+                }
+                if let Some(block) = self.transactions.get(&transaction) {
+                    return Err(TransferFromError::Duplicate { duplicate_of: Nat::from(*block) });
+                }
+            }
+            if fee.as_ref().is_some_and(|fee| fee.0 != self.fee.into()) {
+                return Err(TransferFromError::BadFee { expected_fee: Nat::from(self.fee) });
+            }
+
+            let amount = nat_to_u64(amount).unwrap();
+            let debit = amount + self.fee;
+            let allowance = spender.map(|spender| self.allowances.get(&(from.owner, spender)).copied().unwrap_or_default());
+            if let Some(allowance) = allowance.filter(|allowance| *allowance < debit) {
+                return Err(TransferFromError::InsufficientAllowance { allowance: Nat::from(allowance) });
+            }
+            let balance = self.balance(&from);
+            if balance < debit {
+                return Err(TransferFromError::InsufficientFunds { balance: Nat::from(balance) });
+            }
+
+            if let (Some(spender), Some(allowance)) = (spender, allowance) {
+                self.allowances.insert((from.owner, spender), allowance - debit);
+            }
+            self.balances.insert(account_key(&from), balance - debit);
+            *self.balances.entry(account_key(&to)).or_default() += amount;
+            let block = self.blocks;
+            self.blocks += 1;
+            if created_at_time.is_some() {
+                self.transactions.insert(transaction, block);
+            }
+            Ok(Nat::from(block))
+        }
+
+        fn handle(&mut self, method: &str, args: &[u8]) -> Vec<u8> {
+            let transaction = [method.as_bytes(), args].concat();
+            match method {
+                "icrc1_transfer" => {
+                    let arg: TransferArg = candid::decode_one(args).unwrap();
+                    let from = Account { owner: canister_id(), subaccount: arg.from_subaccount };
+                    let result = self
+                        .execute(transaction, None, from, arg.to, &arg.amount, &arg.fee, arg.created_at_time)
+                        .map_err(|e| match e {
+                            TransferFromError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+                            TransferFromError::InsufficientFunds { balance } => TransferError::InsufficientFunds { balance },
+                            TransferFromError::TooOld => TransferError::TooOld,
+                            TransferFromError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+                            TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+                            other => panic!("icrc1_transfer cannot fail with {:?}", other),
+                        });
+                    candid::encode_one(result).unwrap()
+                }
+                "icrc2_transfer_from" => {
+                    let arg: TransferFromArgs = candid::decode_one(args).unwrap();
+                    let result = self.execute(
+                        transaction, Some(canister_id()), arg.from, arg.to, &arg.amount, &arg.fee, arg.created_at_time,
+                    );
+                    candid::encode_one(result).unwrap()
+                }
+                "icrc1_balance_of" => {
+                    let account: Account = candid::decode_one(args).unwrap();
+                    candid::encode_one(Nat::from(self.balance(&account))).unwrap()
+                }
+                other => panic!("{} is not a ledger method", other),
+            }
+        }
+    }
+
+    /// Off-chain, the same calls and retries go to the in-memory ledger
+    struct InMemoryLedger;
+
+    impl LedgerTransport for InMemoryLedger {
+        fn canister_id() -> Principal {
+            canister_id()
+        }
+
+        async fn call<T, R>(config: &TokenLedgerConfig, method: &str, args: T) -> StudiFiResult<R>
+        where
+            T: ArgumentEncoder + Clone,
+            R: for<'de> Deserialize<'de> + CandidType,
+        {
+            call_with_retries(&config.canister_config(), method, ledger_time, |_| async {}, || {
+                let reply = ledger_call(method, args.clone());
+                async move { reply }
+            })
+            .await
+        }
+    }
+
+    type TokenEngine = TokenEngineWith<InMemoryLedger>;
+
+    fn canister_id() -> Principal {
+        Principal::from_slice(&[0xca, 0xfe])
+    }
+
+    fn ledger_time() -> Timestamp {
+        LEDGER.with(|ledger| ledger.borrow().now)
+    }
+
+    fn ledger_call<T, R>(method: &str, args: T) -> Result<R, (RejectionCode, String)>
+    where
+        T: ArgumentEncoder,
+        R: for<'de> Deserialize<'de> + CandidType,
+    {
+        let args = candid::encode_args(args).unwrap();
+        LEDGER.with(|ledger| {
+            let mut ledger = ledger.borrow_mut();
+            let reply = ledger.handle(method, &args);
+            if ledger.lost_replies > 0 {
+                ledger.lost_replies -= 1;
+                return Err((RejectionCode::SysTransient, "reply lost".to_string()));
+            }
+            Ok(candid::decode_one(&reply).unwrap())
+        })
+    }
+
+    fn with_ledger<T>(f: impl FnOnce(&mut TestLedger) -> T) -> T {
+        LEDGER.with(|ledger| f(&mut ledger.borrow_mut()))
+    }
+
+    /// Ledger calls are answered in the same message, so every future here completes on first poll
+    fn run<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        match future.as_mut().poll(&mut cx) {
+            std::task::Poll::Ready(output) => output,
+            std::task::Poll::Pending => panic!("ledger call suspended"),
+        }
+    }
+
+    fn config() -> TokenLedgerConfig {
+        TokenLedgerConfig {
+            ledger_canister_id: Principal::anonymous(),
+            token_symbol: "ckUSDC".to_string(),
+            units_per_cent: 10_000,
+            transfer_fee: 10_000,
+            is_active: true,
+            updated_at: 0,
+            updated_by: Principal::anonymous(),
+        }
+    }
+
+    fn transfer(kind: TokenTransferKind, amount: u64, fee: u64, block_index: u64) -> TokenTransfer {
+        TokenTransfer {
+            loan_id: "LOAN-1".to_string(),
+            kind,
+            reference: None,
+            amount,
+            fee,
+            block_index,
+            recorded_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_subaccounts_and_reconciliation() {
+        assert_eq!(TokenEngine::loan_subaccount("LOAN-1").len(), 32);
+        assert_eq!(TokenEngine::loan_subaccount("LOAN-1"), TokenEngine::loan_subaccount("LOAN-1"));
+        assert_ne!(TokenEngine::loan_subaccount("LOAN-1"), TokenEngine::loan_subaccount("LOAN-2"));
+
+        let config = TokenLedgerConfig {
+            ledger_canister_id: Principal::anonymous(),
+            token_symbol: "ckUSDC".to_string(),
+            units_per_cent: 10_000,
+            transfer_fee: 10_000,
+            is_active: true,
+            updated_at: 0,
+            updated_by: Principal::anonymous(),
+        };
+        assert_eq!(config.to_units(12_345).unwrap(), 123_450_000);
+        assert_eq!(config.fee_in_cents(), 1);
+        assert!(config.to_units(Amount::MAX).is_err());
+
+        // A sweep empties the subaccount less its fee; later repayments collect again
+        let transfers = [
+            transfer(TokenTransferKind::Disbursement, 5_000_000, 10_000, 1),
+            transfer(TokenTransferKind::Repayment, 500_000, 0, 2),
+            transfer(TokenTransferKind::Repayment, 300_000, 0, 3),
+            transfer(TokenTransferKind::Sweep, 790_000, 10_000, 4),
+            transfer(TokenTransferKind::Repayment, 200_000, 0, 5),
+        ];
+        let transfers: Vec<&TokenTransfer> = transfers.iter().collect();
+        assert_eq!(TokenEngine::expected_subaccount_balance(&transfers), 200_000);
+
+        let account = Account { owner: Principal::anonymous(), subaccount: None };
+        let report = ReconciliationReport::new(&config, vec![
+            AccountReconciliation::new(account.clone(), None, 1_000_000, 1_000_000),
+            AccountReconciliation::new(account, Some("LOAN-1".to_string()), 200_000, 150_000),
+        ], 0);
+        assert!(!report.is_reconciled);
+        assert_eq!(report.total_discrepancy, -50_000);
+
+        // A duplicate transfer resolves to the block of the original
+        assert_eq!(block_index(Err(("duplicate".to_string(), Some(Nat::from(7u64))))).unwrap(), 7);
+        assert!(block_index(Err(("TooOld".to_string(), None))).is_err());
+    }
+
+    #[test]
+    fn test_repayment_is_pulled_under_an_approval() {
+        let config = config();
+        let borrower = Principal::from_slice(&[1]);
+        let wallet = Account { owner: borrower, subaccount: None };
+        with_ledger(|ledger| {
+            ledger.now = 1_000;
+            ledger.fee = config.transfer_fee;
+            ledger.balances.insert(account_key(&wallet), 1_000_000);
+        });
+
+        // Without an approval the ledger refuses and nothing is recorded
+        let refused = run(TokenEngine::collect_repayment(&config, "LOAN-1", "PAY-1", borrower, 50, 1_000, 1_000));
+        assert!(matches!(&refused, Err(StudiFiError::InvalidInput(reason)) if reason.contains("approve this canister")));
+        assert!(with_storage(|storage| storage.get_token_transfers()).is_empty());
+
+        // The borrower bears the fee; the loan's subaccount receives the full payment
+        with_ledger(|ledger| ledger.allowances.insert((borrower, canister_id()), 510_000));
+        let transfer = run(TokenEngine::collect_repayment(&config, "LOAN-1", "PAY-1", borrower, 50, 1_000, 1_000)).unwrap();
+        assert_eq!((transfer.kind, transfer.amount, transfer.fee, transfer.block_index), (TokenTransferKind::Repayment, 500_000, 0, 0));
+        assert_eq!(with_ledger(|ledger| ledger.balance(&wallet)), 490_000);
+        assert_eq!(with_ledger(|ledger| ledger.balance(&TokenEngine::loan_account("LOAN-1"))), 500_000);
+        assert_eq!(with_storage(|storage| storage.get_token_transfers()).len(), 1);
+
+        // A lost reply is not a refusal, and retrying with the first attempt's time pulls the payment once
+        with_ledger(|ledger| {
+            ledger.allowances.insert((borrower, canister_id()), 1_000_000);
+            ledger.lost_replies = 3;
+        });
+        let lost = run(TokenEngine::collect_repayment(&config, "LOAN-1", "PAY-2", borrower, 20, 1_000, 1_000));
+        assert!(matches!(lost, Err(StudiFiError::NetworkError(_))));
+        let retried = run(TokenEngine::collect_repayment(&config, "LOAN-1", "PAY-2", borrower, 20, 1_000, 2_000)).unwrap();
+        assert_eq!(retried.block_index, 1);
+        assert_eq!(with_ledger(|ledger| ledger.balance(&wallet)), 280_000);
+    }
+
+    #[test]
+    fn test_retried_payout_is_paid_once() {
+        let config = config();
+        let student = Principal::from_slice(&[1]);
+        let wallet = Account { owner: student, subaccount: None };
+        with_ledger(|ledger| {
+            ledger.now = 1_000;
+            ledger.fee = config.transfer_fee;
+            ledger.balances.insert(account_key(&TokenEngine::treasury_account()), 100_000_000);
+        });
+
+        // The ledger executes the payout but every reply is lost, so the call fails
+        with_ledger(|ledger| ledger.lost_replies = 3);
+        let payout = TokenEngine::pay_out(&config, "LOAN-1", "DSB-1", Some(student), 5_000, 1_000, 1_000);
+        assert!(run(payout).is_err());
+        assert_eq!(with_ledger(|ledger| ledger.balance(&wallet)), 50_000_000);
+
+        // A later retry with the first attempt's time resolves to the original block
+        with_ledger(|ledger| ledger.now = 2_000);
+        let payout = TokenEngine::pay_out(&config, "LOAN-1", "DSB-1", Some(student), 5_000, 1_000, 2_000);
+        let transfer = run(payout).unwrap().unwrap();
+        assert_eq!((transfer.block_index, transfer.recorded_at), (0, 2_000));
+        assert_eq!(with_ledger(|ledger| ledger.balance(&wallet)), 50_000_000);
+        assert_eq!(with_ledger(|ledger| ledger.blocks), 1);
+
+        // Past the transaction window the ledger no longer deduplicates, so it refuses
+        with_ledger(|ledger| ledger.now = 1_000 + TRANSACTION_WINDOW + 1);
+        let payout = TokenEngine::pay_out(&config, "LOAN-1", "DSB-1", Some(student), 5_000, 1_000, 3_000);
+        assert!(run(payout).unwrap_err().to_string().contains("TooOld"));
+        assert_eq!(with_ledger(|ledger| ledger.balance(&wallet)), 50_000_000);
+    }
+
+    #[test]
+    fn test_books_reconcile_with_ledger() {
+        let config = config();
+        let loan = TreasuryType::Loan;
+        let borrower = Principal::from_slice(&[1]);
+        with_ledger(|ledger| {
+            ledger.now = 1_000;
+            ledger.fee = config.transfer_fee;
+            ledger.balances.insert(account_key(&TokenEngine::treasury_account()), 1_000_000_000);
+            ledger.balances.insert(account_key(&Account { owner: borrower, subaccount: None }), 100_000_000);
+            ledger.allowances.insert((borrower, canister_id()), 100_000_000);
+        });
+        LedgerEngine::record_contribution(&loan, 100_000, "test", 1).unwrap();
+
+        // Pay out a tranche, then collect a repayment into the loan's subaccount
+        LedgerEngine::record_allocation(&loan, 40_000, "LOAN-1", None, 2).unwrap();
+        LedgerEngine::post_prepared(LedgerEngine::disbursement_entry("LOAN-1", "DSB-1", 30_000).unwrap(), 3);
+        run(TokenEngine::pay_out(&config, "LOAN-1", "DSB-1", Some(borrower), 30_000, 1_000, 1_000)).unwrap();
+        let allocation = PaymentAllocation { principal: 5_000, ..Default::default() };
+        LedgerEngine::post_prepared(LedgerEngine::payment_entry("LOAN-1", "PAY-1", false, &allocation).unwrap(), 4);
+        run(TokenEngine::collect_repayment(&config, "LOAN-1", "PAY-1", borrower, 5_000, 1_000, 1_000)).unwrap();
+
+        let report = run(TokenEngine::reconcile(&config, 5)).unwrap();
+        assert!(report.is_reconciled, "{:?}", report.accounts);
+        assert_eq!(report.accounts[1].ledger_balance, 50_000_000);
+
+        // After the sweep the subaccount is empty and its fee is on the books
+        let sweep = run(TokenEngine::sweep(&config, "LOAN-1", 1_000)).unwrap().unwrap();
+        assert_eq!(sweep.amount, 49_990_000);
+        let report = run(TokenEngine::reconcile(&config, 6)).unwrap();
+        assert!(report.is_reconciled, "{:?}", report.accounts);
+        assert_eq!(report.accounts[1].ledger_balance, 0);
+
+        // Tokens moved without a booking show up as a discrepancy
+        with_ledger(|ledger| *ledger.balances.entry(account_key(&TokenEngine::treasury_account())).or_default() -= 10_000);
+        let report = run(TokenEngine::reconcile(&config, 7)).unwrap();
+        assert!(!report.is_reconciled);
+        assert_eq!(report.total_discrepancy, -10_000);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::types::*;
 use crate::storage::*;
+use crate::ledger::*;
 use shared::*;
 
 /// Multi-treasury management engine for separated fund allocation
@@ -19,7 +20,8 @@ impl Default for TreasuryType {
     }
 }
 
-/// Individual treasury configuration
+/// Individual treasury configuration. The fund amounts are derived from the
+/// general ledger whenever the config is read through `TreasuryEngine`.
 #[derive(candid::CandidType, candid::Deserialize, Clone, Debug, serde::Serialize)]
 pub struct SeparateTreasuryConfig {
     pub treasury_type: TreasuryType,
@@ -114,9 +116,11 @@ impl TreasuryEngine {
 
         // Check allocation ratio
         let new_reserved = loan_treasury.reserved_funds + loan_amount;
-        let allocation_ratio = new_reserved as f64 / loan_treasury.total_funds as f64;
+        let max_allocation = apply_percentage(
+            loan_treasury.total_funds, loan_treasury.maximum_allocation_ratio, RoundingMode::Down
+        )?;
 
-        if allocation_ratio > loan_treasury.maximum_allocation_ratio {
+        if new_reserved > max_allocation {
             return Err(StudiFiError::InsufficientFunds(
                 "Loan would exceed maximum allocation ratio for loan treasury".to_string()
             ));
@@ -124,9 +128,11 @@ impl TreasuryEngine {
 
         // Check reserve requirements
         let remaining_funds = loan_treasury.available_funds - loan_amount;
-        let reserve_ratio = remaining_funds as f64 / loan_treasury.total_funds as f64;
+        let minimum_reserve = apply_percentage(
+            loan_treasury.total_funds, loan_treasury.minimum_reserve_ratio, RoundingMode::Up
+        )?;
 
-        if reserve_ratio < loan_treasury.minimum_reserve_ratio {
+        if remaining_funds < minimum_reserve {
             return Err(StudiFiError::InsufficientFunds(
                 "Loan would violate minimum reserve requirements".to_string()
             ));
@@ -135,12 +141,29 @@ impl TreasuryEngine {
         Ok(())
     }
 
-    /// Get treasury configuration for a specific treasury type
+    /// Get treasury configuration for a specific treasury type, with its funds taken from the ledger
     pub fn get_treasury_config(treasury_type: TreasuryType) -> StudiFiResult<SeparateTreasuryConfig> {
         with_storage(|storage| {
             storage.get_separate_treasury_config(&treasury_type)
                 .ok_or_else(|| StudiFiError::NotFound(format!("Treasury {:?} not found", treasury_type)))
         })
+        .map(Self::with_ledger_funds)
+    }
+
+    /// Every treasury configuration, with funds taken from the ledger
+    pub fn get_all_treasury_configs() -> Vec<SeparateTreasuryConfig> {
+        with_storage(|storage| storage.get_all_separate_treasuries())
+            .into_iter()
+            .map(Self::with_ledger_funds)
+            .collect()
+    }
+
+    fn with_ledger_funds(mut config: SeparateTreasuryConfig) -> SeparateTreasuryConfig {
+        let (available, reserved) = LedgerEngine::treasury_funds(&config.treasury_type);
+        config.available_funds = available;
+        config.reserved_funds = reserved;
+        config.total_funds = available + reserved;
+        config
     }
 
     /// Initialize all treasury types with default configurations
@@ -179,9 +202,11 @@ impl TreasuryEngine {
 
         // Check allocation limits
         let new_reserved = treasury.reserved_funds + amount;
-        let allocation_ratio = new_reserved as f64 / treasury.total_funds as f64;
+        let max_allocation = apply_percentage(
+            treasury.total_funds, treasury.maximum_allocation_ratio, RoundingMode::Down
+        )?;
 
-        if allocation_ratio > treasury.maximum_allocation_ratio {
+        if new_reserved > max_allocation {
             return Err(StudiFiError::InsufficientFunds(
                 format!("Allocation would exceed maximum ratio for {:?} treasury", treasury_type)
             ));
@@ -189,9 +214,11 @@ impl TreasuryEngine {
 
         // Check reserve requirements
         let remaining_funds = treasury.available_funds - amount;
-        let reserve_ratio = remaining_funds as f64 / treasury.total_funds as f64;
+        let minimum_reserve = apply_percentage(
+            treasury.total_funds, treasury.minimum_reserve_ratio, RoundingMode::Up
+        )?;
 
-        if reserve_ratio < treasury.minimum_reserve_ratio {
+        if remaining_funds < minimum_reserve {
             return Err(StudiFiError::InsufficientFunds(
                 format!("Allocation would violate reserve requirements for {:?} treasury", treasury_type)
             ));
@@ -201,78 +228,60 @@ impl TreasuryEngine {
     }
 
     /// Allocate funds for a new loan from the loan treasury
-    pub fn allocate_loan_funds(loan_amount: Amount) -> StudiFiResult<()> {
+    pub fn allocate_loan_funds(loan_id: &str, loan_amount: Amount) -> StudiFiResult<()> {
         Self::check_loan_eligibility(loan_amount)?;
 
-        with_storage_mut(|storage| {
-            let mut loan_treasury = storage.get_separate_treasury_config(&TreasuryType::Loan)
-                .unwrap_or_else(|| SeparateTreasuryConfig::new(TreasuryType::Loan));
-
-            loan_treasury.available_funds -= loan_amount;
-            loan_treasury.reserved_funds += loan_amount;
-            loan_treasury.last_updated = current_time();
-
-            storage.set_separate_treasury_config(loan_treasury);
-        });
+        LedgerEngine::record_allocation(
+            &TreasuryType::Loan,
+            loan_amount,
+            &format!("loan {}", loan_id),
+            Some(loan_id.to_string()),
+            current_time(),
+        )?;
+        Self::touch(&TreasuryType::Loan);
 
         ic_cdk::println!("Allocated {} from loan treasury", format_currency(loan_amount));
         Ok(())
     }
 
-    /// Allocate funds from a specific treasury (with governance check)
+    /// Allocate funds from a specific treasury (with governance check). Returns the journal entry posted.
     pub fn allocate_treasury_funds(
         treasury_type: TreasuryType,
         amount: Amount,
         purpose: String,
         governance_approved: bool,
-    ) -> StudiFiResult<()> {
+    ) -> StudiFiResult<JournalEntry> {
         Self::check_allocation_eligibility(treasury_type.clone(), amount, governance_approved)?;
 
-        with_storage_mut(|storage| {
-            let mut treasury = storage.get_separate_treasury_config(&treasury_type)
-                .unwrap_or_else(|| SeparateTreasuryConfig::new(treasury_type.clone()));
-
-            treasury.available_funds -= amount;
-            treasury.reserved_funds += amount;
-            treasury.last_updated = current_time();
-
-            storage.set_separate_treasury_config(treasury);
-        });
+        let entry = LedgerEngine::record_allocation(&treasury_type, amount, &purpose, None, current_time())?;
+        Self::touch(&treasury_type);
 
         ic_cdk::println!("Allocated {} from {:?} treasury for {}",
                         format_currency(amount), treasury_type, purpose);
-        Ok(())
+        Ok(entry)
     }
 
-    /// Add funds to a specific treasury
+    /// Add funds to a specific treasury. Returns the journal entry posted.
     pub fn add_treasury_funds(
         treasury_type: TreasuryType,
         amount: Amount,
         source: String,
-    ) -> StudiFiResult<()> {
-        with_storage_mut(|storage| {
-            let mut treasury = storage.get_separate_treasury_config(&treasury_type)
-                .unwrap_or_else(|| SeparateTreasuryConfig::new(treasury_type.clone()));
-
-            treasury.total_funds += amount;
-            treasury.available_funds += amount;
-            treasury.last_updated = current_time();
-
-            storage.set_separate_treasury_config(treasury);
-        });
+    ) -> StudiFiResult<JournalEntry> {
+        let entry = LedgerEngine::record_contribution(&treasury_type, amount, &source, current_time())?;
+        Self::touch(&treasury_type);
 
         ic_cdk::println!("Added {} to {:?} treasury from {}",
                         format_currency(amount), treasury_type, source);
-        Ok(())
+        Ok(entry)
     }
 
-    /// Transfer funds between treasuries (requires governance approval)
+    /// Transfer funds between treasuries (requires governance approval). Returns the journal entry posted.
     pub fn transfer_between_treasuries(
         from_treasury: TreasuryType,
         to_treasury: TreasuryType,
         amount: Amount,
         governance_approved: bool,
-    ) -> StudiFiResult<()> {
+    ) -> StudiFiResult<JournalEntry> {
         if !governance_approved {
             return Err(StudiFiError::Unauthorized(
                 "Governance approval required for inter-treasury transfers".to_string()
@@ -288,141 +297,124 @@ impl TreasuryEngine {
         }
 
         // Perform transfer
-        with_storage_mut(|storage| {
-            // Deduct from source treasury
-            let mut from_config = storage.get_separate_treasury_config(&from_treasury)
-                .unwrap();
-            from_config.total_funds -= amount;
-            from_config.available_funds -= amount;
-            from_config.last_updated = current_time();
-            storage.set_separate_treasury_config(from_config);
-
-            // Add to destination treasury
-            let mut to_config = storage.get_separate_treasury_config(&to_treasury)
-                .unwrap_or_else(|| SeparateTreasuryConfig::new(to_treasury.clone()));
-            to_config.total_funds += amount;
-            to_config.available_funds += amount;
-            to_config.last_updated = current_time();
-            storage.set_separate_treasury_config(to_config);
-        });
+        let entry = LedgerEngine::record_transfer(&from_treasury, &to_treasury, amount, current_time())?;
+        Self::touch(&from_treasury);
+        Self::touch(&to_treasury);
 
         ic_cdk::println!("Transferred {} from {:?} to {:?} treasury",
                         format_currency(amount), from_treasury, to_treasury);
-        Ok(())
+        Ok(entry)
     }
 
-    /// Process a loan payment and update loan treasury
-    pub fn process_payment_to_treasury(
-        principal_portion: Amount,
-        interest_portion: Amount,
-        late_fee: Amount,
-    ) -> StudiFiResult<()> {
-        with_storage_mut(|storage| {
-            let mut loan_treasury = storage.get_separate_treasury_config(&TreasuryType::Loan)
-                .unwrap_or_else(|| SeparateTreasuryConfig::new(TreasuryType::Loan));
-
-            // Principal portion goes back to available funds
-            loan_treasury.available_funds += principal_portion;
-            loan_treasury.reserved_funds -= principal_portion;
-
-            // Interest and fees increase total treasury (revenue)
-            loan_treasury.total_funds += interest_portion + late_fee;
-            loan_treasury.available_funds += interest_portion + late_fee;
-            loan_treasury.last_updated = current_time();
+    /// Journal entry for a loan payment into the loan treasury. `loan` is the loan
+    /// as it stood before the payment. It is prepared before the funds are
+    /// collected, so booking them afterwards cannot fail.
+    pub fn prepare_payment_to_treasury(
+        loan: &Loan,
+        payment_id: &str,
+        allocation: &PaymentAllocation,
+    ) -> StudiFiResult<PreparedEntry> {
+        let charged_off = loan.status == LoanStatus::Default;
+        LedgerEngine::payment_entry(&loan.id, payment_id, charged_off, allocation)
+    }
 
-            storage.set_separate_treasury_config(loan_treasury);
-        });
+    /// Book a payment prepared with `prepare_payment_to_treasury`
+    pub fn process_payment_to_treasury(entry: PreparedEntry, now: Timestamp) {
+        let entry = LedgerEngine::post_prepared(entry, now);
+        Self::touch(&TreasuryType::Loan);
 
-        ic_cdk::println!(
-            "Processed payment to loan treasury: principal={}, interest={}, late_fee={}",
-            format_currency(principal_portion),
-            format_currency(interest_portion),
-            format_currency(late_fee)
-        );
-        Ok(())
+        ic_cdk::println!("Processed payment to loan treasury: {} ({})", entry.description, entry.id);
     }
 
-    /// Return funds to treasury when allocation is completed/cancelled
+    /// Release reserved funds back to the treasury when an allocation is cancelled
     pub fn return_treasury_funds(
         treasury_type: TreasuryType,
         amount: Amount,
-        revenue: Amount, // Additional revenue generated (e.g., interest)
+        reference: Option<String>,
     ) -> StudiFiResult<()> {
-        with_storage_mut(|storage| {
-            let mut treasury = storage.get_separate_treasury_config(&treasury_type)
-                .unwrap_or_else(|| SeparateTreasuryConfig::new(treasury_type.clone()));
+        LedgerEngine::record_release(&treasury_type, amount, reference, current_time())?;
+        Self::touch(&treasury_type);
 
-            // Return principal to available funds
-            treasury.available_funds += amount;
-            treasury.reserved_funds -= amount;
-
-            // Add any revenue generated
-            if revenue > 0 {
-                treasury.total_funds += revenue;
-                treasury.available_funds += revenue;
-            }
+        ic_cdk::println!("Returned {} to {:?} treasury", format_currency(amount), treasury_type);
+        Ok(())
+    }
 
-            treasury.last_updated = current_time();
-            storage.set_separate_treasury_config(treasury);
-        });
+    /// Provide for and charge off a defaulted loan's balance, accrued interest and fees
+    pub fn handle_loan_default(loan: &Loan) -> StudiFiResult<()> {
+        let fees = with_storage(|storage| storage.get_outstanding_fees(&loan.id));
+        LedgerEngine::record_default(loan, fees, current_time())?;
+        Self::touch(&TreasuryType::Loan);
 
-        ic_cdk::println!("Returned {} to {:?} treasury with {} revenue",
-                        format_currency(amount), treasury_type, format_currency(revenue));
+        ic_cdk::println!("Handled loan default: {}", format_currency(loan.total_owed() + fees));
         Ok(())
     }
 
-    /// Handle loan default and update treasury
-    pub fn handle_loan_default(remaining_balance: Amount) -> StudiFiResult<()> {
+    /// Stamp a treasury's config after a movement; its amounts live in the ledger
+    fn touch(treasury_type: &TreasuryType) {
         with_storage_mut(|storage| {
-            let mut config = storage.get_treasury_config();
-            
-            // Remove defaulted amount from reserved funds
-            config.reserved_funds = config.reserved_funds.saturating_sub(remaining_balance);
-            config.total_funds = config.total_funds.saturating_sub(remaining_balance);
-            
-            storage.set_treasury_config(config);
+            if let Some(mut config) = storage.get_separate_treasury_config(treasury_type) {
+                config.last_updated = current_time();
+                storage.set_separate_treasury_config(config);
+            }
         });
+    }
 
-        ic_cdk::println!("Handled loan default: {}", format_currency(remaining_balance));
-        Ok(())
+    /// Platform treasury policy, with its funds taken from the loan treasury's ledger accounts
+    pub fn get_platform_treasury_config() -> TreasuryConfig {
+        let mut config = with_storage(|storage| storage.get_treasury_config());
+        let (available, reserved) = LedgerEngine::treasury_funds(&TreasuryType::Loan);
+        config.available_funds = available;
+        config.reserved_funds = reserved;
+        config.total_funds = available + reserved;
+        config
     }
 
-    /// Rebalance treasury funds based on current portfolio
-    pub fn rebalance_treasury() -> StudiFiResult<()> {
-        let (config, stats) = with_storage(|storage| {
-            (storage.get_treasury_config(), storage.calculate_treasury_stats())
-        });
+    /// Store the platform treasury policy. Fund fields are derived from the
+    /// ledger, so whatever the caller sent for them is discarded.
+    pub fn update_platform_treasury_config(mut config: TreasuryConfig) {
+        config.total_funds = 0;
+        config.available_funds = 0;
+        config.reserved_funds = 0;
+        with_storage_mut(|storage| storage.set_treasury_config(config));
+    }
 
+    /// Check the loan treasury's ledger funds against the reserve policy. Funds
+    /// only move through journal entries, so this reports a shortfall rather
+    /// than rewriting balances.
+    pub fn rebalance_treasury() -> StudiFiResult<()> {
+        let config = Self::get_platform_treasury_config();
         if !config.auto_rebalance_enabled {
             return Ok(());
         }
 
-        let mut new_config = config.clone();
-        
-        // Calculate optimal fund allocation
-        let target_reserve = (new_config.total_funds as f64 * new_config.minimum_reserve_ratio) as Amount;
-        let target_emergency = (new_config.total_funds as f64 * new_config.emergency_fund_ratio) as Amount;
-        let target_interest_reserve = (new_config.total_funds as f64 * new_config.interest_reserve_ratio) as Amount;
-        
-        let total_reserves_needed = target_reserve + target_emergency + target_interest_reserve;
-        let available_for_loans = new_config.total_funds.saturating_sub(total_reserves_needed);
-        
-        // Update configuration
-        new_config.available_funds = available_for_loans.saturating_sub(stats.total_loans_outstanding);
-        new_config.reserved_funds = stats.total_loans_outstanding;
-        new_config.last_rebalance = current_time();
+        let total_reserves_needed = [
+            config.minimum_reserve_ratio,
+            config.emergency_fund_ratio,
+            config.interest_reserve_ratio,
+        ]
+        .into_iter()
+        .map(|ratio| apply_percentage(config.total_funds, ratio, RoundingMode::Up))
+        .sum::<StudiFiResult<Amount>>()?;
+
+        if config.available_funds < total_reserves_needed {
+            ic_cdk::println!(
+                "Loan treasury below reserve policy: {} available, {} required",
+                config.available_funds,
+                total_reserves_needed
+            );
+        }
 
         with_storage_mut(|storage| {
-            storage.set_treasury_config(new_config);
+            let mut stored = storage.get_treasury_config();
+            stored.last_rebalance = current_time();
+            storage.set_treasury_config(stored);
         });
-
-        ic_cdk::println!("Treasury rebalanced successfully");
         Ok(())
     }
 
     /// Get current treasury health metrics
     pub fn get_treasury_health() -> TreasuryHealth {
-        let config = with_storage(|storage| storage.get_treasury_config());
+        let config = Self::get_platform_treasury_config();
         let stats = with_storage(|storage| storage.calculate_treasury_stats());
 
         let reserve_ratio = if config.total_funds > 0 {
@@ -511,8 +503,9 @@ impl TreasuryEngine {
     fn generate_recommendations(config: &TreasuryConfig, stats: &TreasuryStats) -> Vec<String> {
         let mut recommendations = Vec::new();
 
-        let reserve_ratio = config.available_funds as f64 / config.total_funds as f64;
-        let loan_to_fund_ratio = stats.total_loans_outstanding as f64 / config.total_funds as f64;
+        let total_funds = config.total_funds.max(1) as f64;
+        let reserve_ratio = config.available_funds as f64 / total_funds;
+        let loan_to_fund_ratio = stats.total_loans_outstanding as f64 / total_funds;
 
         if reserve_ratio < 0.15 {
             recommendations.push("Increase treasury reserves to maintain liquidity".to_string());
@@ -1,6 +1,11 @@
 use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
 use shared::*;
+use crate::amortization::{AmortizationSchedule, OverpaymentPreference};
+use crate::disbursement::Disbursement;
+use crate::agreement::LoanAgreement;
+use crate::fees::FeeReceivable;
+use crate::accrual::{DayCountConvention, InterestAccrual};
 
 /// Active loan with comprehensive tracking
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
@@ -8,6 +13,7 @@ pub struct Loan {
     pub id: String,
     pub student_id: Principal,
     pub original_amount: Amount,
+    /// Outstanding principal; unpaid interest is tracked in `accrued_interest`
     pub current_balance: Amount,
     pub interest_rate: Percentage,
     pub term_months: u32,
@@ -24,6 +30,26 @@ pub struct Loan {
     pub collateral_required: bool,
     pub cosigner_id: Option<Principal>,
     pub special_conditions: Vec<String>,
+    /// When the final tranche settled and repayment terms took effect
+    #[serde(default)]
+    pub activated_at: Option<Timestamp>,
+    /// Borrower's choice for payments beyond what is currently due
+    #[serde(default)]
+    pub overpayment_preference: OverpaymentPreference,
+    #[serde(default)]
+    pub day_count_convention: DayCountConvention,
+    /// Interest accrued and not yet paid
+    #[serde(default)]
+    pub accrued_interest: Amount,
+    /// Start of the last calendar day interest was accrued for
+    #[serde(default)]
+    pub last_accrual_date: Option<Timestamp>,
+    /// Interest below a cent, in millionths of a cent, carried into the next day's accrual
+    #[serde(default)]
+    pub accrual_remainder: u64,
+    /// Credit application the loan was created from; repeated requests for it return this loan
+    #[serde(default)]
+    pub application_id: Option<String>,
 }
 
 impl Loan {
@@ -42,7 +68,6 @@ impl Loan {
         special_conditions: Vec<String>,
     ) -> Self {
         let now = current_time();
-        let grace_period_nanos = months_to_nanos(grace_period_months);
 
         Self {
             id,
@@ -54,9 +79,9 @@ impl Loan {
             monthly_payment,
             grace_period_months,
             origination_fee,
-            status: LoanStatus::Active,
+            status: LoanStatus::PendingSignature,
             created_at: now,
-            first_payment_due: now + grace_period_nanos,
+            first_payment_due: add_months(now, grace_period_months),
             last_payment_date: None,
             payments_made: 0,
             late_payments: 0,
@@ -64,18 +89,57 @@ impl Loan {
             collateral_required,
             cosigner_id,
             special_conditions,
+            activated_at: None,
+            overpayment_preference: OverpaymentPreference::default(),
+            day_count_convention: DayCountConvention::default(),
+            accrued_interest: 0,
+            last_accrual_date: None,
+            accrual_remainder: 0,
+            application_id: None,
         }
     }
 
-    /// Calculate next payment due date
+    /// Calculate next payment due date (same day of each calendar month as the first payment).
+    /// `payments_made` counts settled installments, so partial payments do not move it.
     pub fn next_payment_due(&self) -> Timestamp {
+        add_months(self.first_payment_due, self.payments_made)
+    }
+
+    /// Start of the current payment period (the previous due date, or activation)
+    pub fn current_period_start(&self) -> Timestamp {
         if self.payments_made == 0 {
-            self.first_payment_due
+            self.activated_at.unwrap_or(self.created_at)
         } else {
-            self.first_payment_due + months_to_nanos(self.payments_made)
+            add_months(self.first_payment_due, self.payments_made - 1)
         }
     }
 
+    /// First day interest accrues when none has been recorded: activation, since
+    /// interest accrues through the grace period, or the current period for loans
+    /// that were already being repaid.
+    pub fn accrual_start(&self) -> Timestamp {
+        self.current_period_start()
+    }
+
+    /// Days on which interest accrued during the grace period is added to principal:
+    /// each monthly anniversary of activation before the first payment period
+    pub fn grace_capitalization_dates(&self) -> Vec<Timestamp> {
+        let activated_at = self.activated_at.unwrap_or(self.created_at);
+        (1..self.grace_period_months)
+            .map(|month| add_months(activated_at, month))
+            .collect()
+    }
+
+    /// Principal the repayment schedule amortizes, with grace-period interest capitalized
+    pub fn amortized_principal(&self) -> StudiFiResult<Amount> {
+        capitalize_grace_interest(self.original_amount, self.interest_rate, self.grace_period_months)
+    }
+
+    /// Principal plus accrued interest
+    pub fn total_owed(&self) -> Amount {
+        self.current_balance + self.accrued_interest
+    }
+
     /// Check if payment is overdue
     pub fn is_overdue(&self) -> bool {
         if self.status != LoanStatus::Active {
@@ -93,11 +157,34 @@ impl Loan {
         overdue_nanos / days_to_nanos(1)
     }
 
-    /// Calculate total interest paid so far
-    pub fn total_interest_paid(&self) -> Amount {
-        let total_paid = self.monthly_payment * self.payments_made as u64;
-        let principal_paid = self.original_amount - self.current_balance;
-        total_paid.saturating_sub(principal_paid)
+    /// Loan tape row for regulatory reporting. Unlike `days_overdue`, late and
+    /// defaulted loans keep counting days past due.
+    pub fn to_tape_record(&self, now: Timestamp) -> LoanTapeRecord {
+        let next_payment_due = self.next_payment_due();
+        let in_repayment = matches!(self.status, LoanStatus::Active | LoanStatus::Late | LoanStatus::Default);
+        let days_past_due = if in_repayment && now > next_payment_due {
+            (now - next_payment_due) / days_to_nanos(1)
+        } else {
+            0
+        };
+
+        LoanTapeRecord {
+            loan_id: self.id.clone(),
+            student_id: self.student_id,
+            cosigner_id: self.cosigner_id,
+            status: self.status.clone(),
+            original_amount: self.original_amount,
+            current_balance: self.current_balance,
+            interest_rate: self.interest_rate,
+            term_months: self.term_months,
+            monthly_payment: self.monthly_payment,
+            payments_made: self.payments_made,
+            late_payments: self.late_payments,
+            days_past_due,
+            originated_at: self.created_at,
+            next_payment_due,
+            last_payment_date: self.last_payment_date,
+        }
     }
 
     /// Calculate remaining term in months
@@ -105,10 +192,11 @@ impl Loan {
         self.term_months.saturating_sub(self.payments_made)
     }
 
-    /// Calculate total interest that will be paid over the life of the loan
-    pub fn calculate_total_interest(&self) -> Amount {
-        let total_payments = self.monthly_payment * self.term_months as u64;
-        total_payments.saturating_sub(self.original_amount)
+    /// Calculate total interest that will be paid over the life of the loan, capitalized grace interest included
+    pub fn calculate_total_interest(&self) -> StudiFiResult<Amount> {
+        let schedule = build_amortization_schedule(self.amortized_principal()?, self.interest_rate, self.term_months)?;
+        let total_payments: Amount = schedule.iter().map(|line| line.payment).sum();
+        Ok(total_payments.saturating_sub(self.original_amount))
     }
 }
 
@@ -132,18 +220,6 @@ impl Identifiable for Loan {
     }
 }
 
-/// Loan status enumeration
-#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
-pub enum LoanStatus {
-    Active,
-    Late,
-    Default,
-    PaidOff,
-    Deferred,
-    InGracePeriod,
-    Cancelled,
-}
-
 /// Payment record for tracking all transactions
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct Payment {
@@ -161,6 +237,10 @@ pub struct Payment {
     pub processed_at: Option<Timestamp>,
     pub transaction_hash: Option<String>,
     pub notes: String,
+    /// Time of the first attempt to collect the payment on the token ledger, reused
+    /// as the transfer's creation time so a retry is deduplicated by the ledger
+    #[serde(default)]
+    pub collection_attempted_at: Option<Timestamp>,
 }
 
 impl Payment {
@@ -190,6 +270,7 @@ impl Payment {
             processed_at: None,
             transaction_hash: None,
             notes: String::new(),
+            collection_attempted_at: None,
         }
     }
 }
@@ -222,12 +303,14 @@ pub enum PaymentType {
     LatePayment,
     PartialPayment,
     FullPayoff,
+    /// Superseded by fee receivables; kept for records made before them
     LateFee,
 }
 
 /// Payment method enumeration
 #[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
 pub enum PaymentMethod {
+    /// Tokens on the configured ICRC ledger, pulled from the payer under an ICRC-2 approval
     ICP,
     BankTransfer,
     CreditCard,
@@ -245,7 +328,8 @@ pub enum PaymentStatus {
     Refunded,
 }
 
-/// Treasury configuration for fund management
+/// Treasury configuration for fund management. The fund fields mirror the
+/// loan treasury's ledger accounts and are only filled in when read.
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct TreasuryConfig {
     pub total_funds: Amount,
@@ -262,9 +346,9 @@ pub struct TreasuryConfig {
 impl Default for TreasuryConfig {
     fn default() -> Self {
         Self {
-            total_funds: 1_000_000_00, // $1M initial treasury
-            available_funds: 800_000_00, // $800K available for loans
-            reserved_funds: 200_000_00, // $200K reserved
+            total_funds: 0,
+            available_funds: 0,
+            reserved_funds: 0,
             minimum_reserve_ratio: 0.15, // 15% minimum reserve
             maximum_loan_to_fund_ratio: 0.80, // 80% max loan-to-fund ratio
             interest_reserve_ratio: 0.05, // 5% for interest payments
@@ -288,3 +372,17 @@ pub struct TreasuryStats {
     pub average_loan_size: Amount,
     pub portfolio_yield: Percentage,
 }
+
+/// Everything this service holds about a data subject, for export
+#[derive(Clone, Debug, Serialize)]
+pub struct LoanSubjectData {
+    /// Loans the subject borrowed or cosigned
+    pub loans: Vec<Loan>,
+    pub payments: Vec<Payment>,
+    pub schedules: Vec<AmortizationSchedule>,
+    pub pending_aml_reports: Vec<AmlTransactionReport>,
+    pub disbursements: Vec<Disbursement>,
+    pub agreements: Vec<LoanAgreement>,
+    pub fees: Vec<FeeReceivable>,
+    pub interest_accruals: Vec<InterestAccrual>,
+}
@@ -0,0 +1,239 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+use crate::types::*;
+use crate::storage::*;
+use crate::amortization::*;
+use shared::*;
+
+/// Where part of a payment can go
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum AllocationBucket {
+    /// Outstanding fee receivables, oldest first
+    Fees,
+    /// Interest accrued and not yet paid
+    AccruedInterest,
+    /// Principal of the installments currently due
+    CurrentPrincipal,
+    /// Principal beyond the installments currently due
+    Prepayment,
+}
+
+/// Order used for products without their own configuration
+pub const DEFAULT_ALLOCATION_ORDER: [AllocationBucket; 4] = [
+    AllocationBucket::Fees,
+    AllocationBucket::AccruedInterest,
+    AllocationBucket::CurrentPrincipal,
+    AllocationBucket::Prepayment,
+];
+
+/// Allocation order for one loan product. Products are keyed by the loan's
+/// purpose (e.g. "Tuition"); "default" applies to every other loan.
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct PaymentWaterfallConfig {
+    pub product: String,
+    pub order: Vec<AllocationBucket>,
+    pub updated_at: Timestamp,
+    pub updated_by: Principal,
+}
+
+/// How a payment would be split across the waterfall
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct PaymentBreakdown {
+    pub total_amount: Amount,
+    /// Fees plus the interest and principal of the installments currently due
+    pub amount_due: Amount,
+    pub fee_portion: Amount,
+    pub interest_portion: Amount,
+    pub principal_portion: Amount,
+    pub prepayment_portion: Amount,
+    /// Amount left after every bucket is satisfied
+    pub unapplied_amount: Amount,
+    pub remaining_balance: Amount,
+    pub remaining_interest: Amount,
+    pub remaining_fees: Amount,
+    pub allocation_order: Vec<AllocationBucket>,
+}
+
+impl PaymentBreakdown {
+    /// Everything that reduces the loan balance
+    pub fn total_principal(&self) -> Amount {
+        self.principal_portion + self.prepayment_portion
+    }
+
+    /// The part applied to the installments currently due
+    pub fn scheduled_portion(&self) -> Amount {
+        self.interest_portion + self.principal_portion
+    }
+
+    /// Whether the payment leaves part of what is due unpaid
+    pub fn is_partial(&self) -> bool {
+        self.fee_portion + self.scheduled_portion() < self.amount_due
+    }
+}
+
+/// What the borrower owes in each bucket before a payment
+#[derive(Clone, Debug)]
+struct AmountsOwed {
+    fees: Amount,
+    interest: Amount,
+    current_principal: Amount,
+    balance: Amount,
+}
+
+/// Payment allocation across fees, interest and principal
+pub struct PaymentWaterfall;
+
+impl PaymentWaterfall {
+    pub fn get_config(product: &str) -> Option<PaymentWaterfallConfig> {
+        with_storage(|storage| storage.get_waterfall_config(product))
+    }
+
+    /// The product's order, falling back to the "default" product and then the built-in order
+    pub fn order_for(product: &str) -> Vec<AllocationBucket> {
+        Self::get_config(product)
+            .or_else(|| Self::get_config("default"))
+            .map_or_else(|| DEFAULT_ALLOCATION_ORDER.to_vec(), |config| config.order)
+    }
+
+    pub fn set_order(
+        product: String,
+        order: Vec<AllocationBucket>,
+        updated_by: Principal,
+        now: Timestamp,
+    ) -> StudiFiResult<PaymentWaterfallConfig> {
+        let product = product.trim().to_string();
+        if product.is_empty() {
+            return Err(StudiFiError::InvalidInput("Product name is required".to_string()));
+        }
+        Self::validate_order(&order)?;
+
+        let config = PaymentWaterfallConfig {
+            product,
+            order,
+            updated_at: now,
+            updated_by,
+        };
+        with_storage_mut(|storage| storage.set_waterfall_config(config.clone()));
+        Ok(config)
+    }
+
+    /// Split `amount` across what the loan owes as of `now`, in its product's order.
+    /// `loan` should have been accrued up to `now`.
+    pub fn breakdown(loan: &Loan, amount: Amount, now: Timestamp) -> StudiFiResult<PaymentBreakdown> {
+        // Interest is what has actually accrued; the installments due set how much
+        // principal is due with it
+        let (scheduled_interest, scheduled_principal) = AmortizationEngine::amounts_due(&loan.id, now)?;
+        let owed = AmountsOwed {
+            fees: with_storage(|storage| storage.get_outstanding_fees(&loan.id)),
+            interest: loan.accrued_interest,
+            current_principal: (scheduled_interest + scheduled_principal)
+                .saturating_sub(loan.accrued_interest)
+                .min(loan.current_balance),
+            balance: loan.current_balance,
+        };
+        Ok(Self::allocate(amount, &owed, Self::order_for(&loan.purpose)))
+    }
+
+    fn allocate(amount: Amount, owed: &AmountsOwed, order: Vec<AllocationBucket>) -> PaymentBreakdown {
+        let mut breakdown = PaymentBreakdown {
+            total_amount: amount,
+            amount_due: owed.fees + owed.interest + owed.current_principal,
+            fee_portion: 0,
+            interest_portion: 0,
+            principal_portion: 0,
+            prepayment_portion: 0,
+            unapplied_amount: 0,
+            remaining_balance: owed.balance,
+            remaining_interest: owed.interest,
+            remaining_fees: owed.fees,
+            allocation_order: order.clone(),
+        };
+
+        let mut remaining = amount;
+        for bucket in order {
+            let (portion, owed_in_bucket) = match bucket {
+                AllocationBucket::Fees => (&mut breakdown.fee_portion, owed.fees),
+                AllocationBucket::AccruedInterest => (&mut breakdown.interest_portion, owed.interest),
+                AllocationBucket::CurrentPrincipal => (&mut breakdown.principal_portion, owed.current_principal),
+                AllocationBucket::Prepayment => (
+                    &mut breakdown.prepayment_portion,
+                    owed.balance.saturating_sub(owed.current_principal),
+                ),
+            };
+            *portion = remaining.min(owed_in_bucket);
+            remaining -= *portion;
+        }
+
+        breakdown.unapplied_amount = remaining;
+        breakdown.remaining_balance = owed.balance.saturating_sub(breakdown.total_principal());
+        breakdown.remaining_interest = owed.interest - breakdown.interest_portion;
+        breakdown.remaining_fees = owed.fees - breakdown.fee_portion;
+        breakdown
+    }
+
+    /// Every bucket exactly once, with prepayment last so scheduled amounts are met first
+    fn validate_order(order: &[AllocationBucket]) -> StudiFiResult<()> {
+        let complete = order.len() == DEFAULT_ALLOCATION_ORDER.len()
+            && DEFAULT_ALLOCATION_ORDER.iter().all(|bucket| order.contains(bucket));
+        if !complete {
+            return Err(StudiFiError::InvalidInput(
+                "Allocation order must list each bucket exactly once".to_string()
+            ));
+        }
+        if order.last() != Some(&AllocationBucket::Prepayment) {
+            return Err(StudiFiError::InvalidInput("Prepayment must be the last bucket".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waterfall_allocation() {
+        let owed = AmountsOwed {
+            fees: 2_500,
+            interest: 5_000,
+            current_principal: 15_000,
+            balance: 100_000,
+        };
+
+        // A short payment clears fees and interest before touching principal
+        let partial = PaymentWaterfall::allocate(10_000, &owed, DEFAULT_ALLOCATION_ORDER.to_vec());
+        assert_eq!(
+            (partial.fee_portion, partial.interest_portion, partial.principal_portion, partial.prepayment_portion),
+            (2_500, 5_000, 2_500, 0)
+        );
+        assert_eq!((partial.remaining_balance, partial.remaining_fees), (97_500, 0));
+
+        // Anything past the installment is prepaid; past the balance it is left unapplied
+        let extra = PaymentWaterfall::allocate(42_500, &owed, DEFAULT_ALLOCATION_ORDER.to_vec());
+        assert_eq!((extra.principal_portion, extra.prepayment_portion), (15_000, 20_000));
+        assert_eq!(extra.remaining_balance, 65_000);
+        let excess = PaymentWaterfall::allocate(110_000, &owed, DEFAULT_ALLOCATION_ORDER.to_vec());
+        assert_eq!((excess.remaining_balance, excess.unapplied_amount), (0, 2_500));
+
+        // Products can put principal ahead of fees
+        let order = vec![
+            AllocationBucket::AccruedInterest,
+            AllocationBucket::CurrentPrincipal,
+            AllocationBucket::Fees,
+            AllocationBucket::Prepayment,
+        ];
+        assert!(PaymentWaterfall::validate_order(&order).is_ok());
+        let principal_first = PaymentWaterfall::allocate(10_000, &owed, order);
+        assert_eq!((principal_first.fee_portion, principal_first.principal_portion), (0, 5_000));
+        assert_eq!(principal_first.remaining_fees, 2_500);
+
+        assert!(PaymentWaterfall::validate_order(&DEFAULT_ALLOCATION_ORDER[..3]).is_err());
+        assert!(PaymentWaterfall::validate_order(&[
+            AllocationBucket::Prepayment,
+            AllocationBucket::Fees,
+            AllocationBucket::AccruedInterest,
+            AllocationBucket::CurrentPrincipal,
+        ]).is_err());
+    }
+}
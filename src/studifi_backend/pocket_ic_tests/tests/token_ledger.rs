@@ -0,0 +1,227 @@
+//! The token engine against the reference ICRC-1/ICRC-2 ledger running in PocketIC.
+//!
+//! Skipped unless ICRC1_LEDGER_WASM points at the ledger wasm from an IC release
+//! (ic-icrc1-ledger.wasm.gz). The PocketIC server is found through POCKET_IC_BIN.
+
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Deserialize, Nat, Principal, Reserved};
+use loan_management_service::token::*;
+use pocket_ic::PocketIc;
+use shared::*;
+use std::cell::RefCell;
+use std::future::Future;
+
+const TRANSFER_FEE: u64 = 10_000;
+
+thread_local! {
+    static PIC: RefCell<Option<PocketIc>> = const { RefCell::new(None) };
+}
+
+/// Ledger calls made by the loan canister's principal as PocketIC update calls
+struct PocketIcLedger;
+
+impl LedgerTransport for PocketIcLedger {
+    fn canister_id() -> Principal {
+        Principal::from_slice(&[0xca, 0xfe])
+    }
+
+    async fn call<T, R>(config: &TokenLedgerConfig, method: &str, args: T) -> StudiFiResult<R>
+    where
+        T: ArgumentEncoder + Clone,
+        R: for<'de> Deserialize<'de> + CandidType,
+    {
+        let reply = update(config.ledger_canister_id, Self::canister_id(), method, args)
+            .map_err(StudiFiError::ExternalServiceError)?;
+        candid::decode_one(&reply).map_err(|e| StudiFiError::ExternalServiceError(e.to_string()))
+    }
+}
+
+type TokenEngine = TokenEngineWith<PocketIcLedger>;
+
+fn update<T: ArgumentEncoder>(canister_id: Principal, sender: Principal, method: &str, args: T) -> Result<Vec<u8>, String> {
+    let payload = candid::encode_args(args).map_err(|e| e.to_string())?;
+    PIC.with(|pic| {
+        let pic = pic.borrow();
+        let pic = pic.as_ref().expect("PocketIC is not set up");
+        pic.update_call(canister_id, sender, method, payload).map_err(|reject| reject.reject_message)
+    })
+}
+
+fn ledger_time() -> Timestamp {
+    PIC.with(|pic| pic.borrow().as_ref().expect("PocketIC is not set up").get_time().as_nanos_since_unix_epoch())
+}
+
+/// PocketIC answers synchronously, so every future here completes on first poll
+fn run<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    match future.as_mut().poll(&mut cx) {
+        std::task::Poll::Ready(output) => output,
+        std::task::Poll::Pending => panic!("ledger call suspended"),
+    }
+}
+
+// ============================================================================
+// LEDGER SETUP
+// ============================================================================
+
+#[derive(CandidType)]
+enum LedgerArgument {
+    Init(InitArgs),
+}
+
+/// Only the type is needed; no metadata is set
+#[allow(dead_code)]
+#[derive(CandidType)]
+enum MetadataValue {
+    Text(String),
+}
+
+#[derive(CandidType)]
+struct FeatureFlags {
+    icrc2: bool,
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+}
+
+#[derive(CandidType)]
+struct InitArgs {
+    minting_account: Account,
+    transfer_fee: Nat,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, MetadataValue)>,
+    initial_balances: Vec<(Account, Nat)>,
+    feature_flags: Option<FeatureFlags>,
+    archive_options: ArchiveOptions,
+}
+
+#[derive(CandidType, Clone)]
+struct ApproveArgs {
+    from_subaccount: Option<Vec<u8>>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+fn wallet(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+/// Installs the ledger with the treasury and a borrower funded, or None when
+/// the ledger wasm is not available
+fn setup(borrower: Principal) -> Option<TokenLedgerConfig> {
+    let Ok(path) = std::env::var("ICRC1_LEDGER_WASM") else {
+        eprintln!("ICRC1_LEDGER_WASM is not set; skipping");
+        return None;
+    };
+    let wasm = std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+
+    let pic = PocketIc::new();
+    let ledger_canister_id = pic.create_canister();
+    pic.add_cycles(ledger_canister_id, 2_000_000_000_000);
+    let minter = Principal::from_slice(&[0x01, 0x01]);
+    let init = LedgerArgument::Init(InitArgs {
+        minting_account: wallet(minter),
+        transfer_fee: Nat::from(TRANSFER_FEE),
+        token_symbol: "ckUSDC".to_string(),
+        token_name: "ckUSDC".to_string(),
+        metadata: vec![],
+        initial_balances: vec![
+            (TokenEngine::treasury_account(), Nat::from(1_000_000_000u64)),
+            (wallet(borrower), Nat::from(100_000_000u64)),
+        ],
+        feature_flags: Some(FeatureFlags { icrc2: true }),
+        archive_options: ArchiveOptions {
+            num_blocks_to_archive: 1_000,
+            trigger_threshold: 2_000,
+            controller_id: minter,
+        },
+    });
+    pic.install_canister(ledger_canister_id, wasm, candid::encode_one(init).unwrap(), None);
+    PIC.with(|cell| *cell.borrow_mut() = Some(pic));
+
+    Some(TokenLedgerConfig {
+        ledger_canister_id,
+        token_symbol: "ckUSDC".to_string(),
+        units_per_cent: 10_000,
+        transfer_fee: TRANSFER_FEE,
+        is_active: true,
+        updated_at: 0,
+        updated_by: Principal::anonymous(),
+    })
+}
+
+fn balance(config: &TokenLedgerConfig, account: Account) -> u64 {
+    let reply = update(config.ledger_canister_id, Principal::anonymous(), "icrc1_balance_of", (account,)).unwrap();
+    let balance: Nat = candid::decode_one(&reply).unwrap();
+    u64::try_from(&balance.0).unwrap()
+}
+
+fn approve(config: &TokenLedgerConfig, owner: Principal, amount: u64) {
+    let args = ApproveArgs {
+        from_subaccount: None,
+        spender: wallet(PocketIcLedger::canister_id()),
+        amount: Nat::from(amount),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let reply = update(config.ledger_canister_id, owner, "icrc2_approve", (args,)).unwrap();
+    let result: Result<Nat, Reserved> = candid::decode_one(&reply).unwrap();
+    assert!(result.is_ok(), "approve failed");
+}
+
+// ============================================================================
+// TESTS
+// ============================================================================
+
+#[test]
+fn test_token_engine_against_icrc_ledger() {
+    let borrower = Principal::from_slice(&[0x02]);
+    let student = Principal::from_slice(&[0x03]);
+    let Some(config) = setup(borrower) else {
+        return;
+    };
+
+    // A retried payout with the first attempt's time resolves to the original block
+    let created_at = ledger_time();
+    let payout = run(TokenEngine::pay_out(&config, "LOAN-1", "DSB-1", Some(student), 5_000, created_at, created_at))
+        .unwrap()
+        .unwrap();
+    let retried = run(TokenEngine::pay_out(&config, "LOAN-1", "DSB-1", Some(student), 5_000, created_at, ledger_time()))
+        .unwrap()
+        .unwrap();
+    assert_eq!(retried.block_index, payout.block_index);
+    assert_eq!(balance(&config, wallet(student)), 50_000_000);
+
+    // Without an approval the ledger refuses the repayment
+    let created_at = ledger_time();
+    let refused = run(TokenEngine::collect_repayment(&config, "LOAN-1", "PAY-1", borrower, 5_000, created_at, created_at));
+    assert!(matches!(&refused, Err(StudiFiError::InvalidInput(reason)) if reason.contains("approve this canister")));
+
+    // Under an approval the borrower pays the fee and the loan's subaccount receives the full amount, once
+    approve(&config, borrower, 60_000_000);
+    let created_at = ledger_time();
+    let collected = run(TokenEngine::collect_repayment(&config, "LOAN-1", "PAY-1", borrower, 5_000, created_at, created_at)).unwrap();
+    let retried = run(TokenEngine::collect_repayment(&config, "LOAN-1", "PAY-1", borrower, 5_000, created_at, ledger_time())).unwrap();
+    assert_eq!(retried.block_index, collected.block_index);
+    assert_eq!(balance(&config, TokenEngine::loan_account("LOAN-1")), 50_000_000);
+    assert_eq!(balance(&config, wallet(borrower)), 100_000_000 - 50_000_000 - TRANSFER_FEE - TRANSFER_FEE);
+
+    // The sweep empties the subaccount into the treasury account less its fee
+    let sweep = run(TokenEngine::sweep(&config, "LOAN-1", ledger_time())).unwrap().unwrap();
+    assert_eq!(sweep.amount, 50_000_000 - TRANSFER_FEE);
+    assert_eq!(balance(&config, TokenEngine::loan_account("LOAN-1")), 0);
+}
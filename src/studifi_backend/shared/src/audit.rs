@@ -0,0 +1,168 @@
+use candid::Principal;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::clients::*;
+use crate::constants::*;
+use crate::types::*;
+use crate::utils::current_time;
+
+// ============================================================================
+// AUDIT EVENTS
+// ============================================================================
+
+thread_local! {
+    static AUDIT_EVENT_COUNTER: Cell<u64> = const { Cell::new(0) };
+    static PENDING_AUDIT_EVENTS: RefCell<VecDeque<AuditEvent>> = const { RefCell::new(VecDeque::new()) };
+    static AUDIT_FLUSH_SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Create an audit event
+pub fn create_audit_event(
+    event_type: AuditEventType,
+    user_principal: Principal,
+    canister_id: Principal,
+    function_name: &str,
+    details: &str,
+    success: bool,
+) -> AuditEvent {
+    let timestamp = current_time();
+    // Several events can share a timestamp within one message
+    let sequence = AUDIT_EVENT_COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        counter.get()
+    });
+
+    AuditEvent {
+        event_id: format!("{}-{}-{}", AUDIT_LOG_PREFIX, timestamp, sequence),
+        event_type,
+        user_principal,
+        canister_id,
+        function_name: function_name.to_string(),
+        details: details.to_string(),
+        timestamp,
+        success,
+    }
+}
+
+/// Log an audit event and ship it to the central audit log in compliance_service.
+///
+/// Events are sent in batches from a timer so callers never wait on the audit log.
+/// Undelivered events are retried until `AUDIT_MAX_PENDING_EVENTS` are waiting,
+/// after which the oldest are dropped; the queue lives on the heap and does not
+/// survive an upgrade.
+pub fn log_audit_event(event: AuditEvent) {
+    ic_cdk::println!("AUDIT: {:?}", event);
+    queue_audit_event(event);
+    schedule_audit_flush(Duration::ZERO);
+}
+
+/// Number of audit events waiting to be shipped
+pub fn pending_audit_event_count() -> usize {
+    PENDING_AUDIT_EVENTS.with(|pending| pending.borrow().len())
+}
+
+fn queue_audit_event(event: AuditEvent) {
+    PENDING_AUDIT_EVENTS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if pending.len() >= AUDIT_MAX_PENDING_EVENTS {
+            if let Some(dropped) = pending.pop_front() {
+                ic_cdk::println!("Audit queue full, dropping event {}", dropped.event_id);
+            }
+        }
+        pending.push_back(event);
+    });
+}
+
+fn take_audit_batch() -> Vec<AuditEvent> {
+    PENDING_AUDIT_EVENTS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let size = pending.len().min(AUDIT_BATCH_SIZE);
+        pending.drain(..size).collect()
+    })
+}
+
+/// Put an undelivered batch back at the front of the queue, keeping event order
+fn requeue_audit_batch(batch: Vec<AuditEvent>) {
+    PENDING_AUDIT_EVENTS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        for event in batch.into_iter().rev() {
+            pending.push_front(event);
+        }
+        while pending.len() > AUDIT_MAX_PENDING_EVENTS {
+            pending.pop_front();
+        }
+    });
+}
+
+fn schedule_audit_flush(delay: Duration) {
+    if AUDIT_FLUSH_SCHEDULED.with(|scheduled| scheduled.replace(true)) {
+        return;
+    }
+
+    ic_cdk_timers::set_timer(delay, || {
+        ic_cdk::spawn(async {
+            AUDIT_FLUSH_SCHEDULED.with(|scheduled| scheduled.set(false));
+            flush_audit_events().await;
+        });
+    });
+}
+
+async fn flush_audit_events() {
+    let batch = take_audit_batch();
+    if batch.is_empty() {
+        return;
+    }
+
+    match ComplianceClient::ingest_audit_events(batch.clone()).await {
+        Ok(_) if pending_audit_event_count() > 0 => schedule_audit_flush(Duration::ZERO),
+        Ok(_) => {}
+        Err(e) => {
+            ic_cdk::println!("Failed to ship {} audit events: {}", batch.len(), e);
+            requeue_audit_batch(batch);
+            schedule_audit_flush(Duration::from_secs(AUDIT_RETRY_INTERVAL_SECONDS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: usize) -> AuditEvent {
+        AuditEvent {
+            event_id: id.to_string(),
+            event_type: AuditEventType::LoanCreated,
+            user_principal: Principal::anonymous(),
+            canister_id: Principal::anonymous(),
+            function_name: "create_loan".to_string(),
+            details: String::new(),
+            timestamp: id as Timestamp,
+            success: true,
+        }
+    }
+
+    #[test]
+    fn test_audit_queue_keeps_order_and_bounds() {
+        for id in 0..AUDIT_BATCH_SIZE + 5 {
+            queue_audit_event(event(id));
+        }
+
+        // A failed batch goes back ahead of events queued after it
+        let batch = take_audit_batch();
+        assert_eq!(batch.len(), AUDIT_BATCH_SIZE);
+        assert_eq!(pending_audit_event_count(), 5);
+        requeue_audit_batch(batch);
+        assert_eq!(take_audit_batch()[0].event_id, "0");
+        take_audit_batch();
+        assert_eq!(pending_audit_event_count(), 0);
+
+        // The oldest events are dropped once the queue is full
+        for id in 0..AUDIT_MAX_PENDING_EVENTS + 1 {
+            queue_audit_event(event(id));
+        }
+        assert_eq!(pending_audit_event_count(), AUDIT_MAX_PENDING_EVENTS);
+        assert_eq!(take_audit_batch()[0].event_id, "1");
+    }
+}
@@ -0,0 +1,479 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::constants::MAX_PAGE_LIMIT;
+use crate::types::*;
+use crate::utils::call_canister;
+
+// ============================================================================
+// CANISTER REGISTRY
+// ============================================================================
+
+/// StudiFi services reachable through the canister registry
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum ServiceName {
+    StudentIdentity,
+    CreditAssessment,
+    LoanManagement,
+    DaoGovernance,
+    Compliance,
+    Authentication,
+    UniversityCredential,
+}
+
+impl ServiceName {
+    /// Registry key, matching the canister name in dfx.json
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServiceName::StudentIdentity => "student_identity_service",
+            ServiceName::CreditAssessment => "credit_assessment_service",
+            ServiceName::LoanManagement => "loan_management_service",
+            ServiceName::DaoGovernance => "dao_governance_service",
+            ServiceName::Compliance => "compliance_service",
+            ServiceName::Authentication => "authentication_service",
+            ServiceName::UniversityCredential => "university_credential_service",
+        }
+    }
+
+    pub fn all() -> [ServiceName; 7] {
+        [
+            ServiceName::StudentIdentity,
+            ServiceName::CreditAssessment,
+            ServiceName::LoanManagement,
+            ServiceName::DaoGovernance,
+            ServiceName::Compliance,
+            ServiceName::Authentication,
+            ServiceName::UniversityCredential,
+        ]
+    }
+
+    pub fn from_name(name: &str) -> Option<ServiceName> {
+        Self::all().into_iter().find(|service| service.as_str() == name)
+    }
+}
+
+/// Persisted form of the canister registry
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Serialize)]
+pub struct CanisterRegistry {
+    pub canisters: Vec<CanisterConfig>,
+}
+
+/// Init and post-upgrade argument shared by every StudiFi canister
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Serialize)]
+pub struct ServiceInitArgs {
+    pub canister_registry: Vec<CanisterConfig>,
+}
+
+thread_local! {
+    static CANISTER_REGISTRY: RefCell<BTreeMap<String, CanisterConfig>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Replace the in-memory registry; entries with unknown service names are rejected
+pub fn load_canister_registry(canisters: Vec<CanisterConfig>) -> StudiFiResult<()> {
+    if let Some(unknown) = canisters.iter().find(|config| ServiceName::from_name(&config.name).is_none()) {
+        return Err(StudiFiError::InvalidInput(format!("Unknown service in canister registry: {}", unknown.name)));
+    }
+
+    CANISTER_REGISTRY.with(|registry| {
+        *registry.borrow_mut() = canisters
+            .into_iter()
+            .map(|config| (config.name.clone(), config))
+            .collect();
+    });
+    Ok(())
+}
+
+/// Load the registry at `init`/`post_upgrade`, preferring install arguments over the persisted copy.
+///
+/// Traps on an invalid registry so a misconfigured install or upgrade is rolled back.
+/// Returns the loaded registry for the canister to persist.
+pub fn init_canister_registry(args: Option<ServiceInitArgs>, persisted: CanisterRegistry) -> CanisterRegistry {
+    let canisters = match args {
+        Some(args) => args.canister_registry,
+        None => persisted.canisters,
+    };
+
+    if let Err(e) = load_canister_registry(canisters) {
+        ic_cdk::trap(&format!("Invalid canister registry: {}", e));
+    }
+
+    current_canister_registry()
+}
+
+/// Snapshot of the registry, suitable for persisting to stable memory
+pub fn current_canister_registry() -> CanisterRegistry {
+    CANISTER_REGISTRY.with(|registry| CanisterRegistry {
+        canisters: registry.borrow().values().cloned().collect(),
+    })
+}
+
+/// Look up an active service's canister configuration
+pub fn service_config(service: ServiceName) -> StudiFiResult<CanisterConfig> {
+    let config = CANISTER_REGISTRY.with(|registry| registry.borrow().get(service.as_str()).cloned())
+        .ok_or_else(|| StudiFiError::InternalError(format!("{} canister not configured", service.as_str())))?;
+
+    if !config.is_active {
+        return Err(StudiFiError::ExternalServiceError(format!("{} canister is disabled", service.as_str())));
+    }
+
+    Ok(config)
+}
+
+/// Identify which registered service, if any, a principal belongs to
+pub fn service_for_principal(principal: &Principal) -> Option<ServiceName> {
+    CANISTER_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .values()
+            .find(|config| config.canister_id == *principal)
+            .and_then(|config| ServiceName::from_name(&config.name))
+    })
+}
+
+// ============================================================================
+// TYPED SERVICE CLIENTS
+// ============================================================================
+
+/// Loan creation request sent to loan_management_service's `create_loan`
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct CreateLoanRequest {
+    pub student_id: Principal,
+    pub principal_amount: Amount,
+    pub interest_rate: Percentage,
+    pub term_months: u32,
+    pub grace_period_months: u32,
+    pub purpose: String,
+    pub collateral_required: bool,
+    pub cosigner_id: Option<Principal>,
+    pub special_conditions: Vec<String>,
+    /// Idempotency key: requests for the same application create a single loan
+    pub application_id: String,
+}
+
+/// Subset of the loan record returned by loan_management_service's `create_loan`
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct LoanSummary {
+    pub id: String,
+    pub student_id: Principal,
+    pub original_amount: Amount,
+    pub monthly_payment: Amount,
+}
+
+/// One row of the loan tape returned by loan_management_service's `get_loan_tape`
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct LoanTapeRecord {
+    pub loan_id: String,
+    pub student_id: Principal,
+    pub cosigner_id: Option<Principal>,
+    pub status: LoanStatus,
+    pub original_amount: Amount,
+    pub current_balance: Amount,
+    pub interest_rate: Percentage,
+    pub term_months: u32,
+    pub monthly_payment: Amount,
+    pub payments_made: u32,
+    pub late_payments: u32,
+    /// Days since the next scheduled payment fell due, 0 for loans no longer in repayment
+    pub days_past_due: u64,
+    pub originated_at: Timestamp,
+    pub next_payment_due: Timestamp,
+    pub last_payment_date: Option<Timestamp>,
+}
+
+/// One page of the loan tape, in loan id order
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct LoanTapePage {
+    pub records: Vec<LoanTapeRecord>,
+    /// Pass as `after_loan_id` for the next page; None on the last page
+    pub next_after: Option<String>,
+}
+
+/// Effective credit score of one student, as returned by credit_assessment_service
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct CreditScoreSnapshot {
+    pub student_id: Principal,
+    pub score: Option<u32>,
+}
+
+/// Erasure of a data subject's records, sent by compliance_service to every canister
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct DataErasureRequest {
+    /// Id of the data subject request in compliance_service
+    pub request_id: String,
+    pub subject: Principal,
+    /// Stands in for the subject on records kept for the platform's own integrity,
+    /// the same in every canister
+    pub pseudonym: Principal,
+    /// The subject still owes or cosigns a loan, so records backing it are retained
+    pub has_active_loans: bool,
+}
+
+/// What one canister did with a data subject's records
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ErasureReceipt {
+    pub service: String,
+    pub erased: u32,
+    pub pseudonymized: u32,
+    pub retained: u32,
+    /// Why records were retained
+    pub notes: Vec<String>,
+}
+
+impl ErasureReceipt {
+    pub fn new(service: ServiceName) -> Self {
+        Self {
+            service: service.as_str().to_string(),
+            erased: 0,
+            pseudonymized: 0,
+            retained: 0,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn retain(&mut self, count: u32, reason: &str) {
+        if count > 0 {
+            self.retained += count;
+            self.notes.push(format!("{} record(s) retained: {}", count, reason));
+        }
+    }
+}
+
+/// Client for student_identity_service
+pub struct IdentityClient;
+
+impl IdentityClient {
+    pub async fn is_student_verified(student_id: Principal) -> StudiFiResult<bool> {
+        let config = service_config(ServiceName::StudentIdentity)?;
+        call_canister(&config, "is_student_verified", (student_id,)).await
+    }
+
+    /// Report the outcome of a KYC case for a student's profile
+    pub async fn apply_kyc_outcome(student_id: Principal, status: KycStatus, case_id: String) -> StudiFiResult<()> {
+        let config = service_config(ServiceName::StudentIdentity)?;
+        let result: StudiFiResult<()> =
+            call_canister(&config, "apply_kyc_outcome", (student_id, status, case_id)).await?;
+
+        result
+    }
+}
+
+/// Client for credit_assessment_service
+pub struct CreditClient;
+
+impl CreditClient {
+    pub async fn get_effective_credit_score(student_id: Principal) -> StudiFiResult<Option<u32>> {
+        let config = service_config(ServiceName::CreditAssessment)?;
+        call_canister(&config, "get_effective_credit_score", (student_id,)).await
+    }
+
+    pub async fn get_effective_credit_scores(student_ids: Vec<Principal>) -> StudiFiResult<Vec<CreditScoreSnapshot>> {
+        let config = service_config(ServiceName::CreditAssessment)?;
+        call_canister(&config, "get_effective_credit_scores", (student_ids,)).await
+    }
+
+    /// Remove a student's evidence from community validation after they revoke disclosure consent
+    pub async fn withdraw_validation_disclosure(student_id: Principal) -> StudiFiResult<u32> {
+        let config = service_config(ServiceName::CreditAssessment)?;
+        let result: StudiFiResult<u32> = call_canister(&config, "withdraw_validation_disclosure", (student_id,)).await?;
+        result
+    }
+}
+
+/// Client for loan_management_service
+pub struct LoanClient;
+
+impl LoanClient {
+    /// Create a loan. Never retried here: a failure that may have reached the loan
+    /// service is retried by the caller with the same application id, which returns
+    /// the loan already created rather than a second one.
+    pub async fn create_loan(request: CreateLoanRequest) -> StudiFiResult<LoanSummary> {
+        let config = CanisterConfig {
+            retry_count: 0,
+            ..service_config(ServiceName::LoanManagement)?
+        };
+        let result: StudiFiResult<LoanSummary> = call_canister(
+            &config,
+            "create_loan",
+            (
+                request.student_id,
+                request.principal_amount,
+                request.interest_rate,
+                request.term_months,
+                request.grace_period_months,
+                request.purpose,
+                request.collateral_required,
+                request.cosigner_id,
+                request.special_conditions,
+                Some(request.application_id),
+            ),
+        ).await?;
+
+        result
+    }
+
+    /// Number of loans `subject` still owes or cosigns
+    pub async fn count_outstanding_loans(subject: Principal) -> StudiFiResult<u32> {
+        let config = service_config(ServiceName::LoanManagement)?;
+        call_canister(&config, "count_outstanding_loans", (subject,)).await
+    }
+
+    /// Every loan on the books, for regulatory reporting, fetched a page at a time
+    pub async fn get_loan_tape() -> StudiFiResult<Vec<LoanTapeRecord>> {
+        let config = service_config(ServiceName::LoanManagement)?;
+        let mut records = Vec::new();
+        let mut after: Option<String> = None;
+        loop {
+            let page: LoanTapePage =
+                call_canister(&config, "get_loan_tape", (after, Some(MAX_PAGE_LIMIT))).await?;
+            records.extend(page.records);
+            match page.next_after {
+                Some(next) => after = Some(next),
+                None => return Ok(records),
+            }
+        }
+    }
+}
+
+/// Client for the data subject endpoints every StudiFi canister exposes to compliance_service
+pub struct DataSubjectClient;
+
+impl DataSubjectClient {
+    /// Everything `service` holds about `subject`, as a JSON document
+    pub async fn export(service: ServiceName, subject: Principal) -> StudiFiResult<String> {
+        let config = service_config(service)?;
+        let result: StudiFiResult<String> =
+            call_canister(&config, "export_subject_data", (subject,)).await?;
+
+        result
+    }
+
+    /// Erase or pseudonymize what `service` holds about the request's subject
+    pub async fn erase(service: ServiceName, request: DataErasureRequest) -> StudiFiResult<ErasureReceipt> {
+        let config = service_config(service)?;
+        let result: StudiFiResult<ErasureReceipt> =
+            call_canister(&config, "erase_subject_data", (request,)).await?;
+
+        result
+    }
+}
+
+/// Client for dao_governance_service
+pub struct GovernanceClient;
+
+impl GovernanceClient {
+    pub async fn get_effective_voting_power(user: Principal) -> StudiFiResult<u64> {
+        let config = service_config(ServiceName::DaoGovernance)?;
+        call_canister(&config, "get_effective_voting_power", (user,)).await
+    }
+}
+
+/// Client for authentication_service
+pub struct AuthClient;
+
+impl AuthClient {
+    pub async fn validate_session(session_id: String, required_permission: Option<Permission>) -> StudiFiResult<UserSession> {
+        let config = service_config(ServiceName::Authentication)?;
+        let result: StudiFiResult<UserSession> = call_canister(
+            &config,
+            "validate_session",
+            (session_id, required_permission),
+        ).await?;
+
+        result
+    }
+
+    pub async fn get_user_roles(user_principal: Principal) -> StudiFiResult<Vec<Role>> {
+        let config = service_config(ServiceName::Authentication)?;
+        call_canister(&config, "get_user_roles", (user_principal,)).await
+    }
+
+    pub async fn get_role_assignments() -> StudiFiResult<Vec<RoleAssignment>> {
+        let config = service_config(ServiceName::Authentication)?;
+        call_canister(&config, "get_role_assignments", ()).await
+    }
+}
+
+/// Client for compliance_service
+pub struct ComplianceClient;
+
+impl ComplianceClient {
+    pub async fn perform_kyc_check(entity_id: Principal) -> StudiFiResult<String> {
+        let config = service_config(ServiceName::Compliance)?;
+        let result: StudiFiResult<String> =
+            call_canister(&config, "perform_kyc_check", (entity_id,)).await?;
+
+        result
+    }
+
+    /// Screen a party against the sanctions watchlists
+    pub async fn screen_party(request: ScreeningRequest) -> StudiFiResult<ScreeningDecision> {
+        let config = service_config(ServiceName::Compliance)?;
+        let result: StudiFiResult<ScreeningDecision> =
+            call_canister(&config, "screen_party", (request,)).await?;
+
+        result
+    }
+
+    /// Append events to the central audit log, returning the new chain length
+    pub async fn ingest_audit_events(events: Vec<AuditEvent>) -> StudiFiResult<u64> {
+        let config = service_config(ServiceName::Compliance)?;
+        let result: StudiFiResult<u64> =
+            call_canister(&config, "ingest_audit_events", (events,)).await?;
+
+        result
+    }
+
+    /// Report a money movement for AML monitoring, returning the ids of any alerts raised
+    pub async fn report_aml_transaction(report: AmlTransactionReport) -> StudiFiResult<Vec<String>> {
+        let config = service_config(ServiceName::Compliance)?;
+        let result: StudiFiResult<Vec<String>> =
+            call_canister(&config, "report_aml_transaction", (report,)).await?;
+
+        result
+    }
+
+    /// Whether `subject` holds a current consent for `purpose`
+    pub async fn has_consent(subject: Principal, purpose: ConsentPurpose) -> StudiFiResult<bool> {
+        let config = service_config(ServiceName::Compliance)?;
+        let result: StudiFiResult<bool> =
+            call_canister(&config, "has_consent", (subject, purpose)).await?;
+
+        result
+    }
+
+    /// Fail unless `subject` holds a current consent for `purpose`. Fails closed
+    /// when the consent ledger cannot be reached.
+    pub async fn require_consent(subject: Principal, purpose: ConsentPurpose) -> StudiFiResult<()> {
+        if Self::has_consent(subject, purpose).await? {
+            Ok(())
+        } else {
+            Err(StudiFiError::Unauthorized(
+                format!("No current {:?} consent on file for {}", purpose, subject)
+            ))
+        }
+    }
+
+    /// The notice currently published for `purpose`
+    pub async fn get_consent_notice(purpose: ConsentPurpose) -> StudiFiResult<ConsentNotice> {
+        let config = service_config(ServiceName::Compliance)?;
+        let notice: Option<ConsentNotice> =
+            call_canister(&config, "get_consent_notice", (purpose,)).await?;
+
+        notice.ok_or_else(|| StudiFiError::NotFound(format!("No {:?} consent notice is published", purpose)))
+    }
+}
+
+/// Client for university_credential_service
+pub struct UniversityClient;
+
+impl UniversityClient {
+    pub async fn verify_student(student_id: String) -> StudiFiResult<String> {
+        let config = service_config(ServiceName::UniversityCredential)?;
+        let result: StudiFiResult<String> =
+            call_canister(&config, "verify_student", (student_id,)).await?;
+
+        result
+    }
+}
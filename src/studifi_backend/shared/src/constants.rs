@@ -27,6 +27,12 @@ pub const MAX_LOAN_TERM_MONTHS: u32 = 120; // 10 years
 pub const MIN_INTEREST_RATE: Percentage = 0.0; // 0%
 pub const MAX_INTEREST_RATE: Percentage = 0.25; // 25%
 
+/// Fee schedule (in basis points)
+pub const ORIGINATION_FEE_BPS: u32 = 100; // 1% of principal
+pub const LATE_FEE_BPS: u32 = 500; // 5% of monthly payment
+pub const MIN_LATE_FEE: Amount = 25_00; // $25
+pub const PREPAYMENT_PENALTY_BPS: u32 = 200; // 2% of remaining balance
+
 /// Grace period limits
 pub const MIN_GRACE_PERIOD_MONTHS: u32 = 0;
 pub const MAX_GRACE_PERIOD_MONTHS: u32 = 12;
@@ -58,6 +64,32 @@ pub const AUTOMATION_INTERVAL_SECONDS: u64 = 3600; // 1 hour
 pub const PAYMENT_REMINDER_DAYS: u64 = 7;
 pub const OVERDUE_CHECK_DAYS: u64 = 1;
 
+/// Inter-canister call resilience
+pub const RETRY_BASE_DELAY_MS: u64 = 250;
+pub const RETRY_MAX_DELAY_MS: u64 = 8_000;
+pub const CIRCUIT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+pub const CIRCUIT_BREAKER_COOLDOWN_SECONDS: u64 = 60;
+
+/// Role cache used by authorization guards
+pub const ROLE_CACHE_REFRESH_INTERVAL_SECONDS: u64 = 300; // 5 minutes
+pub const ROLE_CACHE_MAX_AGE_SECONDS: u64 = 900; // 15 minutes
+
+/// Cache of sessions validated by authentication_service
+pub const SESSION_CACHE_TTL_SECONDS: u64 = 30;
+pub const SESSION_CACHE_MAX_ENTRIES: usize = 1_000;
+
+/// Shipping of audit events to the central audit log in compliance_service
+pub const AUDIT_BATCH_SIZE: usize = 100;
+pub const AUDIT_MAX_PENDING_EVENTS: usize = 1_000;
+pub const AUDIT_RETRY_INTERVAL_SECONDS: u64 = 60;
+pub const AUDIT_MAX_VERIFY_RANGE: u64 = 10_000;
+pub const AUDIT_QUERY_MAX_LIMIT: u32 = 500;
+pub const AUDIT_QUERY_MAX_SCAN: usize = 10_000;
+
+/// Scheduled regulatory reports generated by compliance_service
+pub const REPORT_GENERATION_INTERVAL_SECONDS: u64 = 86_400; // 1 day
+pub const REPORT_RETENTION_PER_TYPE: usize = 90;
+
 /// Treasury limits
 pub const MIN_PROPOSAL_AMOUNT: Amount = 100_00; // $100
 pub const MAX_PROPOSAL_AMOUNT: Amount = 1_000_000_00; // $1,000,000
@@ -80,6 +112,9 @@ pub const STUDENT_PROFILE_PREFIX: &str = "STUDENT";
 pub const LOAN_APPLICATION_PREFIX: &str = "APP";
 pub const LOAN_PREFIX: &str = "LOAN";
 pub const PAYMENT_PREFIX: &str = "PAY";
+pub const DISBURSEMENT_PREFIX: &str = "DSB";
+pub const FEE_PREFIX: &str = "FEE";
+pub const JOURNAL_ENTRY_PREFIX: &str = "JE";
 pub const PROPOSAL_PREFIX: &str = "PROP";
 pub const SCHOLARSHIP_PREFIX: &str = "SCHOL";
 pub const COMPLIANCE_RECORD_PREFIX: &str = "COMP";
@@ -0,0 +1,403 @@
+use candid::Principal;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::clients::*;
+use crate::constants::*;
+use crate::types::*;
+use crate::utils::current_time;
+
+// ============================================================================
+// ROLE CACHE
+// ============================================================================
+
+/// Snapshot of role assignments pulled from authentication_service
+#[derive(Default)]
+struct RoleCache {
+    roles: BTreeMap<Principal, Vec<Role>>,
+    synced_at: Option<Timestamp>,
+}
+
+impl RoleCache {
+    fn load(&mut self, assignments: Vec<RoleAssignment>, now: Timestamp) {
+        self.roles = assignments
+            .into_iter()
+            .map(|assignment| (assignment.principal, assignment.roles))
+            .collect();
+        self.synced_at = Some(now);
+    }
+
+    /// Roles held by `principal`, refusing to answer from a missing or stale snapshot
+    fn roles_at(&self, principal: &Principal, now: Timestamp) -> StudiFiResult<Vec<Role>> {
+        let synced_at = self.synced_at
+            .ok_or_else(|| StudiFiError::Unauthorized("Role cache has not been synced yet".to_string()))?;
+
+        if now.saturating_sub(synced_at) > ROLE_CACHE_MAX_AGE_SECONDS * 1_000_000_000 {
+            return Err(StudiFiError::Unauthorized("Role cache is stale".to_string()));
+        }
+
+        Ok(self.roles.get(principal).cloned().unwrap_or_default())
+    }
+}
+
+/// Resolves a principal's roles directly from a canister's own store
+pub type RoleSource = fn(&Principal) -> Vec<Role>;
+
+thread_local! {
+    static ROLE_CACHE: RefCell<RoleCache> = RefCell::new(RoleCache::default());
+    static LOCAL_ROLE_SOURCE: Cell<Option<RoleSource>> = const { Cell::new(None) };
+}
+
+/// Replace the cached snapshot of role assignments
+pub fn load_role_cache(assignments: Vec<RoleAssignment>, now: Timestamp) {
+    ROLE_CACHE.with(|cache| cache.borrow_mut().load(assignments, now));
+}
+
+/// Resolve roles from this canister's own store instead of the cache.
+///
+/// Only authentication_service, which owns role assignments, should do this.
+pub fn use_local_role_source(source: RoleSource) {
+    LOCAL_ROLE_SOURCE.with(|local| local.set(Some(source)));
+}
+
+/// Pull the current role assignments from authentication_service
+pub async fn sync_role_cache() -> StudiFiResult<u32> {
+    let assignments = AuthClient::get_role_assignments().await?;
+    let count = assignments.len() as u32;
+    load_role_cache(assignments, current_time());
+    Ok(count)
+}
+
+/// Sync the role cache straight away and then periodically; call from `init` and `post_upgrade`
+pub fn start_role_cache_sync() {
+    fn spawn_sync() {
+        ic_cdk::spawn(async {
+            if let Err(e) = sync_role_cache().await {
+                ic_cdk::println!("Role cache sync failed: {}", e);
+            }
+        });
+    }
+
+    ic_cdk_timers::set_timer(Duration::ZERO, spawn_sync);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(ROLE_CACHE_REFRESH_INTERVAL_SECONDS), spawn_sync);
+}
+
+// ============================================================================
+// AUTHORIZATION
+// ============================================================================
+
+fn roles_for(principal: &Principal) -> StudiFiResult<Vec<Role>> {
+    if let Some(source) = LOCAL_ROLE_SOURCE.with(|local| local.get()) {
+        return Ok(source(principal));
+    }
+    ROLE_CACHE.with(|cache| cache.borrow().roles_at(principal, current_time()))
+}
+
+/// Check that `principal` holds `permission` through one of its roles.
+///
+/// Controllers are always authorized so they can bootstrap the first administrators.
+pub fn authorize(principal: &Principal, permission: &Permission) -> StudiFiResult<()> {
+    if ic_cdk::api::is_controller(principal) {
+        return Ok(());
+    }
+
+    let roles = roles_for(principal)?;
+    if roles.iter().any(|role| role_has_permission(role, permission)) {
+        Ok(())
+    } else {
+        Err(StudiFiError::Unauthorized(format!("{:?} permission required", permission)))
+    }
+}
+
+/// Whether `principal` holds `permission`; see [`authorize`]
+pub fn is_authorized(principal: &Principal, permission: &Permission) -> bool {
+    authorize(principal, permission).is_ok()
+}
+
+fn caller_has(permission: Permission) -> Result<(), String> {
+    authorize(&ic_cdk::caller(), &permission).map_err(|e| e.to_string())
+}
+
+fn caller_is_service_or_has(permission: Permission) -> Result<(), String> {
+    if service_for_principal(&ic_cdk::caller()).is_some() {
+        return Ok(());
+    }
+    caller_has(permission)
+}
+
+// ============================================================================
+// ENDPOINT GUARDS
+// ============================================================================
+// For use as `#[update(guard = "...")]` / `#[query(guard = "...")]`
+
+/// Platform configuration and treasury administration
+pub fn require_manage_system() -> Result<(), String> {
+    caller_has(Permission::ManageSystem)
+}
+
+/// Role and user administration
+pub fn require_manage_users() -> Result<(), String> {
+    caller_has(Permission::ManageUsers)
+}
+
+/// Platform-wide listings of other users' records
+pub fn require_view_all_data() -> Result<(), String> {
+    caller_has(Permission::ViewAllData)
+}
+
+/// Audit and compliance trails
+pub fn require_access_audit_logs() -> Result<(), String> {
+    caller_has(Permission::AccessAuditLogs)
+}
+
+/// KYC case review and other compliance decisions
+pub fn require_review_compliance() -> Result<(), String> {
+    caller_has(Permission::ReviewCompliance)
+}
+
+/// Student verification on behalf of a university
+pub fn require_verify_students() -> Result<(), String> {
+    caller_has(Permission::VerifyStudents)
+}
+
+/// Credential issuance on behalf of a university
+pub fn require_issue_credentials() -> Result<(), String> {
+    caller_has(Permission::IssueCredentials)
+}
+
+/// Methods driven by other StudiFi canisters, which administrators may also call
+pub fn require_service_or_manage_system() -> Result<(), String> {
+    caller_is_service_or_has(Permission::ManageSystem)
+}
+
+/// Methods only StudiFi canisters may call, such as audit log ingestion
+pub fn require_studifi_service() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if caller == ic_cdk::id() || service_for_principal(&caller).is_some() {
+        return Ok(());
+    }
+    Err("Only registered StudiFi canisters may call this method".to_string())
+}
+
+/// Outcomes that only compliance_service may report
+pub fn require_compliance_service() -> Result<(), String> {
+    match service_for_principal(&ic_cdk::caller()) {
+        Some(ServiceName::Compliance) => Ok(()),
+        _ => Err("Only compliance_service may call this method".to_string()),
+    }
+}
+
+/// Student verification changes, which compliance_service, university issuers and
+/// `VerifyStudents` holders may attest to
+pub fn require_verification_attester() -> Result<(), String> {
+    if is_attesting_service(&ic_cdk::caller()) {
+        return Ok(());
+    }
+    caller_has(Permission::VerifyStudents)
+}
+
+/// StudiFi canisters trusted to attest student verification without a role
+fn is_attesting_service(principal: &Principal) -> bool {
+    matches!(
+        service_for_principal(principal),
+        Some(ServiceName::Compliance) | Some(ServiceName::UniversityCredential)
+    )
+}
+
+/// Read access for other StudiFi canisters and data administrators
+pub fn require_service_or_view_all_data() -> Result<(), String> {
+    caller_is_service_or_has(Permission::ViewAllData)
+}
+
+// ============================================================================
+// SESSION VALIDATION
+// ============================================================================
+
+struct CachedSession {
+    session: UserSession,
+    cached_at: Timestamp,
+}
+
+/// Sessions recently confirmed by authentication_service
+#[derive(Default)]
+struct SessionCache {
+    entries: BTreeMap<String, CachedSession>,
+}
+
+impl SessionCache {
+    fn is_fresh(entry: &CachedSession, now: Timestamp) -> bool {
+        now.saturating_sub(entry.cached_at) <= SESSION_CACHE_TTL_SECONDS * 1_000_000_000
+            && now < entry.session.expires_at
+    }
+
+    fn get(&self, session_id: &str, now: Timestamp) -> Option<UserSession> {
+        self.entries
+            .get(session_id)
+            .filter(|entry| Self::is_fresh(entry, now))
+            .map(|entry| entry.session.clone())
+    }
+
+    fn insert(&mut self, session: UserSession, now: Timestamp) {
+        self.entries.retain(|_, entry| Self::is_fresh(entry, now));
+
+        if self.entries.len() >= SESSION_CACHE_MAX_ENTRIES {
+            let oldest = self.entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.cached_at)
+                .map(|(session_id, _)| session_id.clone());
+            if let Some(session_id) = oldest {
+                self.entries.remove(&session_id);
+            }
+        }
+
+        self.entries.insert(session.session_id.clone(), CachedSession { session, cached_at: now });
+    }
+}
+
+thread_local! {
+    static SESSION_CACHE: RefCell<SessionCache> = RefCell::new(SessionCache::default());
+}
+
+/// Resolve a session through authentication_service, checking `required_permission` if given.
+///
+/// Successful validations are cached for a short while, so a session terminated in
+/// authentication_service may keep working here for up to `SESSION_CACHE_TTL_SECONDS`.
+/// Failures are never cached, and are recorded as audit events by authentication_service.
+pub async fn validate_session_with_auth_service(
+    session_id: String,
+    required_permission: Option<Permission>,
+) -> StudiFiResult<UserSession> {
+    let cached = SESSION_CACHE.with(|cache| cache.borrow().get(&session_id, current_time()));
+    if let Some(session) = cached {
+        if let Some(permission) = &required_permission {
+            if !session.has_permission(permission) {
+                // The cached copy may predate a role change, so let authentication_service decide
+                return AuthClient::validate_session(session_id, required_permission).await;
+            }
+        }
+        return Ok(session);
+    }
+
+    let session = AuthClient::validate_session(session_id, required_permission).await?;
+    SESSION_CACHE.with(|cache| cache.borrow_mut().insert(session.clone(), current_time()));
+    Ok(session)
+}
+
+/// Authorize the caller of a sensitive update that optionally accepts a `session_id`.
+///
+/// With a session, it must be valid, grant `permission` and belong to the caller.
+/// Without one, the caller's own roles must grant `permission`, as for a guard.
+pub async fn authorize_caller_session(session_id: Option<String>, permission: Permission) -> StudiFiResult<()> {
+    let caller = ic_cdk::caller();
+    let Some(session_id) = session_id else {
+        return authorize(&caller, &permission);
+    };
+
+    let session = validate_session_with_auth_service(session_id, Some(permission)).await?;
+    if session.user_principal != caller {
+        return Err(StudiFiError::Unauthorized("Session belongs to a different principal".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Timestamp = 1_000_000_000;
+
+    fn admin() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn cache_with_admin(now: Timestamp) -> RoleCache {
+        let mut cache = RoleCache::default();
+        cache.load(vec![RoleAssignment { principal: admin(), roles: vec![Role::Admin] }], now);
+        cache
+    }
+
+    #[test]
+    fn test_unsynced_cache_denies() {
+        let cache = RoleCache::default();
+        assert!(cache.roles_at(&admin(), SECOND).is_err());
+    }
+
+    #[test]
+    fn test_cache_resolves_roles() {
+        let cache = cache_with_admin(SECOND);
+        assert_eq!(cache.roles_at(&admin(), 2 * SECOND).unwrap(), vec![Role::Admin]);
+        assert!(cache.roles_at(&Principal::anonymous(), 2 * SECOND).unwrap().is_empty());
+    }
+
+    fn session(session_id: &str, expires_at: Timestamp) -> UserSession {
+        UserSession {
+            session_id: session_id.to_string(),
+            user_principal: admin(),
+            roles: vec![Role::Student],
+            created_at: 0,
+            expires_at,
+            last_activity: 0,
+            is_active: true,
+        }
+    }
+
+    #[test]
+    fn test_session_cache_expires_entries() {
+        let mut cache = SessionCache::default();
+        cache.insert(session("SESSION-1", 1_000 * SECOND), SECOND);
+
+        assert!(cache.get("SESSION-1", 2 * SECOND).is_some());
+        assert!(cache.get("SESSION-1", 2 * SECOND + SESSION_CACHE_TTL_SECONDS * SECOND).is_none());
+        assert!(cache.get("SESSION-2", 2 * SECOND).is_none());
+
+        // Never outlives the session itself
+        cache.insert(session("SESSION-3", 3 * SECOND), SECOND);
+        assert!(cache.get("SESSION-3", 3 * SECOND).is_none());
+    }
+
+    #[test]
+    fn test_session_cache_is_bounded() {
+        let mut cache = SessionCache::default();
+        for i in 0..=SESSION_CACHE_MAX_ENTRIES as u64 {
+            cache.insert(session(&format!("SESSION-{}", i), 1_000 * SECOND), SECOND + i);
+        }
+
+        assert_eq!(cache.entries.len(), SESSION_CACHE_MAX_ENTRIES);
+        assert!(cache.get("SESSION-0", 2 * SECOND).is_none());
+    }
+
+    #[test]
+    fn test_stale_cache_denies() {
+        let cache = cache_with_admin(SECOND);
+        let limit = SECOND + ROLE_CACHE_MAX_AGE_SECONDS * SECOND;
+        assert!(cache.roles_at(&admin(), limit).is_ok());
+        assert!(cache.roles_at(&admin(), limit + 1).is_err());
+    }
+
+    #[test]
+    fn test_verification_attesters() {
+        let canister = |id: u8, service: ServiceName| CanisterConfig {
+            canister_id: Principal::from_slice(&[id, 0xAA]),
+            name: service.as_str().to_string(),
+            is_active: true,
+            retry_count: 0,
+            timeout_seconds: 0,
+        };
+        load_canister_registry(vec![
+            canister(1, ServiceName::Compliance),
+            canister(2, ServiceName::UniversityCredential),
+            canister(3, ServiceName::LoanManagement),
+        ])
+        .unwrap();
+
+        assert!(is_attesting_service(&Principal::from_slice(&[1, 0xAA])));
+        assert!(is_attesting_service(&Principal::from_slice(&[2, 0xAA])));
+        assert!(!is_attesting_service(&Principal::from_slice(&[3, 0xAA])));
+        assert!(!is_attesting_service(&admin()));
+
+        // Everyone else needs a role carrying VerifyStudents
+        assert!(role_has_permission(&Role::University, &Permission::VerifyStudents));
+        assert!(!role_has_permission(&Role::Student, &Permission::VerifyStudents));
+    }
+}
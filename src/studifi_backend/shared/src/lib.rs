@@ -1,8 +1,18 @@
 pub mod types;
 pub mod utils;
 pub mod constants;
+pub mod clients;
+pub mod resilience;
+pub mod storable;
+pub mod guards;
+pub mod audit;
 
 // Re-export commonly used types and functions
 pub use types::*;
 pub use utils::*;
 pub use constants::*;
+pub use clients::*;
+pub use resilience::*;
+pub use storable::*;
+pub use guards::*;
+pub use audit::*;
@@ -0,0 +1,441 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::RejectionCode;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::constants::*;
+use crate::types::*;
+
+// ============================================================================
+// REJECTION CLASSIFICATION
+// ============================================================================
+
+/// How a failed inter-canister call should be handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    /// Temporary condition on the subnet (e.g. queue full); worth retrying
+    Transient,
+    /// The callee is unreachable or broken; retrying will not help, but it counts against its health
+    Unhealthy,
+    /// The callee deliberately rejected the call; retrying will not help and the callee is healthy
+    Rejected,
+}
+
+/// Classify a rejection code from `ic_cdk::call`
+pub fn classify_rejection(code: RejectionCode) -> FailureKind {
+    match code {
+        RejectionCode::SysTransient => FailureKind::Transient,
+        RejectionCode::CanisterReject => FailureKind::Rejected,
+        RejectionCode::NoError
+        | RejectionCode::SysFatal
+        | RejectionCode::DestinationInvalid
+        | RejectionCode::CanisterError
+        | RejectionCode::Unknown => FailureKind::Unhealthy,
+    }
+}
+
+// ============================================================================
+// BACKOFF
+// ============================================================================
+
+/// Delay before retry number `attempt` (0-based): doubles from the base delay, capped at the maximum
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let multiplier = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+    let delay_ms = RETRY_BASE_DELAY_MS.saturating_mul(multiplier).min(RETRY_MAX_DELAY_MS);
+    Duration::from_millis(delay_ms)
+}
+
+#[derive(Default)]
+struct SleepState {
+    elapsed: bool,
+    waker: Option<Waker>,
+}
+
+/// Future that completes once an `ic_cdk_timers` one-shot timer fires
+pub struct Sleep {
+    state: Rc<RefCell<SleepState>>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.borrow_mut();
+        if state.elapsed {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Suspend the current call context for `delay`
+pub fn sleep(delay: Duration) -> Sleep {
+    let state = Rc::new(RefCell::new(SleepState::default()));
+    let timer_state = state.clone();
+
+    ic_cdk_timers::set_timer(delay, move || {
+        let waker = {
+            let mut state = timer_state.borrow_mut();
+            state.elapsed = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+
+    Sleep { state }
+}
+
+// ============================================================================
+// RETRY
+// ============================================================================
+
+/// Drive `call` against `config`'s canister with retries and circuit breaking.
+///
+/// Only transient rejections are retried, up to `config.retry_count` times with
+/// exponential backoff, and only while the next attempt would start within
+/// `config.timeout_seconds` of the first (zero means no limit). `wait` suspends
+/// the call between attempts; `call_canister` passes `sleep`. Calls to a canister
+/// whose circuit breaker is open fail fast.
+pub async fn call_with_retries<R, F, Fut, W, WFut>(
+    config: &CanisterConfig,
+    method: &str,
+    clock: impl Fn() -> Timestamp,
+    mut wait: W,
+    mut call: F,
+) -> StudiFiResult<R>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<R, (RejectionCode, String)>>,
+    W: FnMut(Duration) -> WFut,
+    WFut: Future<Output = ()>,
+{
+    let deadline = match config.timeout_seconds {
+        0 => Timestamp::MAX,
+        timeout => clock().saturating_add(timeout.saturating_mul(1_000_000_000)),
+    };
+    let mut attempt = 0;
+
+    loop {
+        if !circuit_allows(config, clock()) {
+            return Err(StudiFiError::ExternalServiceError(
+                format!("{} on {} skipped: circuit breaker is open", method, config.name)
+            ));
+        }
+
+        let (code, message) = match call().await {
+            Ok(result) => {
+                record_call_success(config, clock());
+                return Ok(result);
+            }
+            Err(e) => e,
+        };
+
+        let now = clock();
+        let kind = classify_rejection(code);
+        if kind != FailureKind::Rejected {
+            record_call_failure(config, now, format!("{} ({:?}): {}", method, code, message));
+        }
+
+        if kind != FailureKind::Transient {
+            return Err(StudiFiError::ExternalServiceError(
+                format!("{} on {} rejected ({:?}): {}", method, config.name, code, message)
+            ));
+        }
+
+        let delay = backoff_delay(attempt);
+        if attempt >= config.retry_count || now.saturating_add(delay.as_nanos() as u64) >= deadline {
+            return Err(StudiFiError::NetworkError(
+                format!("{} on {} unavailable after {} attempt(s): {}", method, config.name, attempt + 1, message)
+            ));
+        }
+
+        wait(delay).await;
+        attempt += 1;
+    }
+}
+
+// ============================================================================
+// CIRCUIT BREAKER
+// ============================================================================
+
+/// Circuit breaker state
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum CircuitState {
+    /// Calls flow normally
+    Closed,
+    /// Calls fail fast until the cooldown elapses
+    Open,
+    /// Cooldown elapsed; the next call is a probe that closes or re-opens the circuit
+    HalfOpen,
+}
+
+/// Health of a single downstream canister as seen by this canister
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct CircuitBreaker {
+    pub canister_id: Principal,
+    pub service: String,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub opened_at: Option<Timestamp>,
+    pub last_failure: Option<String>,
+    pub last_failure_at: Option<Timestamp>,
+    pub last_success_at: Option<Timestamp>,
+    pub total_failures: u64,
+    pub total_successes: u64,
+}
+
+impl CircuitBreaker {
+    pub fn new(canister_id: Principal, service: String) -> Self {
+        Self {
+            canister_id,
+            service,
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            last_failure: None,
+            last_failure_at: None,
+            last_success_at: None,
+            total_failures: 0,
+            total_successes: 0,
+        }
+    }
+
+    /// Whether a call may be attempted at `now`; moves an expired open circuit to half-open
+    pub fn allow_request(&mut self, now: Timestamp) -> bool {
+        match self.state {
+            CircuitState::Closed | CircuitState::HalfOpen => true,
+            CircuitState::Open => {
+                let cooldown_elapsed = self.opened_at
+                    .map(|opened_at| now.saturating_sub(opened_at) >= CIRCUIT_BREAKER_COOLDOWN_SECONDS * 1_000_000_000)
+                    .unwrap_or(true);
+                if cooldown_elapsed {
+                    self.state = CircuitState::HalfOpen;
+                }
+                cooldown_elapsed
+            }
+        }
+    }
+
+    pub fn record_success(&mut self, now: Timestamp) {
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+        self.last_success_at = Some(now);
+        self.total_successes += 1;
+    }
+
+    pub fn record_failure(&mut self, now: Timestamp, reason: String) {
+        self.consecutive_failures += 1;
+        self.total_failures += 1;
+        self.last_failure = Some(reason);
+        self.last_failure_at = Some(now);
+
+        // A failed probe re-opens immediately; otherwise trip after repeated failures
+        if self.state == CircuitState::HalfOpen || self.consecutive_failures >= CIRCUIT_BREAKER_FAILURE_THRESHOLD {
+            self.state = CircuitState::Open;
+            self.opened_at = Some(now);
+        }
+    }
+}
+
+thread_local! {
+    static CIRCUIT_BREAKERS: RefCell<BTreeMap<Principal, CircuitBreaker>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn with_breaker<R>(config: &CanisterConfig, f: impl FnOnce(&mut CircuitBreaker) -> R) -> R {
+    CIRCUIT_BREAKERS.with(|breakers| {
+        let mut breakers = breakers.borrow_mut();
+        let breaker = breakers
+            .entry(config.canister_id)
+            .or_insert_with(|| CircuitBreaker::new(config.canister_id, config.name.clone()));
+        f(breaker)
+    })
+}
+
+/// Check the target's breaker before calling it
+pub fn circuit_allows(config: &CanisterConfig, now: Timestamp) -> bool {
+    with_breaker(config, |breaker| breaker.allow_request(now))
+}
+
+pub fn record_call_success(config: &CanisterConfig, now: Timestamp) {
+    with_breaker(config, |breaker| breaker.record_success(now));
+}
+
+pub fn record_call_failure(config: &CanisterConfig, now: Timestamp, reason: String) {
+    with_breaker(config, |breaker| breaker.record_failure(now, reason));
+}
+
+/// Breaker state for every downstream canister this canister has called since its last upgrade
+pub fn circuit_breaker_states() -> Vec<CircuitBreaker> {
+    CIRCUIT_BREAKERS.with(|breakers| breakers.borrow().values().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Timestamp = 1_000_000_000;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(Principal::anonymous(), "loan_management_service".to_string())
+    }
+
+    #[test]
+    fn test_rejection_classification() {
+        assert_eq!(classify_rejection(RejectionCode::SysTransient), FailureKind::Transient);
+        assert_eq!(classify_rejection(RejectionCode::CanisterReject), FailureKind::Rejected);
+        assert_eq!(classify_rejection(RejectionCode::DestinationInvalid), FailureKind::Unhealthy);
+        assert_eq!(classify_rejection(RejectionCode::CanisterError), FailureKind::Unhealthy);
+    }
+
+    #[test]
+    fn test_breaker_trips_after_threshold() {
+        let mut breaker = breaker();
+        for i in 1..CIRCUIT_BREAKER_FAILURE_THRESHOLD {
+            breaker.record_failure(i as u64 * SECOND, "unreachable".to_string());
+            assert_eq!(breaker.state, CircuitState::Closed);
+        }
+
+        let now = 100 * SECOND;
+        breaker.record_failure(now, "unreachable".to_string());
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert!(!breaker.allow_request(now + SECOND));
+    }
+
+    #[test]
+    fn test_breaker_half_open_probe() {
+        let mut breaker = breaker();
+        for _ in 0..CIRCUIT_BREAKER_FAILURE_THRESHOLD {
+            breaker.record_failure(SECOND, "unreachable".to_string());
+        }
+
+        let after_cooldown = SECOND + CIRCUIT_BREAKER_COOLDOWN_SECONDS * SECOND;
+        assert!(breaker.allow_request(after_cooldown));
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+
+        // A failed probe re-opens the circuit straight away
+        breaker.record_failure(after_cooldown, "still down".to_string());
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert!(!breaker.allow_request(after_cooldown + SECOND));
+
+        // A successful probe closes it
+        let later = after_cooldown + CIRCUIT_BREAKER_COOLDOWN_SECONDS * SECOND;
+        assert!(breaker.allow_request(later));
+        breaker.record_success(later);
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures, 0);
+    }
+
+    /// Poll a future to completion. The test clock is advanced by the waits
+    /// instead of a timer, so the retry loop never stays pending.
+    fn run<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("call_with_retries suspended"),
+        }
+    }
+
+    fn config(retry_count: u32) -> CanisterConfig {
+        CanisterConfig {
+            canister_id: Principal::from_slice(&[retry_count as u8, 7]),
+            name: "credit_assessment_service".to_string(),
+            is_active: true,
+            retry_count,
+            timeout_seconds: 60,
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_and_caps() {
+        assert_eq!(backoff_delay(0), Duration::from_millis(RETRY_BASE_DELAY_MS));
+        assert_eq!(backoff_delay(1), Duration::from_millis(RETRY_BASE_DELAY_MS * 2));
+        assert_eq!(backoff_delay(2), Duration::from_millis(RETRY_BASE_DELAY_MS * 4));
+        assert_eq!(backoff_delay(40), Duration::from_millis(RETRY_MAX_DELAY_MS));
+        assert_eq!(backoff_delay(u32::MAX), Duration::from_millis(RETRY_MAX_DELAY_MS));
+    }
+
+    #[test]
+    fn test_retries_back_off_between_attempts() {
+        let config = config(2);
+        let attempts = std::cell::Cell::new(0);
+        let now = std::cell::Cell::new(10 * SECOND);
+        let waits = RefCell::new(Vec::new());
+
+        let result = run(call_with_retries(
+            &config,
+            "get_score",
+            || now.get(),
+            |delay: Duration| {
+                waits.borrow_mut().push(delay);
+                now.set(now.get() + delay.as_nanos() as u64);
+                async {}
+            },
+            || {
+                attempts.set(attempts.get() + 1);
+                let outcome = if attempts.get() < 3 {
+                    Err((RejectionCode::SysTransient, "queue full".to_string()))
+                } else {
+                    Ok(720u32)
+                };
+                async move { outcome }
+            },
+        ));
+
+        assert_eq!(result.unwrap(), 720);
+        assert_eq!(attempts.get(), 3);
+        assert_eq!(*waits.borrow(), vec![backoff_delay(0), backoff_delay(1)]);
+        let breaker = circuit_breaker_states().into_iter()
+            .find(|breaker| breaker.canister_id == config.canister_id)
+            .unwrap();
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.total_failures, 2);
+        assert_eq!(breaker.total_successes, 1);
+    }
+
+    #[test]
+    fn test_retries_stop_at_retry_count_deadline_and_rejection() {
+        let attempts = std::cell::Cell::new(0);
+        let now = std::cell::Cell::new(SECOND);
+        let transient = || {
+            attempts.set(attempts.get() + 1);
+            async { Err::<u32, _>((RejectionCode::SysTransient, "queue full".to_string())) }
+        };
+        let wait = |delay: Duration| {
+            now.set(now.get() + delay.as_nanos() as u64);
+            async {}
+        };
+
+        let result = run(call_with_retries(&config(1), "get_score", || now.get(), wait, transient));
+        assert!(matches!(result, Err(StudiFiError::NetworkError(_))));
+        assert_eq!(attempts.get(), 2);
+
+        // A retry that would start past the timeout is not attempted
+        let mut short = config(10);
+        short.timeout_seconds = 1;
+        attempts.set(0);
+        let result = run(call_with_retries(&short, "get_score", || now.get(), wait, transient));
+        assert!(matches!(result, Err(StudiFiError::NetworkError(_))));
+        let total: u64 = (0..attempts.get() - 1).map(|attempt| backoff_delay(attempt).as_millis() as u64).sum();
+        assert!(total < 1_000 && total + backoff_delay(attempts.get() - 1).as_millis() as u64 >= 1_000);
+
+        attempts.set(0);
+        let result: StudiFiResult<u32> = run(call_with_retries(&config(1), "get_score", || now.get(), wait, || {
+            attempts.set(attempts.get() + 1);
+            async { Err((RejectionCode::CanisterReject, "unknown student".to_string())) }
+        }));
+        assert!(matches!(result, Err(StudiFiError::ExternalServiceError(_))));
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,430 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager},
+    storable::Bound,
+    Memory, RestrictedMemory, StableBTreeMap, Storable,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::ops::RangeBounds;
+
+use crate::clients::CanisterRegistry;
+use crate::types::*;
+
+// ============================================================================
+// VERSIONED ENVELOPE
+// ============================================================================
+
+/// Marks bytes written by `encode_versioned`. Neither JSON nor candid ("DIDL") can start with it.
+const ENVELOPE_MAGIC: &[u8; 3] = b"SFV";
+const ENVELOPE_HEADER_LEN: usize = ENVELOPE_MAGIC.len() + 2;
+
+/// A record type kept in stable memory.
+///
+/// Records are stored as `magic + version (u16 LE) + JSON payload`. Adding a field
+/// only needs `#[serde(default)]` on it; a breaking change bumps `VERSION` and
+/// teaches `migrate` how to read the older payloads.
+pub trait VersionedRecord: Serialize + DeserializeOwned {
+    /// Schema version written by this build
+    const VERSION: u16;
+
+    /// Decode a payload written at `version`, which may be older than `VERSION`
+    fn migrate(version: u16, payload: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(payload)
+            .map_err(|e| format!("cannot decode version {} payload: {}", version, e))
+    }
+
+    /// Decode bytes written before records carried an envelope
+    fn decode_legacy(bytes: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(bytes).map_err(|e| format!("cannot decode legacy record: {}", e))
+    }
+}
+
+/// Encode a record in the current version's envelope
+pub fn encode_versioned<T: VersionedRecord>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ENVELOPE_HEADER_LEN + 64);
+    bytes.extend_from_slice(ENVELOPE_MAGIC);
+    bytes.extend_from_slice(&T::VERSION.to_le_bytes());
+    serde_json::to_writer(&mut bytes, value).expect("stable records must serialize to JSON");
+    bytes
+}
+
+/// Decode an enveloped or legacy record, migrating older versions to the current one
+pub fn decode_versioned<T: VersionedRecord>(bytes: &[u8]) -> Result<T, UndecodableRecord> {
+    let undecodable = |version, error| UndecodableRecord {
+        version,
+        bytes: bytes.to_vec(),
+        error,
+    };
+
+    if bytes.len() < ENVELOPE_HEADER_LEN || !bytes.starts_with(ENVELOPE_MAGIC) {
+        return T::decode_legacy(bytes).map_err(|e| undecodable(None, e));
+    }
+
+    let version = u16::from_le_bytes([bytes[3], bytes[4]]);
+    if version > T::VERSION {
+        return Err(undecodable(
+            Some(version),
+            format!("record version {} is newer than supported version {}", version, T::VERSION),
+        ));
+    }
+
+    T::migrate(version, &bytes[ENVELOPE_HEADER_LEN..]).map_err(|e| undecodable(Some(version), e))
+}
+
+/// Stored bytes that could not be decoded, kept verbatim so nothing is lost
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct UndecodableRecord {
+    pub version: Option<u16>,
+    pub bytes: Vec<u8>,
+    pub error: String,
+}
+
+/// Storable wrapper that decodes through the versioned envelope without panicking
+#[derive(Clone, Debug)]
+pub struct Versioned<T>(pub Result<T, UndecodableRecord>);
+
+impl<T> Versioned<T> {
+    pub fn new(value: T) -> Self {
+        Versioned(Ok(value))
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.0.ok()
+    }
+}
+
+impl<T: VersionedRecord> Storable for Versioned<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match &self.0 {
+            Ok(value) => Cow::Owned(encode_versioned(value)),
+            // Write undecodable records back untouched so a later build can still recover them
+            Err(record) => Cow::Borrowed(&record.bytes),
+        }
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Versioned(decode_versioned(&bytes))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// ============================================================================
+// VERSIONED MAP
+// ============================================================================
+
+/// `StableBTreeMap` whose values are stored in the versioned envelope.
+///
+/// Records that fail to decode are skipped by reads rather than trapping the
+/// canister; `undecodable_entries` lists them for inspection.
+pub struct VersionedMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: VersionedRecord,
+    M: Memory,
+{
+    inner: StableBTreeMap<K, Versioned<V>, M>,
+}
+
+impl<K, V, M> VersionedMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: VersionedRecord,
+    M: Memory,
+{
+    pub fn init(memory: M) -> Self {
+        Self {
+            inner: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.inner.get(key).and_then(Versioned::into_inner)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.inner.insert(key, Versioned::new(value)).and_then(Versioned::into_inner)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.inner.remove(key).and_then(Versioned::into_inner)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    /// Number of stored records, including undecodable ones
+    pub fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Iterate over decodable records in key order
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        self.inner
+            .iter()
+            .filter_map(|(key, value)| value.into_inner().map(|value| (key, value)))
+    }
+
+    /// Iterate over decodable records with keys in `key_range`, in key order
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        self.inner
+            .range(key_range)
+            .filter_map(|(key, value)| value.into_inner().map(|value| (key, value)))
+    }
+
+    /// Records that could not be decoded by this build
+    pub fn undecodable_entries(&self) -> Vec<(K, UndecodableRecord)> {
+        self.inner
+            .iter()
+            .filter_map(|(key, value)| value.0.err().map(|record| (key, record)))
+            .collect()
+    }
+}
+
+// ============================================================================
+// LEGACY LAYOUT MIGRATION
+// ============================================================================
+
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+const BTREE_MAGIC: &[u8; 3] = b"BTR";
+
+/// Whether stable memory still uses the old layout, where each map was confined
+/// to its own 64 KiB page through `RestrictedMemory`
+pub fn has_legacy_layout<M: Memory>(memory: &M) -> bool {
+    if memory.size() == 0 {
+        return false;
+    }
+
+    let mut magic = [0u8; 3];
+    memory.read(0, &mut magic);
+    &magic != MEMORY_MANAGER_MAGIC
+}
+
+/// A map read out of the legacy layout, waiting to be written into its `MemoryManager` memory
+pub trait LegacyMap<M: Memory> {
+    /// Copy the entries into the map's new memory, returning how many were copied
+    fn restore(self: Box<Self>, memory_manager: &MemoryManager<M>) -> u64;
+}
+
+struct LegacyEntries<K> {
+    memory_id: MemoryId,
+    entries: Vec<(K, Vec<u8>)>,
+}
+
+impl<K, M> LegacyMap<M> for LegacyEntries<K>
+where
+    K: Storable + Ord + Clone,
+    M: Memory,
+{
+    fn restore(self: Box<Self>, memory_manager: &MemoryManager<M>) -> u64 {
+        // Values are copied as raw bytes, so legacy encodings are still recognised by `decode_versioned`
+        let mut map: StableBTreeMap<K, Vec<u8>, _> = StableBTreeMap::init(memory_manager.get(self.memory_id));
+        for (key, value) in self.entries {
+            map.insert(key, value);
+        }
+        map.len()
+    }
+}
+
+/// Read the map kept in `page` of the legacy layout, to be restored into `memory_id`.
+///
+/// `K` must be the key type the map was written with.
+pub fn read_legacy_map<K, M>(memory: &M, page: u64, memory_id: MemoryId) -> Box<dyn LegacyMap<M>>
+where
+    K: Storable + Ord + Clone + 'static,
+    M: Memory + Clone + 'static,
+{
+    let restricted = RestrictedMemory::new(memory.clone(), page..page + 1);
+
+    let mut magic = [0u8; 3];
+    if restricted.size() > 0 {
+        restricted.read(0, &mut magic);
+    }
+
+    let entries = if &magic == BTREE_MAGIC {
+        StableBTreeMap::<K, Vec<u8>, _>::load(restricted).iter().collect()
+    } else {
+        Vec::new()
+    };
+
+    Box::new(LegacyEntries { memory_id, entries })
+}
+
+/// Initialise the canister's `MemoryManager`, first carrying over any maps still in the legacy layout.
+///
+/// `legacy_maps` lists every map the canister kept in the legacy layout; it is only called
+/// when a legacy layout is found, and everything is read before the manager takes over the memory.
+pub fn init_memory_manager<M, F>(memory: M, legacy_maps: F) -> MemoryManager<M>
+where
+    M: Memory + Clone,
+    F: FnOnce(&M) -> Vec<Box<dyn LegacyMap<M>>>,
+{
+    let legacy = if has_legacy_layout(&memory) {
+        legacy_maps(&memory)
+    } else {
+        Vec::new()
+    };
+
+    let memory_manager = MemoryManager::init(memory);
+    for map in legacy {
+        map.restore(&memory_manager);
+    }
+
+    memory_manager
+}
+
+// ============================================================================
+// SHARED RECORD VERSIONS
+// ============================================================================
+
+impl VersionedRecord for UserSession {
+    const VERSION: u16 = 1;
+
+    // Sessions were candid-encoded before the envelope was introduced
+    fn decode_legacy(bytes: &[u8]) -> Result<Self, String> {
+        candid::decode_one(bytes).map_err(|e| format!("cannot decode legacy session: {}", e))
+    }
+}
+
+impl VersionedRecord for CanisterRegistry {
+    const VERSION: u16 = 1;
+}
+
+impl VersionedRecord for AmlTransactionReport {
+    const VERSION: u16 = 1;
+}
+
+impl VersionedRecord for ConsentNotice {
+    const VERSION: u16 = 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    struct RecordV1 {
+        name: String,
+    }
+
+    impl VersionedRecord for RecordV1 {
+        const VERSION: u16 = 1;
+    }
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    struct RecordV2 {
+        full_name: String,
+        #[serde(default)]
+        nickname: Option<String>,
+    }
+
+    impl VersionedRecord for RecordV2 {
+        const VERSION: u16 = 2;
+
+        fn migrate(version: u16, payload: &[u8]) -> Result<Self, String> {
+            match version {
+                1 => {
+                    let old: RecordV1 = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+                    Ok(RecordV2 { full_name: old.name, nickname: None })
+                }
+                _ => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_versioned_round_trip() {
+        let record = RecordV2 { full_name: "Ada".to_string(), nickname: Some("A".to_string()) };
+        let bytes = encode_versioned(&record);
+        assert!(bytes.starts_with(ENVELOPE_MAGIC));
+        assert_eq!(decode_versioned::<RecordV2>(&bytes).unwrap(), record);
+    }
+
+    #[test]
+    fn test_old_version_is_migrated() {
+        let bytes = encode_versioned(&RecordV1 { name: "Ada".to_string() });
+        let migrated = decode_versioned::<RecordV2>(&bytes).unwrap();
+        assert_eq!(migrated, RecordV2 { full_name: "Ada".to_string(), nickname: None });
+    }
+
+    #[test]
+    fn test_legacy_json_is_decoded() {
+        let legacy = serde_json::to_vec(&RecordV1 { name: "Ada".to_string() }).unwrap();
+        assert_eq!(decode_versioned::<RecordV1>(&legacy).unwrap(), RecordV1 { name: "Ada".to_string() });
+    }
+
+    #[test]
+    fn test_undecodable_bytes_do_not_panic() {
+        let newer = {
+            let mut bytes = encode_versioned(&RecordV2 { full_name: "Ada".to_string(), nickname: None });
+            bytes[3..5].copy_from_slice(&3u16.to_le_bytes());
+            bytes
+        };
+        let error = decode_versioned::<RecordV2>(&newer).unwrap_err();
+        assert_eq!(error.version, Some(3));
+        assert_eq!(error.bytes, newer);
+
+        let garbage = Versioned::<RecordV1>::from_bytes(Cow::Borrowed(b"\xff\x00garbage"));
+        assert!(garbage.0.is_err());
+        assert_eq!(garbage.to_bytes().as_ref(), b"\xff\x00garbage");
+    }
+
+    #[test]
+    fn test_legacy_layout_is_migrated() {
+        let memory = VectorMemory::default();
+        {
+            let mut names: StableBTreeMap<String, Vec<u8>, _> =
+                StableBTreeMap::init(RestrictedMemory::new(memory.clone(), 0..1));
+            names.insert("ada".to_string(), serde_json::to_vec(&RecordV1 { name: "Ada".to_string() }).unwrap());
+            let mut counts: StableBTreeMap<u64, u64, _> =
+                StableBTreeMap::init(RestrictedMemory::new(memory.clone(), 1..2));
+            counts.insert(7, 42);
+        }
+        assert!(has_legacy_layout(&memory));
+
+        let legacy_maps = |memory: &VectorMemory| {
+            vec![
+                read_legacy_map::<String, _>(memory, 0, MemoryId::new(0)),
+                read_legacy_map::<u64, _>(memory, 1, MemoryId::new(1)),
+                read_legacy_map::<u64, _>(memory, 2, MemoryId::new(2)),
+            ]
+        };
+        let memory_manager = init_memory_manager(memory.clone(), legacy_maps);
+        assert!(!has_legacy_layout(&memory));
+
+        let names: VersionedMap<String, RecordV1, _> = VersionedMap::init(memory_manager.get(MemoryId::new(0)));
+        assert_eq!(names.get(&"ada".to_string()), Some(RecordV1 { name: "Ada".to_string() }));
+        let counts: StableBTreeMap<u64, u64, _> = StableBTreeMap::init(memory_manager.get(MemoryId::new(1)));
+        assert_eq!(counts.get(&7), Some(42));
+
+        // Later upgrades find the MemoryManager layout and leave the data alone
+        let memory_manager = init_memory_manager(memory, |_: &VectorMemory| panic!("no legacy layout expected"));
+        let names: VersionedMap<String, RecordV1, _> = VersionedMap::init(memory_manager.get(MemoryId::new(0)));
+        assert_eq!(names.len(), 1);
+    }
+
+    #[test]
+    fn test_versioned_map_skips_undecodable_records() {
+        let memory = VectorMemory::default();
+        {
+            let mut raw: StableBTreeMap<u64, Vec<u8>, _> = StableBTreeMap::init(memory.clone());
+            raw.insert(1, encode_versioned(&RecordV1 { name: "Ada".to_string() }));
+            raw.insert(2, b"not json".to_vec());
+        }
+
+        let map: VersionedMap<u64, RecordV1, _> = VersionedMap::init(memory);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&1), Some(RecordV1 { name: "Ada".to_string() }));
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.iter().count(), 1);
+        assert_eq!(map.undecodable_entries().len(), 1);
+    }
+}
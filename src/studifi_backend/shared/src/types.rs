@@ -1,4 +1,7 @@
 use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+use crate::utils::current_time;
 
 /// Common result type used across all canisters
 pub type StudiFiResult<T> = Result<T, StudiFiError>;
@@ -49,6 +52,268 @@ pub type Percentage = f64;
 /// Common ID type for entities
 pub type EntityId = String;
 
+/// Rounding strategies used when fixed-point values are reduced in precision
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round half to even (banker's rounding)
+    HalfEven,
+    /// Round half away from zero
+    HalfUp,
+    /// Truncate towards zero
+    Down,
+    /// Round away from zero
+    Up,
+}
+
+/// Divide `numerator` by a positive `denominator`, rounding the quotient with `mode`
+fn div_round(numerator: i128, denominator: i128, mode: RoundingMode) -> i128 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    if remainder == 0 {
+        return quotient;
+    }
+
+    let sign = if numerator < 0 { -1 } else { 1 };
+    let twice_remainder = remainder.abs() * 2;
+    let round_away = match mode {
+        RoundingMode::Down => false,
+        RoundingMode::Up => true,
+        RoundingMode::HalfUp => twice_remainder >= denominator,
+        RoundingMode::HalfEven => {
+            twice_remainder > denominator || (twice_remainder == denominator && quotient % 2 != 0)
+        }
+    };
+
+    if round_away {
+        quotient + sign
+    } else {
+        quotient
+    }
+}
+
+/// Multiply two fixed-point values sharing `scale` without overflowing the intermediate product
+fn mul_scaled(a: i128, b: i128, scale: i128, mode: RoundingMode) -> Option<i128> {
+    let negative = (a < 0) != (b < 0);
+    let (a, b) = (a.checked_abs()?, b.checked_abs()?);
+    let (a_hi, a_lo) = (a / scale, a % scale);
+    let (b_hi, b_lo) = (b / scale, b % scale);
+
+    let whole = a_hi
+        .checked_mul(b_hi)?
+        .checked_mul(scale)?
+        .checked_add(a_hi.checked_mul(b_lo)?)?
+        .checked_add(a_lo.checked_mul(b_hi)?)?;
+    let fraction = div_round(a_lo.checked_mul(b_lo)?, scale, mode);
+    let magnitude = whole.checked_add(fraction)?;
+
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+fn arithmetic_overflow(operation: &str) -> StudiFiError {
+    StudiFiError::InternalError(format!("Arithmetic overflow in {}", operation))
+}
+
+/// Fixed-point monetary value with sub-cent precision.
+///
+/// Values are held as signed micro-cents so intermediate interest and fee
+/// calculations keep their precision until they are explicitly rounded back
+/// to an `Amount` in cents.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Money(i128);
+
+impl Money {
+    /// Fixed-point units per cent
+    pub const UNITS_PER_CENT: i128 = 1_000_000;
+    pub const ZERO: Money = Money(0);
+
+    pub fn from_cents(cents: Amount) -> Self {
+        Money(cents as i128 * Self::UNITS_PER_CENT)
+    }
+
+    pub fn from_raw(units: i128) -> Self {
+        Money(units)
+    }
+
+    pub fn raw(&self) -> i128 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    /// Round to a whole number of cents, keeping the `Money` representation
+    pub fn round_to_cent(&self, mode: RoundingMode) -> Money {
+        Money(div_round(self.0, Self::UNITS_PER_CENT, mode) * Self::UNITS_PER_CENT)
+    }
+
+    /// Round to cents and convert to an `Amount`
+    pub fn to_cents(&self, mode: RoundingMode) -> StudiFiResult<Amount> {
+        let cents = div_round(self.0, Self::UNITS_PER_CENT, mode);
+        if cents < 0 {
+            return Err(StudiFiError::InvalidInput(
+                "Monetary amount cannot be negative".to_string()
+            ));
+        }
+        Amount::try_from(cents).map_err(|_| arithmetic_overflow("Money::to_cents"))
+    }
+
+    pub fn checked_add(self, other: Money) -> StudiFiResult<Money> {
+        self.0.checked_add(other.0).map(Money).ok_or_else(|| arithmetic_overflow("Money::checked_add"))
+    }
+
+    pub fn checked_sub(self, other: Money) -> StudiFiResult<Money> {
+        self.0.checked_sub(other.0).map(Money).ok_or_else(|| arithmetic_overflow("Money::checked_sub"))
+    }
+
+    pub fn checked_mul(self, factor: u64) -> StudiFiResult<Money> {
+        self.0.checked_mul(factor as i128).map(Money).ok_or_else(|| arithmetic_overflow("Money::checked_mul"))
+    }
+
+    pub fn checked_div(self, divisor: u64, mode: RoundingMode) -> StudiFiResult<Money> {
+        if divisor == 0 {
+            return Err(StudiFiError::InvalidInput("Division by zero".to_string()));
+        }
+        Ok(Money(div_round(self.0, divisor as i128, mode)))
+    }
+
+    /// Multiply by a rate, rounding to the nearest fixed-point unit
+    pub fn checked_mul_rate(self, rate: Rate) -> StudiFiResult<Money> {
+        mul_scaled(self.0, rate.0, Rate::SCALE, RoundingMode::HalfEven)
+            .map(Money)
+            .ok_or_else(|| arithmetic_overflow("Money::checked_mul_rate"))
+    }
+
+    /// Divide by a rate, rounding to the nearest fixed-point unit
+    pub fn checked_div_rate(self, rate: Rate) -> StudiFiResult<Money> {
+        if rate.0 == 0 {
+            return Err(StudiFiError::InvalidInput("Division by zero rate".to_string()));
+        }
+        let numerator = self.0.checked_mul(Rate::SCALE)
+            .ok_or_else(|| arithmetic_overflow("Money::checked_div_rate"))?;
+        Ok(Money(div_round(numerator, rate.0, RoundingMode::HalfEven)))
+    }
+
+    pub fn min(self, other: Money) -> Money {
+        std::cmp::min(self, other)
+    }
+
+    pub fn max(self, other: Money) -> Money {
+        std::cmp::max(self, other)
+    }
+}
+
+/// Fixed-point rate (1.0 == 100%) with 18 decimal places of precision
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rate(i128);
+
+impl Rate {
+    pub const SCALE: i128 = 1_000_000_000_000_000_000;
+    pub const ZERO: Rate = Rate(0);
+    pub const ONE: Rate = Rate(Self::SCALE);
+
+    /// Convert a `Percentage` (0.05 == 5%) into a fixed-point rate.
+    ///
+    /// Input is rounded to 12 decimal places so binary floating point noise
+    /// (e.g. 0.05 stored as 0.05000000000000000277) does not leak into the
+    /// fixed-point value.
+    pub fn from_percentage(percentage: Percentage) -> StudiFiResult<Rate> {
+        if !percentage.is_finite() {
+            return Err(StudiFiError::InvalidInput("Rate must be a finite number".to_string()));
+        }
+        let scaled = (percentage * 1e12).round();
+        if scaled.abs() > 1e24 {
+            return Err(arithmetic_overflow("Rate::from_percentage"));
+        }
+        Ok(Rate(scaled as i128 * 1_000_000))
+    }
+
+    pub fn from_basis_points(basis_points: u32) -> Rate {
+        Rate(basis_points as i128 * (Self::SCALE / 10_000))
+    }
+
+    pub fn from_raw(units: i128) -> Rate {
+        Rate(units)
+    }
+
+    pub fn raw(&self) -> i128 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Lossy conversion back to a `Percentage`, for display and reporting only
+    pub fn to_percentage(&self) -> Percentage {
+        self.0 as f64 / Self::SCALE as f64
+    }
+
+    pub fn checked_add(self, other: Rate) -> StudiFiResult<Rate> {
+        self.0.checked_add(other.0).map(Rate).ok_or_else(|| arithmetic_overflow("Rate::checked_add"))
+    }
+
+    pub fn checked_sub(self, other: Rate) -> StudiFiResult<Rate> {
+        self.0.checked_sub(other.0).map(Rate).ok_or_else(|| arithmetic_overflow("Rate::checked_sub"))
+    }
+
+    pub fn checked_mul(self, other: Rate) -> StudiFiResult<Rate> {
+        mul_scaled(self.0, other.0, Self::SCALE, RoundingMode::HalfEven)
+            .map(Rate)
+            .ok_or_else(|| arithmetic_overflow("Rate::checked_mul"))
+    }
+
+    /// Divide by an integer, e.g. an annual rate into a per-period rate
+    pub fn checked_div_int(self, divisor: u32) -> StudiFiResult<Rate> {
+        if divisor == 0 {
+            return Err(StudiFiError::InvalidInput("Division by zero".to_string()));
+        }
+        Ok(Rate(div_round(self.0, divisor as i128, RoundingMode::HalfEven)))
+    }
+
+    pub fn checked_div(self, other: Rate) -> StudiFiResult<Rate> {
+        if other.0 == 0 {
+            return Err(StudiFiError::InvalidInput("Division by zero rate".to_string()));
+        }
+        let numerator = self.0.checked_mul(Self::SCALE)
+            .ok_or_else(|| arithmetic_overflow("Rate::checked_div"))?;
+        Ok(Rate(div_round(numerator, other.0, RoundingMode::HalfEven)))
+    }
+
+    /// Raise to an integer power by repeated squaring
+    pub fn checked_pow(self, exponent: u32) -> StudiFiResult<Rate> {
+        let mut result = Rate::ONE;
+        let mut base = self;
+        let mut remaining = exponent;
+
+        while remaining > 0 {
+            if remaining & 1 == 1 {
+                result = result.checked_mul(base)?;
+            }
+            remaining >>= 1;
+            if remaining > 0 {
+                base = base.checked_mul(base)?;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// One line of a level-payment amortization schedule
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ScheduledPayment {
+    pub period: u32,
+    pub payment: Amount,
+    pub principal: Amount,
+    pub interest: Amount,
+    pub remaining_balance: Amount,
+}
+
 /// Platform-wide constants and limits
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct PlatformLimits {
@@ -142,63 +407,165 @@ pub struct Statistics {
     pub average_amount: Amount,
 }
 
-/// Inter-canister call types
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum CanisterCall {
-    StudentIdentityService(StudentIdentityServiceCall),
-    CreditAssessmentService(CreditAssessmentServiceCall),
-    LoanManagementService(LoanManagementServiceCall),
-    DaoGovernanceService(DaoGovernanceServiceCall),
-    ComplianceService(ComplianceServiceCall),
-    AuthenticationService(AuthenticationServiceCall),
+/// KYC status of a student profile, as decided by compliance_service
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Serialize)]
+pub enum KycStatus {
+    #[default]
+    Pending,
+    InProgress,
+    Verified,
+    Rejected,
+    Expired,
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum StudentIdentityServiceCall {
-    GetStudentProfile(Principal),
-    VerifyStudent(Principal),
-    IsStudentVerified(Principal),
+/// Status of a loan held by loan_management_service
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum LoanStatus {
+    /// Created and awaiting the borrower's acceptance
+    PendingSignature,
+    /// Accepted; tranches are still to be paid out
+    PendingDisbursement,
+    Active,
+    Late,
+    Default,
+    PaidOff,
+    Deferred,
+    InGracePeriod,
+    Cancelled,
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum CreditAssessmentServiceCall {
-    GetCreditScore(Principal),
-    ProcessApplication(String),
-    GetEffectiveCreditScore(Principal),
-    CreateLoanFromApplication(String),
+impl LoanStatus {
+    /// Whether the loan is still owed, as opposed to paid off or cancelled
+    pub fn is_outstanding(&self) -> bool {
+        !matches!(self, LoanStatus::PaidOff | LoanStatus::Cancelled)
+    }
+
+    /// Whether the loan has been paid out and is still owed
+    pub fn is_in_repayment(&self) -> bool {
+        self.is_outstanding()
+            && !matches!(self, LoanStatus::PendingSignature | LoanStatus::PendingDisbursement)
+    }
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum LoanManagementServiceCall {
-    CreateLoan(Principal, Amount, Percentage, u32, u32, String),
-    ProcessPayment(String, Amount),
-    GetStudentLoans(Principal),
+/// Kind of money movement reported to compliance_service for AML monitoring
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum AmlTransactionType {
+    LoanDisbursement,
+    LoanPayment,
+    EarlyPayoff,
+    TreasuryMovement,
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum DaoGovernanceServiceCall {
-    GetStakeholder(Principal),
-    ExecuteProposal(String),
-    CheckTreasuryApproval(Amount),
+/// Money movement reported to compliance_service for AML monitoring
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AmlTransactionReport {
+    /// Id of the originating record, e.g. a loan or payment id
+    pub reference_id: String,
+    pub transaction_type: AmlTransactionType,
+    pub amount: Amount,
+    /// Principal that moved the funds: the payer, the borrower receiving a
+    /// disbursement, or the administrator moving treasury funds
+    pub party: Principal,
+    /// Other principal the funds relate to, e.g. the borrower whose loan a cosigner pays
+    pub counterparty: Option<Principal>,
+    pub loan_id: Option<String>,
+    pub occurred_at: Timestamp,
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum ComplianceServiceCall {
-    PerformKycCheck(Principal),
-    LogAuditEvent(Principal, String, String),
+/// Capacity in which a party is screened against sanctions watchlists
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum ScreeningRole {
+    Borrower,
+    Cosigner,
+    Donor,
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub enum AuthenticationServiceCall {
-    ValidateSession(String, Option<String>), // Use String instead of Permission enum
-    GetUserRoles(Principal),
-    LogAuthEvent(Principal, String, String),
+/// Result of screening a party against the loaded watchlists
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum ScreeningOutcome {
+    Clear,
+    /// Scored above the review threshold; held until an analyst clears it
+    PotentialMatch,
+    Match,
+    /// No name is on file for the party, so it could not be screened
+    DetailsRequired,
+}
+
+/// Screening requested by another StudiFi canister
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ScreeningRequest {
+    pub subject: Principal,
+    pub role: ScreeningRole,
+    /// Name to screen; when absent the subject's screening details on file are used
+    pub full_name: Option<String>,
+    /// ISO 8601 date, e.g. "1990-01-31"
+    pub date_of_birth: Option<String>,
+    /// ISO 3166 alpha-2 country code
+    pub country: Option<String>,
+    /// Operation that triggered the screening, e.g. "create_loan"
+    pub context: String,
+}
+
+/// Persisted screening result as returned to the requesting canister
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ScreeningDecision {
+    pub screening_id: String,
+    pub outcome: ScreeningOutcome,
+}
+
+impl ScreeningDecision {
+    /// Turn anything but a clear result into an error, returning the screening id otherwise
+    pub fn into_clearance(self) -> StudiFiResult<String> {
+        match self.outcome {
+            ScreeningOutcome::Clear => Ok(self.screening_id),
+            ScreeningOutcome::PotentialMatch => Err(StudiFiError::Unauthorized(
+                format!("Sanctions screening {} is pending compliance review", self.screening_id)
+            )),
+            ScreeningOutcome::Match => Err(StudiFiError::Unauthorized(
+                format!("Sanctions screening {} matched a watchlist entry", self.screening_id)
+            )),
+            ScreeningOutcome::DetailsRequired => Err(StudiFiError::InvalidInput(
+                "Screening details must be submitted to compliance_service first".to_string()
+            )),
+        }
+    }
+}
+
+/// Purpose a data subject consents to their data being used for
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum ConsentPurpose {
+    /// Scoring loan applications from submitted financial information
+    CreditScoring,
+    /// Verifying enrollment with the student's university
+    UniversityVerification,
+    /// Disclosing validation evidence to community validators
+    CommunityValidationDisclosure,
+    Marketing,
 }
 
+impl ConsentPurpose {
+    pub const ALL: [ConsentPurpose; 4] = [
+        ConsentPurpose::CreditScoring,
+        ConsentPurpose::UniversityVerification,
+        ConsentPurpose::CommunityValidationDisclosure,
+        ConsentPurpose::Marketing,
+    ];
+}
 
+/// Versioned consent text published by compliance_service for one purpose
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ConsentNotice {
+    pub purpose: ConsentPurpose,
+    pub version: u32,
+    pub text: String,
+    /// Consents given to versions below this must be renewed
+    pub minimum_version: u32,
+    pub published_at: Timestamp,
+    pub published_by: Principal,
+}
 
 /// Audit event for tracking system actions
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct AuditEvent {
     pub event_id: String,
     pub event_type: AuditEventType,
@@ -211,7 +578,7 @@ pub struct AuditEvent {
 }
 
 /// Types of audit events
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
 pub enum AuditEventType {
     ProfileCreated,
     ProfileUpdated,
@@ -226,6 +593,11 @@ pub enum AuditEventType {
     SessionCreated,
     SystemConfigChanged,
     TreasuryOperation,
+    SessionTerminated,
+    RoleRevoked,
+    AccessDenied,
+    PersonalDataExported,
+    PersonalDataErased,
 }
 
 /// Inter-canister communication result
@@ -238,7 +610,7 @@ pub struct InterCanisterResult<T> {
 }
 
 /// Canister configuration for inter-canister calls
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct CanisterConfig {
     pub canister_id: Principal,
     pub name: String,
@@ -246,3 +618,150 @@ pub struct CanisterConfig {
     pub retry_count: u32,
     pub timeout_seconds: u64,
 }
+
+/// User session information
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct UserSession {
+    pub session_id: String,
+    pub user_principal: Principal,
+    pub roles: Vec<Role>,
+    pub created_at: Timestamp,
+    pub expires_at: Timestamp,
+    pub last_activity: Timestamp,
+    pub is_active: bool,
+}
+
+/// User roles in the system
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum Role {
+    Student,
+    Donor,
+    University,
+    CommunityValidator,
+    TeamMember,
+    Admin,
+    SystemAdmin,
+}
+
+/// Permissions for different operations
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum Permission {
+    // Student permissions
+    CreateProfile,
+    UpdateProfile,
+    SubmitLoanApplication,
+    MakePayment,
+    ViewOwnData,
+    
+    // Community permissions
+    ValidateCredit,
+    VoteOnProposals,
+    CreateProposals,
+    
+    // University permissions
+    VerifyStudents,
+    IssueCredentials,
+    
+    // Admin permissions
+    ManageUsers,
+    ViewAllData,
+    ManageSystem,
+    AccessAuditLogs,
+    ReviewCompliance,
+    
+    // System permissions
+    ManageCanisters,
+    SystemMaintenance,
+}
+
+/// Roles assigned to a principal by authentication_service
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct RoleAssignment {
+    pub principal: Principal,
+    pub roles: Vec<Role>,
+}
+
+impl UserSession {
+    pub fn new(
+        session_id: String,
+        user_principal: Principal,
+        roles: Vec<Role>,
+        duration_hours: u64,
+    ) -> Self {
+        let now = current_time();
+        let expires_at = now + (duration_hours * 60 * 60 * 1_000_000_000);
+        
+        Self {
+            session_id,
+            user_principal,
+            roles,
+            created_at: now,
+            expires_at,
+            last_activity: now,
+            is_active: true,
+        }
+    }
+    
+    pub fn is_expired(&self) -> bool {
+        current_time() > self.expires_at
+    }
+    
+    pub fn update_activity(&mut self) {
+        self.last_activity = current_time();
+    }
+    
+    pub fn has_role(&self, role: &Role) -> bool {
+        self.roles.contains(role)
+    }
+    
+    pub fn has_permission(&self, permission: &Permission) -> bool {
+        self.roles.iter().any(|role| role_has_permission(role, permission))
+    }
+}
+
+/// Check if a role has a specific permission
+pub fn role_has_permission(role: &Role, permission: &Permission) -> bool {
+    match role {
+        Role::Student => matches!(permission,
+            Permission::CreateProfile |
+            Permission::UpdateProfile |
+            Permission::SubmitLoanApplication |
+            Permission::MakePayment |
+            Permission::ViewOwnData |
+            Permission::VoteOnProposals
+        ),
+        Role::Donor => matches!(permission,
+            Permission::ViewOwnData |
+            Permission::VoteOnProposals |
+            Permission::CreateProposals
+        ),
+        Role::University => matches!(permission,
+            Permission::VerifyStudents |
+            Permission::IssueCredentials |
+            Permission::ViewOwnData
+        ),
+        Role::CommunityValidator => matches!(permission,
+            Permission::ValidateCredit |
+            Permission::VoteOnProposals |
+            Permission::ViewOwnData
+        ),
+        Role::TeamMember => matches!(permission,
+            Permission::ViewAllData |
+            Permission::ManageUsers |
+            Permission::AccessAuditLogs |
+            Permission::ReviewCompliance |
+            Permission::CreateProposals |
+            Permission::VoteOnProposals
+        ),
+        Role::Admin => matches!(permission,
+            Permission::ManageUsers |
+            Permission::ViewAllData |
+            Permission::ManageSystem |
+            Permission::AccessAuditLogs |
+            Permission::ReviewCompliance |
+            Permission::CreateProposals |
+            Permission::VoteOnProposals
+        ),
+        Role::SystemAdmin => true, // System admin has all permissions
+    }
+}
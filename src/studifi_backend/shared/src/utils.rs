@@ -1,4 +1,5 @@
 use crate::types::*;
+use crate::resilience::*;
 use ic_cdk::api::time;
 use candid::Principal;
 
@@ -27,11 +28,75 @@ pub fn months_to_nanos(months: u32) -> Timestamp {
     months as u64 * 30 * 24 * 60 * 60 * 1_000_000_000
 }
 
+/// Convert days since the Unix epoch into a (year, month, day) civil date
+pub fn days_to_civil_date(days_since_epoch: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's days_from_civil inverse, valid for the proleptic Gregorian calendar
+    let z = days_since_epoch + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Convert a (year, month, day) civil date into days since the Unix epoch
+pub fn civil_date_to_days(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Number of days in a calendar month
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        _ if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        _ => 28,
+    }
+}
+
+/// Add calendar months to a timestamp, clamping the day to the end of shorter months
+/// (e.g. Jan 31 + 1 month = Feb 28/29). Time of day is preserved.
+pub fn add_months(timestamp: Timestamp, months: u32) -> Timestamp {
+    let day_nanos = days_to_nanos(1);
+    let days_since_epoch = (timestamp / day_nanos) as i64;
+    let time_of_day = timestamp % day_nanos;
+
+    let (year, month, day) = days_to_civil_date(days_since_epoch);
+    let month_index = (month - 1) as i64 + months as i64;
+    let target_year = year + month_index.div_euclid(12);
+    let target_month = month_index.rem_euclid(12) as u32 + 1;
+    let target_day = day.min(days_in_month(target_year, target_month));
+
+    civil_date_to_days(target_year, target_month, target_day) as u64 * day_nanos + time_of_day
+}
+
 /// Generate a unique ID with prefix and counter
 pub fn generate_id(prefix: &str, counter: u64) -> String {
     format!("{}-{:08}", prefix, counter)
 }
 
+/// Highest counter among ids produced by `generate_id` with `prefix`, or 0 if there are none
+pub fn max_generated_id<I: IntoIterator<Item = String>>(prefix: &str, ids: I) -> u64 {
+    ids.into_iter()
+        .filter_map(|id| {
+            id.strip_prefix(prefix)
+                .and_then(|rest| rest.strip_prefix('-'))
+                .and_then(|counter| counter.parse::<u64>().ok())
+        })
+        .max()
+        .unwrap_or(0)
+}
+
 /// Validate email format (basic validation)
 pub fn validate_email(email: &str) -> StudiFiResult<()> {
     if email.contains('@') && email.contains('.') && email.len() > 5 {
@@ -68,66 +133,192 @@ pub fn validate_percentage(percentage: Percentage) -> StudiFiResult<()> {
     }
 }
 
-/// Calculate compound interest
+/// Calculate the value of `principal` after compounding `periods_per_year` times
+/// a year for `years` years, rounded to the cent with banker's rounding
 pub fn calculate_compound_interest(
     principal: Amount,
     annual_rate: Percentage,
     periods_per_year: u32,
-    years: f64,
-) -> Amount {
-    let rate_per_period = annual_rate / periods_per_year as f64;
-    let total_periods = periods_per_year as f64 * years;
-    let compound_factor = (1.0 + rate_per_period).powf(total_periods);
-    (principal as f64 * compound_factor) as Amount
+    years: u32,
+) -> StudiFiResult<Amount> {
+    let rate_per_period = annual_rate_to_periodic(annual_rate, periods_per_year)?;
+    let total_periods = periods_per_year
+        .checked_mul(years)
+        .ok_or_else(|| StudiFiError::InvalidInput("Too many compounding periods".to_string()))?;
+    let compound_factor = Rate::ONE.checked_add(rate_per_period)?.checked_pow(total_periods)?;
+
+    Money::from_cents(principal)
+        .checked_mul_rate(compound_factor)?
+        .to_cents(RoundingMode::HalfEven)
+}
+
+/// Calculate interest for one period on `balance`, rounded to the cent with banker's rounding
+pub fn calculate_periodic_interest(
+    balance: Amount,
+    annual_rate: Percentage,
+    periods_per_year: u32,
+) -> StudiFiResult<Amount> {
+    let rate_per_period = annual_rate_to_periodic(annual_rate, periods_per_year)?;
+    Money::from_cents(balance)
+        .checked_mul_rate(rate_per_period)?
+        .to_cents(RoundingMode::HalfEven)
 }
 
-/// Calculate monthly payment for a loan
+/// Calculate the level monthly payment for a loan, rounded half-up to the cent.
+///
+/// Rounding the level payment leaves a residue of a few cents which is absorbed
+/// by the final installment (see `build_amortization_schedule`).
 pub fn calculate_monthly_payment(
     principal: Amount,
     annual_rate: Percentage,
     term_months: u32,
-) -> Amount {
-    if annual_rate == 0.0 {
-        return principal / term_months as u64;
+) -> StudiFiResult<Amount> {
+    if term_months == 0 {
+        return Err(StudiFiError::InvalidInput("Loan term must be at least one month".to_string()));
     }
-    
-    let monthly_rate = annual_rate / 12.0;
-    let num_payments = term_months as f64;
-    let factor = (1.0 + monthly_rate).powf(num_payments);
-    let payment = (principal as f64 * monthly_rate * factor) / (factor - 1.0);
-    payment as Amount
+
+    let principal = Money::from_cents(principal);
+    let monthly_rate = annual_rate_to_periodic(annual_rate, 12)?;
+
+    if monthly_rate.is_zero() {
+        return principal
+            .checked_div(term_months as u64, RoundingMode::Up)?
+            .to_cents(RoundingMode::Up);
+    }
+
+    let factor = Rate::ONE.checked_add(monthly_rate)?.checked_pow(term_months)?;
+    principal
+        .checked_mul_rate(monthly_rate)?
+        .checked_mul_rate(factor)?
+        .checked_div_rate(factor.checked_sub(Rate::ONE)?)?
+        .to_cents(RoundingMode::HalfUp)
 }
 
-/// Calculate remaining balance after payments
+/// Principal a loan is amortized on once its grace period ends.
+///
+/// Interest accrues from disbursement and is added to the principal on each
+/// monthly anniversary of the grace period except the last; the last month's
+/// interest is charged in the first installment like any other month.
+pub fn capitalize_grace_interest(
+    principal: Amount,
+    annual_rate: Percentage,
+    grace_period_months: u32,
+) -> StudiFiResult<Amount> {
+    let monthly_rate = annual_rate_to_periodic(annual_rate, 12)?;
+    let factor = Rate::ONE
+        .checked_add(monthly_rate)?
+        .checked_pow(grace_period_months.saturating_sub(1))?;
+    Money::from_cents(principal)
+        .checked_mul_rate(factor)?
+        .to_cents(RoundingMode::HalfEven)
+}
+
+/// Build a level-payment amortization schedule.
+///
+/// Interest is charged on the outstanding balance each month and rounded with
+/// banker's rounding; the final installment clears whatever balance remains,
+/// so the payments always sum to principal plus interest exactly.
+pub fn build_amortization_schedule(
+    principal: Amount,
+    annual_rate: Percentage,
+    term_months: u32,
+) -> StudiFiResult<Vec<ScheduledPayment>> {
+    let level_payment = calculate_monthly_payment(principal, annual_rate, term_months)?;
+    let mut balance = principal;
+    let mut schedule = Vec::with_capacity(term_months as usize);
+
+    for period in 1..=term_months {
+        if balance == 0 {
+            break;
+        }
+
+        let interest = calculate_periodic_interest(balance, annual_rate, 12)?;
+        let principal_portion = if period == term_months {
+            balance
+        } else {
+            level_payment.saturating_sub(interest).min(balance)
+        };
+        balance -= principal_portion;
+
+        schedule.push(ScheduledPayment {
+            period,
+            payment: principal_portion + interest,
+            principal: principal_portion,
+            interest,
+            remaining_balance: balance,
+        });
+    }
+
+    Ok(schedule)
+}
+
+/// Calculate remaining balance after a number of level payments
 pub fn calculate_remaining_balance(
     original_principal: Amount,
     monthly_payment: Amount,
     annual_rate: Percentage,
     payments_made: u32,
-) -> Amount {
-    if annual_rate == 0.0 {
-        return original_principal.saturating_sub(monthly_payment * payments_made as u64);
+) -> StudiFiResult<Amount> {
+    let mut balance = original_principal;
+
+    for _ in 0..payments_made {
+        if balance == 0 {
+            break;
+        }
+        let interest = calculate_periodic_interest(balance, annual_rate, 12)?;
+        balance = (balance + interest).saturating_sub(monthly_payment);
     }
-    
-    let monthly_rate = annual_rate / 12.0;
-    let factor = (1.0 + monthly_rate).powf(payments_made as f64);
-    let remaining = original_principal as f64 * factor - 
-                   monthly_payment as f64 * ((factor - 1.0) / monthly_rate);
-    remaining.max(0.0) as Amount
+
+    Ok(balance)
+}
+
+/// Calculate a fee of `basis_points` on `amount`, rounded half-up to the cent
+pub fn calculate_fee(amount: Amount, basis_points: u32) -> StudiFiResult<Amount> {
+    Money::from_cents(amount)
+        .checked_mul_rate(Rate::from_basis_points(basis_points))?
+        .to_cents(RoundingMode::HalfUp)
+}
+
+/// Apply a `Percentage` to an amount, rounded to the cent with `mode`
+pub fn apply_percentage(amount: Amount, percentage: Percentage, mode: RoundingMode) -> StudiFiResult<Amount> {
+    Money::from_cents(amount)
+        .checked_mul_rate(Rate::from_percentage(percentage)?)?
+        .to_cents(mode)
+}
+
+/// Convert an annual `Percentage` into a fixed-point rate per period
+fn annual_rate_to_periodic(annual_rate: Percentage, periods_per_year: u32) -> StudiFiResult<Rate> {
+    if annual_rate < 0.0 {
+        return Err(StudiFiError::InvalidInput("Interest rate cannot be negative".to_string()));
+    }
+    Rate::from_percentage(annual_rate)?.checked_div_int(periods_per_year)
 }
 
 /// Format amount as currency string
 pub fn format_currency(amount: Amount) -> String {
-    format!("${:.2}", amount as f64 / 100.0)
+    format!("${}.{:02}", amount / 100, amount % 100)
 }
 
 /// Parse currency string to amount
 pub fn parse_currency(currency_str: &str) -> StudiFiResult<Amount> {
-    let cleaned = currency_str.trim_start_matches('$').replace(',', "");
-    match cleaned.parse::<f64>() {
-        Ok(value) => Ok((value * 100.0) as Amount),
-        Err(_) => Err(StudiFiError::InvalidInput("Invalid currency format".to_string())),
+    let invalid = || StudiFiError::InvalidInput("Invalid currency format".to_string());
+    let cleaned = currency_str.trim().trim_start_matches('$').replace(',', "");
+    let (dollars, cents) = match cleaned.split_once('.') {
+        Some((dollars, cents)) => (dollars, cents),
+        None => (cleaned.as_str(), ""),
+    };
+
+    if cents.len() > 2 || !cents.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
     }
+
+    let dollars: Amount = if dollars.is_empty() { 0 } else { dollars.parse().map_err(|_| invalid())? };
+    let cents: Amount = format!("{:0<2}", cents).parse().map_err(|_| invalid())?;
+
+    dollars
+        .checked_mul(100)
+        .and_then(|value| value.checked_add(cents))
+        .ok_or_else(invalid)
 }
 
 /// Check if caller is authorized (basic implementation)
@@ -139,76 +330,21 @@ pub fn check_authorization(caller: Principal, authorized_principals: &[Principal
     }
 }
 
-/// Create an audit event
-pub fn create_audit_event(
-    event_type: crate::types::AuditEventType,
-    user_principal: Principal,
-    canister_id: Principal,
-    function_name: &str,
-    details: &str,
-    success: bool,
-) -> crate::types::AuditEvent {
-    crate::types::AuditEvent {
-        event_id: generate_id("AUDIT", current_time()),
-        event_type,
-        user_principal,
-        canister_id,
-        function_name: function_name.to_string(),
-        details: details.to_string(),
-        timestamp: current_time(),
-        success,
-    }
-}
-
-/// Log an audit event (placeholder for actual implementation)
-pub fn log_audit_event(event: crate::types::AuditEvent) {
-    ic_cdk::println!("AUDIT: {:?}", event);
-    // In a real implementation, this would call the audit service
-}
-
-/// Validate session with authentication service
-pub async fn validate_session_with_auth_service(
-    _auth_canister_id: Principal,
-    session_id: String,
-    _required_permission: Option<String>,
-) -> StudiFiResult<bool> {
-    // This is a placeholder for actual inter-canister call
-    // In real implementation, this would call the authentication service
-    ic_cdk::println!("Validating session {} with auth service", session_id);
-
-    // For now, return success for demo purposes
-    Ok(true)
-}
-
-/// Call another canister with error handling and retry logic
-pub async fn call_canister<T, R>(
-    canister_id: Principal,
-    method: &str,
-    args: T,
-    retry_count: u32,
-) -> StudiFiResult<R>
+/// Call another canister with retries and circuit breaking.
+///
+/// `args` is the full candid argument tuple, e.g. `(student_id,)`; the method
+/// is expected to return a single value. Only transient rejections are retried,
+/// with exponential backoff on a timer; see `call_with_retries`.
+pub async fn call_canister<T, R>(config: &CanisterConfig, method: &str, args: T) -> StudiFiResult<R>
 where
-    T: candid::CandidType + Clone,
+    T: candid::utils::ArgumentEncoder + Clone,
     R: for<'de> candid::Deserialize<'de> + candid::CandidType,
 {
-    let mut last_error = None;
-
-    for attempt in 0..=retry_count {
-        match ic_cdk::call::<(T,), (R,)>(canister_id, method, (args.clone(),)).await {
-            Ok((result,)) => return Ok(result),
-            Err(e) => {
-                last_error = Some(e);
-                if attempt < retry_count {
-                    // Simple delay for retry (in production, use proper async delay)
-                    ic_cdk::println!("Retrying inter-canister call, attempt {}", attempt + 1);
-                }
-            }
-        }
-    }
-
-    Err(StudiFiError::SystemError(
-        format!("Inter-canister call failed after {} retries: {:?}", retry_count, last_error)
-    ))
+    call_with_retries(config, method, current_time, sleep, || {
+        ic_cdk::call::<T, (R,)>(config.canister_id, method, args.clone())
+    })
+    .await
+    .map(|(result,)| result)
 }
 
 /// Sanitize text input
@@ -256,6 +392,12 @@ pub fn paginate<T: Clone>(
     PaginatedResponse::new(paginated_items, total_count, params.offset, params.limit)
 }
 
+/// Serialize the records a canister holds about a data subject for export
+pub fn to_export_json<T: serde::Serialize>(data: &T) -> StudiFiResult<String> {
+    serde_json::to_string(data)
+        .map_err(|e| StudiFiError::InternalError(format!("Failed to serialize export: {}", e)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -268,12 +410,108 @@ mod tests {
 
     #[test]
     fn test_calculate_monthly_payment() {
-        let payment = calculate_monthly_payment(100000, 0.05, 12);
+        let payment = calculate_monthly_payment(100000, 0.05, 12).unwrap();
         assert!(payment > 0);
+        assert_eq!(payment, 8561);
+    }
+
+    #[test]
+    fn test_capitalize_grace_interest() {
+        assert_eq!(capitalize_grace_interest(1_200_000, 0.06, 0).unwrap(), 1_200_000);
+        assert_eq!(capitalize_grace_interest(1_200_000, 0.06, 1).unwrap(), 1_200_000);
+        // Five months capitalized at 0.5% a month
+        assert_eq!(capitalize_grace_interest(1_200_000, 0.06, 6).unwrap(), 1_230_302);
+        assert_eq!(capitalize_grace_interest(1_200_000, 0.0, 6).unwrap(), 1_200_000);
+    }
+
+    #[test]
+    fn test_amortization_schedule_reconciles_to_the_cent() {
+        let principal = 2_500_000;
+        let schedule = build_amortization_schedule(principal, 0.0725, 120).unwrap();
+        let level_payment = calculate_monthly_payment(principal, 0.0725, 120).unwrap();
+
+        let total_paid: Amount = schedule.iter().map(|line| line.payment).sum();
+        let total_principal: Amount = schedule.iter().map(|line| line.principal).sum();
+        let total_interest: Amount = schedule.iter().map(|line| line.interest).sum();
+
+        assert_eq!(schedule.len(), 120);
+        assert_eq!(total_principal, principal);
+        assert_eq!(total_paid, principal + total_interest);
+        assert_eq!(schedule.last().unwrap().remaining_balance, 0);
+        assert!(schedule[..119].iter().all(|line| line.payment == level_payment));
+        assert!(schedule[119].payment.abs_diff(level_payment) < 120);
+    }
+
+    #[test]
+    fn test_zero_rate_schedule() {
+        let schedule = build_amortization_schedule(1000, 0.0, 3).unwrap();
+        let payments: Vec<Amount> = schedule.iter().map(|line| line.payment).collect();
+        assert_eq!(payments, vec![334, 334, 332]);
+    }
+
+    #[test]
+    fn test_remaining_balance_matches_schedule() {
+        let schedule = build_amortization_schedule(1_000_000, 0.06, 36).unwrap();
+        let payment = calculate_monthly_payment(1_000_000, 0.06, 36).unwrap();
+        let balance = calculate_remaining_balance(1_000_000, payment, 0.06, 12).unwrap();
+        assert_eq!(balance, schedule[11].remaining_balance);
+    }
+
+    #[test]
+    fn test_money_rounding_modes() {
+        let half_cent = Money::from_raw(Money::UNITS_PER_CENT / 2);
+        let one_and_half = Money::from_raw(Money::UNITS_PER_CENT * 3 / 2);
+
+        assert_eq!(half_cent.to_cents(RoundingMode::HalfEven).unwrap(), 0);
+        assert_eq!(one_and_half.to_cents(RoundingMode::HalfEven).unwrap(), 2);
+        assert_eq!(half_cent.to_cents(RoundingMode::HalfUp).unwrap(), 1);
+        assert_eq!(one_and_half.to_cents(RoundingMode::Down).unwrap(), 1);
+        assert!(Money::from_raw(-Money::UNITS_PER_CENT).to_cents(RoundingMode::HalfUp).is_err());
+    }
+
+    #[test]
+    fn test_checked_arithmetic_overflow() {
+        let huge = Money::from_raw(i128::MAX);
+        assert!(huge.checked_add(Money::from_cents(1)).is_err());
+        assert!(Rate::from_percentage(f64::NAN).is_err());
+        assert!(Rate::from_percentage(2.0).unwrap().checked_pow(1_000).is_err());
+    }
+
+    #[test]
+    fn test_add_months_uses_calendar_months() {
+        let day = days_to_nanos(1);
+        let jan_31_2024 = civil_date_to_days(2024, 1, 31) as u64 * day + 3_600_000_000_000;
+
+        assert_eq!(days_to_civil_date(civil_date_to_days(2024, 1, 31)), (2024, 1, 31));
+        assert_eq!(days_to_civil_date((add_months(jan_31_2024, 1) / day) as i64), (2024, 2, 29));
+        assert_eq!(days_to_civil_date((add_months(jan_31_2024, 13) / day) as i64), (2025, 2, 28));
+        assert_eq!(days_to_civil_date((add_months(jan_31_2024, 2) / day) as i64), (2024, 3, 31));
+        assert_eq!(add_months(jan_31_2024, 0), jan_31_2024);
+        assert_eq!(add_months(jan_31_2024, 2) % day, 3_600_000_000_000);
     }
 
     #[test]
     fn test_format_currency() {
         assert_eq!(format_currency(12345), "$123.45");
+        assert_eq!(format_currency(5), "$0.05");
+    }
+
+    #[test]
+    fn test_parse_currency() {
+        assert_eq!(parse_currency("$1,234.29").unwrap(), 123429);
+        assert_eq!(parse_currency("0.5").unwrap(), 50);
+        assert!(parse_currency("1.234").is_err());
+    }
+
+    #[test]
+    fn test_max_generated_id() {
+        let ids = vec![
+            generate_id("APP", 3),
+            generate_id("APP", 12),
+            generate_id("VAL", 40),
+            "APP-garbage".to_string(),
+        ];
+        assert_eq!(max_generated_id("APP", ids.clone()), 12);
+        assert_eq!(max_generated_id("LOAN", ids), 0);
     }
 }
@@ -15,7 +15,11 @@ use shared::*;
 
 /// Initialize the canister
 #[init]
-fn init() {
+fn init(args: Option<ServiceInitArgs>) {
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("Identity Manager canister initialized");
 }
 
@@ -28,7 +32,12 @@ fn pre_upgrade() {
 
 /// Post-upgrade hook
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<ServiceInitArgs>) {
+    // Keep the previously registered canisters unless a new registry is supplied
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("Identity Manager canister upgraded successfully");
 }
 
@@ -46,6 +55,24 @@ async fn create_student_profile(request: VerificationRequest) -> StudiFiResult<S
         return Err(StudiFiError::AlreadyExists("Profile already exists for this principal".to_string()));
     }
 
+    // Enrollment is checked with the university as soon as the profile is stored
+    ComplianceClient::require_consent(caller, ConsentPurpose::UniversityVerification).await?;
+
+    // Screen the applicant against sanctions watchlists before anything is stored
+    ComplianceClient::screen_party(ScreeningRequest {
+        subject: caller,
+        role: ScreeningRole::Borrower,
+        full_name: Some(request.full_name.clone()),
+        date_of_birth: None,
+        country: None,
+        context: "create_student_profile".to_string(),
+    }).await?.into_clearance()?;
+
+    // Check again, as another profile may have been created during the call
+    if with_storage(|storage| storage.get_student_profile(&caller)).is_some() {
+        return Err(StudiFiError::AlreadyExists("Profile already exists for this principal".to_string()));
+    }
+
     // Create new student profile
     let now = current_time();
     let profile = StudentProfile {
@@ -61,6 +88,7 @@ async fn create_student_profile(request: VerificationRequest) -> StudiFiResult<S
         kyc_status: KycStatus::Pending,
         created_at: now,
         updated_at: now,
+        verification_history: Vec::new(),
     };
 
     // Validate the profile
@@ -104,63 +132,94 @@ fn get_my_profile() -> Option<StudentProfile> {
     with_storage(|storage| storage.get_student_profile(&caller))
 }
 
-/// Update KYC status
-#[update]
-#[candid_method(update)]
-fn update_kyc_status(status: KycStatus) -> StudiFiResult<StudentProfile> {
-    let caller = caller();
-
-    with_storage_mut(|storage| {
-        match storage.get_student_profile(&caller) {
-            Some(mut profile) => {
-                profile.kyc_status = status;
-                profile.set_updated_at(current_time());
+/// Check whether a student's identity has been verified (used by other canisters)
+#[query]
+#[candid_method(query)]
+fn is_student_verified(student_id: Principal) -> bool {
+    with_storage(|storage| storage.get_student_profile(&student_id))
+        .map(|profile| profile.is_verified)
+        .unwrap_or(false)
+}
 
-                // Auto-verify if KYC is verified
-                if profile.kyc_status == KycStatus::Verified {
-                    profile.is_verified = true;
-                }
+/// Apply a KYC case outcome decided by compliance_service
+#[update(guard = "require_compliance_service")]
+#[candid_method(update)]
+fn apply_kyc_outcome(student_id: Principal, status: KycStatus, case_id: String) -> StudiFiResult<()> {
+    let profile = with_storage(|storage| storage.get_student_profile(&student_id))
+        .ok_or_else(|| StudiFiError::NotFound("Profile not found".to_string()))?;
+
+    // Only a final outcome changes whether the student counts as verified
+    let is_verified = match status {
+        KycStatus::Verified => true,
+        KycStatus::Rejected | KycStatus::Expired => false,
+        KycStatus::Pending | KycStatus::InProgress => profile.is_verified,
+    };
 
-                storage.update_student_profile(caller, profile)
-            }
-            None => Err(StudiFiError::NotFound("Profile not found".to_string())),
-        }
-    })
+    record_verification(student_id, VerificationProvenance {
+        attested_by: caller(),
+        method: VerificationMethod::ComplianceKyc,
+        evidence_reference: case_id,
+        is_verified,
+        kyc_status: status,
+        gpa: None,
+        attested_at: current_time(),
+    })?;
+    Ok(())
 }
 
-/// Verify student with GPA update
-#[update]
+/// Change a student's verification state on the attester's authority
+#[update(guard = "require_verification_attester")]
 #[candid_method(update)]
-async fn verify_student(gpa: f64) -> StudiFiResult<StudentProfile> {
+fn attest_verification(attestation: VerificationAttestation) -> StudiFiResult<StudentProfile> {
+    let student_id = attestation.student_id;
+    let provenance = attestation.into_provenance(caller(), current_time())?;
+    record_verification(student_id, provenance)
+}
+
+/// Get the provenance of every verification change to a student's profile
+#[query]
+#[candid_method(query)]
+fn get_verification_history(student_id: Principal) -> StudiFiResult<Vec<VerificationProvenance>> {
     let caller = caller();
+    if caller != student_id && !is_authorized(&caller, &Permission::VerifyStudents) {
+        return Err(StudiFiError::Unauthorized("Cannot view other user's verification history".to_string()));
+    }
 
-    // Validate GPA
-    validate_gpa(gpa)?;
+    with_storage(|storage| storage.get_student_profile(&student_id))
+        .map(|profile| profile.verification_history)
+        .ok_or_else(|| StudiFiError::NotFound("Profile not found".to_string()))
+}
 
-    with_storage_mut(|storage| {
-        match storage.get_student_profile(&caller) {
-            Some(mut profile) => {
-                profile.gpa = gpa;
-                profile.is_verified = true;
-                profile.kyc_status = KycStatus::Verified;
-                profile.set_updated_at(current_time());
+fn record_verification(student_id: Principal, provenance: VerificationProvenance) -> StudiFiResult<StudentProfile> {
+    let profile = with_storage_mut(|storage| {
+        let mut profile = storage.get_student_profile(&student_id)
+            .ok_or_else(|| StudiFiError::NotFound("Profile not found".to_string()))?;
 
-                storage.update_student_profile(caller, profile)
-            }
-            None => Err(StudiFiError::NotFound("Profile not found".to_string())),
-        }
-    })
+        profile.apply_verification(provenance.clone());
+        storage.update_student_profile(student_id, profile)
+    })?;
+
+    ic_cdk::println!(
+        "Verification of {:?} set to {} ({:?}) by {:?} via {:?}, evidence {}",
+        student_id,
+        provenance.is_verified,
+        provenance.kyc_status,
+        provenance.attested_by,
+        provenance.method,
+        provenance.evidence_reference
+    );
+    Ok(profile)
 }
 
 /// Get all verified students
-#[query]
+#[query(guard = "require_view_all_data")]
 #[candid_method(query)]
 fn get_all_verified_students() -> Vec<StudentProfile> {
     with_storage(|storage| storage.get_verified_students())
 }
 
 /// Search students with filters
-#[query]
+#[query(guard = "require_view_all_data")]
 #[candid_method(query)]
 fn search_students(filters: StudentSearchFilters, pagination: Option<PaginationParams>) -> PaginatedResponse<StudentProfile> {
     let pagination = pagination.unwrap_or_default();
@@ -175,11 +234,10 @@ fn get_verification_stats() -> VerificationStats {
     with_storage(|storage| storage.get_verification_stats())
 }
 
-/// Verify with university API (for authorized callers)
-#[update]
+/// Verify with university API (verifiers only)
+#[update(guard = "require_verify_students")]
 #[candid_method(update)]
 async fn verify_with_university_api(university: String, student_id: String) -> StudiFiResult<UniversityVerification> {
-    // In production, you would check authorization here
     let caller = caller();
     ic_cdk::println!("University verification requested by: {:?}", caller);
 
@@ -191,10 +249,9 @@ async fn verify_with_university_api(university: String, student_id: String) -> S
 }
 
 /// Add university API configuration (admin only)
-#[update]
+#[update(guard = "require_manage_system")]
 #[candid_method(update)]
 fn add_university_config(config: UniversityApiConfig) -> StudiFiResult<()> {
-    // In production, you would check admin authorization here
     let caller = caller();
     ic_cdk::println!("University config added by: {:?}", caller);
 
@@ -218,7 +275,7 @@ fn get_university_configs() -> Vec<UniversityApiConfig> {
 }
 
 /// Batch verify students
-#[update]
+#[update(guard = "require_verify_students")]
 #[candid_method(update)]
 async fn batch_verify_students(
     requests: Vec<VerificationRequest>,
@@ -250,13 +307,15 @@ fn generate_verification_report(student_id: Principal) -> StudiFiResult<String>
 /// Update student profile
 #[update]
 #[candid_method(update)]
-fn update_student_profile(
+async fn update_student_profile(
     email: Option<String>,
     university: Option<String>,
     program: Option<String>,
     year_of_study: Option<u32>,
+    session_id: Option<String>,
 ) -> StudiFiResult<StudentProfile> {
     let caller = caller();
+    authorize_caller_session(session_id, Permission::UpdateProfile).await?;
 
     with_storage_mut(|storage| {
         match storage.get_student_profile(&caller) {
@@ -267,13 +326,13 @@ fn update_student_profile(
                     profile.email = sanitize_text(&email);
                 }
 
-                if let Some(university) = university {
-                    profile.university = sanitize_text(&university);
-                }
-
-                if let Some(program) = program {
-                    profile.program = sanitize_text(&program);
-                }
+                // Changing where or what the student studies lapses their verification
+                profile.change_enrollment(
+                    university.map(|university| sanitize_text(&university)),
+                    program.map(|program| sanitize_text(&program)),
+                    caller,
+                    current_time(),
+                );
 
                 if let Some(year) = year_of_study {
                     if year < MIN_YEAR_OF_STUDY || year > MAX_YEAR_OF_STUDY {
@@ -292,7 +351,8 @@ fn update_student_profile(
     })
 }
 
-/// Delete student profile (self-deletion only)
+/// Delete student profile (self-deletion only). Erasure of everything held across
+/// the platform goes through compliance_service's `request_erasure`.
 #[update]
 #[candid_method(update)]
 fn delete_my_profile() -> StudiFiResult<()> {
@@ -330,6 +390,24 @@ fn get_platform_stats() -> Statistics {
     })
 }
 
+// ============================================================================
+// DATA SUBJECT REQUESTS
+// ============================================================================
+
+/// Everything held about a principal, as JSON
+#[query(guard = "require_compliance_service")]
+#[candid_method(query)]
+fn export_subject_data(subject: Principal) -> StudiFiResult<String> {
+    to_export_json(&with_storage(|storage| storage.export_subject_data(&subject)))
+}
+
+/// Remove a principal's profile and verification data
+#[update(guard = "require_compliance_service")]
+#[candid_method(update)]
+fn erase_subject_data(request: DataErasureRequest) -> StudiFiResult<ErasureReceipt> {
+    Ok(with_storage_mut(|storage| storage.erase_subject_data(&request)))
+}
+
 // ============================================================================
 // VERIFIABLE CREDENTIALS (RELYING PARTY)
 // ============================================================================
@@ -337,7 +415,7 @@ fn get_platform_stats() -> Statistics {
 /// Create a verifiable credential verification session
 #[update]
 #[candid_method(update)]
-fn create_vc_verification_session(
+async fn create_vc_verification_session(
     university_origin: String,
     university_canister_id: Option<Principal>,
     student_id: String,
@@ -350,6 +428,8 @@ fn create_vc_verification_session(
         return Err(StudiFiError::InvalidInput("All fields are required".to_string()));
     }
 
+    ComplianceClient::require_consent(caller, ConsentPurpose::UniversityVerification).await?;
+
     let session = VerifiableCredentialService::create_student_verification_session(
         caller,
         university_origin,
@@ -397,21 +477,8 @@ fn process_vc_response(
     // Process the credential response
     match VerifiableCredentialService::process_credential_response(&mut session, verifiable_presentation) {
         Ok(_) => {
-            // Update student profile with verified status
-            if let Some(response) = &session.response {
-                with_storage_mut(|storage| {
-                    if let Some(mut profile) = storage.get_student_profile(&caller) {
-                        profile.is_verified = response.verified;
-                        profile.kyc_status = if response.verified {
-                            KycStatus::Verified
-                        } else {
-                            KycStatus::Rejected
-                        };
-                        profile.set_updated_at(current_time());
-                        storage.update_student_profile(caller, profile);
-                    }
-                });
-            }
+            // The presentation is only recorded here; the profile's verification state
+            // changes once an attester reviews it via `attest_verification`
 
             // Store updated session
             with_storage_mut(|storage| {
@@ -465,6 +532,27 @@ fn verify_presentation(jwt: String) -> StudiFiResult<bool> {
     VerifiableCredentialService::verify_presentation(&jwt)
 }
 
+/// Re-sync the cached role assignments used by authorization guards
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+async fn refresh_role_cache() -> StudiFiResult<u32> {
+    sync_role_cache().await
+}
+
+/// Get the canister registry used for inter-canister calls
+#[query]
+#[candid_method(query)]
+fn get_canister_registry() -> CanisterRegistry {
+    current_canister_registry()
+}
+
+/// Get the health of downstream canisters this canister has called since its last upgrade
+#[query]
+#[candid_method(query)]
+fn get_circuit_breakers() -> Vec<CircuitBreaker> {
+    circuit_breaker_states()
+}
+
 // Export Candid interface
 candid::export_service!();
 
@@ -1,9 +1,8 @@
 use candid::Principal;
 use ic_stable_structures::{
-    DefaultMemoryImpl, RestrictedMemory, StableBTreeMap, Storable,
-    storable::Bound,
+    memory_manager::{MemoryId, VirtualMemory},
+    DefaultMemoryImpl,
 };
-use std::borrow::Cow;
 use std::cell::RefCell;
 
 use crate::types::*;
@@ -11,89 +10,61 @@ use crate::verifiable_credentials::*;
 use shared::*;
 
 // Memory management for stable storage
-type Memory = RestrictedMemory<DefaultMemoryImpl>;
+type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 // Define memory IDs for different data structures
-const STUDENT_PROFILES_MEMORY_ID: u64 = 0;
-const VERIFICATION_REQUESTS_MEMORY_ID: u64 = 1;
-const UNIVERSITY_CONFIGS_MEMORY_ID: u64 = 2;
-const VC_SESSIONS_MEMORY_ID: u64 = 3;
-
-// Implement Storable for StudentProfile
-impl Storable for StudentProfile {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+const STUDENT_PROFILES_MEMORY_ID: MemoryId = MemoryId::new(0);
+const VERIFICATION_REQUESTS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const UNIVERSITY_CONFIGS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const VC_SESSIONS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const CANISTER_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(4);
+
+// Stable record version for StudentProfile
+impl VersionedRecord for StudentProfile {
+    const VERSION: u16 = 1;
 }
 
-// Implement Storable for VerificationRequest
-impl Storable for VerificationRequest {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for VerificationRequest
+impl VersionedRecord for VerificationRequest {
+    const VERSION: u16 = 1;
 }
 
-// Implement Storable for UniversityApiConfig
-impl Storable for UniversityApiConfig {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for UniversityApiConfig
+impl VersionedRecord for UniversityApiConfig {
+    const VERSION: u16 = 1;
 }
 
-// Implement Storable for VcVerificationSession
-impl Storable for VcVerificationSession {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for VcVerificationSession
+impl VersionedRecord for VcVerificationSession {
+    const VERSION: u16 = 1;
 }
 
 // Storage structure
 pub struct IdentityStorage {
-    pub student_profiles: StableBTreeMap<Principal, StudentProfile, Memory>,
-    pub verification_requests: StableBTreeMap<String, VerificationRequest, Memory>,
-    pub university_configs: StableBTreeMap<String, UniversityApiConfig, Memory>,
-    pub vc_sessions: StableBTreeMap<String, VcVerificationSession, Memory>,
+    pub student_profiles: VersionedMap<Principal, StudentProfile, Memory>,
+    pub verification_requests: VersionedMap<String, VerificationRequest, Memory>,
+    pub university_configs: VersionedMap<String, UniversityApiConfig, Memory>,
+    pub vc_sessions: VersionedMap<String, VcVerificationSession, Memory>,
+    pub canister_registry: VersionedMap<String, CanisterRegistry, Memory>,
 }
 
 impl IdentityStorage {
-    pub fn new() -> Self {
+    pub fn init(memory: DefaultMemoryImpl) -> Self {
+        // Canisters installed before the MemoryManager layout kept each map in its own page
+        let memory_manager = init_memory_manager(memory, |memory| vec![
+            read_legacy_map::<Principal, _>(memory, 0, STUDENT_PROFILES_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 1, VERIFICATION_REQUESTS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 2, UNIVERSITY_CONFIGS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 3, VC_SESSIONS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 4, CANISTER_REGISTRY_MEMORY_ID),
+        ]);
+
         Self {
-            student_profiles: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), STUDENT_PROFILES_MEMORY_ID..STUDENT_PROFILES_MEMORY_ID + 1)
-            ),
-            verification_requests: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), VERIFICATION_REQUESTS_MEMORY_ID..VERIFICATION_REQUESTS_MEMORY_ID + 1)
-            ),
-            university_configs: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), UNIVERSITY_CONFIGS_MEMORY_ID..UNIVERSITY_CONFIGS_MEMORY_ID + 1)
-            ),
-            vc_sessions: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), VC_SESSIONS_MEMORY_ID..VC_SESSIONS_MEMORY_ID + 1)
-            ),
+            student_profiles: VersionedMap::init(memory_manager.get(STUDENT_PROFILES_MEMORY_ID)),
+            verification_requests: VersionedMap::init(memory_manager.get(VERIFICATION_REQUESTS_MEMORY_ID)),
+            university_configs: VersionedMap::init(memory_manager.get(UNIVERSITY_CONFIGS_MEMORY_ID)),
+            vc_sessions: VersionedMap::init(memory_manager.get(VC_SESSIONS_MEMORY_ID)),
+            canister_registry: VersionedMap::init(memory_manager.get(CANISTER_REGISTRY_MEMORY_ID)),
         }
     }
 
@@ -314,6 +285,58 @@ impl IdentityStorage {
         count
     }
 
+    // Data subject requests
+    /// Verification requests submitted with the subject's profile, by their keys
+    fn get_subject_verification_requests(&self, profile: &StudentProfile) -> Vec<(String, VerificationRequest)> {
+        self.verification_requests
+            .iter()
+            .filter(|(id, request)| *id == profile.student_id || request.email == profile.email)
+            .collect()
+    }
+
+    pub fn export_subject_data(&self, subject: &Principal) -> IdentitySubjectData {
+        let profile = self.get_student_profile(subject);
+        let verification_requests = profile
+            .as_ref()
+            .map(|profile| self.get_subject_verification_requests(profile))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_, request)| request)
+            .collect();
+
+        IdentitySubjectData {
+            profile,
+            verification_requests,
+            vc_sessions: self.get_vc_sessions_by_user(subject),
+        }
+    }
+
+    /// Remove the subject's profile, verification requests and credential sessions.
+    /// The profile is kept while the subject has outstanding loans.
+    pub fn erase_subject_data(&mut self, request: &DataErasureRequest) -> ErasureReceipt {
+        let mut receipt = ErasureReceipt::new(ServiceName::StudentIdentity);
+
+        if let Some(profile) = self.get_student_profile(&request.subject) {
+            for (id, _) in self.get_subject_verification_requests(&profile) {
+                self.verification_requests.remove(&id);
+                receipt.erased += 1;
+            }
+            if request.has_active_loans {
+                receipt.retain(1, "the profile identifies the borrower of an outstanding loan");
+            } else {
+                self.student_profiles.remove(&request.subject);
+                receipt.erased += 1;
+            }
+        }
+
+        for session in self.get_vc_sessions_by_user(&request.subject) {
+            self.vc_sessions.remove(&session.id);
+            receipt.erased += 1;
+        }
+
+        receipt
+    }
+
     // Utility methods
     pub fn count_students(&self) -> u64 {
         self.student_profiles.len()
@@ -330,11 +353,21 @@ impl IdentityStorage {
     pub fn count_vc_sessions(&self) -> u64 {
         self.vc_sessions.len()
     }
+
+    pub fn get_canister_registry(&self) -> CanisterRegistry {
+        self.canister_registry
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_canister_registry(&mut self, registry: CanisterRegistry) {
+        self.canister_registry.insert("default".to_string(), registry);
+    }
 }
 
 // Thread-local storage
 thread_local! {
-    static STORAGE: RefCell<IdentityStorage> = RefCell::new(IdentityStorage::new());
+    static STORAGE: RefCell<IdentityStorage> = RefCell::new(IdentityStorage::init(DefaultMemoryImpl::default()));
 }
 
 // Storage access functions
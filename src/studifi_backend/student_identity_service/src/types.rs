@@ -17,6 +17,60 @@ pub struct StudentProfile {
     pub kyc_status: KycStatus,
     pub created_at: Timestamp,
     pub updated_at: Timestamp,
+    /// Every change to `is_verified`/`kyc_status`, oldest first
+    #[serde(default)]
+    pub verification_history: Vec<VerificationProvenance>,
+}
+
+impl StudentProfile {
+    /// Apply an attested verification state change and record its provenance
+    pub fn apply_verification(&mut self, provenance: VerificationProvenance) {
+        self.is_verified = provenance.is_verified;
+        self.kyc_status = provenance.kyc_status.clone();
+        if let Some(gpa) = provenance.gpa {
+            self.gpa = gpa;
+        }
+        self.updated_at = provenance.attested_at;
+        self.verification_history.push(provenance);
+    }
+
+    /// Most recent verification state change, if any
+    pub fn latest_verification(&self) -> Option<&VerificationProvenance> {
+        self.verification_history.last()
+    }
+
+    /// Change the university and program the student reported. Verification
+    /// attested the old enrollment, so a verified profile lapses to unverified
+    /// until an attester confirms the new one.
+    pub fn change_enrollment(
+        &mut self,
+        university: Option<String>,
+        program: Option<String>,
+        changed_by: Principal,
+        now: Timestamp,
+    ) {
+        let mut changed = Vec::new();
+        if let Some(university) = university.filter(|university| *university != self.university) {
+            self.university = university;
+            changed.push("university");
+        }
+        if let Some(program) = program.filter(|program| *program != self.program) {
+            self.program = program;
+            changed.push("program");
+        }
+
+        if self.is_verified && !changed.is_empty() {
+            self.apply_verification(VerificationProvenance {
+                attested_by: changed_by,
+                method: VerificationMethod::ProfileChange,
+                evidence_reference: format!("{} changed by the student", changed.join(" and ")),
+                is_verified: false,
+                kyc_status: self.kyc_status.clone(),
+                gpa: None,
+                attested_at: now,
+            });
+        }
+    }
 }
 
 impl Validate for StudentProfile {
@@ -50,6 +104,70 @@ impl Validate for StudentProfile {
     }
 }
 
+/// How a verification state change was established
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
+pub enum VerificationMethod {
+    /// KYC case decided in compliance_service
+    ComplianceKyc,
+    /// Enrollment confirmed by a university issuer
+    UniversityRecords,
+    /// University-issued verifiable credential checked by a reviewer
+    VerifiableCredential,
+    /// Documents checked by a principal holding `VerifyStudents`
+    ManualReview,
+    /// The student changed attested enrollment details, lapsing verification
+    ProfileChange,
+}
+
+/// Verification state change submitted by an authorized attester
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct VerificationAttestation {
+    pub student_id: Principal,
+    pub is_verified: bool,
+    pub kyc_status: KycStatus,
+    pub gpa: Option<f64>,
+    pub method: VerificationMethod,
+    /// Reference to the supporting evidence, e.g. a KYC case id or credential hash
+    pub evidence_reference: String,
+}
+
+impl VerificationAttestation {
+    /// Validate the attestation and turn it into the provenance recorded on the profile
+    pub fn into_provenance(self, attested_by: Principal, now: Timestamp) -> StudiFiResult<VerificationProvenance> {
+        if self.evidence_reference.trim().is_empty() {
+            return Err(StudiFiError::InvalidInput("An evidence reference is required".to_string()));
+        }
+        if let Some(gpa) = self.gpa {
+            validate_gpa(gpa)?;
+        }
+        if self.method == VerificationMethod::ProfileChange {
+            return Err(StudiFiError::InvalidInput("Profile changes are recorded automatically".to_string()));
+        }
+
+        Ok(VerificationProvenance {
+            attested_by,
+            method: self.method,
+            evidence_reference: sanitize_text(&self.evidence_reference),
+            is_verified: self.is_verified,
+            kyc_status: self.kyc_status,
+            gpa: self.gpa,
+            attested_at: now,
+        })
+    }
+}
+
+/// Who changed a profile's verification state, how, and on what evidence
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct VerificationProvenance {
+    pub attested_by: Principal,
+    pub method: VerificationMethod,
+    pub evidence_reference: String,
+    pub is_verified: bool,
+    pub kyc_status: KycStatus,
+    pub gpa: Option<f64>,
+    pub attested_at: Timestamp,
+}
+
 impl Timestamped for StudentProfile {
     fn created_at(&self) -> Timestamp {
         self.created_at
@@ -70,22 +188,6 @@ impl Identifiable for StudentProfile {
     }
 }
 
-/// KYC status with enhanced tracking
-#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Serialize)]
-pub enum KycStatus {
-    Pending,
-    InProgress,
-    Verified,
-    Rejected,
-    Expired,
-}
-
-impl Default for KycStatus {
-    fn default() -> Self {
-        KycStatus::Pending
-    }
-}
-
 /// University verification result
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct UniversityVerification {
@@ -207,4 +309,119 @@ pub enum DocumentType {
     Other(String),
 }
 
+/// Everything this service holds about a data subject, for export
+#[derive(Clone, Debug, Serialize)]
+pub struct IdentitySubjectData {
+    pub profile: Option<StudentProfile>,
+    pub verification_requests: Vec<VerificationRequest>,
+    pub vc_sessions: Vec<crate::verifiable_credentials::VcVerificationSession>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn student() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn attester() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn profile() -> StudentProfile {
+        StudentProfile {
+            id: student(),
+            email: "ada@example.edu".to_string(),
+            full_name: "Ada Lovelace".to_string(),
+            university: "University of London".to_string(),
+            student_id: "S-1".to_string(),
+            program: "Mathematics".to_string(),
+            year_of_study: 2,
+            gpa: 3.5,
+            is_verified: false,
+            kyc_status: KycStatus::Pending,
+            created_at: 0,
+            updated_at: 0,
+            verification_history: Vec::new(),
+        }
+    }
+
+    fn attestation(evidence_reference: &str) -> VerificationAttestation {
+        VerificationAttestation {
+            student_id: student(),
+            is_verified: true,
+            kyc_status: KycStatus::Verified,
+            gpa: Some(3.8),
+            method: VerificationMethod::UniversityRecords,
+            evidence_reference: evidence_reference.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_attestation_is_validated() {
+        assert!(attestation("  ").into_provenance(attester(), 10).is_err());
+
+        let mut bad_gpa = attestation("ENROLLMENT-42");
+        bad_gpa.gpa = Some(9.0);
+        assert!(bad_gpa.into_provenance(attester(), 10).is_err());
+
+        let mut reserved = attestation("ENROLLMENT-42");
+        reserved.method = VerificationMethod::ProfileChange;
+        assert!(reserved.into_provenance(attester(), 10).is_err());
+
+        let provenance = attestation("ENROLLMENT-42").into_provenance(attester(), 10).unwrap();
+        assert_eq!(provenance.attested_by, attester());
+        assert_eq!(provenance.attested_at, 10);
+        assert_eq!(provenance.evidence_reference, "ENROLLMENT-42");
+    }
+
+    #[test]
+    fn test_verification_history_records_provenance() {
+        let mut profile = profile();
+        profile.apply_verification(attestation("ENROLLMENT-42").into_provenance(attester(), 10).unwrap());
+        assert!(profile.is_verified);
+        assert_eq!(profile.kyc_status, KycStatus::Verified);
+        assert_eq!(profile.gpa, 3.8);
+        assert_eq!(profile.updated_at, 10);
 
+        let mut revoked = attestation("ENROLLMENT-43");
+        revoked.is_verified = false;
+        revoked.kyc_status = KycStatus::Expired;
+        revoked.gpa = None;
+        profile.apply_verification(revoked.into_provenance(attester(), 20).unwrap());
+        assert!(!profile.is_verified);
+        assert_eq!(profile.gpa, 3.8);
+
+        let history: Vec<(&str, bool)> = profile.verification_history
+            .iter()
+            .map(|provenance| (provenance.evidence_reference.as_str(), provenance.is_verified))
+            .collect();
+        assert_eq!(history, vec![("ENROLLMENT-42", true), ("ENROLLMENT-43", false)]);
+        assert_eq!(profile.latest_verification().unwrap().attested_at, 20);
+    }
+
+    #[test]
+    fn test_enrollment_change_lapses_verification() {
+        let mut profile = profile();
+        profile.apply_verification(attestation("ENROLLMENT-42").into_provenance(attester(), 10).unwrap());
+
+        // Restating the attested enrollment keeps the profile verified
+        profile.change_enrollment(Some("University of London".to_string()), None, student(), 20);
+        assert!(profile.is_verified);
+        assert_eq!(profile.verification_history.len(), 1);
+
+        profile.change_enrollment(None, Some("Computer Science".to_string()), student(), 30);
+        assert!(!profile.is_verified);
+        assert_eq!(profile.program, "Computer Science");
+        let lapse = profile.latest_verification().unwrap();
+        assert_eq!(lapse.method, VerificationMethod::ProfileChange);
+        assert_eq!(lapse.attested_by, student());
+        assert_eq!(lapse.evidence_reference, "program changed by the student");
+
+        // An unverified profile has nothing to lapse
+        profile.change_enrollment(Some("Cambridge".to_string()), None, student(), 40);
+        assert_eq!(profile.university, "Cambridge");
+        assert_eq!(profile.verification_history.len(), 2);
+    }
+}
@@ -65,6 +65,17 @@ impl VerificationService {
 
     /// Generate verification report
     pub fn generate_verification_report(profile: &StudentProfile) -> String {
+        let attestation = match profile.latest_verification() {
+            Some(provenance) => format!(
+                "{:?} by {} (evidence: {}) at {}",
+                provenance.method,
+                provenance.attested_by,
+                provenance.evidence_reference,
+                provenance.attested_at
+            ),
+            None => "None".to_string(),
+        };
+
         format!(
             "Verification Report for {}\n\
              Student ID: {}\n\
@@ -74,6 +85,7 @@ impl VerificationService {
              GPA: {:.2}\n\
              KYC Status: {:?}\n\
              Verified: {}\n\
+             Last Attestation: {}\n\
              Last Updated: {}",
             profile.full_name,
             profile.student_id,
@@ -83,6 +95,7 @@ impl VerificationService {
             profile.gpa,
             profile.kyc_status,
             profile.is_verified,
+            attestation,
             profile.updated_at
         )
     }
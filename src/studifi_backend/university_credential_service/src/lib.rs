@@ -11,7 +11,11 @@ use types::*;
 use storage::*;
 
 #[init]
-fn init() {
+fn init(args: Option<ServiceInitArgs>) {
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("University Issuer canister initialized");
 }
 
@@ -21,7 +25,12 @@ fn pre_upgrade() {
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<ServiceInitArgs>) {
+    // Keep the previously registered canisters unless a new registry is supplied
+    let persisted = with_storage(|storage| storage.get_canister_registry());
+    let registry = init_canister_registry(args, persisted);
+    with_storage_mut(|storage| storage.set_canister_registry(registry));
+    start_role_cache_sync();
     ic_cdk::println!("University Issuer canister upgraded successfully");
 }
 
@@ -29,10 +38,11 @@ fn post_upgrade() {
 // VERIFIABLE CREDENTIALS ISSUER API (IC VC SPEC COMPLIANCE)
 // ============================================================================
 
-/// Get consent message for credential issuance
-#[query]
-#[candid_method(query)]
-fn vc_consent_message(credential_spec: CredentialSpec) -> StudiFiResult<String> {
+/// Get consent message for credential issuance, ending with the university
+/// verification notice currently published in compliance_service's consent ledger
+#[update]
+#[candid_method(update)]
+async fn vc_consent_message(credential_spec: CredentialSpec) -> StudiFiResult<String> {
     let message = match credential_spec.credential_type.as_str() {
         "VerifiedStudent" => {
             "This university will issue a verifiable credential confirming your current student status. \
@@ -45,8 +55,9 @@ fn vc_consent_message(credential_spec: CredentialSpec) -> StudiFiResult<String>
         },
         _ => "This university will issue a verifiable credential with the requested information."
     };
-    
-    Ok(message.to_string())
+
+    let notice = ComplianceClient::get_consent_notice(ConsentPurpose::UniversityVerification).await?;
+    Ok(format!("{}\n\n{} (consent notice version {})", message, notice.text, notice.version))
 }
 
 /// Get derivation origin for this issuer
@@ -68,6 +79,10 @@ fn prepare_credential(request: PrepareCredentialRequest) -> StudiFiResult<Prepar
     if request.credential_spec.credential_type != "VerifiedStudent" {
         return Err(StudiFiError::InvalidInput("Unsupported credential type".to_string()));
     }
+    let config = with_storage(|storage| storage.get_university_config());
+    if config.is_some_and(|config| !config.is_active || !config.supports_credential(&request.credential_spec.credential_type)) {
+        return Err(StudiFiError::InvalidInput("Credential type not offered by this university".to_string()));
+    }
 
     // Extract student ID from arguments
     let student_id = request.credential_spec.arguments
@@ -121,6 +136,16 @@ fn get_credential(request: GetCredentialRequest) -> StudiFiResult<IssuedCredenti
     // Create the verifiable credential JWT
     let vc_jwt = create_student_credential_jwt(&student, &request.credential_spec)?;
 
+    // Keep a record of what was issued to whom
+    with_storage_mut(|storage| {
+        let id = storage.next_credential_record_id();
+        storage.add_credential_record(CredentialIssuanceRecord::new(
+            id,
+            student.id.clone(),
+            request.credential_spec.credential_type.clone(),
+        ));
+    });
+
     Ok(IssuedCredentialData {
         vc_jws: vc_jwt,
     })
@@ -131,7 +156,7 @@ fn get_credential(request: GetCredentialRequest) -> StudiFiResult<IssuedCredenti
 // ============================================================================
 
 /// Add a student to university records
-#[update]
+#[update(guard = "require_issue_credentials")]
 #[candid_method(update)]
 fn add_student(
     student_id: String,
@@ -184,7 +209,7 @@ fn get_platform_stats() -> Statistics {
         Statistics {
             total_count,
             active_count,
-            completed_count: active_count, // For simplicity
+            completed_count: storage.count_valid_credentials() as u32,
             failed_count: 0,
             total_amount: 0,
             average_amount: 0,
@@ -257,6 +282,27 @@ fn base64_encode(input: &str) -> String {
     format!("base64_{}", input.len())
 }
 
+/// Re-sync the cached role assignments used by authorization guards
+#[update(guard = "require_manage_system")]
+#[candid_method(update)]
+async fn refresh_role_cache() -> StudiFiResult<u32> {
+    sync_role_cache().await
+}
+
+/// Get the canister registry used for inter-canister calls
+#[query]
+#[candid_method(query)]
+fn get_canister_registry() -> CanisterRegistry {
+    current_canister_registry()
+}
+
+/// Get the health of downstream canisters this canister has called since its last upgrade
+#[query]
+#[candid_method(query)]
+fn get_circuit_breakers() -> Vec<CircuitBreaker> {
+    circuit_breaker_states()
+}
+
 // Export Candid interface
 candid::export_service!();
 
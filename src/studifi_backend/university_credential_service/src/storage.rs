@@ -1,80 +1,59 @@
-use candid::Principal;
 use ic_stable_structures::{
-    DefaultMemoryImpl, RestrictedMemory, StableBTreeMap, Storable,
-    storable::Bound,
+    memory_manager::{MemoryId, VirtualMemory},
+    DefaultMemoryImpl,
 };
-use std::borrow::Cow;
 use std::cell::RefCell;
 
 use crate::types::*;
 use shared::*;
 
 // Memory management for stable storage
-type Memory = RestrictedMemory<DefaultMemoryImpl>;
+type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 // Define memory IDs for different data structures
-const STUDENTS_MEMORY_ID: u64 = 0;
-const UNIVERSITY_CONFIG_MEMORY_ID: u64 = 1;
-const CREDENTIAL_RECORDS_MEMORY_ID: u64 = 2;
-
-// Implement Storable for Student
-impl Storable for Student {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+const STUDENTS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const UNIVERSITY_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(1);
+const CREDENTIAL_RECORDS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const CANISTER_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+// Stable record version for Student
+impl VersionedRecord for Student {
+    const VERSION: u16 = 1;
 }
 
-// Implement Storable for UniversityConfig
-impl Storable for UniversityConfig {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for UniversityConfig
+impl VersionedRecord for UniversityConfig {
+    const VERSION: u16 = 1;
 }
 
-// Implement Storable for CredentialIssuanceRecord
-impl Storable for CredentialIssuanceRecord {
-    const BOUND: Bound = Bound::Unbounded;
-
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
-    }
+// Stable record version for CredentialIssuanceRecord
+impl VersionedRecord for CredentialIssuanceRecord {
+    const VERSION: u16 = 1;
 }
 
 // Storage structure
 pub struct UniversityStorage {
-    pub students: StableBTreeMap<String, Student, Memory>,
-    pub university_config: StableBTreeMap<String, UniversityConfig, Memory>,
-    pub credential_records: StableBTreeMap<String, CredentialIssuanceRecord, Memory>,
+    pub students: VersionedMap<String, Student, Memory>,
+    pub university_config: VersionedMap<String, UniversityConfig, Memory>,
+    pub credential_records: VersionedMap<String, CredentialIssuanceRecord, Memory>,
+    pub canister_registry: VersionedMap<String, CanisterRegistry, Memory>,
 }
 
 impl UniversityStorage {
-    pub fn new() -> Self {
+    pub fn init(memory: DefaultMemoryImpl) -> Self {
+        // Canisters installed before the MemoryManager layout kept each map in its own page
+        let memory_manager = init_memory_manager(memory, |memory| vec![
+            read_legacy_map::<String, _>(memory, 0, STUDENTS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 1, UNIVERSITY_CONFIG_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 2, CREDENTIAL_RECORDS_MEMORY_ID),
+            read_legacy_map::<String, _>(memory, 3, CANISTER_REGISTRY_MEMORY_ID),
+        ]);
+
         Self {
-            students: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), STUDENTS_MEMORY_ID..STUDENTS_MEMORY_ID + 1)
-            ),
-            university_config: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), UNIVERSITY_CONFIG_MEMORY_ID..UNIVERSITY_CONFIG_MEMORY_ID + 1)
-            ),
-            credential_records: StableBTreeMap::init(
-                RestrictedMemory::new(DefaultMemoryImpl::default(), CREDENTIAL_RECORDS_MEMORY_ID..CREDENTIAL_RECORDS_MEMORY_ID + 1)
-            ),
+            students: VersionedMap::init(memory_manager.get(STUDENTS_MEMORY_ID)),
+            university_config: VersionedMap::init(memory_manager.get(UNIVERSITY_CONFIG_MEMORY_ID)),
+            credential_records: VersionedMap::init(memory_manager.get(CREDENTIAL_RECORDS_MEMORY_ID)),
+            canister_registry: VersionedMap::init(memory_manager.get(CANISTER_REGISTRY_MEMORY_ID)),
         }
     }
 
@@ -153,6 +132,12 @@ impl UniversityStorage {
         self.credential_records.get(&id.to_string())
     }
 
+    /// Id for the next issuance record, after the highest one stored
+    pub fn next_credential_record_id(&self) -> String {
+        let last = max_generated_id("CRED", self.credential_records.iter().map(|(id, _)| id));
+        generate_id("CRED", last + 1)
+    }
+
     pub fn add_credential_record(&mut self, record: CredentialIssuanceRecord) -> Option<CredentialIssuanceRecord> {
         let id = record.id.clone();
         self.credential_records.insert(id, record)
@@ -265,11 +250,21 @@ impl UniversityStorage {
             false
         }
     }
+
+    pub fn get_canister_registry(&self) -> CanisterRegistry {
+        self.canister_registry
+            .get(&"default".to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_canister_registry(&mut self, registry: CanisterRegistry) {
+        self.canister_registry.insert("default".to_string(), registry);
+    }
 }
 
 // Thread-local storage
 thread_local! {
-    static STORAGE: RefCell<UniversityStorage> = RefCell::new(UniversityStorage::new());
+    static STORAGE: RefCell<UniversityStorage> = RefCell::new(UniversityStorage::init(DefaultMemoryImpl::default()));
 }
 
 // Storage access functions
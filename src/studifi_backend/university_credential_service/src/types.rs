@@ -194,9 +194,9 @@ pub struct CredentialIssuanceRecord {
 }
 
 impl CredentialIssuanceRecord {
-    pub fn new(student_id: String, credential_type: String) -> Self {
+    pub fn new(id: String, student_id: String, credential_type: String) -> Self {
         Self {
-            id: generate_id("CRED", 1),
+            id,
             student_id,
             credential_type,
             issued_at: current_time(),